@@ -6,3 +6,4 @@
 #include "util/memstream.h"
 #include "rust_helpers.h"
 #include "nir.h"
+#include "nir_serialize.h"
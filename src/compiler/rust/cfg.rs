@@ -13,6 +13,10 @@ pub struct CFGNode<N> {
     dom_pre_idx: usize,
     dom_post_idx: usize,
     lph: usize,
+    pdom: usize,
+    pdom_pre_idx: usize,
+    pdom_post_idx: usize,
+    loop_idx: usize,
     pred: Vec<usize>,
     succ: Vec<usize>,
 }
@@ -143,9 +147,16 @@ fn calc_dominance<N>(nodes: &mut Vec<CFGNode<N>>) {
     loop {
         let mut changed = false;
         for i in 1..nodes.len() {
-            let mut dom = nodes[i].pred[0];
-            for p in &nodes[i].pred[1..] {
-                if nodes[*p].dom != usize::MAX {
+            // The first predecessor may be a back-edge which we haven't
+            // processed yet so start from whichever one we have.
+            let mut dom = usize::MAX;
+            for p in &nodes[i].pred {
+                if nodes[*p].dom == usize::MAX {
+                    continue;
+                }
+                if dom == usize::MAX {
+                    dom = *p;
+                } else {
                     dom = find_common_dom(nodes, dom, *p);
                 }
             }
@@ -176,6 +187,137 @@ fn calc_dominance<N>(nodes: &mut Vec<CFGNode<N>>) {
     debug_assert!(count == nodes.len() * 2);
 }
 
+fn reverse_post_dfs<N>(
+    nodes: &Vec<CFGNode<N>>,
+    id: usize,
+    seen: &mut BitSet,
+    post_order: &mut Vec<usize>,
+) {
+    if seen.get(id) {
+        return;
+    }
+    seen.insert(id);
+
+    // The virtual exit node has index nodes.len() and its successors in the
+    // reverse graph are all the nodes with no successors.
+    if id == nodes.len() {
+        for i in 0..nodes.len() {
+            if nodes[i].succ.is_empty() {
+                reverse_post_dfs(nodes, i, seen, post_order);
+            }
+        }
+    } else {
+        for p in nodes[id].pred.iter() {
+            reverse_post_dfs(nodes, *p, seen, post_order);
+        }
+    }
+
+    post_order.push(id);
+}
+
+fn pdom_idx_dfs<N>(
+    nodes: &mut Vec<CFGNode<N>>,
+    pdom_children: &Vec<Vec<usize>>,
+    id: usize,
+    count: &mut usize,
+) {
+    if id < nodes.len() {
+        nodes[id].pdom_pre_idx = *count;
+    }
+    *count += 1;
+
+    for c in pdom_children[id].iter() {
+        pdom_idx_dfs(nodes, pdom_children, *c, count);
+    }
+
+    if id < nodes.len() {
+        nodes[id].pdom_post_idx = *count;
+    }
+    *count += 1;
+}
+
+/// Computes post-dominance by running the dominance algorithm on the reverse
+/// graph, rooted at a virtual exit node which succeeds every node with no
+/// successors.  Nodes which cannot reach an exit, such as those in infinite
+/// loops, get no post-dominator.
+fn calc_post_dominance<N>(nodes: &mut Vec<CFGNode<N>>) {
+    let exit = nodes.len();
+
+    let mut seen = BitSet::new();
+    let mut post_order = Vec::new();
+    reverse_post_dfs(nodes, exit, &mut seen, &mut post_order);
+
+    // Reverse post-order index of each node, counting the exit
+    let mut rpo_idx = Vec::new();
+    rpo_idx.resize(exit + 1, usize::MAX);
+    for (i, id) in post_order.iter().rev().enumerate() {
+        rpo_idx[*id] = i;
+    }
+    assert!(rpo_idx[exit] == 0);
+
+    let mut pdom = Vec::new();
+    pdom.resize(exit + 1, usize::MAX);
+    pdom[exit] = exit;
+
+    let find_common_pdom = |pdom: &Vec<usize>, mut a: usize, mut b: usize| {
+        while a != b {
+            while rpo_idx[a] > rpo_idx[b] {
+                a = pdom[a];
+            }
+            while rpo_idx[b] > rpo_idx[a] {
+                b = pdom[b];
+            }
+        }
+        a
+    };
+
+    loop {
+        let mut changed = false;
+        for id in post_order.iter().rev().skip(1) {
+            let succ = &nodes[*id].succ;
+            if succ.is_empty() {
+                pdom[*id] = exit;
+                continue;
+            }
+
+            let mut new_pdom = usize::MAX;
+            for s in succ.iter().copied() {
+                if pdom[s] == usize::MAX {
+                    continue;
+                }
+                if new_pdom == usize::MAX {
+                    new_pdom = s;
+                } else {
+                    new_pdom = find_common_pdom(&pdom, new_pdom, s);
+                }
+            }
+            assert!(new_pdom != usize::MAX);
+            if pdom[*id] != new_pdom {
+                pdom[*id] = new_pdom;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut pdom_children = Vec::new();
+    pdom_children.resize(exit + 1, Vec::new());
+
+    for i in 0..exit {
+        nodes[i].pdom = pdom[i];
+        if pdom[i] != usize::MAX {
+            pdom_children[pdom[i]].push(i);
+        }
+    }
+
+    let mut count = 0_usize;
+    pdom_idx_dfs(nodes, &pdom_children, exit, &mut count);
+    debug_assert!(count == post_order.len() * 2);
+}
+
 fn loop_detect_dfs<N>(
     nodes: &Vec<CFGNode<N>>,
     id: usize,
@@ -223,8 +365,203 @@ fn detect_loops<N>(nodes: &mut Vec<CFGNode<N>>) -> bool {
     has_loop
 }
 
+fn loop_forest_dfs<N>(
+    nodes: &Vec<CFGNode<N>>,
+    id: usize,
+    pre_idx: &mut Vec<usize>,
+    last_idx: &mut Vec<usize>,
+    count: &mut usize,
+) {
+    pre_idx[id] = *count;
+    *count += 1;
+
+    // Walk successors in the same order as loop_detect_dfs() so that we find
+    // the same loop headers, even in irreducible control-flow.
+    for s in nodes[id].succ.iter() {
+        if pre_idx[*s] == usize::MAX {
+            loop_forest_dfs(nodes, *s, pre_idx, last_idx, count);
+        }
+    }
+
+    last_idx[id] = *count - 1;
+}
+
+fn union_find_root(parent: &mut [usize], mut id: usize) -> usize {
+    let mut root = id;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    while parent[id] != root {
+        let next = parent[id];
+        parent[id] = root;
+        id = next;
+    }
+    root
+}
+
+/// A loop in the loop-nesting forest of a CFG
+pub struct CFGLoop {
+    /// The index of the loop header.  For irreducible loops, which can be
+    /// entered through more than one block, this is the first entry reached
+    /// by a depth-first search of the CFG.
+    pub header: usize,
+    /// The index in CFG::loops() of the innermost loop containing this one
+    pub parent: Option<usize>,
+    /// The number of loops containing this one, including itself
+    pub depth: usize,
+    /// True if the loop can be entered through a block other than its header
+    pub irreducible: bool,
+    /// The blocks outside the loop with a predecessor inside it, in order
+    pub exits: Vec<usize>,
+}
+
+/// Computes the loop-nesting forest using Havlak's algorithm, which also
+/// handles irreducible loops.  Loops are sorted so that every loop comes after
+/// the loops which contain it.
+fn calc_loop_forest<N>(nodes: &mut Vec<CFGNode<N>>) -> Vec<CFGLoop> {
+    let mut pre_idx = Vec::new();
+    pre_idx.resize(nodes.len(), usize::MAX);
+    let mut last_idx = Vec::new();
+    last_idx.resize(nodes.len(), usize::MAX);
+    let mut count = 0_usize;
+    loop_forest_dfs(nodes, 0, &mut pre_idx, &mut last_idx, &mut count);
+    assert!(count == nodes.len());
+
+    let is_ancestor = |a: usize, b: usize| {
+        pre_idx[a] <= pre_idx[b] && pre_idx[b] <= last_idx[a]
+    };
+
+    let mut back_preds = Vec::new();
+    back_preds.resize(nodes.len(), Vec::new());
+    let mut non_back_preds = Vec::new();
+    non_back_preds.resize(nodes.len(), Vec::new());
+    for (i, n) in nodes.iter().enumerate() {
+        for p in n.pred.iter() {
+            if is_ancestor(i, *p) {
+                back_preds[i].push(*p);
+            } else {
+                non_back_preds[i].push(*p);
+            }
+        }
+    }
+
+    let mut by_pre_idx = Vec::new();
+    by_pre_idx.resize(nodes.len(), usize::MAX);
+    for (i, pre) in pre_idx.iter().enumerate() {
+        by_pre_idx[*pre] = i;
+    }
+
+    // For each node, the header of the innermost loop which contains it,
+    // not counting the loop it is the header of.
+    let mut header = Vec::new();
+    header.resize(nodes.len(), usize::MAX);
+    let mut is_header = BitSet::new();
+    let mut is_irreducible = BitSet::new();
+
+    let mut union_find = Vec::from_iter(0..nodes.len());
+    let mut in_body = BitSet::new();
+    for w in by_pre_idx.iter().rev().copied() {
+        in_body.clear();
+        let mut body = Vec::new();
+        for v in back_preds[w].iter() {
+            if *v == w {
+                is_header.insert(w);
+            } else {
+                let v = union_find_root(&mut union_find, *v);
+                if in_body.insert(v) {
+                    body.push(v);
+                }
+            }
+        }
+
+        let mut worklist = body.clone();
+        while let Some(x) = worklist.pop() {
+            for j in 0..non_back_preds[x].len() {
+                let y = union_find_root(&mut union_find, non_back_preds[x][j]);
+                if !is_ancestor(w, y) {
+                    // An edge into the loop which does not go through w
+                    is_irreducible.insert(w);
+                    non_back_preds[w].push(y);
+                } else if y != w && in_body.insert(y) {
+                    body.push(y);
+                    worklist.push(y);
+                }
+            }
+        }
+
+        if !body.is_empty() {
+            is_header.insert(w);
+        }
+        for x in body {
+            header[x] = w;
+            union_find[x] = w;
+        }
+    }
+
+    let mut headers = Vec::from_iter(is_header.iter());
+    headers.sort_by_key(|h| pre_idx[*h]);
+
+    let mut loop_idx = Vec::new();
+    loop_idx.resize(nodes.len(), usize::MAX);
+    let mut loops: Vec<CFGLoop> = Vec::new();
+    for h in headers {
+        let parent = if header[h] == usize::MAX {
+            None
+        } else {
+            Some(loop_idx[header[h]])
+        };
+        let depth = parent.map_or(0, |p| loops[p].depth) + 1;
+        loop_idx[h] = loops.len();
+        loops.push(CFGLoop {
+            header: h,
+            parent: parent,
+            depth: depth,
+            irreducible: is_irreducible.get(h),
+            exits: Vec::new(),
+        });
+    }
+
+    for i in 0..nodes.len() {
+        nodes[i].loop_idx = if is_header.get(i) {
+            loop_idx[i]
+        } else if header[i] != usize::MAX {
+            loop_idx[header[i]]
+        } else {
+            usize::MAX
+        };
+    }
+
+    let loop_contains = |loops: &Vec<CFGLoop>, l: usize, id: usize| {
+        let mut i = nodes[id].loop_idx;
+        while i != usize::MAX {
+            if i == l {
+                return true;
+            }
+            i = loops[i].parent.unwrap_or(usize::MAX);
+        }
+        false
+    };
+
+    for n in nodes.iter() {
+        for s in n.succ.iter() {
+            let mut l = n.loop_idx;
+            while l != usize::MAX && !loop_contains(&loops, l, *s) {
+                loops[l].exits.push(*s);
+                l = loops[l].parent.unwrap_or(usize::MAX);
+            }
+        }
+    }
+    for l in loops.iter_mut() {
+        l.exits.sort();
+        l.exits.dedup();
+    }
+
+    loops
+}
+
 pub struct CFG<N> {
     has_loop: bool,
+    loops: Vec<CFGLoop>,
     nodes: Vec<CFGNode<N>>,
 }
 
@@ -239,6 +576,10 @@ impl<N> CFG<N> {
             dom_pre_idx: usize::MAX,
             dom_post_idx: 0,
             lph: usize::MAX,
+            pdom: usize::MAX,
+            pdom_pre_idx: usize::MAX,
+            pdom_post_idx: 0,
+            loop_idx: usize::MAX,
             pred: Vec::new(),
             succ: Vec::new(),
         }));
@@ -250,10 +591,13 @@ impl<N> CFG<N> {
 
         rev_post_order_sort(&mut nodes);
         calc_dominance(&mut nodes);
+        calc_post_dominance(&mut nodes);
         let has_loop = detect_loops(&mut nodes);
+        let loops = calc_loop_forest(&mut nodes);
 
         CFG {
             has_loop: has_loop,
+            loops: loops,
             nodes: nodes,
         }
     }
@@ -302,6 +646,77 @@ impl<N> CFG<N> {
             && self.dom_dfs_post_index(child) <= self.dom_dfs_post_index(parent)
     }
 
+    pub fn pdom_dfs_pre_index(&self, idx: usize) -> usize {
+        self.nodes[idx].pdom_pre_idx
+    }
+
+    pub fn pdom_dfs_post_index(&self, idx: usize) -> usize {
+        self.nodes[idx].pdom_post_idx
+    }
+
+    /// Returns the immediate post-dominator of a block or None if it is only
+    /// post-dominated by the virtual exit or never reaches an exit
+    pub fn pdom_parent_index(&self, idx: usize) -> Option<usize> {
+        let pdom = self.nodes[idx].pdom;
+        if pdom < self.nodes.len() {
+            Some(pdom)
+        } else {
+            None
+        }
+    }
+
+    pub fn post_dominates(&self, parent: usize, child: usize) -> bool {
+        // Same trick as dominates().  A block which can't reach an exit is
+        // vacuously post-dominated by every block.
+        self.pdom_dfs_pre_index(child) >= self.pdom_dfs_pre_index(parent)
+            && self.pdom_dfs_post_index(child)
+                <= self.pdom_dfs_post_index(parent)
+    }
+
+    /// Computes the dominance frontier of every block
+    pub fn dominance_frontiers(&self) -> Vec<BitSet> {
+        let mut df = Vec::new();
+        df.resize(self.nodes.len(), BitSet::new());
+        for (b, n) in self.nodes.iter().enumerate() {
+            // The entry block has no dominator to stop at, so any loop back
+            // to it puts it in the frontier of everything on the way.
+            let stop = if b == 0 { usize::MAX } else { n.dom };
+            for p in n.pred.iter() {
+                let mut r = *p;
+                while r != stop {
+                    df[r].insert(b);
+                    if r == 0 {
+                        break;
+                    }
+                    r = self.nodes[r].dom;
+                }
+            }
+        }
+        df
+    }
+
+    /// Computes the post-dominance frontier of every block.  Block b is in
+    /// the post-dominance frontier of block a if and only if a is control
+    /// dependent on b.
+    pub fn post_dominance_frontiers(&self) -> Vec<BitSet> {
+        let exit = self.nodes.len();
+        let mut pdf = Vec::new();
+        pdf.resize(self.nodes.len(), BitSet::new());
+        for (b, n) in self.nodes.iter().enumerate() {
+            if n.pdom == usize::MAX {
+                continue;
+            }
+            for s in n.succ.iter() {
+                let mut r = *s;
+                while r != n.pdom && r != exit && r != usize::MAX {
+                    pdf[r].insert(b);
+                    r = self.nodes[r].pdom;
+                }
+            }
+        }
+        pdf
+    }
+
     pub fn has_loop(&self) -> bool {
         self.has_loop
     }
@@ -320,6 +735,42 @@ impl<N> CFG<N> {
         }
     }
 
+    /// Returns the loop-nesting forest, with outer loops before the loops they
+    /// contain.  Unlike is_loop_header() and friends, this treats the entry
+    /// block like any other.
+    pub fn loops(&self) -> &[CFGLoop] {
+        &self.loops
+    }
+
+    pub fn has_irreducible_loop(&self) -> bool {
+        self.loops.iter().any(|l| l.irreducible)
+    }
+
+    /// Returns the index in loops() of the innermost loop containing a block
+    pub fn loop_index(&self, idx: usize) -> Option<usize> {
+        let l = self.nodes[idx].loop_idx;
+        if l == usize::MAX {
+            None
+        } else {
+            Some(l)
+        }
+    }
+
+    pub fn loop_depth(&self, idx: usize) -> usize {
+        self.loop_index(idx).map_or(0, |l| self.loops[l].depth)
+    }
+
+    pub fn loop_contains(&self, loop_idx: usize, idx: usize) -> bool {
+        let mut l = self.loop_index(idx);
+        while let Some(i) = l {
+            if i == loop_idx {
+                return true;
+            }
+            l = self.loops[i].parent;
+        }
+        false
+    }
+
     pub fn succ_indices(&self, idx: usize) -> &[usize] {
         &self.nodes[idx].succ[..]
     }
@@ -330,6 +781,7 @@ impl<N> CFG<N> {
 
     pub fn drain(&mut self) -> impl Iterator<Item = N> + '_ {
         self.has_loop = false;
+        self.loops.clear();
         self.nodes.drain(..).map(|n| n.node)
     }
 }
@@ -402,8 +854,328 @@ impl<K: Eq + Hash, N> CFGBuilder<K, N> {
     }
 }
 
+impl<K: Clone + Eq + Hash, N> CFGBuilder<K, N> {
+    /// Returns the set of builder node indices in the loop headed by the
+    /// given node, which must be the header of a reducible loop
+    fn loop_body(&self, header: &K) -> BitSet {
+        let edges = self
+            .edges
+            .iter()
+            .map(|(s, p)| (self.key_map[s], self.key_map[p]));
+        let cfg = CFG::from_blocks_edges(0..self.nodes.len(), edges);
+
+        let h = self.key_map[header];
+        let h = (0..cfg.len()).find(|i| cfg[*i] == h).unwrap();
+        let l = cfg.loop_index(h).unwrap();
+        assert!(cfg.loops()[l].header == h, "Not a loop header");
+        assert!(!cfg.loops()[l].irreducible);
+
+        let mut body = BitSet::new();
+        for i in 0..cfg.len() {
+            if cfg.loop_contains(l, i) {
+                body.insert(cfg[i]);
+            }
+        }
+        body
+    }
+
+    /// Inserts a new node which becomes the only predecessor of the loop
+    /// header from outside the loop.  Edges from outside the loop are
+    /// redirected to the preheader, which then falls through to the header.
+    /// If the header is the entry node, the preheader becomes the new entry.
+    pub fn insert_loop_preheader(&mut self, header: &K, k: K, n: N) {
+        let body = self.loop_body(header);
+        let h = self.key_map[header];
+        for (s, p) in self.edges.iter_mut() {
+            if self.key_map[p] == h && !body.get(self.key_map[s]) {
+                *p = k.clone();
+            }
+        }
+        self.edges.push((k.clone(), header.clone()));
+
+        if h == 0 {
+            // The entry is always the first node
+            for i in self.key_map.values_mut() {
+                *i += 1;
+            }
+            self.key_map.insert(k, 0);
+            self.nodes.insert(0, n);
+        } else {
+            self.add_node(k, n);
+        }
+    }
+
+    /// Inserts a new node on every edge from inside the loop to the given
+    /// exit so that the exit block is only reached through it.
+    pub fn insert_loop_exit(&mut self, header: &K, exit: &K, k: K, n: N) {
+        let body = self.loop_body(header);
+        let e = self.key_map[exit];
+        assert!(!body.get(e), "Not a loop exit");
+        for (s, p) in self.edges.iter_mut() {
+            if self.key_map[p] == e && body.get(self.key_map[s]) {
+                *p = k.clone();
+            }
+        }
+        self.edges.push((k.clone(), exit.clone()));
+        self.add_node(k, n);
+    }
+}
+
 impl<K, N> Default for CFGBuilder<K, N> {
     fn default() -> Self {
         CFGBuilder::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(n: usize, edges: &[(usize, usize)]) -> CFG<usize> {
+        CFG::from_blocks_edges(0..n, edges.iter().copied())
+    }
+
+    /// Maps a node from the input graph to its index in the CFG
+    fn idx(cfg: &CFG<usize>, n: usize) -> usize {
+        (0..cfg.len()).find(|i| cfg[*i] == n).unwrap()
+    }
+
+    fn to_nodes(cfg: &CFG<usize>, set: &BitSet) -> Vec<usize> {
+        let mut v = Vec::from_iter(set.iter().map(|i| cfg[i]));
+        v.sort();
+        v
+    }
+
+    fn pdom(cfg: &CFG<usize>, n: usize) -> Option<usize> {
+        cfg.pdom_parent_index(idx(cfg, n)).map(|i| cfg[i])
+    }
+
+    fn loop_of(cfg: &CFG<usize>, n: usize) -> Option<&CFGLoop> {
+        cfg.loop_index(idx(cfg, n)).map(|l| &cfg.loops()[l])
+    }
+
+    #[test]
+    fn test_post_dominance() {
+        //   0
+        //  / \
+        // 1   2
+        //  \ / \
+        //   3   4
+        //    \ /
+        //     5
+        let c =
+            cfg(6, &[(0, 1), (0, 2), (1, 3), (2, 3), (2, 4), (3, 5), (4, 5)]);
+        assert_eq!(pdom(&c, 0), Some(5));
+        assert_eq!(pdom(&c, 1), Some(3));
+        assert_eq!(pdom(&c, 2), Some(5));
+        assert_eq!(pdom(&c, 3), Some(5));
+        assert_eq!(pdom(&c, 4), Some(5));
+        assert_eq!(pdom(&c, 5), None);
+
+        assert!(c.post_dominates(idx(&c, 5), idx(&c, 0)));
+        assert!(c.post_dominates(idx(&c, 3), idx(&c, 1)));
+        assert!(c.post_dominates(idx(&c, 3), idx(&c, 3)));
+        assert!(!c.post_dominates(idx(&c, 3), idx(&c, 2)));
+        assert!(!c.post_dominates(idx(&c, 1), idx(&c, 3)));
+    }
+
+    #[test]
+    fn test_post_dominance_multiple_exits() {
+        // 0 -> 1 -> 2 (exit)
+        //       \-> 3 (exit)
+        // 3 never returns: 3 -> 4 -> 3
+        let c = cfg(5, &[(0, 1), (1, 2), (1, 3), (3, 4), (4, 3)]);
+        assert_eq!(pdom(&c, 0), Some(1));
+        assert_eq!(pdom(&c, 1), Some(2));
+        assert_eq!(pdom(&c, 2), None);
+        assert_eq!(pdom(&c, 3), None);
+        assert_eq!(pdom(&c, 4), None);
+
+        let c = cfg(4, &[(0, 1), (0, 2), (1, 3)]);
+        assert_eq!(pdom(&c, 0), None);
+        assert_eq!(pdom(&c, 1), Some(3));
+    }
+
+    #[test]
+    fn test_dominance_frontiers() {
+        // 0 -> 1 -> 2 -> 4 -> 1
+        //       \-> 3 -/    \-> 5
+        let c =
+            cfg(6, &[(0, 1), (1, 2), (1, 3), (2, 4), (3, 4), (4, 1), (4, 5)]);
+        let df = c.dominance_frontiers();
+        let df = |n| to_nodes(&c, &df[idx(&c, n)]);
+        assert_eq!(df(0), &[]);
+        assert_eq!(df(1), &[1]);
+        assert_eq!(df(2), &[4]);
+        assert_eq!(df(3), &[4]);
+        assert_eq!(df(4), &[1]);
+        assert_eq!(df(5), &[]);
+
+        // A loop back to the entry
+        let c = cfg(2, &[(0, 1), (1, 0)]);
+        let df = c.dominance_frontiers();
+        assert_eq!(to_nodes(&c, &df[0]), &[0]);
+        assert_eq!(to_nodes(&c, &df[1]), &[0]);
+    }
+
+    #[test]
+    fn test_post_dominance_frontiers() {
+        // if (0) { 1 } else { 2; if (2) { 3 } } 4
+        let c = cfg(5, &[(0, 1), (0, 2), (1, 4), (2, 3), (2, 4), (3, 4)]);
+        let pdf = c.post_dominance_frontiers();
+        let pdf = |n| to_nodes(&c, &pdf[idx(&c, n)]);
+        assert_eq!(pdf(0), &[]);
+        assert_eq!(pdf(1), &[0]);
+        assert_eq!(pdf(2), &[0]);
+        assert_eq!(pdf(3), &[2]);
+        assert_eq!(pdf(4), &[]);
+
+        // The body of a loop is control dependent on the loop condition
+        let c = cfg(4, &[(0, 1), (1, 2), (2, 1), (1, 3)]);
+        let pdf = c.post_dominance_frontiers();
+        assert_eq!(to_nodes(&c, &pdf[idx(&c, 1)]), &[1]);
+        assert_eq!(to_nodes(&c, &pdf[idx(&c, 2)]), &[1]);
+    }
+
+    #[test]
+    fn test_loop_nesting() {
+        // 0 -> 1 -> 2 -> 3 -> 2
+        //           ^    |
+        //           |    v
+        //      6 <- 5 <- 4
+        //           \--> 1 (outer back-edge)
+        let c = cfg(
+            7,
+            &[
+                (0, 1),
+                (1, 2),
+                (2, 3),
+                (3, 2),
+                (3, 4),
+                (4, 5),
+                (5, 1),
+                (5, 6),
+            ],
+        );
+        assert_eq!(c.loops().len(), 2);
+        assert!(!c.has_irreducible_loop());
+
+        let outer = loop_of(&c, 1).unwrap();
+        assert_eq!(c[outer.header], 1);
+        assert_eq!(outer.parent, None);
+        assert_eq!(outer.depth, 1);
+        assert_eq!(outer.exits, &[idx(&c, 6)]);
+
+        let inner = loop_of(&c, 3).unwrap();
+        assert_eq!(c[inner.header], 2);
+        assert_eq!(inner.parent, c.loop_index(idx(&c, 1)));
+        assert_eq!(inner.depth, 2);
+        assert_eq!(inner.exits, &[idx(&c, 4)]);
+
+        let depths: Vec<usize> =
+            (0..7).map(|n| c.loop_depth(idx(&c, n))).collect();
+        assert_eq!(depths, &[0, 1, 2, 2, 1, 1, 0]);
+
+        let outer = c.loop_index(idx(&c, 1)).unwrap();
+        assert!(c.loop_contains(outer, idx(&c, 3)));
+        assert!(!c.loop_contains(outer, idx(&c, 6)));
+
+        // The headers agree with the existing loop detection
+        for l in c.loops() {
+            assert!(c.is_loop_header(l.header));
+        }
+    }
+
+    #[test]
+    fn test_self_loop() {
+        let c = cfg(3, &[(0, 1), (1, 1), (1, 2)]);
+        assert_eq!(c.loops().len(), 1);
+        let l = loop_of(&c, 1).unwrap();
+        assert_eq!(c[l.header], 1);
+        assert_eq!(l.exits, &[idx(&c, 2)]);
+        assert_eq!(c.loop_depth(idx(&c, 2)), 0);
+    }
+
+    #[test]
+    fn test_irreducible_loop() {
+        // 1 and 2 form a loop which can be entered at either block
+        let c = cfg(4, &[(0, 1), (0, 2), (1, 2), (2, 1), (2, 3)]);
+        assert!(c.has_irreducible_loop());
+        assert_eq!(c.loops().len(), 1);
+
+        let l = &c.loops()[0];
+        assert!(l.irreducible);
+        assert!(c.loop_contains(0, idx(&c, 1)));
+        assert!(c.loop_contains(0, idx(&c, 2)));
+        assert!(!c.loop_contains(0, idx(&c, 0)));
+        assert_eq!(l.exits, &[idx(&c, 3)]);
+
+        // A reducible loop nested inside an irreducible one
+        let c =
+            cfg(5, &[(0, 1), (0, 2), (1, 2), (2, 3), (3, 3), (3, 1), (3, 4)]);
+        assert!(c.has_irreducible_loop());
+        assert_eq!(c.loops().len(), 2);
+        let inner = loop_of(&c, 3).unwrap();
+        assert!(!inner.irreducible);
+        assert_eq!(inner.depth, 2);
+        assert!(c.loops()[inner.parent.unwrap()].irreducible);
+    }
+
+    #[test]
+    fn test_insert_preheader_exit() {
+        // 0 -> 1 -> 2 -> 1
+        // 3 -> 1    2 -> 4
+        //      1 -> 4
+        let mut b = CFGBuilder::new();
+        for i in 0..5 {
+            b.add_node(i, i);
+        }
+        for (s, p) in [(0, 1), (0, 3), (3, 1), (1, 2), (2, 1), (2, 4), (1, 4)] {
+            b.add_edge(s, p);
+        }
+        b.insert_loop_preheader(&1, 10, 10);
+        b.insert_loop_exit(&1, &4, 11, 11);
+        let c = b.as_cfg();
+
+        let preds = |n| {
+            let mut v = Vec::from_iter(
+                c.pred_indices(idx(&c, n)).iter().map(|i| c[*i]),
+            );
+            v.sort();
+            v
+        };
+        assert_eq!(preds(1), &[2, 10]);
+        assert_eq!(preds(10), &[0, 3]);
+        assert_eq!(preds(4), &[11]);
+        assert_eq!(preds(11), &[1, 2]);
+
+        let l = loop_of(&c, 2).unwrap();
+        assert_eq!(c[l.header], 1);
+        assert_eq!(l.exits, &[idx(&c, 11)]);
+        assert_eq!(c.dom_parent_index(idx(&c, 1)), Some(idx(&c, 10)));
+    }
+
+    #[test]
+    fn test_insert_preheader_at_entry() {
+        // 0 -> 1 -> 0
+        //      1 -> 2
+        let mut b = CFGBuilder::new();
+        for i in 0..3 {
+            b.add_node(i, i);
+        }
+        for (s, p) in [(0, 1), (1, 0), (1, 2)] {
+            b.add_edge(s, p);
+        }
+        b.insert_loop_preheader(&0, 10, 10);
+        let c = b.as_cfg();
+
+        assert_eq!(c.len(), 4);
+        assert_eq!(c[0], 10);
+        assert_eq!(c.pred_indices(idx(&c, 10)), &[]);
+        assert_eq!(c.succ_indices(idx(&c, 10)), &[idx(&c, 0)]);
+
+        let l = loop_of(&c, 1).unwrap();
+        assert_eq!(c[l.header], 0);
+        assert_eq!(c.dom_parent_index(idx(&c, 0)), Some(idx(&c, 10)));
+    }
+}
@@ -440,6 +440,16 @@ impl nir_instr {
             NonNull::new(def).map(|d| d.as_ref())
         }
     }
+
+    pub fn debug_info<'a>(&'a self) -> Option<&'a nir_instr_debug_info> {
+        if self.has_debug_info {
+            let p = self as *const nir_instr as *const u8;
+            let p = unsafe { p.sub(offset_of!(nir_instr_debug_info, instr)) };
+            Some(unsafe { &*(p as *const nir_instr_debug_info) })
+        } else {
+            None
+        }
+    }
 }
 
 impl nir_block {
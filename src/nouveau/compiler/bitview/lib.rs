@@ -5,6 +5,13 @@
 
 use std::ops::Range;
 
+pub use bitview_proc::BitStruct;
+
+// Lets the code generated by #[derive(BitStruct)] name this crate from
+// within its own tests
+#[cfg(test)]
+extern crate self as bitview;
+
 pub trait BitViewable {
     fn bits(&self) -> usize;
 
@@ -342,3 +349,175 @@ impl<T: SetFieldU64> SetField<i8> for T {
         self.set_field(range, i64::from(val));
     }
 }
+
+/// A non-integer type which can be stored in a #[derive(BitStruct)] field
+pub trait BitFieldEnum: Sized {
+    fn to_field_bits(self) -> u64;
+
+    /// Returns None if the bits don't encode a valid value
+    fn from_field_bits(bits: u64) -> Option<Self>;
+}
+
+pub fn ufixed_to_bits(val: f32, bits: usize, frac_bits: usize) -> u64 {
+    assert!(bits >= frac_bits && bits <= 32);
+    let scaled = val * ((1_u64 << frac_bits) as f32);
+    // The maximum may not be representable as an f32 so we clamp again as
+    // an integer after rounding.  Float to int casts saturate.
+    let scaled_max = (1_u64 << bits) - 1;
+    (scaled.max(0.0).round() as u64).min(scaled_max)
+}
+
+pub fn ufixed_from_bits(val: u64, frac_bits: usize) -> f32 {
+    (val as f32) / ((1_u64 << frac_bits) as f32)
+}
+
+pub fn sfixed_to_bits(val: f32, bits: usize, frac_bits: usize) -> u64 {
+    assert!(bits >= frac_bits && bits <= 32);
+    let scaled = val * ((1_u64 << frac_bits) as f32);
+    let scaled_max = (1_i64 << (bits - 1)) - 1;
+    let scaled_min = -(1_i64 << (bits - 1));
+    let i_val = (scaled.round() as i64).clamp(scaled_min, scaled_max);
+    (i_val as u64) & u64_mask_for_bits(bits)
+}
+
+pub fn sfixed_from_bits(val: u64, bits: usize, frac_bits: usize) -> f32 {
+    let shift = 64 - bits;
+    let i_val = ((val << shift) as i64) >> shift;
+    (i_val as f32) / ((1_u64 << frac_bits) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Filter {
+        Nearest,
+        Linear,
+    }
+
+    impl BitFieldEnum for Filter {
+        fn to_field_bits(self) -> u64 {
+            match self {
+                Filter::Nearest => 1,
+                Filter::Linear => 2,
+            }
+        }
+
+        fn from_field_bits(bits: u64) -> Option<Self> {
+            match bits {
+                1 => Some(Filter::Nearest),
+                2 => Some(Filter::Linear),
+                _ => None,
+            }
+        }
+    }
+
+    const LOD_BIAS: Range<u32> = 40..53;
+
+    #[derive(BitStruct, Default)]
+    #[bitfield(enable: bool = 0)]
+    #[bitfield(filter: Filter = 1..3)]
+    #[bitfield(count: u8 = 3..9)]
+    #[bitfield(offset: i8 = 9..14)]
+    #[bitfield(min_lod: ufixed<8> = 14..26)]
+    #[bitfield(lod_bias: sfixed<8> = LOD_BIAS)]
+    #[bitfield(border: f32 = 64..96)]
+    struct Sampler {
+        data: [u32; 3],
+    }
+
+    #[derive(BitStruct)]
+    #[bitfield(lo: u16 = 0..16)]
+    #[bitfield(hi: u16 = 16..32)]
+    struct Packed(u32);
+
+    #[derive(BitStruct)]
+    #[bitfield(mode: u8 = 0..4)]
+    struct Tagged {
+        #[storage]
+        bits: u8,
+        tag: u32,
+    }
+
+    #[test]
+    fn test_bitstruct_round_trip() {
+        let mut s = Sampler::default();
+        s.set_enable(true);
+        s.set_filter(Filter::Linear);
+        s.set_count(63);
+        s.set_offset(-16);
+        s.set_min_lod(3.25);
+        s.set_lod_bias(-2.5);
+        s.set_border(0.75);
+
+        assert!(s.enable());
+        assert_eq!(s.filter(), Filter::Linear);
+        assert_eq!(s.count(), 63);
+        assert_eq!(s.offset(), -16);
+        assert_eq!(s.min_lod(), 3.25);
+        assert_eq!(s.lod_bias(), -2.5);
+        assert_eq!(s.border(), 0.75);
+
+        // Fields don't clobber their neighbors
+        s.set_count(0);
+        assert!(s.enable());
+        assert_eq!(s.filter(), Filter::Linear);
+        assert_eq!(s.offset(), -16);
+
+        assert_eq!(s.data[0] & 0x1ff, 0x005);
+        assert_eq!(s.data[2], 0.75_f32.to_bits());
+
+        let mut p = Packed(0);
+        p.set_hi(0xabcd);
+        p.set_lo(0x1234);
+        assert_eq!(p.0, 0xabcd1234);
+
+        let mut t = Tagged { bits: 0xf0, tag: 7 };
+        t.set_mode(5);
+        assert_eq!(t.bits, 0xf5);
+        assert_eq!(t.tag, 7);
+    }
+
+    #[test]
+    fn test_bitstruct_fixed_clamp() {
+        let mut s = Sampler::default();
+        s.set_min_lod(-1.0);
+        assert_eq!(s.min_lod(), 0.0);
+        s.set_min_lod(1000.0);
+        assert_eq!(s.min_lod(), 4095.0 / 256.0);
+        s.set_lod_bias(-100.0);
+        assert_eq!(s.lod_bias(), -16.0);
+    }
+
+    #[test]
+    fn test_fixed_32bit_clamp() {
+        // The maximum of a 32-bit field rounds up to 2^32 as an f32
+        assert_eq!(ufixed_to_bits(1e12, 32, 8), u64::from(u32::MAX));
+        assert_eq!(ufixed_to_bits(-1.0, 32, 8), 0);
+        assert_eq!(sfixed_to_bits(1e12, 32, 8), 0x7fffffff);
+        assert_eq!(sfixed_to_bits(-1e12, 32, 8), 0x80000000);
+        assert_eq!(sfixed_to_bits(-1.0, 32, 8), 0xffffff00);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bitstruct_overflow() {
+        let mut s = Sampler::default();
+        s.set_offset(16);
+    }
+
+    #[test]
+    fn test_bitstruct_debug() {
+        let mut p = Packed(0);
+        p.set_lo(1);
+        p.set_hi(2);
+        assert_eq!(format!("{p:?}"), "Packed { lo: 1, hi: 2 }");
+
+        let mut s = Sampler::default();
+        s.set_filter(Filter::Nearest);
+        assert!(format!("{s:?}").contains("filter: Nearest,"));
+        s.data[0] = 0b110;
+        assert!(format!("{s:?}").contains("filter: <invalid 0x3>,"));
+    }
+}
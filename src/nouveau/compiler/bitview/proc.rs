@@ -0,0 +1,443 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::*;
+
+enum BitRange {
+    /// A range with literal bounds which we can check while expanding
+    Lit {
+        start: usize,
+        end: usize,
+        span: Span,
+    },
+    /// A path to a Range constant such as the ones in nvidia_headers
+    Path(Path),
+}
+
+impl BitRange {
+    fn start(&self) -> TokenStream2 {
+        match self {
+            BitRange::Lit { start, .. } => quote! { #start },
+            BitRange::Path(p) => quote! { (#p.start as usize) },
+        }
+    }
+
+    fn end(&self) -> TokenStream2 {
+        match self {
+            BitRange::Lit { end, .. } => quote! { #end },
+            BitRange::Path(p) => quote! { (#p.end as usize) },
+        }
+    }
+
+    fn range(&self) -> TokenStream2 {
+        let start = self.start();
+        let end = self.end();
+        quote! { #start..#end }
+    }
+
+    fn len(&self) -> TokenStream2 {
+        let start = self.start();
+        let end = self.end();
+        quote! { (#end - #start) }
+    }
+}
+
+enum FieldKind {
+    Bool,
+    UInt,
+    SInt,
+    F32,
+    UFixed(usize),
+    SFixed(usize),
+    Enum,
+}
+
+struct BitFieldDecl {
+    vis: Visibility,
+    name: Ident,
+    ty: Type,
+    kind: FieldKind,
+    range: BitRange,
+}
+
+fn fixed_frac_bits(seg: &PathSegment) -> Result<usize> {
+    if let PathArguments::AngleBracketed(args) = &seg.arguments {
+        if args.args.len() == 1 {
+            if let GenericArgument::Const(Expr::Lit(ExprLit {
+                lit: Lit::Int(i),
+                ..
+            })) = &args.args[0]
+            {
+                return i.base10_parse();
+            }
+        }
+    }
+    Err(Error::new(
+        seg.span(),
+        "Fixed-point fields need a number of fractional bits",
+    ))
+}
+
+impl Parse for BitFieldDecl {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let vis: Visibility = input.parse()?;
+        let name: Ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let mut ty: Type = input.parse()?;
+        input.parse::<Token![=]>()?;
+
+        let range = if input.peek(LitInt) {
+            let start_lit: LitInt = input.parse()?;
+            let start = start_lit.base10_parse()?;
+            let (end, span) = if input.peek(Token![..]) {
+                input.parse::<Token![..]>()?;
+                let end_lit: LitInt = input.parse()?;
+                (end_lit.base10_parse()?, end_lit.span())
+            } else {
+                // A single bit
+                (start + 1, start_lit.span())
+            };
+            if start >= end {
+                return Err(Error::new(span, "Bit ranges must not be empty"));
+            }
+            BitRange::Lit { start, end, span }
+        } else {
+            BitRange::Path(input.parse()?)
+        };
+
+        let kind = match &ty {
+            Type::Path(p) if p.qself.is_none() => {
+                let seg = p.path.segments.last().unwrap();
+                match seg.ident.to_string().as_str() {
+                    "bool" => FieldKind::Bool,
+                    "u8" | "u16" | "u32" | "u64" => FieldKind::UInt,
+                    "i8" | "i16" | "i32" | "i64" => FieldKind::SInt,
+                    "f32" => FieldKind::F32,
+                    "ufixed" => FieldKind::UFixed(fixed_frac_bits(seg)?),
+                    "sfixed" => FieldKind::SFixed(fixed_frac_bits(seg)?),
+                    _ => FieldKind::Enum,
+                }
+            }
+            _ => FieldKind::Enum,
+        };
+        if matches!(kind, FieldKind::UFixed(_) | FieldKind::SFixed(_)) {
+            ty = parse_quote! { f32 };
+        }
+
+        Ok(BitFieldDecl {
+            vis,
+            name,
+            ty,
+            kind,
+            range,
+        })
+    }
+}
+
+impl BitFieldDecl {
+    fn setter(&self) -> Ident {
+        format_ident!("set_{}", self.name)
+    }
+
+    /// Returns a const expression which is false if the field's type can't
+    /// hold the range
+    fn width_check(&self) -> TokenStream2 {
+        let ty = &self.ty;
+        let len = self.range.len();
+        match self.kind {
+            FieldKind::Bool => quote! { #len == 1 },
+            FieldKind::UInt | FieldKind::SInt => {
+                quote! { #len <= (#ty::BITS as usize) }
+            }
+            FieldKind::F32 => quote! { #len == 32 },
+            FieldKind::UFixed(frac) | FieldKind::SFixed(frac) => {
+                quote! { #len >= #frac && #len <= 32 }
+            }
+            FieldKind::Enum => quote! { #len <= 64 },
+        }
+    }
+
+    fn accessors(&self, storage: &TokenStream2) -> TokenStream2 {
+        let BitFieldDecl { vis, name, ty, .. } = self;
+        let setter = self.setter();
+        let range = self.range.range();
+        let len = self.range.len();
+
+        let raw = quote! {
+            ::bitview::BitViewable::get_bit_range_u64(&self.#storage, #range)
+        };
+        let set_field = quote! {
+            ::bitview::SetField::set_field(
+                &mut ::bitview::BitMutView::new(&mut self.#storage),
+                #range,
+                val,
+            )
+        };
+
+        let (get, set) = match self.kind {
+            FieldKind::Bool => (quote! { #raw != 0 }, set_field),
+            FieldKind::UInt => (quote! { #raw as #ty }, set_field),
+            FieldKind::SInt => (
+                quote! {
+                    let shift = 64 - #len;
+                    (((#raw << shift) as i64) >> shift) as #ty
+                },
+                set_field,
+            ),
+            FieldKind::F32 => {
+                (quote! { f32::from_bits(#raw as u32) }, set_field)
+            }
+            FieldKind::UFixed(frac) => (
+                quote! { ::bitview::ufixed_from_bits(#raw, #frac) },
+                quote! {
+                    let val = ::bitview::ufixed_to_bits(val, #len, #frac);
+                    ::bitview::SetFieldU64::set_field_u64(
+                        &mut ::bitview::BitMutView::new(&mut self.#storage),
+                        #range,
+                        val,
+                    )
+                },
+            ),
+            FieldKind::SFixed(frac) => (
+                quote! { ::bitview::sfixed_from_bits(#raw, #len, #frac) },
+                quote! {
+                    let val = ::bitview::sfixed_to_bits(val, #len, #frac);
+                    ::bitview::SetFieldU64::set_field_u64(
+                        &mut ::bitview::BitMutView::new(&mut self.#storage),
+                        #range,
+                        val,
+                    )
+                },
+            ),
+            FieldKind::Enum => {
+                let msg = format!("Invalid value for field {name}");
+                (
+                    quote! {
+                        <#ty as ::bitview::BitFieldEnum>::from_field_bits(#raw)
+                            .expect(#msg)
+                    },
+                    quote! {
+                        let val = ::bitview::BitFieldEnum::to_field_bits(val);
+                        ::bitview::SetFieldU64::set_field_u64(
+                            &mut ::bitview::BitMutView::new(&mut self.#storage),
+                            #range,
+                            val,
+                        )
+                    },
+                )
+            }
+        };
+
+        quote! {
+            #[inline]
+            #vis fn #name(&self) -> #ty {
+                #get
+            }
+
+            #[inline]
+            #vis fn #setter(&mut self, val: #ty) {
+                #set
+            }
+        }
+    }
+
+    fn fmt_field(&self, storage: &TokenStream2) -> TokenStream2 {
+        let BitFieldDecl { name, ty, .. } = self;
+        let name_str = name.to_string();
+        if let FieldKind::Enum = self.kind {
+            // Don't panic on garbage while debugging
+            let range = self.range.range();
+            quote! {
+                let raw = ::bitview::BitViewable::get_bit_range_u64(
+                    &self.#storage,
+                    #range,
+                );
+                match <#ty as ::bitview::BitFieldEnum>::from_field_bits(raw) {
+                    Some(v) => s.field(#name_str, &v),
+                    None => s.field(
+                        #name_str,
+                        &format_args!("<invalid {:#x}>", raw),
+                    ),
+                };
+            }
+        } else {
+            quote! {
+                s.field(#name_str, &self.#name());
+            }
+        }
+    }
+}
+
+fn find_storage(data: &Data) -> Result<(TokenStream2, Type)> {
+    let Data::Struct(s) = data else {
+        return Err(Error::new(Span::call_site(), "Not a struct type"));
+    };
+
+    let fields: Vec<_> = s.fields.iter().collect();
+    let marked: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.attrs.iter().any(|a| a.path().is_ident("storage")))
+        .collect();
+
+    let (i, f) = match (marked.len(), fields.len()) {
+        (1, _) => marked[0],
+        (0, 1) => (0, &fields[0]),
+        _ => {
+            return Err(Error::new(
+                s.fields.span(),
+                "Exactly one field must be marked #[storage]",
+            ))
+        }
+    };
+
+    let storage = match &f.ident {
+        Some(ident) => quote! { #ident },
+        None => {
+            let idx = Index::from(i);
+            quote! { #idx }
+        }
+    };
+    Ok((storage, f.ty.clone()))
+}
+
+fn derive_bitstruct_impl(input: DeriveInput) -> Result<TokenStream2> {
+    let DeriveInput {
+        attrs,
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+
+    let (storage, storage_ty) = find_storage(&data)?;
+
+    let mut fields: Vec<BitFieldDecl> = Vec::new();
+    for attr in attrs {
+        if attr.path().is_ident("bitfield") {
+            fields.push(attr.parse_args()?);
+        }
+    }
+
+    // Compile-time checks.  Overlaps between literal ranges are reported
+    // here so they point at the offending field, everything else is left to
+    // const evaluation.
+    let mut checks = TokenStream2::new();
+    for (i, a) in fields.iter().enumerate() {
+        for b in &fields[..i] {
+            if let (
+                BitRange::Lit { start, end, span },
+                BitRange::Lit {
+                    start: b_start,
+                    end: b_end,
+                    ..
+                },
+            ) = (&a.range, &b.range)
+            {
+                if start < b_end && b_start < end {
+                    return Err(Error::new(
+                        *span,
+                        format!("Field {} overlaps field {}", a.name, b.name),
+                    ));
+                }
+            } else {
+                let (a_start, a_end) = (a.range.start(), a.range.end());
+                let (b_start, b_end) = (b.range.start(), b.range.end());
+                let msg = format!(
+                    "Field {} of {ident} overlaps field {}",
+                    a.name, b.name
+                );
+                checks.extend(quote! {
+                    assert!(#a_end <= #b_start || #b_end <= #a_start, #msg);
+                });
+            }
+        }
+
+        let check = a.width_check();
+        let msg =
+            format!("Field {} of {ident} has the wrong number of bits", a.name);
+        checks.extend(quote! {
+            assert!(#check, #msg);
+        });
+
+        if let BitRange::Path(_) = a.range {
+            let (start, end) = (a.range.start(), a.range.end());
+            let msg = format!("Field {} of {ident} is empty", a.name);
+            checks.extend(quote! {
+                assert!(#start < #end, #msg);
+            });
+        }
+
+        // Generic storage can't be sized in a const
+        if generics.params.is_empty() {
+            let end = a.range.end();
+            let msg = format!("Field {} of {ident} is out of bounds", a.name);
+            checks.extend(quote! {
+                assert!(
+                    #end <= 8 * ::std::mem::size_of::<#storage_ty>(),
+                    #msg
+                );
+            });
+        }
+    }
+
+    let accessors: TokenStream2 =
+        fields.iter().map(|f| f.accessors(&storage)).collect();
+    let fmt_fields: TokenStream2 =
+        fields.iter().map(|f| f.fmt_field(&storage)).collect();
+    let name_str = ident.to_string();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        const _: () = {
+            #checks
+        };
+
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #accessors
+        }
+
+        impl #impl_generics ::std::fmt::Debug for #ident #ty_generics
+            #where_clause
+        {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                let mut s = f.debug_struct(#name_str);
+                #fmt_fields
+                s.finish()
+            }
+        }
+    })
+}
+
+/// Derives typed accessors for bit fields packed into a storage field
+///
+/// Each `#[bitfield(name: type = range)]` attribute on the struct declares
+/// a field stored in the bits `range` of the storage field, which is either
+/// the only field of the struct or the one marked `#[storage]`.  The range
+/// is `start..end`, a single bit index or a path to a `Range` constant.
+///
+/// `name()` and `set_name()` are generated for each field along with a
+/// `Debug` implementation which decodes them all.  The type may be `bool`,
+/// any sized integer type, `f32`, `ufixed<N>` or `sfixed<N>` for fixed-point
+/// values with `N` fractional bits accessed as `f32`, or any type which
+/// implements `BitFieldEnum`.
+///
+/// Overlapping fields, fields which don't fit their type and fields outside
+/// the storage are all compile-time errors.
+#[proc_macro_derive(BitStruct, attributes(bitfield, storage))]
+pub fn derive_bitstruct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_bitstruct_impl(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
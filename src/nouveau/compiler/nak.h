@@ -19,6 +19,29 @@ extern "C" {
 
 #define NAK_SUBGROUP_SIZE 32
 
+/* Instrumented shaders, such as those compiled with block profiling or
+ * NAK_DEBUG=sanitize, read the 64-bit addresses of the buffers they write to
+ * from a constant buffer.  If nak_needs_debug_cbuf() returns true, the driver
+ * has to reserve a constant buffer slot for it, pass that slot to
+ * nak_compile_shader() and bind NAK_DEBUG_CBUF_SIZE bytes there laid out as
+ * follows.
+ */
+
+/** The offset in the debug cbuf of the address of an array of
+ * nak_shader_bin::profile_block_count 32-bit block counters
+ */
+#define NAK_DEBUG_CBUF_PROFILE_ADDR 0
+
+/** The offset in the debug cbuf of the address of an array of
+ * nak_shader_bin::sanitize_check_count struct nak_sanitize_error
+ */
+#define NAK_DEBUG_CBUF_SANITIZE_ADDR 8
+
+#define NAK_DEBUG_CBUF_SIZE 16
+
+/** Passed to nak_compile_shader() if there is no debug cbuf */
+#define NAK_NO_DEBUG_CBUF -1
+
 struct nak_compiler;
 struct nv_device_info;
 
@@ -27,6 +50,9 @@ void nak_compiler_destroy(struct nak_compiler *nak);
 
 uint64_t nak_debug_flags(const struct nak_compiler *nak);
 
+/** Returns true if shaders may be instrumented and need a debug cbuf */
+bool nak_needs_debug_cbuf(const struct nak_compiler *nak);
+
 const struct nir_shader_compiler_options *
 nak_nir_options(const struct nak_compiler *nak);
 
@@ -146,6 +172,10 @@ struct nak_shader_info {
    /** Size of call/return stack in bytes/warp */
    uint32_t crs_size;
 
+   /** Number of GPR spill stores and fill loads to scratch memory */
+   uint32_t num_spills_to_mem;
+   uint32_t num_fills_from_mem;
+
    union {
       struct {
          /* Local workgroup size */
@@ -196,6 +226,55 @@ struct nak_shader_info {
 };
 #pragma GCC diagnostic pop
 
+/** Maps an encoded instruction back to the NIR it was generated from */
+struct nak_src_loc {
+   /** Byte offset of the instruction in the shader code */
+   uint32_t offset;
+
+   /** Index of the NIR instruction, as set by nir_index_instrs() */
+   uint32_t nir_instr;
+
+   /** From the NIR debug info or 0 if the shader has none */
+   uint32_t spirv_offset;
+   uint32_t line;
+   uint32_t column;
+};
+
+enum nak_sanitize_check_kind {
+   /** A shared memory access past the shader's shared memory size */
+   NAK_SANITIZE_SHARED_BOUNDS,
+
+   /** A local memory access past the shader's scratch size */
+   NAK_SANITIZE_LOCAL_BOUNDS,
+
+   /** A memory access through an undefined address */
+   NAK_SANITIZE_UNDEF_ADDR,
+};
+
+/** A memory access checked by a shader compiled with NAK_DEBUG=sanitize */
+struct nak_sanitize_check {
+   /** Byte offset of the access in the shader code */
+   uint32_t offset;
+
+   /** enum nak_sanitize_check_kind */
+   uint32_t kind;
+
+   /** Size of the access in bytes */
+   uint32_t size;
+
+   /** Size of the memory the access has to stay within */
+   uint32_t limit;
+};
+
+/** What the shader writes to the sanitizer buffer when check i fails */
+struct nak_sanitize_error {
+   /** Incremented every time the check fails */
+   uint32_t count;
+
+   /** The address of the last access which failed the check */
+   uint32_t addr;
+};
+
 struct nak_shader_bin {
    struct nak_shader_info info;
 
@@ -203,15 +282,39 @@ struct nak_shader_bin {
    const void *code;
 
    const char *asm_str;
+
+   /** Sorted by offset.  Instructions with no NIR source are left out. */
+   uint32_t src_map_count;
+   const struct nak_src_loc *src_map;
+
+   /** Only set if the profile_blocks pass ran.  Counter i in the profiling
+    * buffer counts executions of the block starting at byte offset
+    * profile_block_offsets[i] in the shader code.
+    */
+   uint32_t profile_block_count;
+   const uint32_t *profile_block_offsets;
+
+   /** Only set with NAK_DEBUG=sanitize.  Error i in the sanitizer buffer
+    * belongs to sanitize_checks[i].
+    */
+   uint32_t sanitize_check_count;
+   const struct nak_sanitize_check *sanitize_checks;
 };
 
 void nak_shader_bin_destroy(struct nak_shader_bin *bin);
 
+/** Returns the source location of the instruction at the given byte offset
+ * or NULL if it doesn't have one.
+ */
+const struct nak_src_loc *
+nak_shader_bin_src_loc(const struct nak_shader_bin *bin, uint32_t offset);
+
 struct nak_shader_bin *
 nak_compile_shader(nir_shader *nir, bool dump_asm,
                    const struct nak_compiler *nak,
                    nir_variable_mode robust2_modes,
-                   const struct nak_fs_key *fs_key);
+                   const struct nak_fs_key *fs_key,
+                   int8_t debug_cbuf);
 
 struct nak_qmd_cbuf {
    uint32_t index;
@@ -2,7 +2,11 @@
 // SPDX-License-Identifier: MIT
 
 use crate::from_nir::*;
-use crate::ir::{ShaderInfo, ShaderIoInfo, ShaderModel, ShaderStageInfo};
+use crate::ir::{
+    Op, OpNop, SanitizeCheckKind, Shader, ShaderInfo, ShaderIoInfo,
+    ShaderModel, ShaderStageInfo,
+};
+use crate::passes;
 use crate::sm50::ShaderModel50;
 use crate::sm70::ShaderModel70;
 use crate::sph;
@@ -11,11 +15,14 @@ use compiler::bindings::*;
 use nak_bindings::*;
 
 use std::cmp::max;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::{CStr, CString};
 use std::fmt::Write;
+use std::fs;
 use std::os::raw::c_void;
 use std::panic;
+use std::path::Path;
 use std::sync::OnceLock;
 
 #[repr(u8)]
@@ -25,6 +32,8 @@ enum DebugFlags {
     Spill,
     Annotate,
     NoUgpr,
+    Time,
+    Sanitize,
 }
 
 pub struct Debug {
@@ -49,6 +58,8 @@ impl Debug {
                 "spill" => flags |= 1 << DebugFlags::Spill as u8,
                 "annotate" => flags |= 1 << DebugFlags::Annotate as u8,
                 "nougpr" => flags |= 1 << DebugFlags::NoUgpr as u8,
+                "time" => flags |= 1 << DebugFlags::Time as u8,
+                "sanitize" => flags |= 1 << DebugFlags::Sanitize as u8,
                 unk => eprintln!("Unknown NAK_DEBUG flag \"{}\"", unk),
             }
         }
@@ -78,6 +89,14 @@ pub trait GetDebugFlags {
     fn no_ugpr(&self) -> bool {
         self.debug_flags() & (1 << DebugFlags::NoUgpr as u8) != 0
     }
+
+    fn time(&self) -> bool {
+        self.debug_flags() & (1 << DebugFlags::Time as u8) != 0
+    }
+
+    fn sanitize(&self) -> bool {
+        self.debug_flags() & (1 << DebugFlags::Sanitize as u8) != 0
+    }
 }
 
 pub static DEBUG: OnceLock<Debug> = OnceLock::new();
@@ -194,6 +213,11 @@ pub extern "C" fn nak_debug_flags(_nak: *const nak_compiler) -> u64 {
     DEBUG.debug_flags().into()
 }
 
+#[no_mangle]
+pub extern "C" fn nak_needs_debug_cbuf(_nak: *const nak_compiler) -> bool {
+    passes::instrumenting()
+}
+
 #[no_mangle]
 pub extern "C" fn nak_nir_options(
     nak: *const nak_compiler,
@@ -208,6 +232,9 @@ pub struct ShaderBin {
     pub bin: nak_shader_bin,
     code: Vec<u32>,
     asm: CString,
+    src_map: Vec<nak_src_loc>,
+    profile_block_offsets: Vec<u32>,
+    sanitize_checks: Vec<nak_sanitize_check>,
 }
 
 impl ShaderBin {
@@ -217,6 +244,9 @@ impl ShaderBin {
         fs_key: Option<&nak_fs_key>,
         code: Vec<u32>,
         asm: &str,
+        src_map: Vec<nak_src_loc>,
+        profile_block_offsets: Vec<u32>,
+        sanitize_checks: Vec<nak_sanitize_check>,
     ) -> ShaderBin {
         let asm = CString::new(asm)
             .expect("NAK assembly has unexpected null characters");
@@ -241,6 +271,8 @@ impl ShaderBin {
             num_instrs: info.num_instrs,
             slm_size: info.slm_size,
             crs_size: sm.crs_size(info.max_crs_depth),
+            num_spills_to_mem: info.num_spills_to_mem,
+            num_fills_from_mem: info.num_fills_from_mem,
             __bindgen_anon_1: match &info.stage {
                 ShaderStageInfo::Compute(cs_info) => {
                     nak_shader_info__bindgen_ty_1 {
@@ -315,6 +347,8 @@ impl ShaderBin {
             eprintln!("Instruction count: {}", c_info.num_instrs);
             eprintln!("Num GPRs: {}", c_info.num_gprs);
             eprintln!("SLM size: {}", c_info.slm_size);
+            eprintln!("Spills to memory: {}", c_info.num_spills_to_mem);
+            eprintln!("Fills from memory: {}", c_info.num_fills_from_mem);
 
             if c_info.stage != MESA_SHADER_COMPUTE {
                 eprint_hex("Header", &c_info.hdr);
@@ -332,11 +366,23 @@ impl ShaderBin {
             } else {
                 asm.as_ptr()
             },
+            src_map_count: src_map.len().try_into().unwrap(),
+            src_map: src_map.as_ptr(),
+            profile_block_count: profile_block_offsets
+                .len()
+                .try_into()
+                .unwrap(),
+            profile_block_offsets: profile_block_offsets.as_ptr(),
+            sanitize_check_count: sanitize_checks.len().try_into().unwrap(),
+            sanitize_checks: sanitize_checks.as_ptr(),
         };
         ShaderBin {
             bin: bin,
             code: code,
             asm: asm,
+            src_map: src_map,
+            profile_block_offsets: profile_block_offsets,
+            sanitize_checks: sanitize_checks,
         }
     }
 }
@@ -356,6 +402,114 @@ pub extern "C" fn nak_shader_bin_destroy(bin: *mut nak_shader_bin) {
     };
 }
 
+#[no_mangle]
+pub extern "C" fn nak_shader_bin_src_loc(
+    bin: *const nak_shader_bin,
+    offset: u32,
+) -> *const nak_src_loc {
+    assert!(!bin.is_null());
+    let bin = unsafe { &*(bin as *const ShaderBin) };
+    match bin.src_map.binary_search_by_key(&offset, |loc| loc.offset) {
+        Ok(i) => &bin.src_map[i],
+        Err(_) => std::ptr::null(),
+    }
+}
+
+/// Builds the table mapping encoded instructions back to NIR
+fn src_map(sm: &dyn ShaderModel, s: &Shader<'_>) -> Vec<nak_src_loc> {
+    let offsets = sm.instr_offsets(s);
+    let mut src_map = Vec::new();
+    let mut i = 0;
+    s.for_each_instr(&mut |instr| {
+        if let Some(loc) = &instr.src_loc {
+            src_map.push(nak_src_loc {
+                offset: offsets[i],
+                nir_instr: loc.nir_instr,
+                spirv_offset: loc.spirv_offset,
+                line: loc.line,
+                column: loc.column,
+            });
+        }
+        i += 1;
+    });
+    src_map
+}
+
+/// Finds the code offset of every block with a profiling counter
+fn profile_block_offsets(sm: &dyn ShaderModel, s: &Shader<'_>) -> Vec<u32> {
+    if s.info.profile_blocks.is_empty() {
+        return Vec::new();
+    }
+
+    let offsets = sm.instr_offsets(s);
+    let mut block_offsets = HashMap::new();
+    let mut i = 0;
+    for f in &s.functions {
+        for b in &f.blocks {
+            if !b.instrs.is_empty() {
+                block_offsets.insert(b.label, offsets[i]);
+            }
+            i += b.instrs.len();
+        }
+    }
+
+    // A pass may have deleted an instrumented block after the fact
+    s.info
+        .profile_blocks
+        .iter()
+        .map(|label| *block_offsets.get(label).unwrap_or(&u32::MAX))
+        .collect()
+}
+
+/// Finds the code offset of the memory access guarded by each sanitizer check
+fn sanitize_checks(
+    sm: &dyn ShaderModel,
+    s: &Shader<'_>,
+) -> Vec<nak_sanitize_check> {
+    if s.info.sanitize_checks.is_empty() {
+        return Vec::new();
+    }
+
+    // Each access is the first memory instruction after the labeled Nop
+    let offsets = sm.instr_offsets(s);
+    let mut access_offsets = HashMap::new();
+    let mut i = 0;
+    for f in &s.functions {
+        for b in &f.blocks {
+            let mut check = None;
+            for instr in &b.instrs {
+                match &instr.op {
+                    Op::Nop(OpNop { label: Some(label) }) => {
+                        check = Some(*label);
+                    }
+                    Op::Ld(_) | Op::St(_) | Op::Atom(_) => {
+                        if let Some(label) = check.take() {
+                            access_offsets.insert(label, offsets[i]);
+                        }
+                    }
+                    _ => (),
+                }
+                i += 1;
+            }
+        }
+    }
+
+    s.info
+        .sanitize_checks
+        .iter()
+        .map(|check| nak_sanitize_check {
+            offset: *access_offsets.get(&check.label).unwrap_or(&u32::MAX),
+            kind: match check.kind {
+                SanitizeCheckKind::SharedBounds => NAK_SANITIZE_SHARED_BOUNDS,
+                SanitizeCheckKind::LocalBounds => NAK_SANITIZE_LOCAL_BOUNDS,
+                SanitizeCheckKind::UndefAddr => NAK_SANITIZE_UNDEF_ADDR,
+            },
+            size: check.size,
+            limit: check.limit,
+        })
+        .collect()
+}
+
 fn eprint_hex(label: &str, data: &[u32]) {
     eprint!("{}:", label);
     for i in 0..data.len() {
@@ -368,13 +522,156 @@ fn eprint_hex(label: &str, data: &[u32]) {
     eprintln!("");
 }
 
-macro_rules! pass {
-    ($s: expr, $pass: ident) => {
-        $s.$pass();
-        if DEBUG.print() {
-            eprintln!("NAK IR after {}:\n{}", stringify!($pass), $s);
+/// Serializes the NIR handed to us by the driver for a ShaderDump
+fn serialize_nir(nir: *const nir_shader) -> Vec<u8> {
+    let mut blob: blob = unsafe { std::mem::zeroed() };
+
+    // A fixed blob with no data just counts bytes
+    unsafe {
+        blob_init_fixed(&mut blob, std::ptr::null_mut(), usize::MAX);
+        nir_serialize(&mut blob, nir, false);
+    }
+    let mut data = vec![0_u8; blob.size];
+    unsafe {
+        blob_init_fixed(
+            &mut blob,
+            data.as_mut_ptr() as *mut c_void,
+            data.len(),
+        );
+        nir_serialize(&mut blob, nir, false);
+    }
+    assert!(!blob.out_of_memory);
+
+    data
+}
+
+/// Reads n bytes from the front of data
+fn take_bytes<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if data.len() < n {
+        return Err("Truncated shader dump".into());
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+fn take_u32(data: &mut &[u8]) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(take_bytes(data, 4)?.try_into().unwrap()))
+}
+
+/// Everything passed to nak_compile_shader() which affects the generated
+/// code
+///
+/// This is what NAK_NIR_DUMP_DIR writes out and nak-compile reads back.  The
+/// NIR is dumped before nak_postprocess_nir() so the other inputs are needed
+/// to lower it the same way again.
+pub(crate) struct ShaderDump {
+    pub robust2_modes: nir_variable_mode,
+    pub fs_key: Option<nak_fs_key>,
+    pub debug_cbuf: i8,
+    /// The NIR in the nir_serialize() format
+    pub nir: Vec<u8>,
+}
+
+impl ShaderDump {
+    /// Identifies the file format.  The last byte is the version and has to
+    /// be bumped whenever the layout changes.
+    const MAGIC: [u8; 8] = *b"NAKDUMP\x01";
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::from(Self::MAGIC);
+        data.extend_from_slice(&self.robust2_modes.to_le_bytes());
+        data.extend_from_slice(&self.debug_cbuf.to_le_bytes());
+        if let Some(fs_key) = &self.fs_key {
+            data.push(1);
+            data.push(fs_key.zs_self_dep.into());
+            data.push(fs_key.force_sample_shading.into());
+            data.push(fs_key.uses_underestimate.into());
+            data.push(fs_key.sample_info_cb);
+            data.extend_from_slice(
+                &fs_key.sample_locations_offset.to_le_bytes(),
+            );
+            data.extend_from_slice(&fs_key.sample_masks_offset.to_le_bytes());
+        } else {
+            data.push(0);
         }
+        data.extend_from_slice(&self.nir);
+        data
+    }
+
+    /// Parses a shader dump
+    ///
+    /// Anything which doesn't start with the magic is taken to be plain
+    /// nir_serialize() output, such as the dumps from before the header was
+    /// added, and gets no robustness, no fs key and no debug cbuf.
+    pub fn from_bytes(data: &[u8]) -> Result<ShaderDump, String> {
+        let magic = &Self::MAGIC[..Self::MAGIC.len() - 1];
+        let Some(mut data) = data.strip_prefix(&Self::MAGIC[..]) else {
+            if data.starts_with(magic) {
+                return Err("Unsupported shader dump version".into());
+            }
+            return Ok(ShaderDump {
+                robust2_modes: 0,
+                fs_key: None,
+                debug_cbuf: NAK_NO_DEBUG_CBUF as i8,
+                nir: data.to_vec(),
+            });
+        };
+
+        let robust2_modes = take_u32(&mut data)?;
+        let debug_cbuf = i8::from_le_bytes([take_bytes(&mut data, 1)?[0]]);
+        let fs_key = match take_bytes(&mut data, 1)?[0] {
+            0 => None,
+            1 => {
+                let bytes = take_bytes(&mut data, 4)?;
+                Some(nak_fs_key {
+                    zs_self_dep: bytes[0] != 0,
+                    force_sample_shading: bytes[1] != 0,
+                    uses_underestimate: bytes[2] != 0,
+                    sample_info_cb: bytes[3],
+                    sample_locations_offset: take_u32(&mut data)?,
+                    sample_masks_offset: take_u32(&mut data)?,
+                })
+            }
+            _ => return Err("Invalid fs key in shader dump".into()),
+        };
+
+        Ok(ShaderDump {
+            robust2_modes: robust2_modes,
+            fs_key: fs_key,
+            debug_cbuf: debug_cbuf,
+            nir: data.to_vec(),
+        })
+    }
+
+    /// Hashes a serialized dump
+    ///
+    /// This is used for dump file names and NAK_BISECT so it has to be the
+    /// same for every run and every build.
+    pub fn hash(data: &[u8]) -> u64 {
+        let mut blake3 = [0_u8; 32];
+        unsafe {
+            _mesa_blake3_compute(
+                data.as_ptr() as *const c_void,
+                data.len(),
+                blake3.as_mut_ptr(),
+            );
+        }
+        u64::from_be_bytes(blake3[..8].try_into().unwrap())
+    }
+}
+
+/// Writes a shader dump into dir so it can be fed back into nak-compile
+/// later.
+fn write_dump(nir: *const nir_shader, dir: &str, data: &[u8], hash: u64) {
+    let stage_name = unsafe {
+        let c_name = _mesa_shader_stage_to_string((*nir).info.stage() as u32);
+        CStr::from_ptr(c_name).to_str().expect("Invalid UTF-8")
     };
+    let path = Path::new(dir).join(format!("{}-{:016x}.nir", stage_name, hash));
+    if let Err(err) = fs::write(&path, data) {
+        eprintln!("Failed to write {}: {}", path.display(), err);
+    }
 }
 
 fn nak_compile_shader_internal(
@@ -383,7 +680,27 @@ fn nak_compile_shader_internal(
     nak: *const nak_compiler,
     robust2_modes: nir_variable_mode,
     fs_key: *const nak_fs_key,
+    debug_cbuf: i8,
 ) -> *mut nak_shader_bin {
+    let dump_dir = env::var("NAK_NIR_DUMP_DIR").ok();
+    let nir_hash = if dump_dir.is_some() || passes::bisecting() {
+        let dump = ShaderDump {
+            robust2_modes: robust2_modes,
+            fs_key: unsafe { fs_key.as_ref() }.copied(),
+            debug_cbuf: debug_cbuf,
+            nir: serialize_nir(nir),
+        };
+        let data = dump.to_bytes();
+        let hash = ShaderDump::hash(&data);
+
+        if let Some(dir) = &dump_dir {
+            write_dump(nir, dir, &data, hash);
+        }
+        Some(hash)
+    } else {
+        None
+    };
+
     unsafe { nak_postprocess_nir(nir, nak, robust2_modes, fs_key) };
     let nak = unsafe { &*nak };
     let nir = unsafe { &*nir };
@@ -402,32 +719,13 @@ fn nak_compile_shader_internal(
     };
 
     let mut s = nak_shader_from_nir(nak, nir, sm.as_ref());
+    s.info.debug_cbuf = debug_cbuf.try_into().ok();
 
     if DEBUG.print() {
         eprintln!("NAK IR:\n{}", &s);
     }
 
-    pass!(s, opt_bar_prop);
-    pass!(s, opt_uniform_instrs);
-    pass!(s, opt_copy_prop);
-    pass!(s, opt_prmt);
-    pass!(s, opt_lop);
-    pass!(s, opt_copy_prop);
-    pass!(s, opt_dce);
-    pass!(s, opt_out);
-    pass!(s, legalize);
-    pass!(s, assign_regs);
-    pass!(s, lower_par_copies);
-    pass!(s, lower_copy_swap);
-    if nak.sm >= 70 {
-        pass!(s, opt_jump_thread);
-    } else {
-        pass!(s, opt_crs);
-    }
-
-    s.remove_annotations();
-
-    pass!(s, calc_instr_deps);
+    s.run_passes(nir_hash);
 
     s.gather_info();
 
@@ -437,8 +735,19 @@ fn nak_compile_shader_internal(
     }
 
     let code = sm.encode_shader(&s);
-    let bin =
-        Box::new(ShaderBin::new(sm.as_ref(), &s.info, fs_key, code, &asm));
+    let src_map = src_map(sm.as_ref(), &s);
+    let profile_block_offsets = profile_block_offsets(sm.as_ref(), &s);
+    let sanitize_checks = sanitize_checks(sm.as_ref(), &s);
+    let bin = Box::new(ShaderBin::new(
+        sm.as_ref(),
+        &s.info,
+        fs_key,
+        code,
+        &asm,
+        src_map,
+        profile_block_offsets,
+        sanitize_checks,
+    ));
     Box::into_raw(bin) as *mut nak_shader_bin
 }
 
@@ -449,9 +758,109 @@ pub extern "C" fn nak_compile_shader(
     nak: *const nak_compiler,
     robust2_modes: nir_variable_mode,
     fs_key: *const nak_fs_key,
+    debug_cbuf: i8,
 ) -> *mut nak_shader_bin {
     panic::catch_unwind(|| {
-        nak_compile_shader_internal(nir, dump_asm, nak, robust2_modes, fs_key)
+        nak_compile_shader_internal(
+            nir,
+            dump_asm,
+            nak,
+            robust2_modes,
+            fs_key,
+            debug_cbuf,
+        )
     })
     .unwrap_or(std::ptr::null_mut())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_fs_key() -> nak_fs_key {
+        nak_fs_key {
+            zs_self_dep: true,
+            force_sample_shading: false,
+            uses_underestimate: true,
+            sample_info_cb: 2,
+            sample_locations_offset: 0x40,
+            sample_masks_offset: 0x80,
+        }
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        for fs_key in [None, Some(test_fs_key())] {
+            let dump = ShaderDump {
+                robust2_modes: 0x1234,
+                fs_key: fs_key,
+                debug_cbuf: 5,
+                nir: vec![1, 2, 3, 4, 5],
+            };
+            let dump2 = ShaderDump::from_bytes(&dump.to_bytes()).unwrap();
+            assert!(dump2.robust2_modes == dump.robust2_modes);
+            assert!(dump2.debug_cbuf == dump.debug_cbuf);
+            assert!(dump2.nir == dump.nir);
+            match (dump2.fs_key, dump.fs_key) {
+                (None, None) => (),
+                (Some(a), Some(b)) => {
+                    assert!(a.zs_self_dep == b.zs_self_dep);
+                    assert!(a.force_sample_shading == b.force_sample_shading);
+                    assert!(a.uses_underestimate == b.uses_underestimate);
+                    assert!(a.sample_info_cb == b.sample_info_cb);
+                    assert!(
+                        a.sample_locations_offset == b.sample_locations_offset
+                    );
+                    assert!(a.sample_masks_offset == b.sample_masks_offset);
+                }
+                _ => panic!("The fs key did not survive the round trip"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dump_raw_nir() {
+        let nir = [0x4e, 0x49, 0x52, 0x00, 0x01, 0x02];
+        let dump = ShaderDump::from_bytes(&nir).unwrap();
+        assert!(dump.robust2_modes == 0);
+        assert!(dump.fs_key.is_none());
+        assert!(dump.debug_cbuf == NAK_NO_DEBUG_CBUF as i8);
+        assert!(dump.nir == nir);
+    }
+
+    #[test]
+    fn test_dump_truncated() {
+        let dump = ShaderDump {
+            robust2_modes: 0x1234,
+            fs_key: Some(test_fs_key()),
+            debug_cbuf: 5,
+            nir: Vec::new(),
+        };
+        let data = dump.to_bytes();
+
+        // Anything cut off in the header has to fail rather than be taken
+        // as NIR
+        for len in ShaderDump::MAGIC.len() - 1..data.len() {
+            assert!(ShaderDump::from_bytes(&data[..len]).is_err());
+        }
+        assert!(ShaderDump::from_bytes(&data).is_ok());
+    }
+
+    #[test]
+    fn test_dump_invalid() {
+        let dump = ShaderDump {
+            robust2_modes: 0,
+            fs_key: None,
+            debug_cbuf: NAK_NO_DEBUG_CBUF as i8,
+            nir: vec![1, 2, 3],
+        };
+
+        let mut data = dump.to_bytes();
+        data[ShaderDump::MAGIC.len() - 1] += 1;
+        assert!(ShaderDump::from_bytes(&data).is_err());
+
+        let mut data = dump.to_bytes();
+        data[ShaderDump::MAGIC.len() + 5] = 2;
+        assert!(ShaderDump::from_bytes(&data).is_err());
+    }
+}
@@ -1405,8 +1405,8 @@ impl Shader<'_> {
         assert!(self.functions.len() == 1);
         let f = &mut self.functions[0];
 
-        // Convert to CSSA before we spill or assign registers
-        f.to_cssa();
+        // The convert_to_cssa pass has already put us in CSSA form, which
+        // spilling and register assignment rely on.
 
         let mut live = SimpleLiveness::for_function(f);
         let mut max_live = live.calc_max_live(f);
@@ -890,6 +890,7 @@ pub trait SSABuilder: Builder {
 pub struct InstrBuilder<'a> {
     instrs: MappedInstrs,
     sm: &'a dyn ShaderModel,
+    src_loc: Option<SrcLoc>,
 }
 
 impl<'a> InstrBuilder<'a> {
@@ -897,11 +898,17 @@ impl<'a> InstrBuilder<'a> {
         Self {
             instrs: MappedInstrs::None,
             sm,
+            src_loc: None,
         }
     }
 }
 
 impl InstrBuilder<'_> {
+    /// Sets the source location for instructions pushed from now on
+    pub fn set_src_loc(&mut self, src_loc: Option<SrcLoc>) {
+        self.src_loc = src_loc;
+    }
+
     pub fn as_vec(self) -> Vec<Box<Instr>> {
         match self.instrs {
             MappedInstrs::None => Vec::new(),
@@ -916,7 +923,8 @@ impl InstrBuilder<'_> {
 }
 
 impl Builder for InstrBuilder<'_> {
-    fn push_instr(&mut self, instr: Box<Instr>) -> &mut Instr {
+    fn push_instr(&mut self, mut instr: Box<Instr>) -> &mut Instr {
+        instr.src_loc = instr.src_loc.or(self.src_loc);
         self.instrs.push(instr);
         self.instrs.last_mut().unwrap().as_mut()
     }
@@ -944,6 +952,10 @@ impl<'a> SSAInstrBuilder<'a> {
 }
 
 impl SSAInstrBuilder<'_> {
+    pub fn set_src_loc(&mut self, src_loc: Option<SrcLoc>) {
+        self.b.set_src_loc(src_loc);
+    }
+
     pub fn as_vec(self) -> Vec<Box<Instr>> {
         self.b.as_vec()
     }
@@ -0,0 +1,536 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! The nak-compile command-line tool
+//!
+//! This compiles shaders dumped with NAK_NIR_DUMP_DIR without a running
+//! driver.  It lives in the NAK crate so that it runs the exact same
+//! compiler as the driver.  The executable itself is just a C main() which
+//! calls nak_compile_main().
+
+use crate::api::ShaderDump;
+
+use compiler::bindings::*;
+use nak_bindings::*;
+
+use std::ffi::{c_char, c_int, CStr};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Write as IoWrite;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Usage: nak-compile --sm <SM> [OPTIONS] <FILE.nir>...
+       nak-compile --sm <SM> [OPTIONS] --batch <DIR>
+
+Compiles shaders dumped with NAK_NIR_DUMP_DIR with NAK.  The dumps contain
+the NIR along with the fragment shader key and robustness modes the driver
+compiled it with.  Plain serialized NIR is accepted as well.
+
+For each input FILE.nir, writes FILE.bin with the encoded shader, FILE.asm
+with the NAK assembly and FILE.txt with the shader info.  In batch mode,
+every .nir file in DIR is compiled and a CSV with the instruction, GPR and
+spill counts of each shader is written instead.
+
+Options:
+  --sm <SM>          Shader model to compile for (50, 52, 70, 75, 86, ...)
+  --fs-key <KEY>     Replaces the key of fragment shaders with the given
+                     comma-separated nak_fs_key fields:
+                       zs_self_dep, force_sample_shading,
+                       uses_underestimate, sample_info_cb=<N>,
+                       sample_locations_offset=<N>, sample_masks_offset=<N>
+  -o, --output <DIR> Directory to write outputs to (default: next to the
+                     input in single mode, nothing in batch mode)
+  --batch <DIR>      Compile every .nir file in DIR
+  --csv <FILE>       Where to write the batch mode CSV (default: stdout)
+  -h, --help         Print this help
+";
+
+struct Options {
+    sm: u8,
+    fs_key: Option<nak_fs_key>,
+    out_dir: Option<PathBuf>,
+    batch: Option<PathBuf>,
+    csv: Option<PathBuf>,
+    inputs: Vec<PathBuf>,
+}
+
+fn parse_fs_key(s: &str) -> Result<nak_fs_key, String> {
+    let mut key: nak_fs_key = unsafe { std::mem::zeroed() };
+    for field in s.split(',') {
+        let field = field.trim();
+        if field.is_empty() {
+            continue;
+        }
+
+        let (name, value) = match field.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (field, None),
+        };
+
+        let value_u32 = || -> Result<u32, String> {
+            let Some(value) = value else {
+                return Err(format!("fs key field {name} needs a value"));
+            };
+            value.parse().map_err(|_| {
+                format!("Invalid value \"{value}\" for fs key field {name}")
+            })
+        };
+
+        match name {
+            "zs_self_dep" => key.zs_self_dep = true,
+            "force_sample_shading" => key.force_sample_shading = true,
+            "uses_underestimate" => key.uses_underestimate = true,
+            "sample_info_cb" => {
+                key.sample_info_cb = value_u32()?
+                    .try_into()
+                    .map_err(|_| "sample_info_cb is out of range")?;
+            }
+            "sample_locations_offset" => {
+                key.sample_locations_offset = value_u32()?;
+            }
+            "sample_masks_offset" => {
+                key.sample_masks_offset = value_u32()?;
+            }
+            _ => return Err(format!("Unknown fs key field \"{name}\"")),
+        }
+    }
+    Ok(key)
+}
+
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut sm = None;
+    let mut opts = Options {
+        sm: 0,
+        fs_key: None,
+        out_dir: None,
+        batch: None,
+        csv: None,
+        inputs: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {arg}"))
+                .map(|s| s.as_str())
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--sm" => {
+                let s = value()?;
+                let s: u8 =
+                    s.parse().map_err(|_| format!("Invalid SM \"{s}\""))?;
+                if s < 50 {
+                    return Err(format!("SM{s} is not supported by NAK"));
+                }
+                sm = Some(s);
+            }
+            "--fs-key" => opts.fs_key = Some(parse_fs_key(value()?)?),
+            "-o" | "--output" => opts.out_dir = Some(value()?.into()),
+            "--batch" => opts.batch = Some(value()?.into()),
+            "--csv" => opts.csv = Some(value()?.into()),
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option \"{arg}\""));
+            }
+            _ => opts.inputs.push(arg.into()),
+        }
+    }
+
+    opts.sm = sm.ok_or("--sm is required")?;
+
+    if opts.batch.is_some() && !opts.inputs.is_empty() {
+        return Err("--batch does not take input files".into());
+    }
+    if opts.batch.is_none() && opts.inputs.is_empty() {
+        return Err("No input files".into());
+    }
+
+    Ok(Some(opts))
+}
+
+/// The number of warps per SM doesn't affect codegen but we may as well get
+/// it right.
+fn max_warps_per_mp(sm: u8) -> u8 {
+    match sm {
+        75 => 32,
+        86 | 87 | 89 => 48,
+        _ => 64,
+    }
+}
+
+struct Compiler {
+    nak: *mut nak_compiler,
+}
+
+impl Compiler {
+    fn new(sm: u8) -> Compiler {
+        let mut dev: nv_device_info = unsafe { std::mem::zeroed() };
+        dev.sm = sm;
+        dev.max_warps_per_mp = max_warps_per_mp(sm);
+
+        unsafe { glsl_type_singleton_init_or_ref() };
+        let nak = unsafe { nak_compiler_create(&dev) };
+        assert!(!nak.is_null());
+        Compiler { nak: nak }
+    }
+
+    fn compile(
+        &self,
+        data: &[u8],
+        fs_key: Option<&nak_fs_key>,
+    ) -> Result<CompiledShader, String> {
+        let dump = ShaderDump::from_bytes(data)?;
+
+        let mut reader: blob_reader = unsafe { std::mem::zeroed() };
+        let nir = unsafe {
+            blob_reader_init(
+                &mut reader,
+                dump.nir.as_ptr() as *const c_void,
+                dump.nir.len(),
+            );
+            nir_deserialize(
+                std::ptr::null_mut(),
+                nak_nir_options(self.nak),
+                &mut reader,
+            )
+        };
+        if nir.is_null() || reader.overrun {
+            return Err("Failed to deserialize NIR".into());
+        }
+
+        let fs_key = match fs_key.or(dump.fs_key.as_ref()) {
+            Some(key)
+                if unsafe { (*nir).info.stage() } == MESA_SHADER_FRAGMENT =>
+            {
+                key as *const nak_fs_key
+            }
+            _ => std::ptr::null(),
+        };
+
+        let bin = unsafe {
+            let bin = nak_compile_shader(
+                nir,
+                true,
+                self.nak,
+                dump.robust2_modes,
+                fs_key,
+                dump.debug_cbuf,
+            );
+            ralloc_free(nir as *mut c_void);
+            bin
+        };
+
+        if bin.is_null() {
+            Err("Compilation failed".into())
+        } else {
+            Ok(CompiledShader { bin: bin })
+        }
+    }
+}
+
+impl Drop for Compiler {
+    fn drop(&mut self) {
+        unsafe {
+            nak_compiler_destroy(self.nak);
+            glsl_type_singleton_decref();
+        }
+    }
+}
+
+struct CompiledShader {
+    bin: *mut nak_shader_bin,
+}
+
+impl CompiledShader {
+    fn bin(&self) -> &nak_shader_bin {
+        unsafe { &*self.bin }
+    }
+
+    fn info(&self) -> &nak_shader_info {
+        &self.bin().info
+    }
+
+    fn stage_name(&self) -> &'static str {
+        unsafe {
+            let c_name = _mesa_shader_stage_to_string(self.info().stage as u32);
+            CStr::from_ptr(c_name).to_str().expect("Invalid UTF-8")
+        }
+    }
+
+    fn code(&self) -> &[u8] {
+        let bin = self.bin();
+        unsafe {
+            std::slice::from_raw_parts(
+                bin.code as *const u8,
+                bin.code_size.try_into().unwrap(),
+            )
+        }
+    }
+
+    fn asm(&self) -> &str {
+        let bin = self.bin();
+        if bin.asm_str.is_null() {
+            ""
+        } else {
+            unsafe { CStr::from_ptr(bin.asm_str) }
+                .to_str()
+                .expect("Invalid UTF-8")
+        }
+    }
+
+    fn info_str(&self) -> String {
+        let info = self.info();
+        let mut s = String::new();
+        writeln!(s, "stage: {}", self.stage_name()).unwrap();
+        writeln!(s, "sm: {}", info.sm).unwrap();
+        writeln!(s, "instrs: {}", info.num_instrs).unwrap();
+        writeln!(s, "gprs: {}", info.num_gprs).unwrap();
+        writeln!(s, "spills_to_mem: {}", info.num_spills_to_mem).unwrap();
+        writeln!(s, "fills_from_mem: {}", info.num_fills_from_mem).unwrap();
+        writeln!(s, "slm_size: {}", info.slm_size).unwrap();
+        writeln!(s, "crs_size: {}", info.crs_size).unwrap();
+        writeln!(s, "control_barriers: {}", info.num_control_barriers).unwrap();
+
+        match info.stage {
+            MESA_SHADER_COMPUTE => {
+                let cs = unsafe { &info.__bindgen_anon_1.cs };
+                writeln!(s, "local_size: {:?}", cs.local_size).unwrap();
+                writeln!(s, "smem_size: {}", cs.smem_size).unwrap();
+            }
+            MESA_SHADER_FRAGMENT => {
+                let fs = unsafe { &info.__bindgen_anon_1.fs };
+                writeln!(s, "writes_depth: {}", fs.writes_depth).unwrap();
+                writeln!(s, "reads_sample_mask: {}", fs.reads_sample_mask)
+                    .unwrap();
+                writeln!(s, "uses_sample_shading: {}", fs.uses_sample_shading)
+                    .unwrap();
+            }
+            _ => (),
+        }
+        s
+    }
+}
+
+impl Drop for CompiledShader {
+    fn drop(&mut self) {
+        unsafe { nak_shader_bin_destroy(self.bin) };
+    }
+}
+
+fn write_outputs(
+    shader: &CompiledShader,
+    input: &Path,
+    out_dir: &Path,
+) -> Result<(), String> {
+    let stem = input.file_stem().ok_or("Input has no file name")?;
+    let out = out_dir.join(stem);
+
+    let write = |ext: &str, data: &[u8]| {
+        let path = out.with_extension(ext);
+        fs::write(&path, data)
+            .map_err(|err| format!("Failed to write {}: {err}", path.display()))
+    };
+
+    write("bin", shader.code())?;
+    write("asm", shader.asm().as_bytes())?;
+    write("txt", shader.info_str().as_bytes())?;
+    Ok(())
+}
+
+fn compile_file(
+    compiler: &Compiler,
+    opts: &Options,
+    input: &Path,
+) -> Result<CompiledShader, String> {
+    let data = fs::read(input)
+        .map_err(|err| format!("Failed to read {}: {err}", input.display()))?;
+    compiler.compile(&data, opts.fs_key.as_ref())
+}
+
+fn run_single(compiler: &Compiler, opts: &Options) -> bool {
+    let mut ok = true;
+    for input in &opts.inputs {
+        let res = compile_file(compiler, opts, input).and_then(|shader| {
+            let out_dir = match &opts.out_dir {
+                Some(dir) => dir.as_path(),
+                None => input.parent().unwrap_or(Path::new(".")),
+            };
+            write_outputs(&shader, input, out_dir)
+        });
+
+        if let Err(err) = res {
+            eprintln!("{}: {err}", input.display());
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn run_batch(compiler: &Compiler, opts: &Options, dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", dir.display());
+            return false;
+        }
+    };
+
+    // Sort so the CSV is stable and two runs can be diffed directly
+    let mut inputs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "nir"))
+        .collect();
+    inputs.sort();
+
+    let mut csv = String::from(
+        "shader,stage,instrs,gprs,spills_to_mem,fills_from_mem,slm_size\n",
+    );
+    let mut ok = true;
+    for input in &inputs {
+        let name = input.file_name().unwrap().to_string_lossy();
+        match compile_file(compiler, opts, input) {
+            Ok(shader) => {
+                let info = shader.info();
+                writeln!(
+                    csv,
+                    "{name},{},{},{},{},{},{}",
+                    shader.stage_name(),
+                    info.num_instrs,
+                    info.num_gprs,
+                    info.num_spills_to_mem,
+                    info.num_fills_from_mem,
+                    info.slm_size,
+                )
+                .unwrap();
+
+                if let Some(out_dir) = &opts.out_dir {
+                    if let Err(err) = write_outputs(&shader, input, out_dir) {
+                        eprintln!("{}: {err}", input.display());
+                        ok = false;
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("{}: {err}", input.display());
+                writeln!(csv, "{name},error,,,,,").unwrap();
+                ok = false;
+            }
+        }
+    }
+
+    let res = match &opts.csv {
+        Some(path) => fs::write(path, &csv),
+        None => std::io::stdout().write_all(csv.as_bytes()),
+    };
+    if let Err(err) = res {
+        eprintln!("Failed to write CSV: {err}");
+        ok = false;
+    }
+
+    ok
+}
+
+fn compile_main(args: &[String]) -> c_int {
+    let opts = match parse_args(args) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print!("{USAGE}");
+            return 0;
+        }
+        Err(err) => {
+            eprintln!("nak-compile: {err}\n");
+            eprint!("{USAGE}");
+            return 2;
+        }
+    };
+
+    let compiler = Compiler::new(opts.sm);
+    let ok = match &opts.batch {
+        Some(dir) => run_batch(&compiler, &opts, dir),
+        None => run_single(&compiler, &opts),
+    };
+
+    if ok {
+        0
+    } else {
+        1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn nak_compile_main(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let argc = usize::try_from(argc).unwrap();
+    let args: Vec<String> = (1..argc)
+        .map(|i| {
+            let arg = unsafe { CStr::from_ptr(*argv.add(i)) };
+            arg.to_string_lossy().into_owned()
+        })
+        .collect();
+
+    compile_main(&args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_fs_key() {
+        let key = parse_fs_key(
+            "zs_self_dep, sample_info_cb=3,sample_masks_offset=64",
+        )
+        .unwrap();
+        assert!(key.zs_self_dep);
+        assert!(!key.force_sample_shading && !key.uses_underestimate);
+        assert!(key.sample_info_cb == 3);
+        assert!(key.sample_locations_offset == 0);
+        assert!(key.sample_masks_offset == 64);
+
+        assert!(parse_fs_key("sample_info_cb").is_err());
+        assert!(parse_fs_key("sample_info_cb=256").is_err());
+        assert!(parse_fs_key("sample_masks_offset=x").is_err());
+        assert!(parse_fs_key("no_such_field").is_err());
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse_args(&args("--sm 75 -o out a.nir b.nir"))
+            .unwrap()
+            .unwrap();
+        assert!(opts.sm == 75 && opts.fs_key.is_none());
+        assert!(opts.out_dir == Some("out".into()));
+        assert!(opts.inputs == [PathBuf::from("a.nir"), "b.nir".into()]);
+
+        let opts =
+            parse_args(&args("--batch dir --fs-key zs_self_dep --sm 86"))
+                .unwrap()
+                .unwrap();
+        assert!(opts.batch == Some("dir".into()) && opts.inputs.is_empty());
+        assert!(opts.fs_key.unwrap().zs_self_dep);
+
+        assert!(parse_args(&args("--sm 75 --help")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args("a.nir")).is_err());
+        assert!(parse_args(&args("--sm 35 a.nir")).is_err());
+        assert!(parse_args(&args("--sm sm75 a.nir")).is_err());
+        assert!(parse_args(&args("--sm 75")).is_err());
+        assert!(parse_args(&args("--sm 75 --batch dir a.nir")).is_err());
+        assert!(parse_args(&args("--sm 75 --frobnicate a.nir")).is_err());
+        assert!(parse_args(&args("--sm 75 a.nir -o")).is_err());
+    }
+}
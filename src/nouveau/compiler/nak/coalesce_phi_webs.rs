@@ -0,0 +1,505 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+use crate::liveness::SimpleLiveness;
+use crate::union_find::UnionFind;
+
+use std::collections::HashMap;
+
+fn plain_ssa_dst(dst: &Dst) -> Option<SSAValue> {
+    let ssa = dst.as_ssa()?;
+    if ssa.comps() == 1 {
+        Some(ssa[0])
+    } else {
+        None
+    }
+}
+
+fn plain_ssa_src(src: &Src) -> Option<SSAValue> {
+    if !src.src_mod.is_none() || !src.src_swizzle.is_none() {
+        return None;
+    }
+    let ssa = src.src_ref.as_ssa()?;
+    if ssa.comps() == 1 {
+        Some(ssa[0])
+    } else {
+        None
+    }
+}
+
+struct PhiWebCoalescer<'a> {
+    live: &'a SimpleLiveness,
+
+    /// Values which are known to be equal because one is a copy of the other
+    values: UnionFind<SSAValue>,
+
+    /// Phi webs.  Values which are not part of any phi are in a web of their
+    /// own.
+    webs: UnionFind<SSAValue>,
+
+    /// The members of each web with more than one member, keyed by the
+    /// representative of the web
+    members: HashMap<SSAValue, Vec<SSAValue>>,
+}
+
+impl<'a> PhiWebCoalescer<'a> {
+    fn new(f: &Function, live: &'a SimpleLiveness) -> Self {
+        let mut values = UnionFind::new();
+        let mut webs = UnionFind::new();
+
+        let mut phi_dst = HashMap::new();
+        for b in &f.blocks {
+            if let Some(phi) = b.phi_dsts() {
+                for (idx, dst) in phi.dsts.iter() {
+                    phi_dst.insert(*idx, plain_ssa_dst(dst).unwrap());
+                }
+            }
+        }
+
+        let mut in_web = Vec::new();
+        for instr in f.blocks.iter().flat_map(|b| b.instrs.iter()) {
+            if instr.pred.is_true() {
+                match &instr.op {
+                    Op::Copy(op) => {
+                        Self::add_copy(&mut values, &op.dst, &op.src);
+                    }
+                    Op::ParCopy(op) => {
+                        for (dst, src) in op.dsts_srcs.iter() {
+                            Self::add_copy(&mut values, dst, src);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            if let Op::PhiSrcs(phi) = &instr.op {
+                for (idx, src) in phi.srcs.iter() {
+                    let Some(src) = plain_ssa_src(src) else {
+                        continue;
+                    };
+                    let dst = *phi_dst.get(idx).unwrap();
+                    webs.union(dst, src);
+                    in_web.push(dst);
+                    in_web.push(src);
+                }
+            }
+        }
+
+        let mut members: HashMap<SSAValue, Vec<SSAValue>> = HashMap::new();
+        in_web.sort_by_key(|ssa| ssa.idx());
+        in_web.dedup();
+        for ssa in in_web {
+            members.entry(webs.find(ssa)).or_default().push(ssa);
+        }
+
+        PhiWebCoalescer {
+            live: live,
+            values: values,
+            webs: webs,
+            members: members,
+        }
+    }
+
+    fn add_copy(values: &mut UnionFind<SSAValue>, dst: &Dst, src: &Src) {
+        if let (Some(dst), Some(src)) = (plain_ssa_dst(dst), plain_ssa_src(src))
+        {
+            if dst.file() == src.file() {
+                values.union(src, dst);
+            }
+        }
+    }
+
+    fn take_web(&mut self, ssa: SSAValue) -> (SSAValue, Vec<SSAValue>) {
+        let root = self.webs.find(ssa);
+        let members = self.members.remove(&root).unwrap_or_else(|| vec![ssa]);
+        (root, members)
+    }
+
+    /// Two values interfere if they are live at the same time and hold
+    /// different values
+    fn interferes(&mut self, a: SSAValue, b: SSAValue) -> bool {
+        self.live.interferes(&a, &b)
+            && self.values.find(a) != self.values.find(b)
+    }
+
+    /// Tries to merge the web containing @gone with the web containing @keep,
+    /// dropping @gone from the web.  This is used when every use of @gone is
+    /// about to be replaced by @keep.
+    ///
+    /// Because we don't update liveness as we go, we also refuse to merge if
+    /// any other member of the merged web is defined at @moved_ip.  This is
+    /// the one place where the live range of @keep grows when the copy
+    /// between @gone and @keep is removed.
+    fn try_merge(
+        &mut self,
+        gone: SSAValue,
+        keep: SSAValue,
+        moved_ip: (usize, usize),
+    ) -> bool {
+        let (gone_root, mut gone_web) = self.take_web(gone);
+        gone_web.retain(|ssa| *ssa != gone);
+
+        let keep_web = if self.webs.find(keep) == gone_root {
+            Vec::new()
+        } else {
+            let (_, keep_web) = self.take_web(keep);
+            keep_web
+        };
+
+        let mut can_merge = gone_web
+            .iter()
+            .chain(keep_web.iter())
+            .all(|ssa| self.live.def_block_ip(ssa) != moved_ip);
+
+        'outer: for a in &gone_web {
+            if !can_merge {
+                break;
+            }
+            for b in &keep_web {
+                if self.interferes(*a, *b) {
+                    can_merge = false;
+                    break 'outer;
+                }
+            }
+        }
+
+        if can_merge {
+            gone_web.extend(keep_web);
+            self.webs.union(keep, gone);
+            self.members.insert(self.webs.find(keep), gone_web);
+        } else {
+            gone_web.push(gone);
+            let keep_root = self.webs.find(keep);
+            self.members.insert(gone_root, gone_web);
+            if keep_root != gone_root && !keep_web.is_empty() {
+                self.members.insert(keep_root, keep_web);
+            }
+        }
+
+        can_merge
+    }
+}
+
+impl Function {
+    /// Coalesces phi webs across the parallel copies inserted by to_cssa
+    ///
+    /// to_cssa only looks at liveness when deciding whether a phi source or
+    /// destination can share a phi web.  This means that a value and a copy
+    /// of that value which are live at the same time are considered to
+    /// interfere and we end up with a parallel copy between them.  Loops are
+    /// especially bad for this because the copies pile up on back edges.
+    ///
+    /// This pass walks the parallel copies right before each OpPhiSrcs and
+    /// right after each OpPhiDsts and tries to remove each entry by merging
+    /// the phi webs on either side of it.  Two webs can be merged if no two
+    /// values across them interfere, where values only interfere if they're
+    /// live at the same time and aren't copies of each other.  This is the
+    /// value-based interference from "Revisiting Out-of-SSA Translation for
+    /// Correctness, Code Quality, and Effciency" by Boissinot et. al.
+    ///
+    /// Returns the number of copies removed.
+    pub fn coalesce_phi_webs(&mut self) -> usize {
+        let live = SimpleLiveness::for_function(self);
+
+        let mut uses: HashMap<SSAValue, usize> = HashMap::new();
+        for instr in self.blocks.iter().flat_map(|b| b.instrs.iter()) {
+            instr.for_each_ssa_use(|ssa| {
+                *uses.entry(*ssa).or_default() += 1;
+            });
+        }
+
+        let mut c = PhiWebCoalescer::new(self, &live);
+        let mut num_removed = 0;
+
+        for bi in 0..self.blocks.len() {
+            // Copies into temporaries which are only used by the phi:
+            //
+            //    tmp = pcopy src
+            //    phi_srcs tmp
+            //
+            // If we can, we make the phi use src directly.
+            if let Some(phi_ip) = self.blocks[bi].phi_srcs_ip() {
+                let instrs = &mut self.blocks[bi].instrs;
+                if phi_ip > 0 {
+                    let (pre, post) = instrs.split_at_mut(phi_ip);
+                    let Op::PhiSrcs(phi) = &mut post[0].op else {
+                        panic!("Expected OpPhiSrcs");
+                    };
+                    let pcopy = &mut pre[phi_ip - 1];
+                    let pcopy_pred_true = pcopy.pred.is_true();
+                    if let Op::ParCopy(pcopy) = &mut pcopy.op {
+                        pcopy.dsts_srcs.retain(|dst, src| {
+                            let (Some(tmp), Some(src_ssa)) =
+                                (plain_ssa_dst(dst), plain_ssa_src(src))
+                            else {
+                                return true;
+                            };
+                            if !pcopy_pred_true
+                                || tmp.file() != src_ssa.file()
+                                || uses.get(&tmp) != Some(&1)
+                            {
+                                return true;
+                            }
+
+                            let Some((_, phi_src)) = phi
+                                .srcs
+                                .iter_mut()
+                                .find(|(_, s)| plain_ssa_src(s) == Some(tmp))
+                            else {
+                                return true;
+                            };
+
+                            if !c.try_merge(tmp, src_ssa, (bi, phi_ip - 1)) {
+                                return true;
+                            }
+
+                            *phi_src = *src;
+                            num_removed += 1;
+                            false
+                        });
+                    }
+                }
+            }
+
+            // Copies out of temporaries which are only used by the copy:
+            //
+            //    phi_dsts tmp
+            //    dst = pcopy tmp
+            //
+            // If we can, we make the phi define dst directly.
+            if let Some(phi_ip) = self.blocks[bi].phi_dsts_ip() {
+                let instrs = &mut self.blocks[bi].instrs;
+                let (pre, post) = instrs.split_at_mut(phi_ip + 1);
+                let Op::PhiDsts(phi) = &mut pre[phi_ip].op else {
+                    panic!("Expected OpPhiDsts");
+                };
+                let pcopy = post
+                    .iter_mut()
+                    .find(|instr| !matches!(instr.op, Op::Annotate(_)));
+                if let Some(pcopy) = pcopy {
+                    let pcopy_pred_true = pcopy.pred.is_true();
+                    if let Op::ParCopy(pcopy) = &mut pcopy.op {
+                        pcopy.dsts_srcs.retain(|dst, src| {
+                            let (Some(dst_ssa), Some(tmp)) =
+                                (plain_ssa_dst(dst), plain_ssa_src(src))
+                            else {
+                                return true;
+                            };
+                            if !pcopy_pred_true
+                                || tmp.file() != dst_ssa.file()
+                                || uses.get(&tmp) != Some(&1)
+                            {
+                                return true;
+                            }
+
+                            let Some((_, phi_dst)) = phi
+                                .dsts
+                                .iter_mut()
+                                .find(|(_, d)| plain_ssa_dst(d) == Some(tmp))
+                            else {
+                                return true;
+                            };
+
+                            if !c.try_merge(tmp, dst_ssa, (bi, phi_ip)) {
+                                return true;
+                            }
+
+                            *phi_dst = *dst;
+                            num_removed += 1;
+                            false
+                        });
+                    }
+                }
+            }
+
+            self.blocks[bi].instrs.retain(|instr| match &instr.op {
+                Op::ParCopy(pcopy) => !pcopy.dsts_srcs.is_empty(),
+                _ => true,
+            });
+        }
+
+        num_removed
+    }
+}
+
+impl Shader<'_> {
+    pub fn coalesce_phi_webs(&mut self) {
+        for f in &mut self.functions {
+            let num_coalesced = f.coalesce_phi_webs();
+            if DEBUG.print() {
+                eprintln!("NAK coalesced {num_coalesced} phi copies");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liveness::Liveness;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    fn count_pcopies(f: &Function) -> usize {
+        count_instrs(f, |i| matches!(i.op, Op::ParCopy(_)))
+    }
+
+    /// A loop which feeds x2 back into the phi for x while x is still live
+    ///
+    /// x and x2 interfere so to_cssa has to insert a parallel copy before the
+    /// OpPhiSrcs.  If x2 is a copy of x, they hold the same value so we can
+    /// coalesce it away again.  If x2 is x + 1, we can't.
+    fn copy_loop(sm: &dyn ShaderModel, copy: bool) -> Function {
+        let mut t = TestFunctionBuilder::new(sm, 4);
+        let header = t.label(1);
+        let exit = t.label(3);
+        let x = t.alloc_ssa(RegFile::GPR, 1);
+        let x2 = t.alloc_ssa(RegFile::GPR, 1);
+
+        t.add_block(0, false, |b| {
+            let a = b.copy(0.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, a.into());
+            b.push_op(phi);
+        });
+        t.add_block(1, false, |b| {
+            let mut phi = OpPhiDsts::new();
+            phi.dsts.push(0, x.into());
+            b.push_op(phi);
+            if copy {
+                b.copy_to(x2.into(), x.into());
+            } else {
+                b.push_op(OpIAdd3 {
+                    dst: x2.into(),
+                    overflow: [Dst::None, Dst::None],
+                    srcs: [x.into(), 1.into(), 0.into()],
+                });
+            }
+            let z = b.iadd(x.into(), 1.into(), 0.into());
+            let cond =
+                b.isetp(IntCmpType::I32, IntCmpOp::Ge, z.into(), 100.into());
+            b.predicate(cond[0].into()).push_op(OpBra {
+                target: exit,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, false, |b| {
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, x2.into());
+            b.push_op(phi);
+            b.push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(3, false, |b| {
+            let dst = b.alloc_ssa(RegFile::GPR, 1);
+            b.copy_to(dst.into(), x.into());
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 3);
+        t.add_edge(2, 1);
+
+        t.into_function()
+    }
+
+    #[test]
+    fn test_spill_coalesced_web() {
+        let sm = ShaderModel70::new(75);
+        let mut f = copy_loop(&sm, true);
+        f.to_cssa();
+        let num_pcopies = count_pcopies(&f);
+        assert!(f.coalesce_phi_webs() == 1);
+        assert!(count_pcopies(&f) == num_pcopies - 1);
+
+        // x, its copy and x + 1 are all live at once
+        let live = SimpleLiveness::for_function(&f);
+        assert!(live.calc_max_live(&f)[RegFile::GPR] == 3);
+
+        f.spill_values(RegFile::GPR, 2);
+        let live = SimpleLiveness::for_function(&f);
+        assert!(live.calc_max_live(&f)[RegFile::GPR] <= 2);
+
+        let s = test_shader(&sm, f);
+        assert!(s.validate().is_ok());
+    }
+
+    #[test]
+    fn test_interfering_values_stay_split() {
+        let sm = ShaderModel70::new(75);
+        let mut f = copy_loop(&sm, false);
+        f.to_cssa();
+        let num_pcopies = count_pcopies(&f);
+        assert!(num_pcopies > 0);
+
+        // x and x + 1 are live at the same time and hold different values
+        assert!(f.coalesce_phi_webs() == 0);
+        assert!(count_pcopies(&f) == num_pcopies);
+    }
+
+    /// A loop which feeds x back into the phi for t1
+    ///
+    /// Nothing interferes across the webs of tmp0 and x but t1 is in the web
+    /// of x.  Making the OpPhiDsts define x directly would move the def of x
+    /// to where t1 is defined so the copy has to stay.
+    #[test]
+    fn test_def_at_moved_ip() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 4);
+        let header = t.label(1);
+        let exit = t.label(3);
+        let tmp0 = t.alloc_ssa(RegFile::GPR, 1);
+        let t1 = t.alloc_ssa(RegFile::GPR, 1);
+        let x = t.alloc_ssa(RegFile::GPR, 1);
+
+        t.add_block(0, false, |b| {
+            let a = b.copy(0.into());
+            let c = b.copy(a.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, a.into());
+            phi.srcs.push(1, c.into());
+            b.push_op(phi);
+        });
+        t.add_block(1, false, |b| {
+            let mut phi = OpPhiDsts::new();
+            phi.dsts.push(0, tmp0.into());
+            phi.dsts.push(1, t1.into());
+            b.push_op(phi);
+            let mut pcopy = OpParCopy::new();
+            pcopy.push(x.into(), tmp0.into());
+            b.push_op(pcopy);
+            let cond =
+                b.isetp(IntCmpType::I32, IntCmpOp::Ge, x.into(), 100.into());
+            b.predicate(cond[0].into()).push_op(OpBra {
+                target: exit,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, false, |b| {
+            let x2 = b.copy(x.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, x2.into());
+            phi.srcs.push(1, x.into());
+            b.push_op(phi);
+            b.push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(3, false, |b| {
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 3);
+        t.add_edge(2, 1);
+
+        let mut f = t.into_function();
+        assert!(f.coalesce_phi_webs() == 0);
+        assert!(count_pcopies(&f) == 1);
+    }
+}
@@ -26,6 +26,8 @@ fn init_info_from_nir(nak: &nak_compiler, nir: &nir_shader) -> ShaderInfo {
         num_control_barriers: 0,
         slm_size: nir.scratch_size,
         max_crs_depth: 0,
+        num_spills_to_mem: 0,
+        num_fills_from_mem: 0,
         uses_global_mem: false,
         writes_global_mem: false,
         // TODO: handle this.
@@ -166,6 +168,9 @@ fn init_info_from_nir(nak: &nak_compiler, nir: &nir_shader) -> ShaderInfo {
             }
             _ => panic!("Unknown shader stage"),
         },
+        debug_cbuf: None,
+        profile_blocks: Vec::new(),
+        sanitize_checks: Vec::new(),
     }
 }
 
@@ -184,6 +189,21 @@ fn alloc_ssa_for_nir(b: &mut impl SSABuilder, ssa: &nir_def) -> Vec<SSAValue> {
     vec
 }
 
+fn src_loc_for_nir(ni: &nir_instr) -> SrcLoc {
+    let mut loc = SrcLoc {
+        nir_instr: ni.index,
+        spirv_offset: 0,
+        line: 0,
+        column: 0,
+    };
+    if let Some(info) = ni.debug_info() {
+        loc.spirv_offset = info.spirv_offset;
+        loc.line = info.line;
+        loc.column = info.column;
+    }
+    loc
+}
+
 struct PhiAllocMap<'a> {
     alloc: &'a mut PhiAllocator,
     map: HashMap<(u32, u8), u32>,
@@ -3312,6 +3332,7 @@ impl<'a> ShaderFromNir<'a> {
                 None => {
                     b.push_op(OpBra {
                         target: target_label,
+                        cond: SrcRef::True.into(),
                     });
                 }
             }
@@ -3328,15 +3349,37 @@ impl<'a> ShaderFromNir<'a> {
     ) {
         // The fall-through edge has to come first
         self.cfg.add_edge(nb.index, fallthrough.index);
-        let op = if target.index == self.end_block_id {
-            Op::Exit(OpExit {})
-        } else {
-            self.cfg.add_edge(nb.index, target.index);
-            Op::Bra(OpBra {
-                target: self.get_block_label(target),
-            })
+        if target.index == self.end_block_id {
+            b.predicate(pred).push_op(OpExit {});
+            return;
+        }
+
+        self.cfg.add_edge(nb.index, target.index);
+        let target = self.get_block_label(target);
+
+        // On Turing+, BRA can take a uniform predicate directly.  This
+        // avoids a copy to a warp predicate and makes it obvious to later
+        // passes that the branch cannot diverge.  We only do this in uniform
+        // blocks because UPreds get spilled to warp predicates elsewhere.
+        let upred = match pred.pred_ref {
+            PredRef::SSA(ssa) => ssa.file() == RegFile::UPred,
+            _ => false,
         };
-        b.predicate(pred).push_op(op);
+        if upred && !nb.divergent && self.sm.sm() >= 75 {
+            let PredRef::SSA(ssa) = pred.pred_ref else {
+                unreachable!();
+            };
+            let cond: Src = ssa.into();
+            b.push_op(OpBra {
+                target: target,
+                cond: if pred.pred_inv { cond.bnot() } else { cond },
+            });
+        } else {
+            b.predicate(pred).push_op(OpBra {
+                target: target,
+                cond: SrcRef::True.into(),
+            });
+        }
     }
 
     fn parse_block(
@@ -3381,6 +3424,7 @@ impl<'a> ShaderFromNir<'a> {
             let Some(np) = ni.as_phi() else {
                 break;
             };
+            b.set_src_loc(Some(src_loc_for_nir(ni)));
 
             if DEBUG.annotate() {
                 let annotation = self
@@ -3429,8 +3473,11 @@ impl<'a> ShaderFromNir<'a> {
             }
         }
 
+        b.set_src_loc(None);
+
         let mut goto = None;
         for ni in nb.iter_instr_list() {
+            b.set_src_loc(Some(src_loc_for_nir(ni)));
             if DEBUG.annotate() && ni.type_ != nir_instr_type_phi {
                 let annotation = self
                     .nir_instr_printer
@@ -3478,6 +3525,7 @@ impl<'a> ShaderFromNir<'a> {
                 _ => panic!("Unsupported instruction type"),
             }
         }
+        b.set_src_loc(None);
 
         if self.sm.sm() < 70 {
             if let Some(ni) = nb.following_if() {
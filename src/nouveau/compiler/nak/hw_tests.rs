@@ -203,11 +203,16 @@ impl<'a> TestShaderBuilder<'a> {
             num_instrs: 0,
             slm_size: 0,
             max_crs_depth: 0,
+            num_spills_to_mem: 0,
+            num_fills_from_mem: 0,
             uses_global_mem: true,
             writes_global_mem: true,
             uses_fp64: false,
             stage: ShaderStageInfo::Compute(cs_info),
             io: ShaderIoInfo::None,
+            debug_cbuf: None,
+            profile_blocks: Vec::new(),
+            sanitize_checks: Vec::new(),
         };
         let mut s = Shader {
             sm: self.sm,
@@ -220,6 +225,7 @@ impl<'a> TestShaderBuilder<'a> {
         s.opt_dce();
         s.legalize();
 
+        s.convert_to_cssa();
         s.assign_regs();
         s.lower_par_copies();
         s.lower_copy_swap();
@@ -21,7 +21,7 @@ use std::iter::Zip;
 use std::ops::{BitAnd, BitOr, Deref, DerefMut, Index, IndexMut, Not, Range};
 use std::slice;
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Label {
     idx: u32,
 }
@@ -1493,8 +1493,6 @@ pub trait DstsAsSlice: AsSlice<Dst, Attr = DstType> {
         self.as_mut_slice()
     }
 
-    // Currently only used by test code
-    #[allow(dead_code)]
     fn dst_types(&self) -> DstTypeList {
         self.attrs()
     }
@@ -1659,8 +1657,6 @@ impl OpFoldData<'_> {
 }
 
 pub trait Foldable: SrcsAsSlice + DstsAsSlice {
-    // Currently only used by test code
-    #[allow(dead_code)]
     fn fold(&self, sm: &dyn ShaderModel, f: &mut OpFoldData<'_>);
 }
 
@@ -5609,11 +5605,23 @@ impl_display_for_op!(OpBSync);
 #[derive(Clone, SrcsAsSlice, DstsAsSlice)]
 pub struct OpBra {
     pub target: Label,
+
+    /// A warp-uniform branch condition
+    ///
+    /// This is only allowed to be something other than true on SM75+ where
+    /// it must be a UPred.  Non-uniform conditions go in the instruction
+    /// predicate instead.
+    #[src_type(Pred)]
+    pub cond: Src,
 }
 
 impl DisplayOp for OpBra {
     fn fmt_op(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "bra {}", self.target)
+        write!(f, "bra")?;
+        if self.cond.as_bool() != Some(true) {
+            write!(f, " {}", self.cond)?;
+        }
+        write!(f, " {}", self.target)
     }
 }
 impl_display_for_op!(OpBra);
@@ -6710,10 +6718,36 @@ impl fmt::Display for InstrDeps {
     }
 }
 
+/// The NIR instruction an instruction was generated from
+///
+/// The line, column, and SPIR-V offset come from the NIR debug info and are 0
+/// if the shader doesn't have any.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct SrcLoc {
+    pub nir_instr: u32,
+    pub spirv_offset: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for SrcLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nir:{}", self.nir_instr)?;
+        if self.line != 0 {
+            write!(f, " line:{}:{}", self.line, self.column)?;
+        }
+        if self.spirv_offset != 0 {
+            write!(f, " spirv:{:#x}", self.spirv_offset)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Instr {
     pub pred: Pred,
     pub op: Op,
     pub deps: InstrDeps,
+    pub src_loc: Option<SrcLoc>,
 }
 
 impl Instr {
@@ -6722,6 +6756,7 @@ impl Instr {
             op: op.into(),
             pred: true.into(),
             deps: InstrDeps::new(),
+            src_loc: None,
         }
     }
 
@@ -6791,6 +6826,37 @@ impl Instr {
         self.op.is_branch()
     }
 
+    /// Returns true if this is a branch which is always taken
+    pub fn is_uncond_branch(&self) -> bool {
+        if !self.is_branch() || !self.pred.is_true() {
+            return false;
+        }
+
+        match &self.op {
+            Op::Bra(op) => op.cond.as_bool() == Some(true),
+            _ => true,
+        }
+    }
+
+    /// Returns true if this is a branch which cannot cause the warp to
+    /// diverge because every live lane takes the same path.
+    pub fn is_uniform_branch(&self) -> bool {
+        if !self.is_branch() {
+            return false;
+        }
+
+        let pred_uniform = match &self.pred.pred_ref {
+            PredRef::None => true,
+            PredRef::SSA(ssa) => ssa.is_uniform(),
+            PredRef::Reg(reg) => reg.is_uniform(),
+        };
+
+        match &self.op {
+            Op::Bra(op) => pred_uniform && op.cond.is_uniform(),
+            _ => pred_uniform,
+        }
+    }
+
     pub fn uses_global_mem(&self) -> bool {
         match &self.op {
             Op::Atom(op) => op.mem_space != MemSpace::Local,
@@ -7027,12 +7093,19 @@ impl BasicBlock {
     ) {
         let mut instrs = Vec::new();
         for i in self.instrs.drain(..) {
+            // Whatever an instruction gets lowered to still came from the
+            // same place in the source.
+            let src_loc = i.src_loc;
             match map(i) {
                 MappedInstrs::None => (),
-                MappedInstrs::One(i) => {
+                MappedInstrs::One(mut i) => {
+                    i.src_loc = i.src_loc.or(src_loc);
                     instrs.push(i);
                 }
                 MappedInstrs::Many(mut v) => {
+                    for i in &mut v {
+                        i.src_loc = i.src_loc.or(src_loc);
+                    }
                     instrs.append(&mut v);
                 }
             }
@@ -7130,7 +7203,7 @@ impl BasicBlock {
 
     pub fn falls_through(&self) -> bool {
         if let Some(i) = self.branch() {
-            !i.pred.is_true()
+            !i.is_uncond_branch()
         } else {
             true
         }
@@ -7144,6 +7217,21 @@ pub struct Function {
 }
 
 impl Function {
+    /// Returns a label allocator which won't hand out any label already used
+    /// by a block or a labeled Nop in this function
+    pub fn label_alloc(&self) -> LabelAllocator {
+        let mut count = 0;
+        for b in &self.blocks {
+            count = max(count, b.label.idx + 1);
+            for instr in &b.instrs {
+                if let Op::Nop(OpNop { label: Some(label) }) = &instr.op {
+                    count = max(count, label.idx + 1);
+                }
+            }
+        }
+        LabelAllocator { count: count }
+    }
+
     pub fn map_instrs(
         &mut self,
         mut map: impl FnMut(Box<Instr>, &mut SSAValueAllocator) -> MappedInstrs,
@@ -7451,6 +7539,23 @@ pub enum ShaderIoInfo {
     Fragment(FragmentIoInfo),
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SanitizeCheckKind {
+    SharedBounds,
+    LocalBounds,
+    UndefAddr,
+}
+
+/// A memory access checked by sanitize_mem()
+#[derive(Debug)]
+pub struct SanitizeCheck {
+    /// The label of the Nop right before the access
+    pub label: Label,
+    pub kind: SanitizeCheckKind,
+    pub size: u32,
+    pub limit: u32,
+}
+
 #[derive(Debug)]
 pub struct ShaderInfo {
     pub num_gprs: u8,
@@ -7458,11 +7563,24 @@ pub struct ShaderInfo {
     pub num_instrs: u32,
     pub slm_size: u32,
     pub max_crs_depth: u32,
+    pub num_spills_to_mem: u32,
+    pub num_fills_from_mem: u32,
     pub uses_global_mem: bool,
     pub writes_global_mem: bool,
     pub uses_fp64: bool,
     pub stage: ShaderStageInfo,
     pub io: ShaderIoInfo,
+
+    /// The constant buffer the driver reserved for instrumentation.
+    /// Instrumentation passes only run if this is set.
+    pub debug_cbuf: Option<u8>,
+
+    /// The block each profiling counter belongs to.  Only filled out by
+    /// profile_blocks().
+    pub profile_blocks: Vec<Label>,
+
+    /// Only filled out by sanitize_mem()
+    pub sanitize_checks: Vec<SanitizeCheck>,
 }
 
 pub trait ShaderModel {
@@ -7475,6 +7593,10 @@ pub trait ShaderModel {
 
     fn legalize_op(&self, b: &mut LegalizeBuilder, op: &mut Op);
     fn encode_shader(&self, s: &Shader<'_>) -> Vec<u32>;
+
+    /// Returns the byte offset of each instruction in the encoded shader, in
+    /// the order Shader::for_each_instr() visits them
+    fn instr_offsets(&self, s: &Shader<'_>) -> Vec<u32>;
 }
 
 /// For compute shaders, large values of local_size impose an additional limit
@@ -5,32 +5,45 @@ mod api;
 mod assign_regs;
 mod builder;
 mod calc_instr_deps;
+mod cli;
+mod coalesce_phi_webs;
 mod from_nir;
 mod ir;
 mod legalize;
 mod liveness;
 mod lower_copy_swap;
 mod lower_par_copies;
+mod opt_algebraic;
 mod opt_bar_prop;
+mod opt_bssy;
 mod opt_copy_prop;
 mod opt_crs;
 mod opt_dce;
+mod opt_hoist_handles;
 mod opt_jump_thread;
 mod opt_lop;
 mod opt_out;
 mod opt_prmt;
 mod opt_uniform_instrs;
+mod passes;
+mod profile_blocks;
 mod qmd;
 mod repair_ssa;
+mod sanitize_mem;
 mod sm50;
 mod sm70;
 mod sph;
 mod spill_values;
 mod to_cssa;
 mod union_find;
+mod validate;
+mod validate_xfb;
 
 #[cfg(test)]
 mod hw_tests;
 
 #[cfg(test)]
 mod hw_runner;
+
+#[cfg(test)]
+mod test_util;
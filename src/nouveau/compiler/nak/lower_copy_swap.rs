@@ -11,6 +11,8 @@ use std::cmp::max;
 struct LowerCopySwap {
     slm_start: u32,
     slm_size: u32,
+    num_spills: u32,
+    num_fills: u32,
 }
 
 impl LowerCopySwap {
@@ -18,6 +20,8 @@ impl LowerCopySwap {
         Self {
             slm_start: slm_size,
             slm_size: slm_size,
+            num_spills: 0,
+            num_fills: 0,
         }
     }
 
@@ -82,6 +86,7 @@ impl LowerCopySwap {
                         };
                         let addr = self.slm_start + src_reg.base_idx() * 4;
                         self.slm_size = max(self.slm_size, addr + 4);
+                        self.num_fills += 1;
                         b.push_op(OpLd {
                             dst: copy.dst,
                             addr: Src::new_zero(),
@@ -161,6 +166,7 @@ impl LowerCopySwap {
                         };
                         let addr = self.slm_start + dst_reg.base_idx() * 4;
                         self.slm_size = max(self.slm_size, addr + 4);
+                        self.num_spills += 1;
                         b.push_op(OpSt {
                             addr: Src::new_zero(),
                             data: copy.src,
@@ -288,5 +294,7 @@ impl Shader<'_> {
         let mut pass = LowerCopySwap::new(self.info.slm_size);
         pass.run(self);
         self.info.slm_size = pass.slm_size;
+        self.info.num_spills_to_mem = pass.num_spills;
+        self.info.num_fills_from_mem = pass.num_fills;
     }
 }
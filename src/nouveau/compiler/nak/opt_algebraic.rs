@@ -0,0 +1,1021 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! A small rule-driven algebraic optimizer for NAK IR
+//!
+//! NIR does most of our algebraic optimization but a lot of what we emit in
+//! from_nir (64-bit arithmetic splitting, address calculations, integer
+//! multiplies) creates new opportunities which NIR never gets to see.  This
+//! pass does constant folding via the Foldable trait and then runs each
+//! instruction through a table of peephole rules.  Adding a rule is just a
+//! matter of writing a function which takes an op and returns a replacement
+//! and adding it to ALG_RULES.
+//!
+//! This pass leaves behind copies and dead instructions so it should be
+//! followed by opt_copy_prop and opt_dce.
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+
+use std::collections::HashMap;
+
+struct AlgCtx<'a> {
+    sm: &'a dyn ShaderModel,
+
+    /// SSA values which are known to be constant
+    consts: HashMap<SSAValue, FoldData>,
+
+    /// Sources for every unpredicated OpIAdd3 with no overflow, along with
+    /// the block in which it lives
+    iadd3: HashMap<SSAValue, (usize, [Src; 3])>,
+
+    use_counts: HashMap<SSAValue, u32>,
+
+    /// The block we're currently optimizing
+    bi: usize,
+}
+
+impl AlgCtx<'_> {
+    fn src_as_u32(&self, src: &Src) -> Option<u32> {
+        if !src.src_mod.is_none() {
+            return None;
+        }
+
+        match src.src_ref {
+            SrcRef::Zero => Some(0),
+            SrcRef::Imm32(u) => Some(u),
+            SrcRef::SSA(vec) if vec.comps() == 1 => {
+                match self.consts.get(&vec[0]) {
+                    Some(FoldData::U32(u)) => Some(*u),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn src_as_bool(&self, src: &Src) -> Option<bool> {
+        let b = match src.src_ref {
+            SrcRef::True => true,
+            SrcRef::False => false,
+            SrcRef::SSA(vec) if vec.comps() == 1 => {
+                match self.consts.get(&vec[0]) {
+                    Some(FoldData::Pred(b)) => *b,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        match src.src_mod {
+            SrcMod::None => Some(b),
+            SrcMod::BNot => Some(!b),
+            _ => None,
+        }
+    }
+
+    fn use_count(&self, ssa: &SSAValue) -> u32 {
+        *self.use_counts.get(ssa).unwrap_or(&0)
+    }
+}
+
+fn copy_op(dst: Dst, src: Src) -> Op {
+    Op::Copy(OpCopy { dst: dst, src: src })
+}
+
+fn shl_op(sm: &dyn ShaderModel, dst: Dst, x: Src, shift: u32) -> Op {
+    debug_assert!(shift < 32);
+    if sm.sm() >= 70 {
+        Op::Shf(OpShf {
+            dst: dst,
+            low: x,
+            high: 0.into(),
+            shift: shift.into(),
+            right: false,
+            wrap: true,
+            data_type: IntType::I32,
+            dst_high: false,
+        })
+    } else {
+        Op::Shl(OpShl {
+            dst: dst,
+            src: x,
+            shift: shift.into(),
+            wrap: true,
+        })
+    }
+}
+
+fn iadd3_op(dst: Dst, srcs: [Src; 3]) -> Op {
+    Op::IAdd3(OpIAdd3 {
+        dst: dst,
+        overflow: [Dst::None; 2],
+        srcs: srcs,
+    })
+}
+
+/// x * 0 + z => z and x * 1 + z => x + z
+fn imad_trivial(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IMad(imad) = op else {
+        return None;
+    };
+
+    for i in 0..2 {
+        let x = imad.srcs[1 - i];
+        let z = imad.srcs[2];
+        match ctx.src_as_u32(&imad.srcs[i]) {
+            Some(0) => {
+                return Some(iadd3_op(imad.dst, [z, 0.into(), 0.into()]));
+            }
+            Some(1) if x.src_mod.is_none() => {
+                return Some(iadd3_op(imad.dst, [x, z, 0.into()]));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// x * 2^k => x << k and x * 2^k + z => lea(x, z, k)
+fn imad_pow2(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IMad(imad) = op else {
+        return None;
+    };
+
+    for i in 0..2 {
+        let x = imad.srcs[1 - i];
+        let z = imad.srcs[2];
+        let Some(y) = ctx.src_as_u32(&imad.srcs[i]) else {
+            continue;
+        };
+
+        if !y.is_power_of_two() || y == 1 || !x.src_mod.is_none() {
+            continue;
+        }
+
+        let shift = y.trailing_zeros();
+        if z.is_zero() {
+            return Some(shl_op(ctx.sm, imad.dst, x, shift));
+        } else if matches!(z.src_mod, SrcMod::None | SrcMod::INeg) {
+            return Some(Op::Lea(OpLea {
+                dst: imad.dst,
+                overflow: Dst::None,
+                a: x,
+                b: z,
+                a_high: 0.into(),
+                shift: shift.try_into().unwrap(),
+                dst_high: false,
+                intermediate_mod: SrcMod::None,
+            }));
+        }
+    }
+    None
+}
+
+/// x * (2^k + 1) => lea(x, x, k)
+fn imad_pow2_plus_one(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IMad(imad) = op else {
+        return None;
+    };
+
+    if !imad.srcs[2].is_zero() {
+        return None;
+    }
+
+    for i in 0..2 {
+        let x = imad.srcs[1 - i];
+        let Some(y) = ctx.src_as_u32(&imad.srcs[i]) else {
+            continue;
+        };
+
+        if y < 3 || !(y - 1).is_power_of_two() || !x.src_mod.is_none() {
+            continue;
+        }
+
+        if x.src_ref.as_ssa().is_none() {
+            continue;
+        }
+
+        return Some(Op::Lea(OpLea {
+            dst: imad.dst,
+            overflow: Dst::None,
+            a: x,
+            b: x,
+            a_high: 0.into(),
+            shift: (y - 1).trailing_zeros().try_into().unwrap(),
+            dst_high: false,
+            intermediate_mod: SrcMod::None,
+        }));
+    }
+    None
+}
+
+/// The SM50 version of imad_trivial and imad_pow2
+fn imul_pow2(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IMul(imul) = op else {
+        return None;
+    };
+
+    if imul.high {
+        return None;
+    }
+
+    for i in 0..2 {
+        let x = imul.srcs[1 - i];
+        let Some(y) = ctx.src_as_u32(&imul.srcs[i]) else {
+            continue;
+        };
+
+        if !x.src_mod.is_none() {
+            continue;
+        }
+
+        if y == 0 {
+            return Some(copy_op(imul.dst, 0.into()));
+        } else if y == 1 {
+            return Some(copy_op(imul.dst, x));
+        } else if y.is_power_of_two() {
+            let shift = y.trailing_zeros();
+            return Some(shl_op(ctx.sm, imul.dst, x, shift));
+        }
+    }
+    None
+}
+
+/// Shifts by zero are copies
+fn shift_by_zero(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    match op {
+        Op::Shf(shf) => {
+            if ctx.src_as_u32(&shf.shift) != Some(0) {
+                return None;
+            }
+
+            if shf.data_type.bits() != 32 {
+                return None;
+            }
+
+            if !shf.right && !shf.dst_high && shf.high.is_zero() {
+                Some(copy_op(shf.dst, shf.low))
+            } else if shf.right && shf.dst_high && shf.low.is_zero() {
+                Some(copy_op(shf.dst, shf.high))
+            } else {
+                None
+            }
+        }
+        Op::Shl(shl) if ctx.src_as_u32(&shl.shift) == Some(0) => {
+            Some(copy_op(shl.dst, shl.src))
+        }
+        Op::Shr(shr) if ctx.src_as_u32(&shr.shift) == Some(0) => {
+            Some(copy_op(shr.dst, shr.src))
+        }
+        _ => None,
+    }
+}
+
+/// lea(a, b, 0) => a + b
+fn lea_no_shift(_ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::Lea(lea) = op else {
+        return None;
+    };
+
+    if lea.shift != 0
+        || lea.dst_high
+        || !lea.overflow.is_none()
+        || !lea.intermediate_mod.is_none()
+        || !lea.a.src_mod.is_none()
+    {
+        return None;
+    }
+
+    Some(iadd3_op(lea.dst, [lea.a, lea.b, 0.into()]))
+}
+
+/// iadd3.x with no carry-in and no modifiers is just iadd3
+fn iadd3x_no_carry(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IAdd3X(add) = op else {
+        return None;
+    };
+
+    if !add.overflow[0].is_none() || !add.overflow[1].is_none() {
+        return None;
+    }
+
+    for carry in &add.carry {
+        if ctx.src_as_bool(carry) != Some(false) {
+            return None;
+        }
+    }
+
+    for src in &add.srcs {
+        if !src.src_mod.is_none() {
+            return None;
+        }
+    }
+
+    Some(iadd3_op(add.dst, add.srcs))
+}
+
+/// Combine immediates in an iadd3
+fn iadd3_merge_imm(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IAdd3(add) = op else {
+        return None;
+    };
+
+    if !add.overflow[0].is_none() || !add.overflow[1].is_none() {
+        return None;
+    }
+
+    let mut imm_srcs = Vec::new();
+    for (i, src) in add.srcs.iter().enumerate() {
+        if matches!(src.src_ref, SrcRef::Imm32(_)) {
+            if let Some(u) = ctx.src_as_u32(src) {
+                imm_srcs.push((i, u));
+            }
+        }
+    }
+
+    if imm_srcs.len() < 2 {
+        return None;
+    }
+
+    // The sum goes in the first immediate slot so we never clobber a
+    // register source.
+    let mut srcs = add.srcs;
+    let mut sum = 0_u32;
+    for &(i, u) in &imm_srcs {
+        sum = sum.wrapping_add(u);
+        srcs[i] = 0.into();
+    }
+    srcs[imm_srcs[0].0] = sum.into();
+
+    // Keep non-zero sources in the first two slots
+    let mut srcs_iter = srcs.iter().filter(|s| !s.is_zero()).copied();
+    let srcs = [
+        srcs_iter.next().unwrap_or_else(Src::new_zero),
+        srcs_iter.next().unwrap_or_else(Src::new_zero),
+        srcs_iter.next().unwrap_or_else(Src::new_zero),
+    ];
+
+    Some(iadd3_op(add.dst, srcs))
+}
+
+/// (a + b) + c => a + b + c when the inner add has a free slot
+fn iadd3_chain(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::IAdd3(add) = op else {
+        return None;
+    };
+
+    if !add.overflow[0].is_none() || !add.overflow[1].is_none() {
+        return None;
+    }
+
+    let dst = add.dst.as_ssa()?;
+    let num_zero = add.srcs.iter().filter(|s| s.is_zero()).count();
+    if num_zero == 0 {
+        return None;
+    }
+
+    for (i, src) in add.srcs.iter().enumerate() {
+        let Some(ssa) = src.as_ssa() else {
+            continue;
+        };
+        if ssa.comps() != 1 || ctx.use_count(&ssa[0]) != 1 {
+            continue;
+        }
+
+        let Some((inner_bi, inner)) = ctx.iadd3.get(&ssa[0]) else {
+            continue;
+        };
+
+        // Don't extend live ranges across blocks or mix in non-uniform
+        // values into a uniform add.
+        if *inner_bi != ctx.bi || (dst.is_uniform() && !ssa.is_uniform()) {
+            continue;
+        }
+
+        let inner_srcs: Vec<Src> =
+            inner.iter().filter(|s| !s.is_zero()).copied().collect();
+        let outer_srcs: Vec<Src> = add
+            .srcs
+            .iter()
+            .enumerate()
+            .filter(|(j, s)| *j != i && !s.is_zero())
+            .map(|(_, s)| *s)
+            .collect();
+
+        if inner_srcs.len() + outer_srcs.len() > 3 {
+            continue;
+        }
+
+        // The hardware can only take one immediate or cbuf
+        let num_non_reg = inner_srcs
+            .iter()
+            .chain(outer_srcs.iter())
+            .filter(|s| !matches!(s.src_ref, SrcRef::SSA(_)))
+            .count();
+        if num_non_reg > 1 {
+            continue;
+        }
+
+        let mut srcs = [Src::new_zero(); 3];
+        for (j, s) in inner_srcs.iter().chain(outer_srcs.iter()).enumerate() {
+            srcs[j] = *s;
+        }
+        return Some(iadd3_op(add.dst, srcs));
+    }
+    None
+}
+
+/// sel(c, x, x) => x and sel(true/false, x, y) => x/y
+fn sel_trivial(ctx: &AlgCtx, op: &Op) -> Option<Op> {
+    let Op::Sel(sel) = op else {
+        return None;
+    };
+
+    if sel.srcs[0] == sel.srcs[1] {
+        return Some(copy_op(sel.dst, sel.srcs[0]));
+    }
+
+    match ctx.src_as_bool(&sel.cond) {
+        Some(true) => Some(copy_op(sel.dst, sel.srcs[0])),
+        Some(false) => Some(copy_op(sel.dst, sel.srcs[1])),
+        None => None,
+    }
+}
+
+struct AlgRule {
+    name: &'static str,
+    min_sm: u8,
+    apply: fn(&AlgCtx, &Op) -> Option<Op>,
+}
+
+const ALG_RULES: &[AlgRule] = &[
+    AlgRule {
+        name: "imad_trivial",
+        min_sm: 70,
+        apply: imad_trivial,
+    },
+    AlgRule {
+        name: "imad_pow2",
+        min_sm: 70,
+        apply: imad_pow2,
+    },
+    AlgRule {
+        name: "imad_pow2_plus_one",
+        min_sm: 70,
+        apply: imad_pow2_plus_one,
+    },
+    AlgRule {
+        name: "imul_pow2",
+        min_sm: 0,
+        apply: imul_pow2,
+    },
+    AlgRule {
+        name: "shift_by_zero",
+        min_sm: 0,
+        apply: shift_by_zero,
+    },
+    AlgRule {
+        name: "lea_no_shift",
+        min_sm: 70,
+        apply: lea_no_shift,
+    },
+    AlgRule {
+        name: "iadd3x_no_carry",
+        min_sm: 70,
+        apply: iadd3x_no_carry,
+    },
+    AlgRule {
+        name: "iadd3_merge_imm",
+        min_sm: 70,
+        apply: iadd3_merge_imm,
+    },
+    AlgRule {
+        name: "iadd3_chain",
+        min_sm: 70,
+        apply: iadd3_chain,
+    },
+    AlgRule {
+        name: "sel_trivial",
+        min_sm: 0,
+        apply: sel_trivial,
+    },
+];
+
+fn fold_op<T: Foldable>(ctx: &AlgCtx, op: &T) -> Option<Vec<FoldData>> {
+    let src_types = op.src_types();
+    let mut srcs = Vec::new();
+    for (i, src) in op.srcs_as_slice().iter().enumerate() {
+        let data = match &src.src_ref {
+            SrcRef::Zero | SrcRef::Imm32(_) => FoldData::U32(0),
+            SrcRef::True | SrcRef::False => FoldData::Pred(false),
+            SrcRef::SSA(vec) => {
+                if vec.comps() != 1 {
+                    return None;
+                }
+                *ctx.consts.get(&vec[0])?
+            }
+            SrcRef::CBuf(_) | SrcRef::Reg(_) => return None,
+        };
+
+        // Carries are never constant
+        if src_types[i] == SrcType::Carry {
+            return None;
+        }
+
+        srcs.push(data);
+    }
+
+    let dst_types = op.dst_types();
+    let mut dsts = Vec::new();
+    for (i, dst) in op.dsts_as_slice().iter().enumerate() {
+        match dst_types[i] {
+            DstType::Pred => dsts.push(FoldData::Pred(false)),
+            DstType::GPR => dsts.push(FoldData::U32(0)),
+            _ => {
+                if !dst.is_none() {
+                    return None;
+                }
+                dsts.push(FoldData::U32(0));
+            }
+        }
+    }
+
+    let mut f = OpFoldData {
+        dsts: &mut dsts,
+        srcs: &srcs,
+    };
+    op.fold(ctx.sm, &mut f);
+
+    Some(dsts)
+}
+
+fn try_fold(ctx: &AlgCtx, op: &Op) -> Option<Vec<FoldData>> {
+    match op {
+        Op::Flo(op) => fold_op(ctx, op),
+        Op::IAbs(op) => fold_op(ctx, op),
+        Op::IAdd2(op) => fold_op(ctx, op),
+        Op::IAdd2X(op) => fold_op(ctx, op),
+        Op::IAdd3(op) => fold_op(ctx, op),
+        Op::IAdd3X(op) => fold_op(ctx, op),
+        Op::ISetP(op) if !op.ex || ctx.sm.sm() >= 70 => fold_op(ctx, op),
+        Op::Lea(op) => fold_op(ctx, op),
+        Op::LeaX(op) => fold_op(ctx, op),
+        Op::Lop2(op) => fold_op(ctx, op),
+        Op::Lop3(op) => fold_op(ctx, op),
+        Op::Shf(op) => fold_op(ctx, op),
+        Op::Prmt(op) if op.mode == PrmtMode::Index => fold_op(ctx, op),
+        Op::PSetP(op) => fold_op(ctx, op),
+        Op::PopC(op) => fold_op(ctx, op),
+        _ => None,
+    }
+}
+
+struct AlgPass<'a> {
+    ctx: AlgCtx<'a>,
+    num_folded: u32,
+    rule_counts: Vec<u32>,
+}
+
+impl<'a> AlgPass<'a> {
+    fn new(sm: &'a dyn ShaderModel, f: &Function) -> Self {
+        let mut use_counts = HashMap::new();
+        for b in &f.blocks {
+            for instr in &b.instrs {
+                instr.for_each_ssa_use(|ssa| {
+                    *use_counts.entry(*ssa).or_default() += 1;
+                });
+            }
+        }
+
+        AlgPass {
+            ctx: AlgCtx {
+                sm: sm,
+                consts: HashMap::new(),
+                iadd3: HashMap::new(),
+                use_counts: use_counts,
+                bi: 0,
+            },
+            num_folded: 0,
+            rule_counts: vec![0; ALG_RULES.len()],
+        }
+    }
+
+    fn add_instr(&mut self, instr: &Instr) {
+        if !instr.pred.is_true() {
+            return;
+        }
+
+        match &instr.op {
+            Op::Copy(OpCopy { dst, src })
+            | Op::Mov(OpMov {
+                dst,
+                src,
+                quad_lanes: 0xf,
+            }) => {
+                let Some(dst) = dst.as_ssa() else {
+                    return;
+                };
+                if dst.comps() != 1 {
+                    return;
+                }
+                let data = if dst.is_predicate() {
+                    self.ctx.src_as_bool(src).map(FoldData::Pred)
+                } else if dst.is_gpr() {
+                    self.ctx.src_as_u32(src).map(FoldData::U32)
+                } else {
+                    None
+                };
+                if let Some(data) = data {
+                    self.ctx.consts.insert(dst[0], data);
+                }
+            }
+            Op::IAdd3(add)
+                if add.overflow[0].is_none() && add.overflow[1].is_none() =>
+            {
+                if let Some(dst) = add.dst.as_ssa() {
+                    self.ctx.iadd3.insert(dst[0], (self.ctx.bi, add.srcs));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Replaces a foldable instruction with copies of constants
+    fn fold_instr(&mut self, instr: &Instr) -> Option<Vec<Box<Instr>>> {
+        let data = try_fold(&self.ctx, &instr.op)?;
+
+        let mut instrs = Vec::new();
+        for (i, dst) in instr.dsts().iter().enumerate() {
+            let Dst::SSA(vec) = dst else {
+                continue;
+            };
+            assert!(vec.comps() == 1);
+
+            let src: Src = match data[i] {
+                FoldData::Pred(b) => b.into(),
+                FoldData::U32(u) => u.into(),
+                _ => panic!("Unexpected fold result"),
+            };
+            instrs.push(Instr::new_boxed(OpCopy {
+                dst: *dst,
+                src: src,
+            }));
+        }
+        self.num_folded += 1;
+
+        Some(instrs)
+    }
+
+    fn apply_rules(&mut self, instr: &mut Instr) {
+        // Rules may enable other rules so keep going until nothing matches
+        // but don't let a bad pair of rules send us into an infinite loop.
+        for _ in 0..8 {
+            let mut progress = false;
+            for (i, rule) in ALG_RULES.iter().enumerate() {
+                if self.ctx.sm.sm() < rule.min_sm {
+                    continue;
+                }
+
+                if let Some(op) = (rule.apply)(&self.ctx, &instr.op) {
+                    instr.op = op;
+                    self.rule_counts[i] += 1;
+                    progress = true;
+                    break;
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    fn run(&mut self, f: &mut Function) {
+        for (bi, b) in f.blocks.iter_mut().enumerate() {
+            self.ctx.bi = bi;
+
+            let mut instrs = Vec::new();
+            for mut instr in b.instrs.drain(..) {
+                if instr.pred.is_true() {
+                    if let Some(folded) = self.fold_instr(&instr) {
+                        for instr in folded {
+                            self.add_instr(&instr);
+                            instrs.push(instr);
+                        }
+                        continue;
+                    }
+
+                    self.apply_rules(&mut instr);
+                }
+
+                self.add_instr(&instr);
+                instrs.push(instr);
+            }
+            b.instrs = instrs;
+        }
+    }
+
+    fn print_stats(&self) {
+        eprintln!("opt_algebraic: {} instructions folded", self.num_folded);
+        for (i, rule) in ALG_RULES.iter().enumerate() {
+            if self.rule_counts[i] > 0 {
+                eprintln!(
+                    "opt_algebraic: {} applied {} times",
+                    rule.name, self.rule_counts[i],
+                );
+            }
+        }
+    }
+}
+
+impl Shader<'_> {
+    pub fn opt_algebraic(&mut self) {
+        for f in &mut self.functions {
+            let mut pass = AlgPass::new(self.sm, f);
+            pass.run(f);
+            if DEBUG.print() {
+                pass.print_stats();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm50::ShaderModel50;
+    use crate::sm70::ShaderModel70;
+
+    fn test_ctx(sm: &dyn ShaderModel) -> AlgCtx<'_> {
+        AlgCtx {
+            sm: sm,
+            consts: HashMap::new(),
+            iadd3: HashMap::new(),
+            use_counts: HashMap::new(),
+            bi: 0,
+        }
+    }
+
+    /// Checks that 5 + 7 + x folds to x + 12 with x in slot x_slot
+    fn check_merge_imm(x_slot: usize) {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let x = alloc.alloc_vec(RegFile::GPR, 1);
+        let dst = alloc.alloc_vec(RegFile::GPR, 1);
+
+        let mut imms = [5_u32, 7_u32].into_iter();
+        let srcs = [0, 1, 2].map(|i| {
+            if i == x_slot {
+                x.into()
+            } else {
+                imms.next().unwrap().into()
+            }
+        });
+
+        let op = iadd3_op(dst.into(), srcs);
+        let Some(Op::IAdd3(add)) = iadd3_merge_imm(&ctx, &op) else {
+            panic!("Immediates were not merged");
+        };
+
+        let num_x = add.srcs.iter().filter(|s| s.as_ssa() == Some(&x)).count();
+        let num_sum = add
+            .srcs
+            .iter()
+            .filter(|s| s.src_ref == SrcRef::Imm32(12))
+            .count();
+        assert!(num_x == 1 && num_sum == 1);
+        assert!(add.srcs[2].is_zero());
+    }
+
+    #[test]
+    fn test_iadd3_merge_imm_imm_ssa() {
+        check_merge_imm(2);
+    }
+
+    #[test]
+    fn test_iadd3_merge_imm_ssa_imm() {
+        check_merge_imm(1);
+    }
+
+    #[test]
+    fn test_iadd3_merge_ssa_imm_imm() {
+        check_merge_imm(0);
+    }
+
+    fn imad_op(dst: SSARef, srcs: [Src; 3]) -> Op {
+        Op::IMad(OpIMad {
+            dst: dst.into(),
+            srcs: srcs,
+            signed: false,
+        })
+    }
+
+    fn lea_op(dst: SSARef, a: Src, b: Src, shift: u8) -> Op {
+        Op::Lea(OpLea {
+            dst: dst.into(),
+            overflow: Dst::None,
+            a: a,
+            b: b,
+            a_high: 0.into(),
+            shift: shift,
+            dst_high: false,
+            intermediate_mod: SrcMod::None,
+        })
+    }
+
+    #[test]
+    fn test_imad_trivial() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, z, dst] = [(); 3].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let op = imad_op(dst, [x.into(), 1.into(), z.into()]);
+        let Some(Op::IAdd3(add)) = imad_trivial(&ctx, &op) else {
+            panic!("x * 1 + z was not turned into an add");
+        };
+        assert!(add.srcs == [x.into(), z.into(), 0.into()]);
+
+        let op = imad_op(dst, [x.into(), 3.into(), z.into()]);
+        assert!(imad_trivial(&ctx, &op).is_none());
+    }
+
+    #[test]
+    fn test_imad_pow2() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, z, dst] = [(); 3].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let op = imad_op(dst, [8.into(), x.into(), z.into()]);
+        let Some(Op::Lea(lea)) = imad_pow2(&ctx, &op) else {
+            panic!("8 * x + z was not turned into a lea");
+        };
+        assert!(lea.a == x.into() && lea.b == z.into() && lea.shift == 3);
+
+        let op = imad_op(dst, [6.into(), x.into(), z.into()]);
+        assert!(imad_pow2(&ctx, &op).is_none());
+    }
+
+    #[test]
+    fn test_imad_pow2_plus_one() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, dst] = [(); 2].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let op = imad_op(dst, [x.into(), 9.into(), 0.into()]);
+        let Some(Op::Lea(lea)) = imad_pow2_plus_one(&ctx, &op) else {
+            panic!("x * 9 was not turned into a lea");
+        };
+        assert!(lea.a == x.into() && lea.b == x.into() && lea.shift == 3);
+
+        let op = imad_op(dst, [x.into(), 7.into(), 0.into()]);
+        assert!(imad_pow2_plus_one(&ctx, &op).is_none());
+    }
+
+    #[test]
+    fn test_imul_pow2() {
+        let sm = ShaderModel50::new(50);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, dst] = [(); 2].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let imul = |y: u32| {
+            Op::IMul(OpIMul {
+                dst: dst.into(),
+                srcs: [x.into(), y.into()],
+                signed: [false; 2],
+                high: false,
+            })
+        };
+
+        let Some(Op::Shl(shl)) = imul_pow2(&ctx, &imul(16)) else {
+            panic!("x * 16 was not turned into a shift");
+        };
+        assert!(shl.src == x.into() && shl.shift == 4.into());
+
+        assert!(imul_pow2(&ctx, &imul(12)).is_none());
+    }
+
+    #[test]
+    fn test_shift_by_zero() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, dst] = [(); 2].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let shf = |data_type: IntType| {
+            Op::Shf(OpShf {
+                dst: dst.into(),
+                low: x.into(),
+                high: 0.into(),
+                shift: 0.into(),
+                right: false,
+                wrap: true,
+                data_type: data_type,
+                dst_high: false,
+            })
+        };
+
+        let Some(Op::Copy(copy)) = shift_by_zero(&ctx, &shf(IntType::I32))
+        else {
+            panic!("Shift by zero was not turned into a copy");
+        };
+        assert!(copy.src == x.into());
+
+        // 64-bit shifts take their low bits from somewhere else
+        assert!(shift_by_zero(&ctx, &shf(IntType::I64)).is_none());
+    }
+
+    #[test]
+    fn test_lea_no_shift() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [a, b, dst] = [(); 3].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        let op = lea_op(dst, a.into(), b.into(), 0);
+        let Some(Op::IAdd3(add)) = lea_no_shift(&ctx, &op) else {
+            panic!("lea without a shift was not turned into an add");
+        };
+        assert!(add.srcs == [a.into(), b.into(), 0.into()]);
+
+        let op = lea_op(dst, a.into(), b.into(), 2);
+        assert!(lea_no_shift(&ctx, &op).is_none());
+    }
+
+    #[test]
+    fn test_iadd3x_no_carry() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [a, b, dst] = [(); 3].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+        let carry = alloc.alloc_vec(RegFile::Pred, 1);
+
+        let iadd3x = |carry: Src| {
+            Op::IAdd3X(OpIAdd3X {
+                dst: dst.into(),
+                overflow: [Dst::None; 2],
+                srcs: [a.into(), b.into(), 0.into()],
+                carry: [carry, false.into()],
+            })
+        };
+
+        let Some(Op::IAdd3(add)) = iadd3x_no_carry(&ctx, &iadd3x(false.into()))
+        else {
+            panic!("iadd3.x with no carry was not turned into iadd3");
+        };
+        assert!(add.srcs == [a.into(), b.into(), 0.into()]);
+
+        assert!(iadd3x_no_carry(&ctx, &iadd3x(carry.into())).is_none());
+    }
+
+    #[test]
+    fn test_iadd3_chain() {
+        let sm = ShaderModel70::new(75);
+        let mut ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [a, b, c, t, dst] =
+            [(); 5].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+
+        ctx.iadd3.insert(t[0], (0, [a.into(), b.into(), 0.into()]));
+        let op = iadd3_op(dst.into(), [t.into(), c.into(), 0.into()]);
+
+        ctx.use_counts.insert(t[0], 1);
+        let Some(Op::IAdd3(add)) = iadd3_chain(&ctx, &op) else {
+            panic!("(a + b) + c was not merged");
+        };
+        assert!(add.srcs == [a.into(), b.into(), c.into()]);
+
+        // Merging would keep both adds alive
+        ctx.use_counts.insert(t[0], 2);
+        assert!(iadd3_chain(&ctx, &op).is_none());
+    }
+
+    #[test]
+    fn test_sel_trivial() {
+        let sm = ShaderModel70::new(75);
+        let ctx = test_ctx(&sm);
+        let mut alloc = SSAValueAllocator::new();
+        let [x, y, dst] = [(); 3].map(|_| alloc.alloc_vec(RegFile::GPR, 1));
+        let cond = alloc.alloc_vec(RegFile::Pred, 1);
+
+        let sel = |cond: Src| {
+            Op::Sel(OpSel {
+                dst: dst.into(),
+                cond: cond,
+                srcs: [x.into(), y.into()],
+            })
+        };
+
+        let Some(Op::Copy(copy)) = sel_trivial(&ctx, &sel(false.into())) else {
+            panic!("sel with a constant condition was not turned into a copy");
+        };
+        assert!(copy.src == y.into());
+
+        assert!(sel_trivial(&ctx, &sel(cond.into())).is_none());
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use compiler::bitset::BitSet;
+use std::collections::{HashMap, HashSet};
+
+struct BarDef {
+    block: usize,
+    ip: usize,
+    target: Label,
+}
+
+/// Returns true if the warp may diverge between the BSSy in block `start` and
+/// the BSync in block `end`.
+///
+/// The region between them is every block which is reachable from `start`
+/// without going through `end` and which can also reach `end`.  If every
+/// branch in that region is uniform, all of the lanes which execute the BSSy
+/// arrive at the BSync together and the barrier does nothing.
+fn region_may_diverge(f: &Function, start: usize, end: usize) -> bool {
+    let mut fwd = BitSet::new();
+    let mut stack = vec![start];
+    fwd.insert(start);
+    while let Some(bi) = stack.pop() {
+        if bi == end {
+            continue;
+        }
+        for &s in f.blocks.succ_indices(bi) {
+            if fwd.insert(s) {
+                stack.push(s);
+            }
+        }
+    }
+
+    let mut region = BitSet::new();
+    let mut stack = vec![end];
+    region.insert(end);
+    while let Some(bi) = stack.pop() {
+        if bi == start {
+            continue;
+        }
+        for &p in f.blocks.pred_indices(bi) {
+            if fwd.get(p) && region.insert(p) {
+                stack.push(p);
+            }
+        }
+    }
+
+    for bi in region.iter() {
+        // The branch at the end of the BSync block is after the BSync
+        if bi == end {
+            continue;
+        }
+
+        let Some(branch) = f.blocks[bi].branch() else {
+            continue;
+        };
+
+        match &branch.op {
+            // Lanes which exit never block a BSync and the remaining lanes
+            // are still converged.
+            Op::Exit(_) => (),
+            _ => {
+                if !branch.is_uniform_branch() {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
+/// Returns true if this is a barrier move whose result is never used
+///
+/// from_nir moves every barrier to a GPR for NIR's benefit.  After
+/// opt_bar_prop, most of those moves are dead but we run before DCE so we
+/// have to ignore them ourselves.
+fn is_dead_bmov(instr: &Instr, used: &HashSet<SSAValue>) -> bool {
+    match &instr.op {
+        Op::BMov(op) => match &op.dst {
+            Dst::SSA(ssa) => ssa.iter().all(|v| !used.contains(v)),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn opt_bssy(f: &mut Function) {
+    let mut used = HashSet::new();
+    for b in f.blocks.iter() {
+        for instr in &b.instrs {
+            instr.for_each_ssa_use(|ssa| {
+                used.insert(*ssa);
+            });
+        }
+    }
+
+    let mut bar_defs = HashMap::new();
+    let mut bar_uses: HashMap<SSAValue, usize> = HashMap::new();
+    let mut bsyncs = Vec::new();
+
+    for (bi, b) in f.blocks.iter().enumerate() {
+        for (ip, instr) in b.instrs.iter().enumerate() {
+            if is_dead_bmov(instr, &used) {
+                continue;
+            }
+
+            if let Op::BSSy(op) = &instr.op {
+                if let Dst::SSA(ssa) = &op.bar_out {
+                    assert!(ssa.comps() == 1);
+                    bar_defs.insert(
+                        ssa[0],
+                        BarDef {
+                            block: bi,
+                            ip: ip,
+                            target: op.target,
+                        },
+                    );
+                }
+            }
+
+            if let Op::BSync(op) = &instr.op {
+                if instr.pred.is_true() && op.cond.as_bool() == Some(true) {
+                    if let Some(ssa) = op.bar.as_ssa() {
+                        bsyncs.push((bi, ip, ssa[0]));
+                    }
+                }
+            }
+
+            instr.for_each_ssa_use(|ssa| {
+                if ssa.file() == RegFile::Bar {
+                    *bar_uses.entry(*ssa).or_default() += 1;
+                }
+            });
+        }
+    }
+
+    let mut remove: HashSet<(usize, usize)> = HashSet::new();
+    let mut dead_labels = HashSet::new();
+    for (bi, ip, bar) in bsyncs {
+        // If the barrier goes anywhere other than this one BSync (through a
+        // phi, an OpBreak, or a move to a GPR), it's part of a divergent loop
+        // or something else we don't understand.
+        if bar_uses.get(&bar) != Some(&1) {
+            continue;
+        }
+
+        let Some(def) = bar_defs.get(&bar) else {
+            continue;
+        };
+
+        // If the BSSy and BSync are in the same block, there is no control
+        // flow between them so whoever put them there did it on purpose.
+        if def.block == bi {
+            continue;
+        }
+
+        if region_may_diverge(f, def.block, bi) {
+            continue;
+        }
+
+        remove.insert((def.block, def.ip));
+        remove.insert((bi, ip));
+        dead_labels.insert(def.target);
+    }
+
+    if remove.is_empty() {
+        return;
+    }
+
+    for (bi, b) in f.blocks.iter_mut().enumerate() {
+        let mut ip = 0;
+        b.instrs.retain(|instr| {
+            let keep = match &instr.op {
+                Op::Nop(OpNop { label: Some(label) }) => {
+                    !dead_labels.contains(label)
+                }
+                _ => !remove.contains(&(bi, ip)),
+            };
+            ip += 1;
+            keep
+        });
+    }
+}
+
+impl Shader<'_> {
+    /// Removes BSSy/BSync pairs around control flow which cannot diverge
+    ///
+    /// NIR inserts reconvergence barriers based on its own divergence
+    /// analysis but, by the time we get here, we may know more.  Branches on
+    /// uniform predicates or constants never split the warp so there is
+    /// nothing to reconverge.  Removing the pair frees up a barrier register
+    /// and DCE cleans up the BClear and any barrier moves left behind.
+    pub fn opt_bssy(&mut self) {
+        for f in &mut self.functions {
+            opt_bssy(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    /// Builds an if with no else around a reconvergence barrier, the way
+    /// from_nir does after opt_bar_prop.  If uniform is set, the branch is on
+    /// a uniform predicate.
+    fn if_with_barrier(sm: &dyn ShaderModel, uniform: bool) -> Shader<'_> {
+        let mut t = TestFunctionBuilder::new(sm, 3);
+        let bar = t.alloc_ssa(RegFile::Bar, 1);
+        let end = t.label(2);
+        let merge = LabelAllocator::new().alloc();
+
+        t.add_block(0, true, |b| {
+            let bar_clear = b.alloc_ssa(RegFile::Bar, 1);
+            b.push_op(OpBClear {
+                dst: bar_clear.into(),
+            });
+            b.push_op(OpBSSy {
+                bar_out: bar.into(),
+                bar_in: bar_clear.into(),
+                cond: SrcRef::True.into(),
+                target: merge,
+            });
+            // Left behind by opt_bar_prop
+            b.bmov_to_gpr(bar.into());
+
+            if uniform {
+                let cond = b.alloc_ssa(RegFile::UPred, 1);
+                b.copy_to(cond.into(), SrcRef::True.into());
+                b.push_op(OpBra {
+                    target: end,
+                    cond: cond.into(),
+                });
+            } else {
+                let cond = b.alloc_ssa(RegFile::Pred, 1);
+                b.copy_to(cond.into(), SrcRef::True.into());
+                b.predicate(cond[0].into()).push_op(OpBra {
+                    target: end,
+                    cond: SrcRef::True.into(),
+                });
+            }
+        });
+        t.add_block(1, uniform, |b| {
+            b.push_op(OpNop { label: None });
+        });
+        t.add_block(2, true, |b| {
+            b.push_op(OpBSync {
+                bar: bar.into(),
+                cond: SrcRef::True.into(),
+            });
+            b.push_op(OpNop { label: Some(merge) });
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(0, 2);
+        t.add_edge(1, 2);
+        t.into_shader()
+    }
+
+    fn count_bar_instrs(s: &Shader) -> usize {
+        count_instrs(&s.functions[0], |i| {
+            matches!(
+                i.op,
+                Op::BClear(_) | Op::BSSy(_) | Op::BSync(_) | Op::BMov(_)
+            )
+        })
+    }
+
+    #[test]
+    fn test_uniform_branch() {
+        let sm = ShaderModel70::new(75);
+        let mut s = if_with_barrier(&sm, true);
+        s.opt_bssy();
+        s.opt_dce();
+
+        assert_eq!(count_bar_instrs(&s), 0);
+        let labels = count_instrs(&s.functions[0], |i| {
+            matches!(i.op, Op::Nop(OpNop { label: Some(_) }))
+        });
+        assert_eq!(labels, 0);
+    }
+
+    #[test]
+    fn test_divergent_branch() {
+        let sm = ShaderModel70::new(75);
+        let mut s = if_with_barrier(&sm, false);
+        s.opt_bssy();
+        s.opt_dce();
+
+        // The BMov is dead but the rest stays
+        assert_eq!(count_bar_instrs(&s), 3);
+    }
+}
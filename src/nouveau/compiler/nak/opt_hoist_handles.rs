@@ -0,0 +1,671 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+use crate::liveness::{Liveness, SimpleLiveness};
+
+use compiler::bitset::BitSet;
+use std::collections::{HashMap, HashSet};
+
+/// Returns true if the instruction has no side-effects and always produces
+/// the same value given the same sources.  These are safe to speculatively
+/// execute in a loop preheader.
+fn instr_is_hoistable(instr: &Instr) -> bool {
+    if !instr.pred.is_true() {
+        return false;
+    }
+
+    match &instr.op {
+        Op::Copy(_)
+        | Op::Mov(_)
+        | Op::IAdd2(_)
+        | Op::IAdd3(_)
+        | Op::IAdd3X(_)
+        | Op::IMad(_)
+        | Op::IMul(_)
+        | Op::Lea(_)
+        | Op::LeaX(_)
+        | Op::Lop2(_)
+        | Op::Lop3(_)
+        | Op::Prmt(_)
+        | Op::R2UR(_)
+        | Op::Sel(_)
+        | Op::Shf(_)
+        | Op::Shl(_)
+        | Op::Shr(_) => (),
+
+        // Constant buffers are read-only for the duration of the shader
+        Op::Ldc(_) => (),
+
+        _ => return false,
+    }
+
+    // Carries only live between adjacent instructions
+    for dst in instr.dsts() {
+        if let Dst::SSA(ssa) = dst {
+            if ssa.file() == Some(RegFile::Carry) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Returns the SSA values which hold bindless handles used by this
+/// instruction.
+fn for_each_handle_use(instr: &Instr, mut f: impl FnMut(&SSARef, bool)) {
+    for src in instr.srcs() {
+        if let SrcRef::CBuf(CBufRef {
+            buf: CBuf::BindlessSSA(handle),
+            ..
+        }) = &src.src_ref
+        {
+            f(handle, true);
+        }
+    }
+
+    let (tex, src) = match &instr.op {
+        Op::Tex(op) => (&op.tex, &op.srcs[0]),
+        Op::Tld(op) => (&op.tex, &op.srcs[0]),
+        Op::Tld4(op) => (&op.tex, &op.srcs[0]),
+        Op::Tmml(op) => (&op.tex, &op.srcs[0]),
+        Op::Txd(op) => (&op.tex, &op.srcs[0]),
+        Op::Txq(op) => (&op.tex, &op.src),
+        Op::SuLd(op) => (&TexRef::Bindless, &op.handle),
+        Op::SuSt(op) => (&TexRef::Bindless, &op.handle),
+        Op::SuAtom(op) => (&TexRef::Bindless, &op.handle),
+        _ => return,
+    };
+
+    if !matches!(tex, TexRef::Bindless) {
+        return;
+    }
+
+    if let SrcRef::SSA(vec) = &src.src_ref {
+        if matches!(&instr.op, Op::SuLd(_) | Op::SuSt(_) | Op::SuAtom(_)) {
+            f(vec, false);
+        } else {
+            // For texture instructions, the handle is packed into the first
+            // component of the first source vector.
+            f(&vec[0].into(), false);
+        }
+    }
+}
+
+struct Loop {
+    preheader: usize,
+    blocks: BitSet,
+
+    /// Blocks which execute on every iteration of the loop
+    always_executed: BitSet,
+}
+
+impl Loop {
+    /// Finds the blocks in the loop headed by the given block
+    ///
+    /// The loop consists of the header and every block dominated by the
+    /// header which can reach a back-edge.  Because we only hoist into a
+    /// single preheader, loops with more than one entry edge or whose entry
+    /// edge is critical are ignored.
+    fn new(f: &Function, header: usize) -> Option<Loop> {
+        let mut preheader = None;
+        let mut stack = Vec::new();
+        for &p in f.blocks.pred_indices(header) {
+            if f.blocks.dominates(header, p) {
+                stack.push(p);
+            } else if preheader.is_none() {
+                preheader = Some(p);
+            } else {
+                return None;
+            }
+        }
+
+        let preheader = preheader?;
+        if f.blocks.succ_indices(preheader).len() != 1 {
+            return None;
+        }
+
+        let mut blocks = BitSet::new();
+        blocks.insert(header);
+        while let Some(bi) = stack.pop() {
+            if blocks.insert(bi) {
+                for &p in f.blocks.pred_indices(bi) {
+                    if f.blocks.dominates(header, p) {
+                        stack.push(p);
+                    }
+                }
+            }
+        }
+
+        // A block runs on every iteration if it dominates every back-edge and
+        // every exit from the loop.
+        let mut tails = Vec::new();
+        for bi in blocks.iter() {
+            let mut is_tail = false;
+            for &s in f.blocks.succ_indices(bi) {
+                is_tail |= s == header || !blocks.get(s);
+            }
+            if is_tail {
+                tails.push(bi);
+            }
+        }
+
+        let mut always_executed = BitSet::new();
+        for bi in blocks.iter() {
+            if tails.iter().all(|&t| f.blocks.dominates(bi, t)) {
+                always_executed.insert(bi);
+            }
+        }
+
+        Some(Loop {
+            preheader: preheader,
+            blocks: blocks,
+            always_executed: always_executed,
+        })
+    }
+}
+
+struct HoistHandles {
+    def_block: HashMap<SSAValue, usize>,
+    hoisted: HashSet<SSAValue>,
+    cbuf_handles: Vec<SSARef>,
+}
+
+impl HoistHandles {
+    fn new(f: &Function) -> Self {
+        let mut def_block = HashMap::new();
+        let mut cbuf_handles = Vec::new();
+        let mut seen = HashSet::new();
+        for (bi, b) in f.blocks.iter().enumerate() {
+            for instr in &b.instrs {
+                instr.for_each_ssa_def(|ssa| {
+                    def_block.insert(*ssa, bi);
+                });
+                for_each_handle_use(instr, |handle, is_cbuf| {
+                    if is_cbuf && seen.insert(*handle) {
+                        cbuf_handles.push(*handle);
+                    }
+                });
+            }
+        }
+
+        HoistHandles {
+            def_block: def_block,
+            hoisted: HashSet::new(),
+            cbuf_handles: cbuf_handles,
+        }
+    }
+
+    /// Collects the instructions which need to move to the preheader in
+    /// order to hoist ssa, in dependency order.  Returns false if ssa cannot
+    /// be hoisted.
+    fn collect(
+        &self,
+        f: &Function,
+        l: &Loop,
+        ssa: &SSAValue,
+        visited: &mut HashMap<SSAValue, bool>,
+        to_move: &mut Vec<(usize, usize)>,
+    ) -> bool {
+        let Some(&bi) = self.def_block.get(ssa) else {
+            return false;
+        };
+
+        // Anything defined outside the loop dominates the preheader
+        if !l.blocks.get(bi) {
+            return true;
+        }
+
+        // Either we've already collected it or it's part of a cycle, which
+        // will fail once it hits a phi.
+        if let Some(ok) = visited.get(ssa) {
+            return *ok;
+        }
+        visited.insert(*ssa, false);
+
+        let b = &f.blocks[bi];
+        let Some(ip) = b.instrs.iter().position(|instr| {
+            let mut defines = false;
+            instr.for_each_ssa_def(|def| defines |= def == ssa);
+            defines
+        }) else {
+            return false;
+        };
+
+        let instr = &b.instrs[ip];
+        if !instr_is_hoistable(instr) {
+            return false;
+        }
+
+        // An ldc in a conditional block may only be valid on the paths which
+        // actually execute it so we can't speculate it.
+        if matches!(instr.op, Op::Ldc(_)) && !l.always_executed.get(bi) {
+            return false;
+        }
+
+        if instr.is_uniform() && !f.blocks[l.preheader].uniform {
+            return false;
+        }
+
+        let mut srcs_ok = true;
+        instr.for_each_ssa_use(|src| {
+            srcs_ok &= self.collect(f, l, src, visited, to_move);
+        });
+        if !srcs_ok {
+            return false;
+        }
+
+        if !to_move.contains(&(bi, ip)) {
+            to_move.push((bi, ip));
+        }
+        visited.insert(*ssa, true);
+        true
+    }
+
+    fn hoist_loop(&mut self, f: &mut Function, l: &Loop) {
+        let mut roots = Vec::new();
+        for bi in l.blocks.iter() {
+            for instr in &f.blocks[bi].instrs {
+                for_each_handle_use(instr, |handle, _| {
+                    roots.push(*handle);
+                });
+            }
+        }
+
+        for handle in roots {
+            let mut visited = HashMap::new();
+            let mut to_move = Vec::new();
+            let mut ok = true;
+            for ssa in handle.iter() {
+                ok &= self.collect(f, l, ssa, &mut visited, &mut to_move);
+            }
+
+            if !ok || to_move.is_empty() {
+                continue;
+            }
+
+            // Pull the instructions out of the loop.  Remove them in reverse
+            // order of ip so the remaining indices stay valid.
+            let mut removals = to_move.clone();
+            removals.sort_by(|a, b| b.cmp(a));
+            let mut moved = HashMap::new();
+            for (bi, ip) in removals {
+                let instr = f.blocks[bi].instrs.remove(ip);
+                moved.insert((bi, ip), instr);
+            }
+
+            // Insert before the phi sources and branch, if any
+            let pb = &mut f.blocks[l.preheader];
+            let at = pb.phi_srcs_ip().unwrap_or_else(|| {
+                if pb.branch().is_some() {
+                    pb.instrs.len() - 1
+                } else {
+                    pb.instrs.len()
+                }
+            });
+            let mut hoisted = Vec::new();
+            for key in to_move {
+                let instr = moved.remove(&key).unwrap();
+                instr.for_each_ssa_def(|ssa| {
+                    self.def_block.insert(*ssa, l.preheader);
+                    self.hoisted.insert(*ssa);
+                });
+                hoisted.push(instr);
+            }
+            pb.instrs.splice(at..at, hoisted);
+        }
+    }
+
+    /// Pins hoisted cbuf handles so they stay in the same uniform registers
+    /// for the entire loop.
+    ///
+    /// Pinned values are never spilled so we only do this if we don't
+    /// expect to spill UGPRs at all and we keep the total pinned to a
+    /// fraction of the register file.  Everything else stays a normal UGPR
+    /// value which spill_values() can move to GPRs with SpillUniform and
+    /// fill with R2UR right before the use.
+    fn pin_handles(&self, sm: &dyn ShaderModel, f: &mut Function) {
+        let num_ugprs = sm.num_regs(RegFile::UGPR);
+        if num_ugprs == 0 {
+            return;
+        }
+
+        let live = SimpleLiveness::for_function(f);
+        let max_live = live.calc_max_live(f);
+        if max_live[RegFile::UGPR] > num_ugprs {
+            return;
+        }
+
+        let mut pin_budget = num_ugprs / 4;
+        for handle in &self.cbuf_handles {
+            if handle.file() != Some(RegFile::UGPR) {
+                continue;
+            }
+
+            if !handle.iter().all(|ssa| self.hoisted.contains(ssa)) {
+                continue;
+            }
+
+            let comps = u32::from(handle.comps());
+            if comps > pin_budget {
+                break;
+            }
+
+            let bi = self.def_block[&handle[0]];
+            if !handle.iter().all(|ssa| self.def_block[ssa] == bi) {
+                continue;
+            }
+
+            let b = &mut f.blocks[bi];
+
+            // Find the range of instructions which define the handle and
+            // make sure nothing in between reads it.
+            let mut first_def = None;
+            let mut last_def = 0;
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                let mut defines = false;
+                instr.for_each_ssa_def(|ssa| {
+                    defines |= handle.contains(ssa);
+                });
+                if defines {
+                    first_def.get_or_insert(ip);
+                    last_def = ip;
+                }
+            }
+            let Some(first_def) = first_def else {
+                continue;
+            };
+
+            let mut used_early = false;
+            for instr in &b.instrs[first_def..=last_def] {
+                instr.for_each_ssa_use(|ssa| {
+                    used_early |= handle.contains(ssa);
+                });
+            }
+            if used_early {
+                continue;
+            }
+
+            let tmp = f.ssa_alloc.alloc_vec(RegFile::UGPR, handle.comps());
+            for instr in &mut b.instrs[first_def..=last_def] {
+                instr.for_each_ssa_def_mut(|ssa| {
+                    if let Some(c) = handle.iter().position(|h| h == ssa) {
+                        *ssa = tmp[c];
+                    }
+                });
+            }
+
+            let mut pin = vec![Instr::new_boxed(OpPin {
+                src: tmp.into(),
+                dst: (*handle).into(),
+            })];
+            if DEBUG.annotate() {
+                pin.insert(
+                    0,
+                    Instr::new_boxed(OpAnnotate {
+                        annotation: "generated by opt_hoist_handles".into(),
+                    }),
+                );
+            }
+            b.instrs.splice(last_def + 1..last_def + 1, pin);
+
+            pin_budget -= comps;
+        }
+    }
+}
+
+impl Shader<'_> {
+    /// Hoists the computation of bindless handles out of loops
+    ///
+    /// Descriptor indexing tends to leave handle loads and the address math
+    /// which feeds them right next to the texture or ldc which uses them,
+    /// even inside loops.  Since they're loop-invariant, we move them to the
+    /// loop preheader so they're computed once.  Hoisted cbuf handles are
+    /// also pinned in UGPRs if register pressure allows.
+    pub fn opt_hoist_handles(&mut self) {
+        for f in &mut self.functions {
+            if !f.blocks.has_loop() {
+                continue;
+            }
+
+            let mut pass = HoistHandles::new(f);
+
+            // Blocks are in reverse post-order so iterating over loop
+            // headers backwards handles inner loops first.  Anything hoisted
+            // into an inner loop's preheader may then get hoisted again out
+            // of the outer loop.
+            for header in (0..f.blocks.len()).rev() {
+                if !f.blocks.is_loop_header(header) {
+                    continue;
+                }
+
+                if let Some(l) = Loop::new(f, header) {
+                    pass.hoist_loop(f, &l);
+                }
+            }
+
+            if !pass.hoisted.is_empty() {
+                pass.pin_handles(self.sm, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    /// Loads a bindless cbuf handle and reads through it
+    fn load_and_use_handle(b: &mut SSAInstrBuilder) {
+        let handle = b.alloc_ssa(RegFile::UGPR, 2);
+        b.push_op(OpLdc {
+            dst: handle.into(),
+            cb: CBufRef {
+                buf: CBuf::Binding(0),
+                offset: 0x10,
+            }
+            .into(),
+            offset: 0.into(),
+            mode: LdcMode::Indexed,
+            mem_type: MemType::B64,
+        });
+        b.copy(
+            CBufRef {
+                buf: CBuf::BindlessSSA(handle),
+                offset: 0,
+            }
+            .into(),
+        );
+    }
+
+    fn loop_cond(b: &mut SSAInstrBuilder) -> Pred {
+        let cond = b.alloc_ssa(RegFile::Pred, 1);
+        b.copy_to(cond.into(), SrcRef::True.into());
+        cond[0].into()
+    }
+
+    fn count_ldc(b: &BasicBlock) -> usize {
+        b.instrs
+            .iter()
+            .filter(|i| matches!(i.op, Op::Ldc(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_hoist_before_phi_srcs() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 3);
+        let header = t.label(1);
+        let x = t.alloc_ssa(RegFile::GPR, 1);
+        let y = t.alloc_ssa(RegFile::GPR, 1);
+        let z = t.alloc_ssa(RegFile::GPR, 1);
+
+        t.add_block(0, true, |b| {
+            b.copy_to(x.into(), 0.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, x.into());
+            b.push_op(phi);
+        });
+        t.add_block(1, true, |b| {
+            let mut phi = OpPhiDsts::new();
+            phi.dsts.push(0, y.into());
+            b.push_op(phi);
+            load_and_use_handle(b);
+            b.copy_to(z.into(), y.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, z.into());
+            b.push_op(phi);
+            let cond = loop_cond(b);
+            b.predicate(cond).push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, true, |b| {
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 1);
+
+        let mut s = t.into_shader();
+        s.opt_hoist_handles();
+
+        let f = &s.functions[0];
+        assert!(count_ldc(&f.blocks[0]) == 1);
+        assert!(count_ldc(&f.blocks[1]) == 0);
+        assert!(
+            f.blocks[0].phi_srcs_ip() == Some(f.blocks[0].instrs.len() - 1)
+        );
+    }
+
+    #[test]
+    fn test_no_hoist_conditional_ldc() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 5);
+        let header = t.label(1);
+        let cont = t.label(3);
+
+        t.add_block(0, true, |b| {
+            b.push_op(OpNop { label: None });
+        });
+        t.add_block(1, true, |b| {
+            let cond = loop_cond(b);
+            b.predicate(cond).push_op(OpBra {
+                target: cont,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, true, load_and_use_handle);
+        t.add_block(3, true, |b| {
+            let cond = loop_cond(b);
+            b.predicate(cond).push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(4, true, |b| {
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 3);
+        t.add_edge(2, 3);
+        t.add_edge(3, 4);
+        t.add_edge(3, 1);
+
+        let mut s = t.into_shader();
+        s.opt_hoist_handles();
+
+        let f = &s.functions[0];
+        assert!(count_ldc(&f.blocks[0]) == 0);
+        assert!(count_ldc(&f.blocks[2]) == 1);
+    }
+
+    #[test]
+    fn test_pin_under_pressure() {
+        let sm = ShaderModel70::new(75);
+        let num_ugprs = sm.num_regs(RegFile::UGPR);
+        let mut t = TestFunctionBuilder::new(&sm, 3);
+        let header = t.label(1);
+
+        // Values which are live across the loop and leave exactly enough
+        // room for the pinned handle and the value it is pinned from
+        let vals: Vec<SSARef> = (0..num_ugprs - 4)
+            .map(|_| t.alloc_ssa(RegFile::UGPR, 1))
+            .collect();
+
+        t.add_block(0, true, |b| {
+            for (i, v) in vals.iter().enumerate() {
+                b.copy_to((*v).into(), u32::try_from(i).unwrap().into());
+            }
+        });
+        t.add_block(1, true, |b| {
+            load_and_use_handle(b);
+            let cond = loop_cond(b);
+            b.predicate(cond).push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, true, |b| {
+            for v in &vals {
+                b.copy((*v).into());
+            }
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 1);
+
+        let mut s = t.into_shader();
+        s.opt_hoist_handles();
+
+        let f = &s.functions[0];
+        let live = SimpleLiveness::for_function(f);
+        assert!(live.calc_max_live(f)[RegFile::UGPR] == num_ugprs);
+        let num_pins = f.blocks[0]
+            .instrs
+            .iter()
+            .filter(|i| matches!(i.op, Op::Pin(_)))
+            .count();
+        assert!(num_pins == 1);
+
+        s.legalize();
+        s.convert_to_cssa();
+        s.assign_regs();
+
+        // The handle has to stay put for the whole loop
+        let f = &s.functions[0];
+        let mut handle_reg = None;
+        for instr in &f.blocks[1].instrs {
+            for src in instr.srcs() {
+                if let SrcRef::CBuf(CBufRef {
+                    buf: CBuf::BindlessUGPR(reg),
+                    ..
+                }) = &src.src_ref
+                {
+                    handle_reg = Some(*reg);
+                }
+            }
+        }
+        let handle_reg = handle_reg.expect("The handle use went away");
+        assert!(handle_reg.file() == RegFile::UGPR);
+        for instr in &f.blocks[1].instrs {
+            for dst in instr.dsts() {
+                if let Dst::Reg(reg) = dst {
+                    assert!(
+                        reg.file() != RegFile::UGPR
+                            || reg.base_idx() >= handle_reg.base_idx() + 2
+                            || reg.base_idx() + u32::from(reg.comps())
+                                <= handle_reg.base_idx()
+                    );
+                }
+            }
+        }
+    }
+}
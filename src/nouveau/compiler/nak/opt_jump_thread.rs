@@ -41,10 +41,22 @@ fn jump_thread(func: &mut Function) -> bool {
     for i in (0..func.blocks.len()).rev() {
         // Replace the branch if possible
         if let Some(instr) = func.blocks[i].instrs.last_mut() {
-            if let Op::Bra(OpBra { target }) = instr.op {
-                if let Some(replacement) = replacements.get(&target) {
-                    instr.op = clone_branch(replacement);
-                    progress = true;
+            if let Op::Bra(bra) = &mut instr.op {
+                if let Some(replacement) = replacements.get(&bra.target) {
+                    match replacement {
+                        Op::Bra(r) => {
+                            // Keep our condition, if any
+                            bra.target = r.target;
+                            progress = true;
+                        }
+                        // OpExit has no uniform condition so we can only
+                        // replace branches which don't have one.
+                        _ if bra.cond.as_bool() == Some(true) => {
+                            instr.op = clone_branch(replacement);
+                            progress = true;
+                        }
+                        _ => (),
+                    }
                 }
                 // If the branch target was previously a trivial block then the
                 // branch was previously a forward edge (see above) and by
@@ -56,11 +68,9 @@ fn jump_thread(func: &mut Function) -> bool {
         // Is this block trivial?
         let block_label = func.blocks[i].label;
         match &func.blocks[i].instrs[..] {
-            [instr] => {
-                if instr.is_branch() && instr.pred.is_true() {
-                    // Upholds invariant 2 because we updated the branch above
-                    replacements.insert(block_label, clone_branch(&instr.op));
-                }
+            [instr] if instr.is_uncond_branch() => {
+                // Upholds invariant 2 because we updated the branch above
+                replacements.insert(block_label, clone_branch(&instr.op));
             }
             [] => {
                 // Empty block - falls through
@@ -73,6 +83,7 @@ fn jump_thread(func: &mut Function) -> bool {
                     .unwrap_or_else(|| {
                         Op::Bra(OpBra {
                             target: target_label,
+                            cond: SrcRef::True.into(),
                         })
                     });
                 replacements.insert(block_label, replacement);
@@ -123,7 +134,9 @@ fn opt_fall_through(func: &mut Function) {
     for i in 0..func.blocks.len() - 1 {
         let remove_last_instr = match func.blocks[i].branch() {
             Some(b) => match b.op {
-                Op::Bra(OpBra { target }) => target == func.blocks[i + 1].label,
+                Op::Bra(OpBra { target, .. }) => {
+                    target == func.blocks[i + 1].label
+                }
                 _ => false,
             },
             None => false,
@@ -1,30 +1,274 @@
 // Copyright © 2024 Collabora, Ltd.
 // SPDX-License-Identifier: MIT
 
+use crate::api::{GetDebugFlags, DEBUG};
 use crate::ir::*;
+use crate::liveness::{BlockLiveness, Liveness, SimpleLiveness};
+
 use std::collections::HashMap;
 
-fn should_lower_to_warp(
-    sm: &dyn ShaderModel,
-    instr: &Instr,
-    r2ur: &HashMap<SSAValue, SSAValue>,
-) -> bool {
-    if !sm.op_can_be_uniform(&instr.op) {
-        return true;
+/// Cost of a uniform value which gets spilled: a copy to a GPR plus an R2UR
+/// to fill it again.
+///
+/// The spill costs are only a rough estimate.  We don't track pressure at
+/// each instruction, only whether the function as a whole has more live
+/// values than registers in a file.  If it does, every value which is
+/// live-out of its block is charged the spill cost for that file, no matter
+/// whether it's live across the point of maximum pressure.
+const UGPR_SPILL_COST: u32 = 2;
+
+/// Cost of a warp value which gets spilled.  GPRs spill to memory so this is
+/// a lot more expensive than spilling a UGPR.
+const GPR_SPILL_COST: u32 = 4;
+
+/// Instructions which we leave alone entirely.  Their sources are never
+/// rewritten so any uniform sources they have need to stay uniform.
+fn is_fixed_instr(instr: &Instr) -> bool {
+    matches!(
+        &instr.op,
+        Op::PhiDsts(_)
+            | Op::PhiSrcs(_)
+            | Op::Pin(_)
+            | Op::Unpin(_)
+            | Op::Vote(_)
+    )
+}
+
+#[derive(Clone, Copy)]
+enum UniformUse {
+    /// Used by another candidate which may or may not end up uniform
+    Candidate(usize),
+    /// Used by something which requires a uniform value
+    Uniform,
+    /// Used by a warp instruction which can take either
+    Warp,
+}
+
+struct Candidate {
+    /// The instruction cannot be uniform
+    forced_warp: bool,
+
+    /// We've decided to lower the instruction to warp registers
+    lower: bool,
+
+    /// Number of SSA sources in warp registers.  Each of these needs an R2UR
+    /// if the instruction stays uniform.
+    num_warp_srcs: u32,
+
+    /// Candidates which define our SSA sources, one per source use
+    src_defs: Vec<usize>,
+
+    dsts: Vec<SSAValue>,
+
+    /// Extra cost if we stay uniform, from expected spilling
+    uniform_spill_cost: u32,
+
+    /// Extra cost if we're lowered to warp, from expected spilling
+    warp_spill_cost: u32,
+}
+
+/// A function-wide assignment of uniform instructions to either the uniform
+/// or the warp register file
+///
+/// Each uniform instruction either stays uniform, in which case every warp
+/// source needs an R2UR, or gets lowered to warp, in which case we need one
+/// R2UR per destination which still has uniform users.  We start with the
+/// greedy choice and then flip instructions one at a time as long as doing
+/// so reduces the total number of R2URs plus the expected spilling cost.
+struct UniformAssignment {
+    cands: Vec<Candidate>,
+    uses: HashMap<SSAValue, Vec<UniformUse>>,
+}
+
+impl UniformAssignment {
+    fn new(sm: &dyn ShaderModel, f: &Function) -> Self {
+        let live = SimpleLiveness::for_function(f);
+        let max_live = live.calc_max_live(f);
+        let over_limit =
+            PerRegFile::new_with(|file| max_live[file] > sm.num_regs(file));
+
+        let mut cands: Vec<Candidate> = Vec::new();
+        let mut def_cand = HashMap::new();
+        let mut uses: HashMap<SSAValue, Vec<UniformUse>> = HashMap::new();
+
+        for (bi, b) in f.blocks.iter().enumerate() {
+            let bl = live.block_live(bi);
+            for instr in &b.instrs {
+                if is_fixed_instr(instr) || !instr.is_uniform() {
+                    let use_kind = if is_fixed_instr(instr) {
+                        UniformUse::Uniform
+                    } else {
+                        UniformUse::Warp
+                    };
+                    instr.for_each_ssa_use(|ssa| {
+                        uses.entry(*ssa).or_default().push(use_kind);
+                    });
+                    continue;
+                }
+
+                let ci = cands.len();
+                let mut c = Candidate {
+                    forced_warp: !sm.op_can_be_uniform(&instr.op),
+                    lower: false,
+                    num_warp_srcs: 0,
+                    src_defs: Vec::new(),
+                    dsts: Vec::new(),
+                    uniform_spill_cost: 0,
+                    warp_spill_cost: 0,
+                };
+
+                for src in instr.srcs() {
+                    match &src.src_ref {
+                        SrcRef::SSA(vec) => {
+                            for ssa in vec.iter() {
+                                if !ssa.is_uniform() {
+                                    c.num_warp_srcs += 1;
+                                } else if let Some(&di) = def_cand.get(ssa) {
+                                    c.src_defs.push(di);
+                                }
+                                uses.entry(*ssa)
+                                    .or_default()
+                                    .push(UniformUse::Candidate(ci));
+                            }
+                        }
+                        SrcRef::CBuf(CBufRef {
+                            buf: CBuf::BindlessSSA(handle),
+                            ..
+                        }) => {
+                            // R2UR propagation only touches SSA sources so
+                            // bindless handles always need to be uniform.
+                            for ssa in handle.iter() {
+                                uses.entry(*ssa)
+                                    .or_default()
+                                    .push(UniformUse::Uniform);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+
+                instr.for_each_ssa_def(|ssa| {
+                    def_cand.insert(*ssa, ci);
+                    c.dsts.push(*ssa);
+
+                    // Values which are live-out of their block are the
+                    // ones most likely to get spilled.
+                    if bl.is_live_out(ssa) {
+                        let file = ssa.file();
+                        if over_limit[file] {
+                            c.uniform_spill_cost += UGPR_SPILL_COST;
+                        }
+                        if over_limit[file.to_warp()] {
+                            c.warp_spill_cost += GPR_SPILL_COST;
+                        }
+                    }
+                });
+
+                cands.push(c);
+            }
+        }
+
+        // Start with the greedy assignment: lower anything which can't be
+        // uniform or which has at least two non-uniform sources at the time
+        // we see it.
+        for ci in 0..cands.len() {
+            let c = &cands[ci];
+            let num_lowered_srcs =
+                c.src_defs.iter().filter(|di| cands[**di].lower).count();
+            let num_non_uniform_srcs =
+                c.num_warp_srcs + u32::try_from(num_lowered_srcs).unwrap();
+            let lower = c.forced_warp || num_non_uniform_srcs >= 2;
+            cands[ci].lower = lower;
+        }
+
+        UniformAssignment {
+            cands: cands,
+            uses: uses,
+        }
     }
 
-    let mut num_non_uniform_srcs = 0;
-    instr.for_each_ssa_use(|ssa| {
-        if !ssa.is_uniform() || r2ur.contains_key(ssa) {
-            num_non_uniform_srcs += 1;
+    fn has_uniform_use(&self, ssa: &SSAValue) -> bool {
+        let Some(uses) = self.uses.get(ssa) else {
+            return false;
+        };
+
+        uses.iter().any(|u| match u {
+            UniformUse::Candidate(ci) => !self.cands[*ci].lower,
+            UniformUse::Uniform => true,
+            UniformUse::Warp => false,
+        })
+    }
+
+    /// Number of R2URs needed on the destinations of a lowered candidate
+    fn dst_r2ur_cost(&self, ci: usize) -> u32 {
+        let c = &self.cands[ci];
+        if !c.lower {
+            return 0;
         }
-    });
 
-    if num_non_uniform_srcs >= 2 {
-        return true;
+        let n = c.dsts.iter().filter(|d| self.has_uniform_use(d)).count();
+        n.try_into().unwrap()
     }
 
-    return false;
+    fn cost(&self, ci: usize) -> u32 {
+        let c = &self.cands[ci];
+        if c.lower {
+            self.dst_r2ur_cost(ci) + c.warp_spill_cost
+        } else {
+            c.num_warp_srcs + c.uniform_spill_cost
+        }
+    }
+
+    /// The part of the total cost which may change if we flip ci
+    fn local_cost(&self, ci: usize) -> u32 {
+        let mut cost = self.cost(ci);
+
+        let mut src_defs = self.cands[ci].src_defs.clone();
+        src_defs.sort();
+        src_defs.dedup();
+        for di in src_defs {
+            if di != ci {
+                cost += self.dst_r2ur_cost(di);
+            }
+        }
+
+        cost
+    }
+
+    fn total_cost(&self) -> u32 {
+        (0..self.cands.len()).map(|ci| self.cost(ci)).sum()
+    }
+
+    fn optimize(&mut self) {
+        // Flipping one candidate can make flipping its neighbors profitable
+        // so keep going for a few rounds.
+        for _ in 0..8 {
+            let mut progress = false;
+            for ci in 0..self.cands.len() {
+                if self.cands[ci].forced_warp {
+                    continue;
+                }
+
+                let before = self.local_cost(ci);
+                self.cands[ci].lower = !self.cands[ci].lower;
+                let after = self.local_cost(ci);
+
+                if after < before {
+                    progress = true;
+                } else {
+                    self.cands[ci].lower = !self.cands[ci].lower;
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    fn num_lowered(&self) -> usize {
+        self.cands.iter().filter(|c| c.lower).count()
+    }
 }
 
 fn propagate_r2ur(
@@ -52,21 +296,41 @@ fn propagate_r2ur(
 impl Shader<'_> {
     pub fn opt_uniform_instrs(&mut self) {
         let sm = self.sm;
-        let mut r2ur = HashMap::new();
         let mut propagated_r2ur = false;
-        self.map_instrs(|mut instr, alloc| {
-            if matches!(
-                &instr.op,
-                Op::PhiDsts(_)
-                    | Op::PhiSrcs(_)
-                    | Op::Pin(_)
-                    | Op::Unpin(_)
-                    | Op::Vote(_)
-            ) {
-                MappedInstrs::One(instr)
-            } else if instr.is_uniform() {
+        for f in &mut self.functions {
+            let mut assignment = UniformAssignment::new(sm, f);
+            let greedy_cost = assignment.total_cost();
+            let greedy_lowered = assignment.num_lowered();
+            assignment.optimize();
+
+            if DEBUG.print() {
+                eprintln!(
+                    "opt_uniform_instrs: {} of {} uniform instructions \
+                     lowered to warp (greedy: {}), estimated cost {} \
+                     (greedy: {})",
+                    assignment.num_lowered(),
+                    assignment.cands.len(),
+                    greedy_lowered,
+                    assignment.total_cost(),
+                    greedy_cost,
+                );
+            }
+
+            let mut r2ur = HashMap::new();
+            let mut ci = 0;
+            f.map_instrs(|mut instr, alloc| {
+                if is_fixed_instr(&instr) {
+                    return MappedInstrs::One(instr);
+                } else if !instr.is_uniform() {
+                    propagated_r2ur |= propagate_r2ur(&mut instr, &r2ur);
+                    return MappedInstrs::One(instr);
+                }
+
+                let lower = assignment.cands[ci].lower;
+                ci += 1;
+
                 let mut b = InstrBuilder::new(sm);
-                if should_lower_to_warp(sm, &instr, &r2ur) {
+                if lower {
                     propagated_r2ur |= propagate_r2ur(&mut instr, &r2ur);
                     instr.for_each_ssa_def_mut(|ssa| {
                         let w = alloc.alloc(ssa.file().to_warp());
@@ -96,14 +360,129 @@ impl Shader<'_> {
                     b.push_instr(instr);
                     b.as_mapped_instrs()
                 }
-            } else {
-                propagated_r2ur |= propagate_r2ur(&mut instr, &r2ur);
-                MappedInstrs::One(instr)
-            }
-        });
+            });
+            debug_assert!(ci == assignment.cands.len());
+        }
 
         if propagated_r2ur {
             self.opt_dce();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    fn uiadd3(b: &mut SSAInstrBuilder, dst: SSARef, srcs: [Src; 3]) {
+        b.push_op(OpIAdd3 {
+            dst: dst.into(),
+            overflow: [Dst::None; 2],
+            srcs: srcs,
+        });
+    }
+
+    fn alloc_warp(t: &mut TestFunctionBuilder) -> [SSARef; 4] {
+        [(); 4].map(|_| t.alloc_ssa(RegFile::GPR, 1))
+    }
+
+    fn def_warp(b: &mut SSAInstrBuilder, w: &[SSARef]) {
+        for (i, w) in w.iter().enumerate() {
+            b.copy_to((*w).into(), u32::try_from(i).unwrap().into());
+        }
+    }
+
+    #[test]
+    fn test_lower_chain() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 1);
+        let w = alloc_warp(&mut t);
+        let [a, b, c] = [(); 3].map(|_| t.alloc_ssa(RegFile::UGPR, 1));
+
+        // Greedy keeps a and b uniform, which takes an R2UR each, and only
+        // lowers c.  Once b is lowered, a has no uniform users left and
+        // lowering the whole chain needs no R2URs at all.
+        t.add_block(0, true, |bld| {
+            def_warp(bld, &w);
+            uiadd3(bld, a, [w[0].into(), 0.into(), 0.into()]);
+            uiadd3(bld, b, [a.into(), w[1].into(), 0.into()]);
+            uiadd3(bld, c, [b.into(), w[2].into(), w[3].into()]);
+            bld.copy(c.into());
+            bld.push_op(OpExit {});
+        });
+        let f = t.into_function();
+
+        let mut ua = UniformAssignment::new(&sm, &f);
+        assert!(ua.cands.iter().map(|c| c.lower).eq([false, false, true]));
+        assert!(ua.total_cost() == 2);
+
+        ua.optimize();
+        assert!(ua.cands.iter().all(|c| c.lower));
+        assert!(ua.total_cost() == 0);
+    }
+
+    #[test]
+    fn test_stay_uniform() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 1);
+        let w = alloc_warp(&mut t);
+        let [a, b, p] = [(); 3].map(|_| t.alloc_ssa(RegFile::UGPR, 1));
+
+        // b has to end up uniform so lowering either instruction only moves
+        // the R2UR on w[0] somewhere else.
+        t.add_block(0, true, |bld| {
+            def_warp(bld, &w);
+            uiadd3(bld, a, [w[0].into(), 0.into(), 0.into()]);
+            uiadd3(bld, b, [a.into(), 1.into(), 0.into()]);
+            bld.push_op(OpPin {
+                src: b.into(),
+                dst: p.into(),
+            });
+            bld.push_op(OpExit {});
+        });
+        let f = t.into_function();
+
+        let mut ua = UniformAssignment::new(&sm, &f);
+        assert!(ua.total_cost() == 1);
+
+        ua.optimize();
+        assert!(ua.cands.iter().all(|c| !c.lower));
+        assert!(ua.total_cost() == 1);
+    }
+
+    #[test]
+    fn test_forced_warp_stays_lowered() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 1);
+        let [x, y, m, g, p] = [(); 5].map(|_| t.alloc_ssa(RegFile::UGPR, 1));
+
+        // There is no uniform imul.  Making it uniform would save the R2UR
+        // on m but it has to stay lowered.
+        t.add_block(0, true, |bld| {
+            bld.copy_to(x.into(), 3.into());
+            bld.copy_to(y.into(), 5.into());
+            bld.push_op(OpIMul {
+                dst: m.into(),
+                srcs: [x.into(), y.into()],
+                signed: [false; 2],
+                high: false,
+            });
+            uiadd3(bld, g, [m.into(), 1.into(), 0.into()]);
+            bld.push_op(OpPin {
+                src: g.into(),
+                dst: p.into(),
+            });
+            bld.push_op(OpExit {});
+        });
+        let f = t.into_function();
+
+        let mut ua = UniformAssignment::new(&sm, &f);
+        assert!(ua.cands[2].forced_warp && ua.cands[2].lower);
+
+        ua.optimize();
+        assert!(ua.cands[2].lower);
+        assert!(ua.total_cost() == 1);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+
+use std::env;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum PassKind {
+    /// Needed to produce a working shader.  These can't be disabled.
+    Required,
+
+    /// An optimization.  These can be disabled or bisected.
+    Opt,
+
+    /// Instruments the shader.  These only run if enabled.
+    Instrument,
+}
+
+struct Pass {
+    name: &'static str,
+    kind: PassKind,
+    run: fn(&mut Shader<'_>),
+
+    /// Whether the pass applies to the given shader
+    applies: fn(&Shader<'_>) -> bool,
+}
+
+macro_rules! pass {
+    ($kind: ident, $name: ident) => {
+        pass!($kind, $name, |_| true)
+    };
+    ($kind: ident, $name: ident, $applies: expr) => {
+        Pass {
+            name: stringify!($name),
+            kind: PassKind::$kind,
+            run: |s| s.$name(),
+            applies: $applies,
+        }
+    };
+}
+
+const NUM_PASSES: usize = 24;
+
+/// Every pass we know about, in the order they run
+static PASSES: [Pass; NUM_PASSES] = [
+    pass!(Required, validate_xfb),
+    pass!(Opt, opt_bar_prop),
+    pass!(Opt, opt_uniform_instrs),
+    pass!(Opt, opt_copy_prop),
+    pass!(Opt, opt_prmt),
+    pass!(Opt, opt_lop),
+    pass!(Opt, opt_algebraic),
+    pass!(Opt, opt_copy_prop),
+    pass!(Opt, opt_bssy),
+    pass!(Opt, opt_dce),
+    pass!(Opt, opt_hoist_handles),
+    pass!(Opt, opt_out),
+    pass!(Instrument, sanitize_mem, |s| s.info.debug_cbuf.is_some()),
+    pass!(Instrument, profile_blocks, |s| s.info.debug_cbuf.is_some()),
+    pass!(Required, legalize),
+    pass!(Required, convert_to_cssa),
+    pass!(Opt, coalesce_phi_webs),
+    pass!(Required, assign_regs),
+    pass!(Required, lower_par_copies),
+    pass!(Required, lower_copy_swap),
+    pass!(Opt, opt_jump_thread, |s| s.sm.sm() >= 70),
+    pass!(Opt, opt_crs, |s| s.sm.sm() < 70),
+    pass!(Required, remove_annotations),
+    pass!(Required, calc_instr_deps),
+];
+
+struct Bisect {
+    hash: u64,
+
+    /// The index of the optimization pass to skip.  If None, we just list
+    /// the optimization passes which run on the shader.
+    skip: Option<usize>,
+}
+
+struct PassConfig {
+    disabled: [bool; NUM_PASSES],
+    validate: bool,
+    bisect: Option<Bisect>,
+}
+
+impl PassConfig {
+    fn new() -> PassConfig {
+        let mut config = PassConfig {
+            disabled: std::array::from_fn(|i| {
+                PASSES[i].kind == PassKind::Instrument
+            }),
+            validate: false,
+            bisect: None,
+        };
+
+        if DEBUG.sanitize() {
+            config.parse_passes("+sanitize_mem");
+        }
+
+        if let Ok(passes_str) = env::var("NAK_PASSES") {
+            config.parse_passes(&passes_str);
+        }
+
+        if let Ok(bisect_str) = env::var("NAK_BISECT") {
+            config.bisect = parse_bisect(&bisect_str);
+            if config.bisect.is_none() {
+                eprintln!("Invalid NAK_BISECT value \"{}\"", bisect_str);
+            }
+        }
+
+        config
+    }
+
+    fn parse_passes(&mut self, passes_str: &str) {
+        for entry in passes_str.split(',') {
+            let entry = entry.trim();
+            let (enable, name) = if let Some(name) = entry.strip_prefix('+') {
+                (true, name)
+            } else if let Some(name) = entry.strip_prefix('-') {
+                (false, name)
+            } else {
+                eprintln!(
+                    "NAK_PASSES entry \"{}\" must start with + or -",
+                    entry
+                );
+                continue;
+            };
+
+            if name == "validate" {
+                self.validate = enable;
+                continue;
+            }
+
+            let mut found = false;
+            for (i, pass) in PASSES.iter().enumerate() {
+                if pass.name != name {
+                    continue;
+                }
+                found = true;
+
+                if !enable && pass.kind == PassKind::Required {
+                    eprintln!("Cannot disable required NAK pass \"{}\"", name);
+                } else {
+                    self.disabled[i] = !enable;
+                }
+            }
+
+            if !found {
+                eprintln!("Unknown NAK pass \"{}\"", name);
+            }
+        }
+    }
+}
+
+/// Parses HASH or HASH:N where HASH is the shader hash in hex
+fn parse_bisect(bisect_str: &str) -> Option<Bisect> {
+    let (hash_str, skip_str) = match bisect_str.trim().split_once(':') {
+        Some((hash_str, skip_str)) => (hash_str, Some(skip_str)),
+        None => (bisect_str.trim(), None),
+    };
+
+    let hash = u64::from_str_radix(hash_str, 16).ok()?;
+    let skip = match skip_str {
+        Some(s) => Some(s.parse().ok()?),
+        None => None,
+    };
+
+    Some(Bisect {
+        hash: hash,
+        skip: skip,
+    })
+}
+
+static CONFIG: OnceLock<PassConfig> = OnceLock::new();
+
+/// Returns true if NAK_BISECT is set, in which case the caller needs to
+/// provide a shader hash to run_passes().
+pub fn bisecting() -> bool {
+    CONFIG.get_or_init(PassConfig::new).bisect.is_some()
+}
+
+/// Returns true if any instrumentation pass is enabled, in which case the
+/// driver has to reserve a debug cbuf.
+pub fn instrumenting() -> bool {
+    let config = CONFIG.get_or_init(PassConfig::new);
+    PASSES.iter().enumerate().any(|(i, pass)| {
+        pass.kind == PassKind::Instrument && !config.disabled[i]
+    })
+}
+
+impl Shader<'_> {
+    fn num_instrs(&self) -> usize {
+        let mut num_instrs = 0;
+        self.for_each_instr(&mut |_| num_instrs += 1);
+        num_instrs
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.functions.iter().map(|f| f.blocks.len()).sum()
+    }
+
+    fn validate_after(&self, pass: &str) {
+        if let Err(err) = self.validate() {
+            panic!("NAK IR is invalid after {pass}: {err}");
+        }
+    }
+
+    /// Runs the back-end compile pipeline, from freshly translated NIR up
+    /// until the shader is ready to encode
+    ///
+    /// The set of passes can be tweaked with NAK_PASSES, a comma-separated
+    /// list of passes to disable (-name) or enable (+name).  The special
+    /// "validate" pass checks the IR after every other pass.  Required passes
+    /// can't be disabled and instrumentation passes only run if enabled.
+    ///
+    /// If NAK_BISECT is set to the hash of this shader, optimization pass N
+    /// is skipped where N is given after a colon, or the list of optimization
+    /// passes is printed if no N is given.  Running with each N in turn
+    /// quickly finds the pass responsible for a miscompile.
+    pub fn run_passes(&mut self, shader_hash: Option<u64>) {
+        let config = CONFIG.get_or_init(PassConfig::new);
+
+        let mut pipeline: Vec<&Pass> = PASSES
+            .iter()
+            .enumerate()
+            .filter(|(i, pass)| !config.disabled[*i] && (pass.applies)(self))
+            .map(|(_, pass)| pass)
+            .collect();
+
+        if let (Some(bisect), Some(hash)) = (&config.bisect, shader_hash) {
+            if bisect.hash == hash {
+                let opts: Vec<usize> = (0..pipeline.len())
+                    .filter(|&i| pipeline[i].kind == PassKind::Opt)
+                    .collect();
+                match bisect.skip {
+                    Some(n) if n < opts.len() => {
+                        eprintln!(
+                            "NAK bisect {hash:016x}: skipping {} \
+                             (optimization pass {n} of {})",
+                            pipeline[opts[n]].name,
+                            opts.len(),
+                        );
+                        pipeline.remove(opts[n]);
+                    }
+                    Some(n) => {
+                        eprintln!(
+                            "NAK bisect {hash:016x}: no optimization pass \
+                             {n}, the shader only has {}",
+                            opts.len(),
+                        );
+                    }
+                    None => {
+                        eprintln!(
+                            "NAK bisect {hash:016x}: optimization passes:"
+                        );
+                        for (n, &i) in opts.iter().enumerate() {
+                            eprintln!("    {n}: {}", pipeline[i].name);
+                        }
+                    }
+                }
+            }
+        }
+
+        if config.validate {
+            self.validate_after("from_nir");
+        }
+
+        for pass in pipeline {
+            let start = Instant::now();
+            (pass.run)(self);
+            let elapsed = start.elapsed();
+
+            if DEBUG.print() {
+                eprintln!("NAK IR after {}:\n{}", pass.name, self);
+            }
+
+            if DEBUG.time() {
+                eprintln!(
+                    "{:<20} {:>10.3} ms {:>8} instrs {:>6} blocks",
+                    pass.name,
+                    elapsed.as_secs_f64() * 1000.0,
+                    self.num_instrs(),
+                    self.num_blocks(),
+                );
+            }
+
+            if config.validate {
+                self.validate_after(pass.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::builder::*;
+use crate::ir::*;
+
+use nak_bindings::NAK_DEBUG_CBUF_PROFILE_ADDR;
+
+impl Function {
+    fn profile_blocks(&mut self, sm: &dyn ShaderModel, info: &mut ShaderInfo) {
+        let cb = CBuf::Binding(info.debug_cbuf.unwrap());
+        let addr_offset: u16 = NAK_DEBUG_CBUF_PROFILE_ADDR.try_into().unwrap();
+        let counters_lo = CBufRef {
+            buf: cb,
+            offset: addr_offset,
+        };
+        let counters_hi = CBufRef {
+            buf: cb,
+            offset: addr_offset + 4,
+        };
+
+        for bb in &mut self.blocks {
+            let counter = info.profile_blocks.len();
+            info.profile_blocks.push(bb.label);
+
+            let mut b = SSAInstrBuilder::new(sm, &mut self.ssa_alloc);
+            if DEBUG.annotate() {
+                b.push_op(OpAnnotate {
+                    annotation: "generated by profile_blocks".into(),
+                });
+            }
+
+            let addr = b.alloc_ssa(RegFile::GPR, 2);
+            b.copy_to(addr[0].into(), counters_lo.into());
+            b.copy_to(addr[1].into(), counters_hi.into());
+            b.push_op(OpAtom {
+                dst: Dst::None,
+                addr: addr.into(),
+                cmpr: 0.into(),
+                data: 1.into(),
+                atom_op: AtomOp::Add,
+                atom_type: AtomType::U32,
+                addr_offset: (counter * 4).try_into().unwrap(),
+                mem_space: MemSpace::Global(MemAddrType::A64),
+                mem_order: MemOrder::Strong(MemScope::GPU),
+                mem_eviction_priority: MemEvictionPriority::Normal,
+            });
+
+            // Counters go right after the phis so they count every time the
+            // block is entered.
+            let ip = match bb.phi_dsts_ip() {
+                Some(ip) => ip + 1,
+                None => 0,
+            };
+            bb.instrs.splice(ip..ip, b.as_vec());
+        }
+    }
+}
+
+impl Shader<'_> {
+    /// Adds a counter to every block which counts how many times it runs
+    ///
+    /// Each block atomically increments its own 32-bit counter in a buffer
+    /// whose address the driver puts in the debug cbuf.
+    /// Every invocation which executes the block increments the counter.
+    /// The label of the block each counter belongs to is recorded in
+    /// ShaderInfo::profile_blocks so it can be mapped back to code offsets
+    /// once the shader is encoded.
+    pub fn profile_blocks(&mut self) {
+        for f in &mut self.functions {
+            f.profile_blocks(self.sm, &mut self.info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_one_counter_per_block() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 3);
+        let header = t.label(1);
+        let x = t.alloc_ssa(RegFile::GPR, 1);
+        let y = t.alloc_ssa(RegFile::GPR, 1);
+        let z = t.alloc_ssa(RegFile::GPR, 1);
+
+        t.add_block(0, true, |b| {
+            b.copy_to(x.into(), 0.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, x.into());
+            b.push_op(phi);
+        });
+        t.add_block(1, true, |b| {
+            let mut phi = OpPhiDsts::new();
+            phi.dsts.push(0, y.into());
+            b.push_op(phi);
+            b.copy_to(z.into(), y.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, z.into());
+            b.push_op(phi);
+            let cond = b.alloc_ssa(RegFile::Pred, 1);
+            b.copy_to(cond.into(), SrcRef::True.into());
+            b.predicate(cond[0].into()).push_op(OpBra {
+                target: header,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(2, true, |b| {
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(1, 2);
+        t.add_edge(1, 1);
+
+        let mut s = t.into_shader();
+        s.info.debug_cbuf = Some(1);
+        s.profile_blocks();
+
+        let f = &s.functions[0];
+        assert!(s.info.profile_blocks.len() == f.blocks.len());
+
+        let mut offsets = HashSet::new();
+        for bb in f.blocks.iter() {
+            let atoms: Vec<_> = bb
+                .instrs
+                .iter()
+                .enumerate()
+                .filter_map(|(ip, instr)| match &instr.op {
+                    Op::Atom(atom) => Some((ip, atom)),
+                    _ => None,
+                })
+                .collect();
+            assert!(atoms.len() == 1);
+            let (ip, atom) = atoms[0];
+
+            if let Some(phi_ip) = bb.phi_dsts_ip() {
+                assert!(ip > phi_ip);
+            }
+
+            assert!(offsets.insert(atom.addr_offset));
+            let counter = usize::try_from(atom.addr_offset / 4).unwrap();
+            assert!(s.info.profile_blocks[counter] == bb.label);
+        }
+
+        // The phis must still be first in the loop header
+        assert!(f.blocks[1].phi_dsts_ip() == Some(0));
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::builder::*;
+use crate::ir::*;
+
+use nak_bindings::{nak_sanitize_error, NAK_DEBUG_CBUF_SANITIZE_ADDR};
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+
+/// Returns the address, offset, size in bytes, and memory space of a memory
+/// access
+fn mem_access(instr: &Instr) -> Option<(Src, i32, u32, MemSpace)> {
+    let (addr, offset, bits, space) = match &instr.op {
+        Op::Ld(op) => (
+            op.addr,
+            op.offset,
+            op.access.mem_type.bits(),
+            op.access.space,
+        ),
+        Op::St(op) => (
+            op.addr,
+            op.offset,
+            op.access.mem_type.bits(),
+            op.access.space,
+        ),
+        Op::Atom(op) => {
+            (op.addr, op.addr_offset, op.atom_type.bits(), op.mem_space)
+        }
+        _ => return None,
+    };
+    Some((addr, offset, (bits / 8).try_into().unwrap(), space))
+}
+
+fn is_undef(undefs: &HashSet<SSAValue>, src: &Src) -> bool {
+    match &src.src_ref {
+        SrcRef::SSA(ssa) => ssa.iter().all(|v| undefs.contains(v)),
+        _ => false,
+    }
+}
+
+/// Returns the SSA values which are undefined no matter which path the
+/// shader takes to get to them
+///
+/// This starts with the destinations of OpUndef and follows copies and phis
+/// whose sources are all undefined.  Phis which are only undefined on some
+/// paths aren't included since the access may well be fine on the others.
+/// Neither are values computed from an undefined value by anything other
+/// than a copy.
+fn find_undefs(f: &Function) -> HashSet<SSAValue> {
+    let mut undefs = HashSet::new();
+    loop {
+        let num_undefs = undefs.len();
+
+        // Whether all the sources of each phi we've seen so far are undef
+        let mut phi_undef: HashMap<u32, bool> = HashMap::new();
+        for instr in f.blocks.iter().flat_map(|b| b.instrs.iter()) {
+            match &instr.op {
+                Op::Undef(op) => {
+                    undefs.extend(op.dst.iter_ssa().copied());
+                }
+                Op::Copy(op) if is_undef(&undefs, &op.src) => {
+                    undefs.extend(op.dst.iter_ssa().copied());
+                }
+                Op::PhiSrcs(op) => {
+                    for (phi, src) in op.srcs.iter() {
+                        let u = is_undef(&undefs, src);
+                        *phi_undef.entry(*phi).or_insert(true) &= u;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for instr in f.blocks.iter().flat_map(|b| b.instrs.iter()) {
+            if let Op::PhiDsts(op) = &instr.op {
+                for (phi, dst) in op.dsts.iter() {
+                    if phi_undef.get(phi) == Some(&true) {
+                        undefs.extend(dst.iter_ssa().copied());
+                    }
+                }
+            }
+        }
+
+        // Values only ever get added so this terminates
+        if undefs.len() == num_undefs {
+            return undefs;
+        }
+    }
+}
+
+struct Sanitizer<'a> {
+    sm: &'a dyn ShaderModel,
+    debug_cbuf: u8,
+    label_alloc: LabelAllocator,
+    undefs: HashSet<SSAValue>,
+    shared_limit: u32,
+    local_limit: u32,
+    checks: &'a mut Vec<SanitizeCheck>,
+}
+
+impl Sanitizer<'_> {
+    fn check_instr(
+        &mut self,
+        instr: Box<Instr>,
+        alloc: &mut SSAValueAllocator,
+    ) -> MappedInstrs {
+        let Some((addr, offset, size, space)) = mem_access(&instr) else {
+            return MappedInstrs::One(instr);
+        };
+
+        let addr_is_undef = match &addr.src_ref {
+            SrcRef::SSA(ssa) => ssa.iter().any(|v| self.undefs.contains(v)),
+            _ => false,
+        };
+
+        let (kind, limit) = if addr_is_undef {
+            (SanitizeCheckKind::UndefAddr, 0)
+        } else {
+            match space {
+                MemSpace::Shared => {
+                    (SanitizeCheckKind::SharedBounds, self.shared_limit)
+                }
+                MemSpace::Local => {
+                    (SanitizeCheckKind::LocalBounds, self.local_limit)
+                }
+                MemSpace::Global(_) => return MappedInstrs::One(instr),
+            }
+        };
+
+        let label = self.label_alloc.alloc();
+        let check = u32::try_from(self.checks.len()).unwrap();
+        self.checks.push(SanitizeCheck {
+            label: label,
+            kind: kind,
+            size: size,
+            limit: limit,
+        });
+
+        let mut b = SSAInstrBuilder::new(self.sm, alloc);
+        if DEBUG.annotate() {
+            b.push_op(OpAnnotate {
+                annotation: "generated by sanitize_mem".into(),
+            });
+        }
+
+        let (fail, start): (Pred, SSARef) = if addr_is_undef {
+            (true.into(), b.copy(0.into()))
+        } else {
+            // The access is out of bounds if start + size > limit.  Compare
+            // start against limit - size instead so we don't have to worry
+            // about start + size wrapping around.
+            let start = b.iadd(addr, (offset as u32).into(), 0.into());
+            if size > limit {
+                (true.into(), start)
+            } else {
+                let oob = b.isetp(
+                    IntCmpType::U32,
+                    IntCmpOp::Gt,
+                    start.into(),
+                    (limit - size).into(),
+                );
+                (oob[0].into(), start)
+            }
+        };
+
+        let cb = CBuf::Binding(self.debug_cbuf);
+        let errors_offset: u16 =
+            NAK_DEBUG_CBUF_SANITIZE_ADDR.try_into().unwrap();
+        let errors = b.alloc_ssa(RegFile::GPR, 2);
+        b.copy_to(
+            errors[0].into(),
+            CBufRef {
+                buf: cb,
+                offset: errors_offset,
+            }
+            .into(),
+        );
+        b.copy_to(
+            errors[1].into(),
+            CBufRef {
+                buf: cb,
+                offset: errors_offset + 4,
+            }
+            .into(),
+        );
+
+        let error_size =
+            u32::try_from(size_of::<nak_sanitize_error>()).unwrap();
+        let error_offset = i32::try_from(check * error_size).unwrap();
+        b.predicate(fail).push_op(OpAtom {
+            dst: Dst::None,
+            addr: errors.into(),
+            cmpr: 0.into(),
+            data: 1.into(),
+            atom_op: AtomOp::Add,
+            atom_type: AtomType::U32,
+            addr_offset: error_offset,
+            mem_space: MemSpace::Global(MemAddrType::A64),
+            mem_order: MemOrder::Strong(MemScope::GPU),
+            mem_eviction_priority: MemEvictionPriority::Normal,
+        });
+        b.predicate(fail).push_op(OpSt {
+            addr: errors.into(),
+            data: start.into(),
+            offset: error_offset + 4,
+            access: MemAccess {
+                mem_type: MemType::B32,
+                space: MemSpace::Global(MemAddrType::A64),
+                order: MemOrder::Strong(MemScope::GPU),
+                eviction_priority: MemEvictionPriority::Normal,
+            },
+        });
+
+        // The label marks the access so we can find its offset once the
+        // shader has been encoded.  It has to go after the report or we'd
+        // find that instead.
+        b.push_op(OpNop { label: Some(label) });
+        b.push_instr(instr);
+
+        b.as_mapped_instrs()
+    }
+}
+
+impl Shader<'_> {
+    /// Adds bounds checks to shared and local memory accesses
+    ///
+    /// Out-of-bounds shared memory accesses don't fault on NVIDIA hardware,
+    /// they just corrupt whatever lives next door.  With NAK_DEBUG=sanitize,
+    /// every shared or local memory access is checked against the shared
+    /// memory size or scratch size of the shader, respectively.  Accesses
+    /// through an address which is undefined on every path are reported
+    /// unconditionally.  See find_undefs() for what that catches.  Failed
+    /// checks are recorded in a buffer whose address the driver puts in the
+    /// debug cbuf and the access still happens.
+    ///
+    /// This has to run before spilling because spills live in local memory
+    /// past the scratch size.
+    pub fn sanitize_mem(&mut self) {
+        let shared_limit = match &self.info.stage {
+            ShaderStageInfo::Compute(cs) => cs.smem_size,
+            _ => 0,
+        };
+        let local_limit = self.info.slm_size;
+        let debug_cbuf = self.info.debug_cbuf.unwrap();
+
+        for f in &mut self.functions {
+            let undefs = find_undefs(f);
+            let mut sanitizer = Sanitizer {
+                sm: self.sm,
+                debug_cbuf: debug_cbuf,
+                label_alloc: f.label_alloc(),
+                undefs: undefs,
+                shared_limit: shared_limit.into(),
+                local_limit: local_limit,
+                checks: &mut self.info.sanitize_checks,
+            };
+            f.map_instrs(|instr, alloc| sanitizer.check_instr(instr, alloc));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm70::ShaderModel70;
+    use crate::test_util::*;
+
+    fn ld_shared(b: &mut SSAInstrBuilder, addr: SSARef) {
+        let dst = b.alloc_ssa(RegFile::GPR, 1);
+        b.push_op(OpLd {
+            dst: dst.into(),
+            addr: addr.into(),
+            offset: 0,
+            access: MemAccess {
+                mem_type: MemType::B32,
+                space: MemSpace::Shared,
+                order: MemOrder::Strong(MemScope::CTA),
+                eviction_priority: MemEvictionPriority::Normal,
+            },
+        });
+    }
+
+    #[test]
+    fn test_undef_through_copies_and_phis() {
+        let sm = ShaderModel70::new(75);
+        let mut t = TestFunctionBuilder::new(&sm, 3);
+        let merge = t.label(2);
+        let c = t.alloc_ssa(RegFile::GPR, 1);
+        let k = t.alloc_ssa(RegFile::GPR, 1);
+        let p = t.alloc_ssa(RegFile::GPR, 1);
+        let q = t.alloc_ssa(RegFile::GPR, 1);
+
+        t.add_block(0, true, |b| {
+            let u = b.undef();
+            b.copy_to(c.into(), u.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, c.into());
+            phi.srcs.push(1, c.into());
+            b.push_op(phi);
+            let cond = b.alloc_ssa(RegFile::Pred, 1);
+            b.copy_to(cond.into(), SrcRef::True.into());
+            b.predicate(cond[0].into()).push_op(OpBra {
+                target: merge,
+                cond: SrcRef::True.into(),
+            });
+        });
+        t.add_block(1, true, |b| {
+            b.copy_to(k.into(), 0.into());
+            let mut phi = OpPhiSrcs::new();
+            phi.srcs.push(0, c.into());
+            phi.srcs.push(1, k.into());
+            b.push_op(phi);
+        });
+        t.add_block(2, true, |b| {
+            let mut phi = OpPhiDsts::new();
+            phi.dsts.push(0, p.into());
+            phi.dsts.push(1, q.into());
+            b.push_op(phi);
+            ld_shared(b, p);
+            ld_shared(b, q);
+            b.push_op(OpExit {});
+        });
+        t.add_edge(0, 1);
+        t.add_edge(0, 2);
+        t.add_edge(1, 2);
+
+        let mut s = t.into_shader();
+
+        let undefs = find_undefs(&s.functions[0]);
+        assert!(undefs.contains(&c[0]));
+        assert!(undefs.contains(&p[0]));
+        assert!(!undefs.contains(&k[0]));
+        assert!(!undefs.contains(&q[0]));
+
+        s.info.debug_cbuf = Some(1);
+        s.sanitize_mem();
+        let checks = &s.info.sanitize_checks;
+        assert!(checks.len() == 2);
+        assert!(matches!(checks[0].kind, SanitizeCheckKind::UndefAddr));
+        assert!(matches!(checks[1].kind, SanitizeCheckKind::SharedBounds));
+    }
+}
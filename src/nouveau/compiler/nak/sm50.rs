@@ -63,6 +63,25 @@ impl ShaderModel for ShaderModel50 {
     fn encode_shader(&self, s: &Shader<'_>) -> Vec<u32> {
         encode_sm50_shader(self, s)
     }
+
+    fn instr_offsets(&self, s: &Shader<'_>) -> Vec<u32> {
+        assert!(s.functions.len() == 1);
+
+        let mut offsets = Vec::new();
+        let mut block_offset = 0_usize;
+        for b in &s.functions[0].blocks {
+            // Each group of 3 instructions is preceded by a schedule
+            // instruction.  See encode_sm50_shader().
+            for i in 0..b.instrs.len() {
+                let offset = block_offset + (i / 3) * 32 + 8 + (i % 3) * 8;
+                offsets.push(offset.try_into().unwrap());
+            }
+
+            let block_num_instrs = b.instrs.len().next_multiple_of(3);
+            block_offset += (block_num_instrs + (block_num_instrs / 3)) * 8;
+        }
+        offsets
+    }
 }
 
 trait SM50Op {
@@ -2873,6 +2892,7 @@ impl SM50Op for OpBra {
     }
 
     fn encode(&self, e: &mut SM50Encoder<'_>) {
+        assert!(self.cond.as_bool() == Some(true));
         e.set_opcode(0xe240);
         e.set_rel_offset(20..44, &self.target);
         e.set_field(0..5, 0xF_u8); // TODO: Pred?
@@ -112,6 +112,16 @@ impl ShaderModel for ShaderModel70 {
     fn encode_shader(&self, s: &Shader<'_>) -> Vec<u32> {
         encode_sm70_shader(self, s)
     }
+
+    fn instr_offsets(&self, s: &Shader<'_>) -> Vec<u32> {
+        let mut offsets = Vec::new();
+        let mut offset = 0_u32;
+        s.for_each_instr(&mut |_| {
+            offsets.push(offset);
+            offset += 16;
+        });
+        offsets
+    }
 }
 
 /// A per-op trait that implements Volta+ opcode semantics
@@ -3381,14 +3391,38 @@ impl SM70Op for OpBSync {
 }
 
 impl SM70Op for OpBra {
-    fn legalize(&mut self, _b: &mut LegalizeBuilder) {
-        // Nothing to do
+    fn legalize(&mut self, b: &mut LegalizeBuilder) {
+        // The condition has to be true or, on Turing+, a uniform predicate.
+        // Copy propagation may have folded it to a constant false.
+        match &self.cond.src_ref {
+            SrcRef::True | SrcRef::False => {
+                if self.cond.as_bool() != Some(true) {
+                    assert!(b.sm() >= 75);
+                    let tmp = b.alloc_ssa(RegFile::UPred, 1);
+                    b.copy_to(tmp.into(), SrcRef::False.into());
+                    self.cond = tmp.into();
+                }
+            }
+            SrcRef::SSA(_) => {
+                assert!(b.sm() >= 75);
+                assert!(
+                    src_is_upred_reg(&self.cond),
+                    "BRA conditions must be uniform"
+                );
+            }
+            _ => panic!("Not a predicate source"),
+        }
     }
 
     fn encode(&self, e: &mut SM70Encoder<'_>) {
         e.set_opcode(0x947);
         e.set_rel_offset(34..82, &self.target);
-        e.set_field(87..90, 0x7_u8); // TODO: Pred?
+        if e.sm.sm >= 75 {
+            e.set_upred_src(87..90, 90, self.cond);
+        } else {
+            assert!(self.cond.as_bool() == Some(true));
+            e.set_field(87..90, 0x7_u8);
+        }
     }
 }
 
@@ -0,0 +1,121 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Helpers for building small shaders by hand in unit tests
+
+use crate::builder::*;
+use crate::ir::*;
+
+use compiler::cfg::CFGBuilder;
+
+pub struct TestFunctionBuilder<'a> {
+    sm: &'a dyn ShaderModel,
+    alloc: SSAValueAllocator,
+    labels: Vec<Label>,
+    blocks: Vec<Option<BasicBlock>>,
+    edges: Vec<(usize, usize)>,
+}
+
+impl<'a> TestFunctionBuilder<'a> {
+    /// Creates a builder for a function with the given number of blocks.
+    /// Block 0 is the entry block.
+    pub fn new(sm: &'a dyn ShaderModel, num_blocks: usize) -> Self {
+        let mut label_alloc = LabelAllocator::new();
+        Self {
+            sm: sm,
+            alloc: SSAValueAllocator::new(),
+            labels: (0..num_blocks).map(|_| label_alloc.alloc()).collect(),
+            blocks: (0..num_blocks).map(|_| None).collect(),
+            edges: Vec::new(),
+        }
+    }
+
+    pub fn label(&self, block: usize) -> Label {
+        self.labels[block]
+    }
+
+    /// Allocates SSA values which may be used across blocks
+    pub fn alloc_ssa(&mut self, file: RegFile, comps: u8) -> SSARef {
+        self.alloc.alloc_vec(file, comps)
+    }
+
+    pub fn add_block(
+        &mut self,
+        block: usize,
+        uniform: bool,
+        f: impl FnOnce(&mut SSAInstrBuilder),
+    ) {
+        let mut b = SSAInstrBuilder::new(self.sm, &mut self.alloc);
+        f(&mut b);
+        assert!(self.blocks[block].is_none());
+        self.blocks[block] = Some(BasicBlock {
+            label: self.labels[block],
+            uniform: uniform,
+            instrs: b.as_vec(),
+        });
+    }
+
+    /// Adds a CFG edge.  Fall-through edges have to be added first.
+    pub fn add_edge(&mut self, pred: usize, succ: usize) {
+        self.edges.push((pred, succ));
+    }
+
+    pub fn into_function(self) -> Function {
+        let mut cfg = CFGBuilder::new();
+        for (i, b) in self.blocks.into_iter().enumerate() {
+            cfg.add_node(i, b.expect("Every block must be added"));
+        }
+        for (p, s) in self.edges {
+            cfg.add_edge(p, s);
+        }
+
+        Function {
+            ssa_alloc: self.alloc,
+            phi_alloc: PhiAllocator::new(),
+            blocks: cfg.as_cfg(),
+        }
+    }
+
+    pub fn into_shader(self) -> Shader<'a> {
+        let sm = self.sm;
+        test_shader(sm, self.into_function())
+    }
+}
+
+/// Wraps a function in a compute shader
+pub fn test_shader(sm: &dyn ShaderModel, f: Function) -> Shader<'_> {
+    let info = ShaderInfo {
+        num_gprs: 0,
+        num_control_barriers: 0,
+        num_instrs: 0,
+        slm_size: 0,
+        max_crs_depth: 0,
+        num_spills_to_mem: 0,
+        num_fills_from_mem: 0,
+        uses_global_mem: false,
+        writes_global_mem: false,
+        uses_fp64: false,
+        stage: ShaderStageInfo::Compute(ComputeShaderInfo {
+            local_size: [32, 1, 1],
+            smem_size: 0,
+        }),
+        io: ShaderIoInfo::None,
+        debug_cbuf: None,
+        profile_blocks: Vec::new(),
+        sanitize_checks: Vec::new(),
+    };
+
+    Shader {
+        sm: sm,
+        info: info,
+        functions: vec![f],
+    }
+}
+
+/// Counts the instructions in a function for which pred returns true
+pub fn count_instrs(f: &Function, pred: impl Fn(&Instr) -> bool) -> usize {
+    f.blocks
+        .iter()
+        .map(|b| b.instrs.iter().filter(|i| pred(i)).count())
+        .sum()
+}
@@ -402,3 +402,11 @@ impl Function {
         }
     }
 }
+
+impl Shader<'_> {
+    pub fn convert_to_cssa(&mut self) {
+        for f in &mut self.functions {
+            f.to_cssa();
+        }
+    }
+}
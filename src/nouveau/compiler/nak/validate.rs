@@ -0,0 +1,137 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::ir::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+pub enum ValidateError {
+    /// An SSA value is defined more than once
+    MultipleDefs { ssa: SSAValue },
+
+    /// An SSA value is used but never defined
+    UndefinedUse { ssa: SSAValue, block: usize },
+
+    /// An SSA value is used somewhere its definition does not dominate
+    UseNotDominated {
+        ssa: SSAValue,
+        def_block: usize,
+        use_block: usize,
+    },
+
+    /// A branch which is not the last instruction in its block
+    BranchNotLast { block: usize, ip: usize },
+}
+
+impl fmt::Display for ValidateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidateError::MultipleDefs { ssa } => {
+                write!(f, "{ssa} is defined more than once")
+            }
+            ValidateError::UndefinedUse { ssa, block } => {
+                write!(f, "{ssa} is used in block {block} but never defined")
+            }
+            ValidateError::UseNotDominated {
+                ssa,
+                def_block,
+                use_block,
+            } => write!(
+                f,
+                "{ssa} is defined in block {def_block} which does not \
+                 dominate its use in block {use_block}",
+            ),
+            ValidateError::BranchNotLast { block, ip } => write!(
+                f,
+                "Instruction {ip} of block {block} is a branch but is not \
+                 the last instruction in the block",
+            ),
+        }
+    }
+}
+
+impl Function {
+    fn validate(&self) -> Result<(), ValidateError> {
+        let mut defs = HashMap::new();
+        for (bi, b) in self.blocks.iter().enumerate() {
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                if instr.is_branch() && ip + 1 != b.instrs.len() {
+                    return Err(ValidateError::BranchNotLast {
+                        block: bi,
+                        ip: ip,
+                    });
+                }
+
+                let mut err = None;
+                instr.for_each_ssa_def(|ssa| {
+                    if defs.insert(*ssa, (bi, ip)).is_some() {
+                        err.get_or_insert(ValidateError::MultipleDefs {
+                            ssa: *ssa,
+                        });
+                    }
+                });
+                if let Some(err) = err {
+                    return Err(err);
+                }
+            }
+        }
+
+        for (bi, b) in self.blocks.iter().enumerate() {
+            for (ip, instr) in b.instrs.iter().enumerate() {
+                let mut err = None;
+                instr.for_each_ssa_use(|ssa| {
+                    if err.is_some() {
+                        return;
+                    }
+
+                    // Phi sources live at the end of the predecessor and phi
+                    // destinations at the top of the successor so the usual
+                    // dominance rules apply to both without special cases.
+                    err = match defs.get(ssa) {
+                        None => Some(ValidateError::UndefinedUse {
+                            ssa: *ssa,
+                            block: bi,
+                        }),
+                        Some(&(def_bi, def_ip)) => {
+                            let dominated = if def_bi == bi {
+                                def_ip < ip
+                            } else {
+                                self.blocks.dominates(def_bi, bi)
+                            };
+                            if dominated {
+                                None
+                            } else {
+                                Some(ValidateError::UseNotDominated {
+                                    ssa: *ssa,
+                                    def_block: def_bi,
+                                    use_block: bi,
+                                })
+                            }
+                        }
+                    };
+                });
+                if let Some(err) = err {
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Shader<'_> {
+    /// Checks the structural invariants of the IR
+    ///
+    /// This checks that every SSA value has exactly one definition which
+    /// dominates all of its uses and that branches only ever show up at the
+    /// end of a block.  Once registers have been assigned there are no SSA
+    /// values left and only the block structure is checked.
+    pub fn validate(&self) -> Result<(), ValidateError> {
+        for f in &self.functions {
+            f.validate()?;
+        }
+        Ok(())
+    }
+}
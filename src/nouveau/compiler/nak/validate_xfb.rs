@@ -0,0 +1,405 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::api::{GetDebugFlags, DEBUG};
+use crate::ir::*;
+
+use nak_bindings::nak_xfb_info;
+use std::fmt;
+
+const NUM_XFB_BUFFERS: usize = 4;
+const NUM_STREAMS: u8 = 4;
+
+/// The value the hardware uses to skip a dword in the XFB buffer
+const XFB_ATTR_SKIP: u8 = 0xff;
+
+#[derive(Debug)]
+pub enum XfbError {
+    /// A buffer is assigned to a stream which doesn't exist
+    InvalidBufferStream { buffer: usize, stream: u8 },
+
+    /// A buffer is assigned to a stream the shader doesn't output
+    StreamNotEnabled {
+        buffer: usize,
+        stream: u8,
+        stream_out_mask: u8,
+    },
+
+    /// A stream other than 0 is used outside of a geometry shader
+    StreamWithoutGeometry { buffer: usize, stream: u8 },
+
+    /// The buffer stride is not a multiple of 4 bytes
+    UnalignedStride { buffer: usize, stride: u32 },
+
+    /// The attributes captured into a buffer don't fit in its stride
+    StrideTooSmall {
+        buffer: usize,
+        stride: u32,
+        attr_count: u8,
+    },
+
+    /// A buffer captures an attribute that is not a valid output
+    InvalidAttr {
+        buffer: usize,
+        dword: usize,
+        attr_addr: u16,
+    },
+
+    /// OpOut emits to a stream that doesn't exist
+    InvalidEmitStream { stream: u32 },
+}
+
+impl fmt::Display for XfbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XfbError::InvalidBufferStream { buffer, stream } => write!(
+                f,
+                "XFB buffer {buffer} is assigned to stream {stream} but only \
+                 {NUM_STREAMS} streams exist",
+            ),
+            XfbError::StreamNotEnabled {
+                buffer,
+                stream,
+                stream_out_mask,
+            } => write!(
+                f,
+                "XFB buffer {buffer} is assigned to stream {stream} but the \
+                 geometry shader only outputs streams {stream_out_mask:#x}",
+            ),
+            XfbError::StreamWithoutGeometry { buffer, stream } => write!(
+                f,
+                "XFB buffer {buffer} is assigned to stream {stream} but only \
+                 geometry shaders have streams other than 0",
+            ),
+            XfbError::UnalignedStride { buffer, stride } => write!(
+                f,
+                "XFB buffer {buffer} has a stride of {stride} bytes which is \
+                 not a multiple of 4",
+            ),
+            XfbError::StrideTooSmall {
+                buffer,
+                stride,
+                attr_count,
+            } => write!(
+                f,
+                "XFB buffer {buffer} captures {attr_count} dwords per vertex \
+                 but only has a stride of {stride} bytes",
+            ),
+            XfbError::InvalidAttr {
+                buffer,
+                dword,
+                attr_addr,
+            } => write!(
+                f,
+                "XFB buffer {buffer} captures attribute address \
+                 {attr_addr:#x} at dword {dword} which is not a valid output",
+            ),
+            XfbError::InvalidEmitStream { stream } => write!(
+                f,
+                "Geometry shader emits to stream {stream} but only \
+                 {NUM_STREAMS} streams exist",
+            ),
+        }
+    }
+}
+
+impl XfbError {
+    /// Returns whether the error can come from a layout the API allows
+    ///
+    /// A buffer may be assigned to a stream which never gets any vertices, in
+    /// which case nothing is captured into it.  Everything else means there
+    /// is a bug somewhere above us.
+    fn is_legal(&self) -> bool {
+        matches!(self, XfbError::StreamNotEnabled { .. })
+    }
+}
+
+/// Returns whether the attribute at the given address was written or None if
+/// the address is not something which can be captured.
+fn xfb_attr_written(io: &VtgIoInfo, addr: u16) -> Option<bool> {
+    if addr < 0x280
+        || (addr >= 0x2c0 && addr < 0x300)
+        || (addr >= 0x3a0 && addr < 0x3c0)
+    {
+        Some(io.attr_written(addr))
+    } else {
+        None
+    }
+}
+
+/// Checks an XFB layout against the shader's outputs
+///
+/// Entries which capture attributes the shader never writes are turned into
+/// skips.  The values of such attributes are undefined anyway and skipping
+/// them means we leave the buffer alone rather than writing garbage.  Entries
+/// which capture something that isn't a valid output are turned into skips
+/// as well.  Any problems are added to @errors and the number of entries
+/// which were skipped because they are never written is returned.
+fn check_xfb(
+    xfb: &mut nak_xfb_info,
+    io: &VtgIoInfo,
+    stream_out_mask: Option<u8>,
+    errors: &mut Vec<XfbError>,
+) -> u32 {
+    let mut num_skipped = 0;
+    for buffer in 0..NUM_XFB_BUFFERS {
+        let stride = xfb.stride[buffer];
+        let stream = xfb.stream[buffer];
+        let attr_count = xfb.attr_count[buffer];
+
+        if attr_count == 0 && stride == 0 {
+            continue;
+        }
+
+        if stream >= NUM_STREAMS {
+            errors.push(XfbError::InvalidBufferStream { buffer, stream });
+        } else {
+            match stream_out_mask {
+                Some(mask) => {
+                    if mask & (1 << stream) == 0 {
+                        errors.push(XfbError::StreamNotEnabled {
+                            buffer,
+                            stream,
+                            stream_out_mask: mask,
+                        });
+                    }
+                }
+                None => {
+                    if stream != 0 {
+                        errors.push(XfbError::StreamWithoutGeometry {
+                            buffer,
+                            stream,
+                        });
+                    }
+                }
+            }
+        }
+
+        if !stride.is_multiple_of(4) {
+            errors.push(XfbError::UnalignedStride { buffer, stride });
+        }
+
+        if u32::from(attr_count) * 4 > stride {
+            errors.push(XfbError::StrideTooSmall {
+                buffer,
+                stride,
+                attr_count,
+            });
+        }
+
+        for dword in 0..usize::from(attr_count) {
+            let attr_idx = xfb.attr_index[buffer][dword];
+            if attr_idx == XFB_ATTR_SKIP {
+                continue;
+            }
+
+            let attr_addr = u16::from(attr_idx) * 4;
+            match xfb_attr_written(io, attr_addr) {
+                Some(true) => (),
+                Some(false) => {
+                    xfb.attr_index[buffer][dword] = XFB_ATTR_SKIP;
+                    num_skipped += 1;
+                }
+                None => {
+                    xfb.attr_index[buffer][dword] = XFB_ATTR_SKIP;
+                    errors.push(XfbError::InvalidAttr {
+                        buffer,
+                        dword,
+                        attr_addr,
+                    });
+                }
+            }
+        }
+    }
+
+    num_skipped
+}
+
+impl Shader<'_> {
+    /// Returns the mask of streams the shader emits vertices to
+    fn gather_out_streams(&self, errors: &mut Vec<XfbError>) -> u8 {
+        let mut mask = 0_u8;
+        self.for_each_instr(&mut |instr| {
+            if let Op::Out(op) = &instr.op {
+                if let Some(stream) = op.stream.as_u32() {
+                    if stream >= NUM_STREAMS.into() {
+                        errors.push(XfbError::InvalidEmitStream { stream });
+                    } else {
+                        mask |= 1 << stream;
+                    }
+                }
+            }
+        });
+        mask
+    }
+
+    /// Checks the transform feedback layout and geometry streams against
+    /// what the shader actually writes
+    ///
+    /// Layouts which the API allows are only warned about with
+    /// NAK_DEBUG=print.  Anything else is a bug somewhere above us so it is
+    /// always reported and debug builds assert on it.
+    pub fn validate_xfb(&mut self) {
+        let mut errors = Vec::new();
+
+        let mut stream_out_mask = None;
+        if matches!(self.info.stage, ShaderStageInfo::Geometry(_)) {
+            let out_streams = self.gather_out_streams(&mut errors);
+            if let ShaderStageInfo::Geometry(gs) = &mut self.info.stage {
+                // Every stream we emit to needs to be enabled in the header
+                // or the hardware drops the vertices on the floor.
+                gs.stream_out_mask |= out_streams;
+                stream_out_mask = Some(gs.stream_out_mask);
+            }
+        }
+
+        let mut num_skipped = 0;
+        if let ShaderIoInfo::Vtg(io) = &mut self.info.io {
+            if let Some(mut xfb) = io.xfb.take() {
+                num_skipped =
+                    check_xfb(&mut xfb, io, stream_out_mask, &mut errors);
+                io.xfb = Some(xfb);
+            }
+        }
+
+        if DEBUG.print() && num_skipped > 0 {
+            eprintln!(
+                "validate_xfb: skipping {num_skipped} XFB outputs which are \
+                 never written",
+            );
+        }
+
+        for err in &errors {
+            if !err.is_legal() || DEBUG.print() {
+                eprintln!("validate_xfb: {err}");
+            }
+            debug_assert!(err.is_legal(), "Invalid transform feedback: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vtg_io() -> VtgIoInfo {
+        let mut io = VtgIoInfo {
+            sysvals_in: SysValInfo::default(),
+            sysvals_in_d: 0,
+            sysvals_out: SysValInfo::default(),
+            sysvals_out_d: 0,
+            attr_in: [0; 4],
+            attr_out: [0; 4],
+            store_req_start: u8::MAX,
+            store_req_end: 0,
+            clip_enable: 0,
+            cull_enable: 0,
+            xfb: None,
+        };
+        // Generic attributes 0 and 1
+        io.mark_attrs_written(0x80..0xa0);
+        io
+    }
+
+    /// Returns a layout with only buffer 0 capturing the given attributes
+    fn xfb_info(stride: u32, stream: u8, attr_addrs: &[u16]) -> nak_xfb_info {
+        // SAFETY: nak_xfb_info is plain old data
+        let mut xfb: nak_xfb_info = unsafe { std::mem::zeroed() };
+        xfb.stride[0] = stride;
+        xfb.stream[0] = stream;
+        xfb.attr_count[0] = attr_addrs.len().try_into().unwrap();
+        for (i, addr) in attr_addrs.iter().enumerate() {
+            xfb.attr_index[0][i] = (addr / 4).try_into().unwrap();
+        }
+        xfb
+    }
+
+    #[test]
+    fn test_valid_layout() {
+        let io = vtg_io();
+        let mut xfb = xfb_info(32, 0, &[0x80, 0x84, 0x90, 0x9c]);
+        let mut errors = Vec::new();
+        assert!(check_xfb(&mut xfb, &io, None, &mut errors) == 0);
+        assert!(errors.is_empty());
+        assert!(xfb.attr_index[0][..4] == [0x20, 0x21, 0x24, 0x27]);
+    }
+
+    #[test]
+    fn test_unwritten_attrs_are_skipped() {
+        let io = vtg_io();
+        let mut xfb = xfb_info(16, 0, &[0x80, 0xa0, 0x84, 0xa4]);
+        let mut errors = Vec::new();
+        assert!(check_xfb(&mut xfb, &io, None, &mut errors) == 2);
+        assert!(errors.is_empty());
+        assert!(
+            xfb.attr_index[0][..4]
+                == [0x20, XFB_ATTR_SKIP, 0x21, XFB_ATTR_SKIP]
+        );
+    }
+
+    #[test]
+    fn test_stream_not_enabled_is_legal() {
+        let io = vtg_io();
+        let mut xfb = xfb_info(4, 1, &[0x80]);
+        let mut errors = Vec::new();
+        assert!(check_xfb(&mut xfb, &io, Some(0x1), &mut errors) == 0);
+        assert!(matches!(
+            errors[..],
+            [XfbError::StreamNotEnabled {
+                buffer: 0,
+                stream: 1,
+                stream_out_mask: 0x1,
+            }]
+        ));
+        assert!(errors[0].is_legal());
+
+        errors.clear();
+        check_xfb(&mut xfb, &io, Some(0x3), &mut errors);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_layouts() {
+        let io = vtg_io();
+        let mut errors = Vec::new();
+
+        let mut xfb = xfb_info(4, 1, &[0x80]);
+        check_xfb(&mut xfb, &io, None, &mut errors);
+        assert!(matches!(
+            errors[..],
+            [XfbError::StreamWithoutGeometry { .. }]
+        ));
+
+        errors.clear();
+        let mut xfb = xfb_info(4, 4, &[0x80]);
+        check_xfb(&mut xfb, &io, Some(0xf), &mut errors);
+        assert!(matches!(errors[..], [XfbError::InvalidBufferStream { .. }]));
+
+        errors.clear();
+        let mut xfb = xfb_info(6, 0, &[0x80, 0x84]);
+        check_xfb(&mut xfb, &io, None, &mut errors);
+        assert!(matches!(
+            errors[..],
+            [
+                XfbError::UnalignedStride { .. },
+                XfbError::StrideTooSmall { .. },
+            ]
+        ));
+
+        // Fixed-function colors can't be captured and get skipped
+        errors.clear();
+        let mut xfb = xfb_info(8, 0, &[0x80, 0x280]);
+        assert!(check_xfb(&mut xfb, &io, None, &mut errors) == 0);
+        assert!(matches!(
+            errors[..],
+            [XfbError::InvalidAttr {
+                buffer: 0,
+                dword: 1,
+                attr_addr: 0x280,
+            }]
+        ));
+        assert!(xfb.attr_index[0][1] == XFB_ATTR_SKIP);
+
+        assert!(errors.iter().all(|err| !err.is_legal()));
+    }
+}
@@ -9,6 +9,8 @@
 #include "nouveau_context.h"
 #include "nouveau_device.h"
 
+#include "util/mesa-blake3.h"
+
 #if !defined(ANDROID_STRICT)
 #include <xf86drm.h>
 #endif
@@ -0,0 +1,13 @@
+/*
+ * Copyright © 2024 Collabora, Ltd.
+ * SPDX-License-Identifier: MIT
+ */
+
+#include "nak_private.h"
+
+/* All of nak-compile lives in the NAK crate.  See nak/cli.rs. */
+int
+main(int argc, char **argv)
+{
+   return nak_compile_main(argc, (const char *const *)argv);
+}
@@ -1085,12 +1085,13 @@ nak_postprocess_nir(nir_shader *nir,
    }
 
    /* Re-index blocks and compact SSA defs because we'll use them to index
-    * arrays
+    * arrays.  Instruction indices end up in the source map.
     */
    nir_foreach_function(func, nir) {
       if (func->impl) {
          nir_index_blocks(func->impl);
          nir_index_ssa_defs(func->impl);
+         nir_index_instrs(func->impl);
 
          /* Ensure that divergence information is correct. */
          assert(func->impl->valid_metadata & nir_metadata_divergence);
@@ -16,6 +16,8 @@ extern "C" {
 
 bool nak_should_print_nir(void);
 
+int nak_compile_main(int argc, const char *const *argv);
+
 struct nak_compiler {
    uint8_t sm;
    uint8_t warps_per_sm;
@@ -341,6 +341,60 @@ impl ArrayMthd for ${to_camel(mthd_name)} {
 ## Close the first brace.
 }
 %endfor
+
+## Decoding is the reverse of the above and mirrors P_PARSE_* and
+## P_DUMP_*_MTHD_DATA in the C bindings.
+/// Looks up the method at the given address
+pub fn decode_mthd(addr: u16) -> Option<crate::MthdInfo> {
+    let (name, index) = match addr {
+%for mthd_name, mthd in mthddict.items():
+  %if mthd.is_array and mthd.array_size == 0:
+    <% continue %>
+  %endif
+  %if mthd.is_array:
+        ${mthd_pattern(mthd)} => (
+            "${nvcl}_${mthd_name}",
+            Some(${mthd_index(mthd)}),
+        ),
+  %else:
+        ${hex(mthd_addr(mthd))} => ("${nvcl}_${mthd_name}", None),
+  %endif
+%endfor
+        _ => return None,
+    };
+    Some(crate::MthdInfo { name, index })
+}
+
+/// Decodes the fields of the method at the given address
+pub fn decode_mthd_data(addr: u16, data: u32) -> Vec<crate::MthdFieldInfo> {
+    match addr {
+%for mthd_name, mthd in mthddict.items():
+  %if mthd.is_array and mthd.array_size == 0:
+    <% continue %>
+  %endif
+  %if mthd.is_array:
+        ${mthd_pattern(mthd)} => vec![
+  %else:
+        ${hex(mthd_addr(mthd))} => vec![
+  %endif
+  %for field_name in mthd.field_name_start:
+            crate::MthdFieldInfo::new(
+                "${field_name}",
+                ${mthd.field_name_start[field_name]}..${int(mthd.field_name_end[field_name]) + 1},
+                data,
+                &[
+    %for d, v in mthd.field_defs[field_name].items():
+                    ("${d}", ${hex(int(v.strip('()'), 16))}),
+    %endfor
+                ],
+                ${'true' if mthd.is_float else 'false'},
+            ),
+  %endfor
+        ],
+%endfor
+        _ => Vec::new(),
+    }
+}
 """)
 
 ## A mere convenience to convert snake_case to CamelCase. Numbers are prefixed
@@ -362,6 +416,40 @@ def rs_field_name(name):
     else:
         return name
 
+## Evaluates the address of a method or of the i-th element of an array
+## method.
+def mthd_addr(mthd, i=None):
+    addr = mthd.addr
+    if i is not None:
+        addr = addr.replace('(i)', str(i)).replace('(j)', str(i))
+    assert re.fullmatch(r'[0-9a-fA-Fx()+*]+', addr)
+    return eval(addr)
+
+## Returns the base address and stride of an array method.  The elements
+## have to be evenly spaced so they can be decoded as a range.
+def mthd_array_layout(mthd):
+    base = mthd_addr(mthd, 0)
+    stride = mthd_addr(mthd, 1) - base if mthd.array_size > 1 else 4
+    for i in range(mthd.array_size):
+        assert mthd_addr(mthd, i) == base + i * stride
+    return base, stride
+
+## Returns a Rust match pattern for every element of an array method.
+## Arrays which are interleaved with other methods have a stride larger than
+## a dword, so the guard keeps them from matching each other.
+def mthd_pattern(mthd):
+    base, stride = mthd_array_layout(mthd)
+    last = base + (mthd.array_size - 1) * stride
+    pattern = f"{hex(base)}..={hex(last)}"
+    if stride != 4:
+        pattern += f" if (addr - {hex(base)}) % {hex(stride)} == 0"
+    return pattern
+
+## Returns a Rust expression for the array index of the element at addr.
+def mthd_index(mthd):
+    base, stride = mthd_array_layout(mthd)
+    return f"usize::from((addr - {hex(base)}) / {hex(stride)})"
+
 def glob_match(glob, name):
     if glob.endswith('*'):
         return name.startswith(glob[:-1])
@@ -567,6 +655,9 @@ def main():
         'mthddict': mthddict,
         'rs_field_name': rs_field_name,
         'to_camel': to_camel,
+        'mthd_addr': mthd_addr,
+        'mthd_pattern': mthd_pattern,
+        'mthd_index': mthd_index,
         'bs': '\\'
     }
 
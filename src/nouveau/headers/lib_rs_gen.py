@@ -69,6 +69,56 @@ pub trait ArrayMthd {
     fn to_bits(self) -> u32;
 }
 
+/// A method looked up by address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MthdInfo {
+    /// The name of the method, including the class prefix.
+    pub name: &'static str,
+    /// The array index, if this is an array method.
+    pub index: Option<usize>,
+}
+
+/// A decoded field of a method.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MthdFieldInfo {
+    /// The name of the field.
+    pub name: &'static str,
+    /// The raw value of the field.
+    pub value: u32,
+    /// The name of the enumerant matching the value, if any.
+    pub enumerant: <%text>Option<&'static str></%text>,
+    /// Whether the value is an IEEE 754 single.
+    pub is_float: bool,
+}
+
+impl MthdFieldInfo {
+    /// Extracts the given bits of a method's data.
+    pub fn new(
+        name: &'static str,
+        bits: std::ops::Range<u32>,
+        data: u32,
+        enumerants: &[(&'static str, u32)],
+        is_float: bool,
+    ) -> Self {
+        let width = bits.end - bits.start;
+        let value = if width == 32 {
+            data
+        } else {
+            (data >> bits.start) & ((1 << width) - 1)
+        };
+        let enumerant = enumerants
+            .iter()
+            .find(|(_, v)| *v == value)
+            .map(|(name, _)| *name);
+        Self {
+            name,
+            value,
+            enumerant,
+            is_float,
+        }
+    }
+}
+
 ${decl_mod(root, [])}
 """)
 
@@ -0,0 +1,511 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Push buffer decoding and pretty-printing
+//!
+//! This is the Rust equivalent of vk_push_print().  Method names and fields
+//! come from the same class headers which generate the `Mthd` and
+//! `ArrayMthd` types.
+
+use crate::class_to_subc;
+use crate::MthdHeader;
+use crate::MthdType;
+
+use nvidia_headers::classes::*;
+use nvidia_headers::MthdFieldInfo;
+use nvidia_headers::MthdInfo;
+use std::fmt;
+
+/// A single method decoded from a push buffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodedMthd {
+    pub subc: u8,
+    pub addr: u16,
+    pub data: u32,
+}
+
+/// A method header and the methods it encodes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodedHeader<'a> {
+    /// The offset of the header in dwords
+    pub offset: usize,
+    /// The raw header
+    pub header: u32,
+    pub mthd_type: MthdType,
+    pub subc: u8,
+    /// The address of the first method
+    pub addr: u16,
+    /// The data following the header.  Empty for immediates.
+    data: &'a [u32],
+}
+
+impl<'a> DecodedHeader<'a> {
+    /// Returns the number of methods encoded by this header
+    pub fn num_mthds(&self) -> usize {
+        match self.mthd_type {
+            MthdType::Immd => 1,
+            _ => self.data.len(),
+        }
+    }
+
+    /// Returns the i-th method encoded by this header
+    pub fn mthd(&self, i: usize) -> DecodedMthd {
+        assert!(i < self.num_mthds());
+        let (inc, data) = match self.mthd_type {
+            MthdType::NInc => (i, self.data[i]),
+            MthdType::OneInc => (i.min(1), self.data[i]),
+            MthdType::ZeroInc => (0, self.data[i]),
+            MthdType::Immd => (0, self.header >> 16 & 0x1fff),
+        };
+        DecodedMthd {
+            subc: self.subc,
+            addr: self.addr + u16::try_from(inc * 4).unwrap(),
+            data,
+        }
+    }
+
+    /// Returns an iterator over the methods encoded by this header
+    pub fn mthds(self) -> impl Iterator<Item = DecodedMthd> + 'a {
+        (0..self.num_mthds()).map(move |i| self.mthd(i))
+    }
+}
+
+/// An error encountered while decoding a push buffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The header has a method type we don't know how to decode
+    InvalidHeader { offset: usize, header: u32 },
+    /// The header claims more data than is left in the push buffer
+    Truncated { offset: usize, header: u32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidHeader { offset, header } => {
+                write!(f, "invalid header {header:08x} at 0x{offset:08x}")
+            }
+            DecodeError::Truncated { offset, header } => {
+                write!(f, "truncated header {header:08x} at 0x{offset:08x}")
+            }
+        }
+    }
+}
+
+/// Walks the method headers in a push buffer
+///
+/// Decoding stops after the first error.
+pub struct Decoder<'a> {
+    mem: &'a [u32],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(mem: &'a [u32]) -> Self {
+        Self { mem, offset: 0 }
+    }
+
+    /// Returns an iterator over every method in the push buffer
+    pub fn mthds(
+        self,
+    ) -> impl Iterator<Item = Result<DecodedMthd, DecodeError>> + 'a {
+        self.flat_map(|hdr| {
+            let (hdr, err) = match hdr {
+                Ok(hdr) => (Some(hdr), None),
+                Err(err) => (None, Some(Err(err))),
+            };
+            hdr.into_iter().flat_map(|h| h.mthds().map(Ok)).chain(err)
+        })
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<DecodedHeader<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let header = *self.mem.get(offset)?;
+
+        let Ok(mthd_type) = MthdType::try_from((header >> 29) as u8) else {
+            self.offset = self.mem.len();
+            return Some(Err(DecodeError::InvalidHeader { offset, header }));
+        };
+
+        let hdr = MthdHeader(header);
+        let len = match mthd_type {
+            MthdType::Immd => 0,
+            _ => usize::from(hdr.len()),
+        };
+        let Some(data) = self.mem.get(offset + 1..offset + 1 + len) else {
+            self.offset = self.mem.len();
+            return Some(Err(DecodeError::Truncated { offset, header }));
+        };
+
+        self.offset = offset + 1 + len;
+        Some(Ok(DecodedHeader {
+            offset,
+            header,
+            mthd_type,
+            subc: hdr.subc(),
+            addr: hdr.addr(),
+            data,
+        }))
+    }
+}
+
+type DecodeMthdFn = fn(u16) -> Option<MthdInfo>;
+type DecodeMthdDataFn = fn(u16, u32) -> Vec<MthdFieldInfo>;
+
+struct ClassDecoder {
+    class: u16,
+    decode_mthd: DecodeMthdFn,
+    decode_mthd_data: DecodeMthdDataFn,
+}
+
+macro_rules! class_decoders {
+    ($($class:literal => $cl:ident,)*) => {
+        &[$(ClassDecoder {
+            class: $class,
+            decode_mthd: $cl::mthd::decode_mthd,
+            decode_mthd_data: $cl::mthd::decode_mthd_data,
+        },)*]
+    };
+}
+
+/// Every class we have headers for, except the host class
+static CLASS_DECODERS: &[ClassDecoder] = class_decoders! {
+    0x902d => cl902d,
+    0x9039 => cl9039,
+    0x9097 => cl9097,
+    0x90b5 => cl90b5,
+    0x90c0 => cl90c0,
+    0xa040 => cla040,
+    0xa097 => cla097,
+    0xa0b5 => cla0b5,
+    0xa0c0 => cla0c0,
+    0xa140 => cla140,
+    0xa297 => cla297,
+    0xb097 => clb097,
+    0xb0c0 => clb0c0,
+    0xb197 => clb197,
+    0xb1c0 => clb1c0,
+    0xc097 => clc097,
+    0xc0c0 => clc0c0,
+    0xc197 => clc197,
+    0xc1b5 => clc1b5,
+    0xc397 => clc397,
+    0xc3c0 => clc3c0,
+    0xc597 => clc597,
+    0xc5c0 => clc5c0,
+    0xc697 => clc697,
+    0xc6c0 => clc6c0,
+    0xc797 => clc797,
+    0xc7c0 => clc7c0,
+};
+
+/// Methods below this address are handled by the host class
+const HOST_MTHD_END: u16 = 0x100;
+
+static HOST_DECODER: ClassDecoder = ClassDecoder {
+    class: 0x906f,
+    decode_mthd: cl906f::mthd::decode_mthd,
+    decode_mthd_data: cl906f::mthd::decode_mthd_data,
+};
+
+/// Finds the decoder for a class
+///
+/// Like vk_push_print(), this falls back to the newest class of the same
+/// engine that's no newer than the requested one.
+fn class_decoder(class: u16) -> Option<&'static ClassDecoder> {
+    CLASS_DECODERS
+        .iter()
+        .filter(|d| d.class & 0xff == class & 0xff && d.class <= class)
+        .max_by_key(|d| d.class)
+}
+
+/// Pretty-prints a push buffer
+///
+/// The output matches vk_push_print() so that dumps from the C and Rust
+/// push builders can be diffed.  Sub-channels start out bound to the given
+/// classes in the same way `Push` assigns them and are rebound whenever a
+/// SET_OBJECT is encountered.
+pub struct PushDump<'a> {
+    mem: &'a [u32],
+    subc_classes: [Option<u16>; 8],
+}
+
+impl<'a> PushDump<'a> {
+    pub fn new(mem: &'a [u32], classes: &[u16]) -> Self {
+        let mut subc_classes = [None; 8];
+        for &class in classes {
+            subc_classes[usize::from(class_to_subc(class))] = Some(class);
+        }
+        Self { mem, subc_classes }
+    }
+}
+
+fn fmt_mthd(
+    f: &mut fmt::Formatter<'_>,
+    mthd: &DecodedMthd,
+    class: Option<u16>,
+) -> fmt::Result {
+    let decoder = if mthd.addr < HOST_MTHD_END {
+        Some(&HOST_DECODER)
+    } else {
+        class.and_then(class_decoder)
+    };
+
+    let info = decoder.and_then(|d| (d.decode_mthd)(mthd.addr));
+    match info {
+        Some(MthdInfo {
+            name,
+            index: Some(i),
+        }) => writeln!(f, "\tmthd {:04x} {name}({i})", mthd.addr)?,
+        Some(MthdInfo { name, index: None }) => {
+            writeln!(f, "\tmthd {:04x} {name}", mthd.addr)?
+        }
+        None => writeln!(f, "\tmthd {:04x} unknown method", mthd.addr)?,
+    }
+
+    let fields = match decoder {
+        Some(d) => (d.decode_mthd_data)(mthd.addr, mthd.data),
+        None => Vec::new(),
+    };
+    if fields.is_empty() {
+        return writeln!(f, "\t\t.VALUE = 0x{:x}", mthd.data);
+    }
+
+    for field in fields {
+        write!(f, "\t\t.{} = ", field.name)?;
+        if let Some(e) = field.enumerant {
+            writeln!(f, "{e}")?;
+        } else if field.is_float {
+            let v = f32::from_bits(field.value);
+            writeln!(f, "{v:.6}f (0x{:x})", field.value)?;
+        } else {
+            writeln!(f, "(0x{:x})", field.value)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for PushDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut subc_classes = self.subc_classes;
+        for hdr in Decoder::new(self.mem) {
+            let hdr = match hdr {
+                Ok(hdr) => hdr,
+                Err(err) => return writeln!(f, "{err}"),
+            };
+
+            let mthd_type = match hdr.mthd_type {
+                MthdType::NInc => "NINC",
+                MthdType::ZeroInc => "0INC",
+                MthdType::OneInc => "1INC",
+                MthdType::Immd => "IMMD",
+            };
+            writeln!(
+                f,
+                "[0x{:08x}] HDR {:x} subch {} {mthd_type}",
+                hdr.offset, hdr.header, hdr.subc
+            )?;
+
+            for mthd in hdr.mthds() {
+                let subc_class = &mut subc_classes[usize::from(mthd.subc)];
+                if mthd.addr == 0 {
+                    // SET_OBJECT binds a class to the sub-channel
+                    *subc_class = Some(mthd.data as u16);
+                }
+                fmt_mthd(f, &mthd, *subc_class)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Push;
+    use nvidia_headers::classes::cl9097::mthd as cl9097;
+    use nvidia_headers::classes::cla0c0::mthd as cla0c0;
+    use nvidia_headers::ArrayMthd;
+    use nvidia_headers::Mthd;
+
+    fn mthd<M: Mthd>(m: M) -> DecodedMthd {
+        DecodedMthd {
+            subc: class_to_subc(M::CLASS),
+            addr: M::ADDR,
+            data: m.to_bits(),
+        }
+    }
+
+    fn array_mthd<M: ArrayMthd>(i: usize, m: M) -> DecodedMthd {
+        DecodedMthd {
+            subc: class_to_subc(M::CLASS),
+            addr: M::addr(i),
+            data: m.to_bits(),
+        }
+    }
+
+    fn decode(push: &Push) -> Vec<DecodedMthd> {
+        Decoder::new(push).mthds().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut p = Push::new();
+        let mut expected = Vec::new();
+
+        // A long NInc run
+        for i in 0..4 {
+            let v = cl9097::SetColorClearValue {
+                v: 0x10000 + i as u32,
+            };
+            p.push_array_method(i, v);
+            expected.push(array_mthd(i, v));
+        }
+
+        // ZeroInc
+        for i in 0..3 {
+            let v = cl9097::NoOperation { v: 0xdead0000 | i };
+            p.push_method(v);
+            expected.push(mthd(v));
+        }
+
+        // OneInc
+        for i in [0, 1, 1, 1] {
+            let v = cl9097::SetColorClearValue {
+                v: 0xbeef0000 | i as u32,
+            };
+            p.push_array_method(i, v);
+            expected.push(array_mthd(i, v));
+        }
+
+        // Immediates on two sub-channels
+        let v = cl9097::SetDepthTest { enable: true };
+        p.push_method(v);
+        expected.push(mthd(v));
+        let v = cla0c0::SetShaderLocalMemoryA {
+            address_upper: 0x42,
+        };
+        p.push_method(v);
+        expected.push(mthd(v));
+
+        // An NInc on another sub-channel with a large value
+        let v = cla0c0::SetShaderLocalMemoryB {
+            address_lower: 0x80000000,
+        };
+        p.push_method(v);
+        expected.push(mthd(v));
+
+        let types: Vec<_> =
+            Decoder::new(&p).map(|h| h.unwrap().mthd_type).collect();
+        assert_eq!(
+            types,
+            [
+                MthdType::NInc,
+                MthdType::ZeroInc,
+                MthdType::OneInc,
+                MthdType::Immd,
+                MthdType::Immd,
+                MthdType::NInc,
+            ]
+        );
+        assert_eq!(decode(&p), expected);
+    }
+
+    #[test]
+    fn test_mthd_types() {
+        // NV906F_DMA_SEC_OP_NON_INC_METHOD
+        let mut p = Push::new();
+        let mut expected = Vec::new();
+        for i in 0..3 {
+            let v = cl9097::NoOperation { v: 0x10000 + i };
+            p.push_method(v);
+            expected.push(mthd(v));
+        }
+        assert!(p.len() == 4 && p[0] >> 29 == 3);
+        assert!(MthdType::try_from(3) == Ok(MthdType::ZeroInc));
+        assert_eq!(decode(&p), expected);
+
+        // NV906F_DMA_SEC_OP_ONE_INC
+        let mut p = Push::new();
+        let mut expected = Vec::new();
+        for (i, v) in [(0, 0x10000), (1, 0x10001), (1, 0x10002)] {
+            let v = cl9097::SetColorClearValue { v };
+            p.push_array_method(i, v);
+            expected.push(array_mthd(i, v));
+        }
+        assert!(p.len() == 4 && p[0] >> 29 == 5);
+        assert!(MthdType::try_from(5) == Ok(MthdType::OneInc));
+        assert_eq!(decode(&p), expected);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut p = Push::new();
+        p.push_method(cl9097::NoOperation { v: 0xdeadbeef });
+        let mut mem = p.to_vec();
+
+        mem.pop();
+        let err = DecodeError::Truncated {
+            offset: 0,
+            header: mem[0],
+        };
+        assert_eq!(Decoder::new(&mem).next(), Some(Err(err)));
+
+        mem[0] = 0xe0000000;
+        let err = DecodeError::InvalidHeader {
+            offset: 0,
+            header: 0xe0000000,
+        };
+        assert_eq!(Decoder::new(&mem).collect::<Vec<_>>(), [Err(err)]);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut p = Push::new();
+        p.push_method(cla0c0::SetObject {
+            class_id: 0xc3c0,
+            engine_id: 0,
+        });
+        p.push_method(cla0c0::SetShaderLocalMemoryA {
+            address_upper: 0x42,
+        });
+        p.push_method(cl9097::SetDepthTest { enable: true });
+        p.push_array_method(1, cl9097::SetColorClearValue { v: 0x12345678 });
+        p.push_array_method(2, cl9097::SetColorClearValue { v: 0x9abcdef0 });
+        // SET_DEPTH_BIAS
+        p.push_mthd_bits(0, 0x15bc, 1.5_f32.to_bits(), true);
+
+        let dump = PushDump::new(&p, &[0x9097]).to_string();
+        assert_eq!(
+            dump,
+            "[0x00000000] HDR 20012000 subch 1 NINC\n\
+             \tmthd 0000 NV906F_SET_OBJECT\n\
+             \t\t.NVCLASS = (0xc3c0)\n\
+             \t\t.ENGINE = (0x0)\n\
+             \n\
+             [0x00000002] HDR 804221e4 subch 1 IMMD\n\
+             \tmthd 0790 NVC3C0_SET_SHADER_LOCAL_MEMORY_A\n\
+             \t\t.ADDRESS_UPPER = (0x42)\n\
+             \n\
+             [0x00000003] HDR 800104b3 subch 0 IMMD\n\
+             \tmthd 12cc NV9097_SET_DEPTH_TEST\n\
+             \t\t.ENABLE = TRUE\n\
+             \n\
+             [0x00000004] HDR 20020361 subch 0 NINC\n\
+             \tmthd 0d84 NV9097_SET_COLOR_CLEAR_VALUE(1)\n\
+             \t\t.V = (0x12345678)\n\
+             \tmthd 0d88 NV9097_SET_COLOR_CLEAR_VALUE(2)\n\
+             \t\t.V = (0x9abcdef0)\n\
+             \n\
+             [0x00000007] HDR 2001056f subch 0 NINC\n\
+             \tmthd 15bc NV9097_SET_DEPTH_BIAS\n\
+             \t\t.V = 1.500000f (0x3fc00000)\n\
+             \n"
+        );
+    }
+}
@@ -4,6 +4,14 @@
 use nvidia_headers::ArrayMthd;
 use nvidia_headers::Mthd;
 
+mod decode;
+
+pub use decode::DecodeError;
+pub use decode::DecodedHeader;
+pub use decode::DecodedMthd;
+pub use decode::Decoder;
+pub use decode::PushDump;
+
 pub const MAX_MTHD_LEN: u16 = 0x1fff;
 pub const MAX_MTHD_ADDR: u16 = 0x7fff;
 
@@ -19,15 +27,16 @@ fn class_to_subc(class: u16) -> u8 {
 }
 
 #[repr(u8)]
-enum MthdType {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MthdType {
     /// Each dword increments the address by one
     NInc = 1,
-    /// The first dword increments the address by one
-    OneInc = 3,
+    /// The address is not incremented
+    ZeroInc = 3,
     /// Instead of a length, stores 13 bits of immediate data
     Immd = 4,
-    /// The address is not incremented
-    ZeroInc = 5,
+    /// The first dword increments the address by one
+    OneInc = 5,
 }
 
 impl TryFrom<u8> for MthdType {
@@ -36,9 +45,9 @@ impl TryFrom<u8> for MthdType {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(MthdType::NInc),
-            3 => Ok(MthdType::OneInc),
+            3 => Ok(MthdType::ZeroInc),
             4 => Ok(MthdType::Immd),
-            5 => Ok(MthdType::ZeroInc),
+            5 => Ok(MthdType::OneInc),
             _ => Err("Invalid method type"),
         }
     }
@@ -114,23 +123,206 @@ impl MthdHeader {
     }
 }
 
+/// The maximum number of dwords a single GPFIFO entry can point to
+pub const MAX_SEGMENT_LEN: usize = 0x1fffff;
+
+/// An entry in the GPFIFO recorded by a `Push`
+///
+/// Each entry maps to a single GPFIFO entry when the push buffer is
+/// submitted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PushEntry {
+    /// A range of dwords in this push buffer
+    Segment { start: usize, len: usize },
+    /// Fetches len dwords from another push buffer at the MAIN level
+    ///
+    /// This is not a jump.  Once the other push buffer is done, the GPU
+    /// carries on with the next entry.
+    Fetch { addr: u64, len: usize },
+    /// Calls another push buffer of len dwords as a subroutine
+    Call { addr: u64, len: usize },
+}
+
+impl PushEntry {
+    /// Encodes the entry as a GPFIFO entry
+    ///
+    /// `push_addr` is the GPU address this push buffer is uploaded to and is
+    /// only used for segments.
+    pub fn to_gp_entry(&self, push_addr: u64) -> [u32; 2] {
+        let (addr, len, subroutine) = match *self {
+            PushEntry::Segment { start, len } => {
+                (push_addr + u64::try_from(start * 4).unwrap(), len, false)
+            }
+            PushEntry::Fetch { addr, len } => (addr, len, false),
+            PushEntry::Call { addr, len } => (addr, len, true),
+        };
+        assert!(addr & 0x3 == 0 && addr < (1 << 40));
+        assert!(len > 0 && len <= MAX_SEGMENT_LEN);
+
+        // NV906F_GP_ENTRY0_GET and NV906F_GP_ENTRY1_GET_HI, LEVEL and LENGTH
+        let len = u32::try_from(len).unwrap();
+        [
+            addr as u32,
+            (addr >> 32) as u32 | u32::from(subroutine) << 9 | len << 10,
+        ]
+    }
+}
+
+/// Space in a push buffer reserved for a method to be patched later
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Reservation {
+    /// The offset of the reserved data dword
+    offset: usize,
+    /// The method address the reservation was made for
+    addr: u16,
+}
+
 pub struct Push {
     /// The internal memory. Has to be uploaded to a BO through flush().
     mem: Vec<u32>,
     /// Last DW that is an incrementing type or usize::MAX
     last_inc: usize,
+    /// The class bound to each sub-channel with SET_OBJECT
+    subc_classes: [Option<u16>; 8],
+    /// The maximum number of dwords in a segment
+    max_segment_len: usize,
+    /// The first DW of the current segment
+    segment_start: usize,
+    /// The entries recorded so far, not including the current segment
+    entries: Vec<PushEntry>,
 }
 
 impl Push {
     /// Instantiates a new push buffer.
     pub fn new() -> Self {
+        Self::with_max_segment_len(MAX_SEGMENT_LEN)
+    }
+
+    /// Instantiates a new push buffer which is split into segments of at
+    /// most `max_segment_len` dwords.
+    ///
+    /// Method headers never straddle segments so each segment can be
+    /// submitted on its own.
+    pub fn with_max_segment_len(max_segment_len: usize) -> Self {
+        // A segment has to fit at least a header and one dword of data
+        assert!((2..=MAX_SEGMENT_LEN).contains(&max_segment_len));
         Self {
             mem: Vec::new(),
             last_inc: usize::MAX,
+            subc_classes: [None; 8],
+            max_segment_len,
+            segment_start: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn segment_len(&self) -> usize {
+        self.mem.len() - self.segment_start
+    }
+
+    /// Starts a new segment unless there is room for `count` more dwords in
+    /// the current one.
+    fn make_room(&mut self, count: usize) {
+        assert!(count <= self.max_segment_len);
+        if self.segment_len() + count > self.max_segment_len {
+            self.end_segment();
         }
     }
 
-    fn push_mthd_bits(&mut self, subc: u8, addr: u16, bits: u32) {
+    /// Ends the current segment.
+    ///
+    /// Everything pushed from now on goes in a new segment.  This does
+    /// nothing if the current segment is empty.
+    pub fn end_segment(&mut self) {
+        let len = self.segment_len();
+        if len > 0 {
+            self.entries.push(PushEntry::Segment {
+                start: self.segment_start,
+                len,
+            });
+        }
+        self.segment_start = self.mem.len();
+        // A segment may be submitted on its own so we can't extend a header
+        // from a previous one.
+        self.last_inc = usize::MAX;
+    }
+
+    /// Records a fetch of another push buffer of `len` dwords.
+    ///
+    /// The push buffer at `addr` is assumed not to change any sub-channel
+    /// bindings.
+    pub fn push_fetch(&mut self, addr: u64, len: usize) {
+        self.end_segment();
+        self.entries.push(PushEntry::Fetch { addr, len });
+    }
+
+    /// Records a subroutine call to another push buffer of `len` dwords.
+    ///
+    /// The push buffer at `addr` is assumed not to change any sub-channel
+    /// bindings.
+    pub fn push_call(&mut self, addr: u64, len: usize) {
+        self.end_segment();
+        self.entries.push(PushEntry::Call { addr, len });
+    }
+
+    /// Returns the GPFIFO entries recorded so far, including the current
+    /// segment.
+    pub fn entries(&self) -> Vec<PushEntry> {
+        let mut entries = self.entries.clone();
+        let len = self.segment_len();
+        if len > 0 {
+            entries.push(PushEntry::Segment {
+                start: self.segment_start,
+                len,
+            });
+        }
+        entries
+    }
+
+    /// Binds a class to a sub-channel.
+    ///
+    /// This emits a SET_OBJECT and methods for the same engine are sent to
+    /// that sub-channel from then on.  Engines which were never bound use
+    /// the same sub-channels as the C push helpers.
+    pub fn bind_subc(&mut self, subc: u8, class: u16) {
+        assert!(subc <= 0x7);
+        // NV906F_SET_OBJECT_NVCLASS, ENGINE is always zero
+        self.push_mthd_bits(subc, 0, class.into(), true);
+    }
+
+    fn class_subc(&self, class: u16) -> u8 {
+        let subc = self
+            .subc_classes
+            .iter()
+            .position(|c| c.is_some_and(|c| c & 0xff == class & 0xff));
+        match subc {
+            Some(subc) => subc as u8,
+            None => class_to_subc(class),
+        }
+    }
+
+    fn push_mthd_bits(
+        &mut self,
+        subc: u8,
+        addr: u16,
+        bits: u32,
+        allow_immd: bool,
+    ) {
+        if addr == 0 {
+            // SET_OBJECT binds a class to the sub-channel.  Only the most
+            // recent binding of each engine is used for new methods.
+            let class = bits as u16;
+            for c in &mut self.subc_classes {
+                if c.is_some_and(|c| c & 0xff == class & 0xff) {
+                    *c = None;
+                }
+            }
+            self.subc_classes[usize::from(subc)] = Some(class);
+        }
+
+        // Even extending the last header takes a dword
+        self.make_room(1);
+
         let current_len = self.mem.len();
         if let Some(last) = self.mem.get_mut(self.last_inc) {
             let last = MthdHeader::from_bits_mut(last);
@@ -138,7 +330,7 @@ impl Push {
             debug_assert!(
                 self.last_inc + usize::from(last.len()) + 1 == current_len
             );
-            if subc == last.subc() {
+            if subc == last.subc() && last.len() < MAX_MTHD_LEN {
                 match last.mthd_type() {
                     MthdType::NInc => {
                         if addr == last.addr() + last.len() * 4 {
@@ -180,11 +372,12 @@ impl Push {
         //
         // Methods that use 13bits or lower can be encoded as immediates
         // directly.
-        if bits <= 0x1fff {
+        if allow_immd && bits <= 0x1fff {
             self.last_inc = usize::MAX;
             let header = MthdHeader::new_immd(bits as u16, subc, addr);
             self.mem.push(header.to_bits());
         } else {
+            self.make_room(2);
             self.last_inc = self.mem.len();
             let header = MthdHeader::new(MthdType::NInc, subc, addr, 1);
             self.mem.push(header.to_bits());
@@ -193,23 +386,65 @@ impl Push {
     }
 
     pub fn push_method<M: Mthd>(&mut self, mthd: M) {
-        self.push_mthd_bits(class_to_subc(M::CLASS), M::ADDR, mthd.to_bits());
+        let subc = self.class_subc(M::CLASS);
+        self.push_mthd_bits(subc, M::ADDR, mthd.to_bits(), true);
     }
 
     pub fn push_array_method<M: ArrayMthd>(&mut self, i: usize, mthd: M) {
-        self.push_mthd_bits(
-            class_to_subc(M::CLASS),
-            M::addr(i),
-            mthd.to_bits(),
-        );
+        let subc = self.class_subc(M::CLASS);
+        self.push_mthd_bits(subc, M::addr(i), mthd.to_bits(), true);
+    }
+
+    fn reserve_mthd(&mut self, subc: u8, addr: u16) -> Reservation {
+        self.push_mthd_bits(subc, addr, 0, false);
+        Reservation {
+            offset: self.mem.len() - 1,
+            addr,
+        }
+    }
+
+    /// Reserves space for a method whose value is only known later, such as
+    /// a count.  The method has to be filled in with `patch_method()`.
+    pub fn reserve_method<M: Mthd>(&mut self) -> Reservation {
+        self.reserve_mthd(self.class_subc(M::CLASS), M::ADDR)
+    }
+
+    /// Reserves space for an array method whose value is only known later.
+    /// The method has to be filled in with `patch_array_method()`.
+    pub fn reserve_array_method<M: ArrayMthd>(
+        &mut self,
+        i: usize,
+    ) -> Reservation {
+        self.reserve_mthd(self.class_subc(M::CLASS), M::addr(i))
+    }
+
+    pub fn patch_method<M: Mthd>(&mut self, r: Reservation, mthd: M) {
+        assert!(r.addr == M::ADDR, "Reservation is for another method");
+        self.mem[r.offset] = mthd.to_bits();
+    }
+
+    pub fn patch_array_method<M: ArrayMthd>(
+        &mut self,
+        r: Reservation,
+        i: usize,
+        mthd: M,
+    ) {
+        assert!(r.addr == M::addr(i), "Reservation is for another method");
+        self.mem[r.offset] = mthd.to_bits();
     }
 
     /// Push an array of dwords into the push buffer
+    ///
+    /// Inline data always goes in the same segment as the method header
+    /// before it, even if that makes the segment longer than the maximum
+    /// segment length.  Callers pushing a lot of data should call
+    /// `end_segment()` before the header.
     pub fn push_inline_data(&mut self, data: &[u32]) {
         if self.last_inc != usize::MAX {
             panic!("Inline data must only be placed after a method header");
         }
         self.mem.extend_from_slice(data);
+        assert!(self.segment_len() <= MAX_SEGMENT_LEN);
     }
 }
 
@@ -220,3 +455,185 @@ impl std::ops::Deref for Push {
         &self.mem
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nvidia_headers::classes::cl9097::mthd as cl9097;
+    use nvidia_headers::classes::cla0c0::mthd as cla0c0;
+
+    fn decode(mem: &[u32]) -> Vec<DecodedMthd> {
+        Decoder::new(mem).mthds().map(Result::unwrap).collect()
+    }
+
+    fn mthd(subc: u8, addr: u16, data: u32) -> DecodedMthd {
+        DecodedMthd { subc, addr, data }
+    }
+
+    #[test]
+    fn test_bind_subc() {
+        let mut p = Push::new();
+        p.bind_subc(5, 0xc3c0);
+        p.push_method(cla0c0::SetShaderLocalMemoryA { address_upper: 1 });
+        p.push_method(cl9097::SetDepthTest { enable: true });
+        p.bind_subc(6, 0xc5c0);
+        p.push_method(cla0c0::SetShaderLocalMemoryA { address_upper: 2 });
+        p.push_method(cla0c0::SetObject {
+            class_id: 0xc6c0,
+            engine_id: 0,
+        });
+        p.push_method(cla0c0::SetShaderLocalMemoryA { address_upper: 3 });
+
+        assert_eq!(
+            decode(&p),
+            [
+                mthd(5, 0x0000, 0xc3c0),
+                mthd(5, 0x0790, 1),
+                mthd(0, 0x12cc, 1),
+                mthd(6, 0x0000, 0xc5c0),
+                mthd(6, 0x0790, 2),
+                mthd(6, 0x0000, 0xc6c0),
+                mthd(6, 0x0790, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_mthd_len() {
+        let mut p = Push::new();
+        let count = usize::from(MAX_MTHD_LEN) + 2;
+        for i in 0..count {
+            p.push_method(cl9097::NoOperation {
+                v: 0x10000 + i as u32,
+            });
+        }
+
+        let lens: Vec<_> =
+            Decoder::new(&p).map(|h| h.unwrap().num_mthds()).collect();
+        assert_eq!(lens, [usize::from(MAX_MTHD_LEN), 2]);
+
+        let expected: Vec<_> = (0..count)
+            .map(|i| mthd(0, 0x0100, 0x10000 + i as u32))
+            .collect();
+        assert_eq!(decode(&p), expected);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut p = Push::with_max_segment_len(5);
+        let mut expected = Vec::new();
+        for i in 0..4 {
+            let v = 0x10000 + i as u32;
+            p.push_array_method(i, cl9097::SetColorClearValue { v });
+            expected.push(mthd(0, 0x0d80 + i as u16 * 4, v));
+        }
+        for i in 0..3 {
+            p.push_method(cl9097::SetDepthTest { enable: i % 2 == 0 });
+            expected.push(mthd(0, 0x12cc, (i + 1) % 2));
+        }
+        for i in 0..6 {
+            p.push_method(cl9097::NoOperation { v: 0x20000 + i });
+            expected.push(mthd(0, 0x0100, 0x20000 + i));
+        }
+
+        let entries = p.entries();
+        let mut end = 0;
+        let mut mthds = Vec::new();
+        for e in entries {
+            let PushEntry::Segment { start, len } = e else {
+                panic!("Unexpected entry");
+            };
+            assert!(start == end && len <= 5);
+            end = start + len;
+            // Each segment must decode on its own
+            mthds.extend(decode(&p[start..end]));
+        }
+        assert_eq!(end, p.len());
+        assert_eq!(mthds, expected);
+    }
+
+    #[test]
+    fn test_fetch_call() {
+        let mut p = Push::new();
+        p.push_method(cl9097::NoOperation { v: 0x10000 });
+        p.push_call(0x1_0000_1000, 16);
+        p.push_method(cl9097::NoOperation { v: 0x10001 });
+        p.push_method(cl9097::NoOperation { v: 0x10002 });
+        p.push_fetch(0x2000, 4);
+        p.end_segment();
+
+        let entries = p.entries();
+        assert_eq!(
+            entries,
+            [
+                PushEntry::Segment { start: 0, len: 2 },
+                PushEntry::Call {
+                    addr: 0x1_0000_1000,
+                    len: 16,
+                },
+                PushEntry::Segment { start: 2, len: 3 },
+                PushEntry::Fetch {
+                    addr: 0x2000,
+                    len: 4,
+                },
+            ]
+        );
+
+        // Headers don't continue across entries
+        assert_eq!(Decoder::new(&p).count(), 2);
+
+        let gp_entries: Vec<_> = entries
+            .iter()
+            .map(|e| e.to_gp_entry(0x40_0000_0100))
+            .collect();
+        assert_eq!(
+            gp_entries,
+            [
+                [0x0000_0100, 0x40 | (2 << 10)],
+                [0x0000_1000, 0x01 | (1 << 9) | (16 << 10)],
+                [0x0000_0108, 0x40 | (3 << 10)],
+                [0x0000_2000, 4 << 10],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_data_segment() {
+        let mut p = Push::with_max_segment_len(4);
+        for i in 0..3 {
+            p.push_method(cl9097::NoOperation { v: 0x10000 + i });
+        }
+        p.push_method(cl9097::SetDepthTest { enable: true });
+        let data = [0x20000, 0x20001, 0x20002, 0x20003, 0x20004];
+        p.push_inline_data(&data);
+
+        // The data stays with the SetDepthTest header
+        assert_eq!(
+            p.entries(),
+            [
+                PushEntry::Segment { start: 0, len: 4 },
+                PushEntry::Segment { start: 4, len: 6 },
+            ]
+        );
+        assert_eq!(&p[5..], &data);
+    }
+
+    #[test]
+    fn test_reserve_patch() {
+        let mut p = Push::new();
+        let r = p.reserve_method::<cl9097::SetDepthTest>();
+        let ra = p.reserve_array_method::<cl9097::SetColorClearValue>(2);
+        p.push_method(cl9097::NoOperation { v: 0x10000 });
+        p.patch_method(r, cl9097::SetDepthTest { enable: true });
+        p.patch_array_method(ra, 2, cl9097::SetColorClearValue { v: 7 });
+
+        assert_eq!(
+            decode(&p),
+            [
+                mthd(0, 0x12cc, 1),
+                mthd(0, 0x0d88, 7),
+                mthd(0, 0x0100, 0x10000),
+            ]
+        );
+    }
+}
@@ -2,9 +2,11 @@
 // SPDX-License-Identifier: MIT
 
 use crate::extent::{units, Extent4D, Offset4D};
-use crate::tiling::Tiling;
+use crate::image::{Image, ImageDim, SampleLayout};
+use crate::tiling::{GOBType, Tiling};
 
 use std::ffi::c_void;
+use std::marker::PhantomData;
 use std::ops::Range;
 
 // This file is dedicated to the internal tiling layout, mainly in the context
@@ -47,8 +49,8 @@ use std::ops::Range;
 //    layers  >= ceiling(image_depth  / block_depth)
 //
 // Where block_width is a constant 64B (unless for sparse) and block_height
-// can be either 8 or 16 GOBs tall (more on GOBs below). For us, block_depth
-// is one for now.
+// can be either 8 or 16 GOBs tall (more on GOBs below). block_depth is
+// 1 << z_log2 GOBs, which is only ever more than one for 3D images.
 //
 // The >= is in case the blocks around the edges are partial.
 //
@@ -90,18 +92,14 @@ use std::ops::Range;
 //      --------------------------
 //      x5 y2 y1 x4 y0 x3 x2 x1 x0
 //
-// Which would look something like this:
-// fn get_pixel_offset(
-//      x: usize,
-//      y: usize,
-//  ) -> usize {
-//      (x & 15)       |
-//      (y & 1)  << 4  |
-//      (x & 16) << 1  |
-//      (y & 2)  << 5  |
-//      (x & 32) << 3
-//  }
+// That is the Tegra sector layout.  Desktop GPUs, which is what the copy code
+// below implements, swap the roles of x4 and y1:
 //
+//      b8 b7 b6 b5 b4 b3 b2 b1 b0
+//      --------------------------
+//      x5 y2 x4 y1 y0 x3 x2 x1 x0
+//
+// See GOBType::offset_B() for the code version of this.
 //
 
 // The way our implementation will work is by splitting an image into tiles, then
@@ -117,6 +115,330 @@ use std::ops::Range;
 // that tiles/gobs are whole and aligned, we can skip all bounds checking and
 // copy things in fast and tight loops
 
+impl GOBType {
+    /// Returns the offset in bytes within a GOB of the byte at @offset_B
+    pub fn offset_B(&self, offset_B: Offset4D<units::Bytes>) -> u32 {
+        debug_assert!(offset_B.z == 0 && offset_B.a == 0);
+        match self {
+            GOBType::Linear => {
+                debug_assert!(offset_B.x == 0 && offset_B.y == 0);
+                0
+            }
+            GOBType::Fermi8 => {
+                let (x, y) = (offset_B.x, offset_B.y);
+                debug_assert!(x < 64 && y < 8);
+                (x & 0xf)
+                    | ((y & 0x3) << 4)
+                    | ((x & 0x10) << 2)
+                    | ((y & 0x4) << 5)
+                    | ((x & 0x20) << 3)
+            }
+        }
+    }
+
+    /// The inverse of offset_B()
+    pub fn offset_to_B(&self, gob_offset_B: u32) -> Offset4D<units::Bytes> {
+        match self {
+            GOBType::Linear => {
+                debug_assert!(gob_offset_B == 0);
+                Offset4D::new(0, 0, 0, 0)
+            }
+            GOBType::Fermi8 => {
+                let o = gob_offset_B;
+                debug_assert!(o < 512);
+                let x = (o & 0xf) | ((o >> 2) & 0x10) | ((o >> 3) & 0x20);
+                let y = ((o >> 4) & 0x3) | ((o >> 5) & 0x4);
+                Offset4D::new(x, y, 0, 0)
+            }
+        }
+    }
+}
+
+impl Tiling {
+    /// Returns the offset in bytes from the start of a tiled level layer of
+    /// the byte at @offset_B
+    ///
+    /// Tiles are laid out in X, then Y, then Z order across the level and the
+    /// GOBs within a tile are laid out the same way.  @level_extent_B is the
+    /// extent of the level, which gets aligned to tiles.
+    pub fn offset_B(
+        &self,
+        level_extent_B: Extent4D<units::Bytes>,
+        offset_B: Offset4D<units::Bytes>,
+    ) -> u64 {
+        assert!(self.is_tiled());
+        debug_assert!(offset_B.a == 0);
+
+        let tl_ext_B = self.extent_B();
+        let gob_ext_B = self.gob_type.extent_B();
+        let lvl_ext_B = level_extent_B.align(&tl_ext_B);
+        assert!(offset_B.x < lvl_ext_B.width);
+        assert!(offset_B.y < lvl_ext_B.height);
+        assert!(offset_B.z < lvl_ext_B.depth);
+
+        let lvl_width_tl = u64::from(lvl_ext_B.width / tl_ext_B.width);
+        let lvl_height_tl = u64::from(lvl_ext_B.height / tl_ext_B.height);
+        let tl_x = u64::from(offset_B.x / tl_ext_B.width);
+        let tl_y = u64::from(offset_B.y / tl_ext_B.height);
+        let tl_z = u64::from(offset_B.z / tl_ext_B.depth);
+        let tl_idx = (tl_z * lvl_height_tl + tl_y) * lvl_width_tl + tl_x;
+
+        let tl_width_gob = 1_u32 << self.x_log2;
+        let tl_height_gob = 1_u32 << self.y_log2;
+        let x_B = offset_B.x % tl_ext_B.width;
+        let y_B = offset_B.y % tl_ext_B.height;
+        let z_B = offset_B.z % tl_ext_B.depth;
+        let gob_idx = ((z_B / gob_ext_B.depth) * tl_height_gob
+            + y_B / gob_ext_B.height)
+            * tl_width_gob
+            + x_B / gob_ext_B.width;
+
+        let gob_offset_B = self.gob_type.offset_B(Offset4D::new(
+            x_B % gob_ext_B.width,
+            y_B % gob_ext_B.height,
+            0,
+            0,
+        ));
+
+        tl_idx * u64::from(self.size_B())
+            + u64::from(gob_idx * gob_ext_B.size_B() as u32 + gob_offset_B)
+    }
+
+    /// The inverse of offset_B()
+    ///
+    /// The returned offset may be outside @level_extent_B if @lvl_offset_B
+    /// lands in the padding of a tile.
+    pub fn offset_to_B(
+        &self,
+        level_extent_B: Extent4D<units::Bytes>,
+        lvl_offset_B: u64,
+    ) -> Offset4D<units::Bytes> {
+        assert!(self.is_tiled());
+
+        let tl_ext_B = self.extent_B();
+        let gob_ext_B = self.gob_type.extent_B();
+        let lvl_ext_B = level_extent_B.align(&tl_ext_B);
+
+        let tl_size_B = u64::from(self.size_B());
+        let gob_size_B = gob_ext_B.size_B() as u32;
+
+        let lvl_width_tl = u64::from(lvl_ext_B.width / tl_ext_B.width);
+        let lvl_height_tl = u64::from(lvl_ext_B.height / tl_ext_B.height);
+        let tl_idx = lvl_offset_B / tl_size_B;
+        let tl_x = (tl_idx % lvl_width_tl) as u32;
+        let tl_y = ((tl_idx / lvl_width_tl) % lvl_height_tl) as u32;
+        let tl_z = (tl_idx / (lvl_width_tl * lvl_height_tl)) as u32;
+
+        let tl_offset_B = (lvl_offset_B % tl_size_B) as u32;
+        let tl_width_gob = 1_u32 << self.x_log2;
+        let tl_height_gob = 1_u32 << self.y_log2;
+        let gob_idx = tl_offset_B / gob_size_B;
+        let gob_x = gob_idx % tl_width_gob;
+        let gob_y = (gob_idx / tl_width_gob) % tl_height_gob;
+        let gob_z = gob_idx / (tl_width_gob * tl_height_gob);
+
+        let in_gob = self.gob_type.offset_to_B(tl_offset_B % gob_size_B);
+
+        Offset4D::new(
+            tl_x * tl_ext_B.width + gob_x * gob_ext_B.width + in_gob.x,
+            tl_y * tl_ext_B.height + gob_y * gob_ext_B.height + in_gob.y,
+            tl_z * tl_ext_B.depth + gob_z * gob_ext_B.depth,
+            0,
+        )
+    }
+}
+
+/// Identifies a single texel in an image
+///
+/// For block-compressed formats, this is the whole block containing the
+/// pixel and the inverse mapping returns the pixel at its top-left corner.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageTexel {
+    pub level: u32,
+    /// The pixel, with the array layer in @offset_px.a
+    pub offset_px: Offset4D<units::Pixels>,
+    pub sample: u32,
+}
+
+impl Image {
+    #[no_mangle]
+    pub extern "C" fn nil_image_texel_offset_B(
+        &self,
+        texel: ImageTexel,
+    ) -> u64 {
+        self.texel_offset_B(texel)
+    }
+
+    /// Returns the offset in bytes from the start of the image of the first
+    /// byte of the given texel
+    pub fn texel_offset_B(&self, texel: ImageTexel) -> u64 {
+        let ImageTexel {
+            level,
+            offset_px,
+            sample,
+        } = texel;
+
+        let lvl_ext_px = self.level_extent_px(level);
+        assert!(offset_px.x < lvl_ext_px.width);
+        assert!(offset_px.y < lvl_ext_px.height);
+        assert!(offset_px.z < lvl_ext_px.depth);
+        assert!(offset_px.a < lvl_ext_px.array_len);
+        assert!(sample < self.sample_layout.samples());
+
+        if self.sample_layout != SampleLayout::_1x1 {
+            // Each sample is a pixel of the image of samples
+            let px_ext_sa = self.sample_layout.px_extent_sa();
+            let sa_offset = self.sample_layout.sa_offset(sample as u8);
+            let offset_sa = Offset4D::new(
+                offset_px.x * px_ext_sa.width + u32::from(sa_offset.x),
+                offset_px.y * px_ext_sa.height + u32::from(sa_offset.y),
+                offset_px.z,
+                offset_px.a,
+            );
+            return self.msaa_as_samples().texel_offset_B(ImageTexel {
+                level,
+                offset_px: offset_sa,
+                sample: 0,
+            });
+        }
+
+        let offset_B = offset_px.to_B(self.format, self.sample_layout);
+        self.level_byte_offset_B(level, offset_B)
+    }
+
+    /// Returns the offset in bytes from the start of the image of the byte at
+    /// @offset_B in the given level, with the array layer in @offset_B.a
+    ///
+    /// For multisampled images, @offset_B is in samples.
+    fn level_byte_offset_B(
+        &self,
+        level: u32,
+        offset_B: Offset4D<units::Bytes>,
+    ) -> u64 {
+        let lvl = &self.levels[level as usize];
+        let layer_offset_B = self.level_layer_offset_B(level, offset_B.a);
+
+        if lvl.tiling.is_tiled() {
+            let mut lvl_ext_B = self.level_extent_B(level);
+            lvl_ext_B.array_len = 1;
+            let offset_B = Offset4D::new(offset_B.x, offset_B.y, offset_B.z, 0);
+            layer_offset_B + lvl.tiling.offset_B(lvl_ext_B, offset_B)
+        } else {
+            assert!(offset_B.z == 0);
+            layer_offset_B
+                + u64::from(offset_B.y) * u64::from(lvl.row_stride_B)
+                + u64::from(offset_B.x)
+        }
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_texel_at_offset_B(
+        &self,
+        offset_B: u64,
+        texel_out: &mut ImageTexel,
+        el_offset_B_out: &mut u32,
+    ) -> bool {
+        if let Some((texel, el_offset_B)) = self.texel_at_offset_B(offset_B) {
+            *texel_out = texel;
+            *el_offset_B_out = el_offset_B;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The inverse of texel_offset_B()
+    ///
+    /// Returns the texel containing the byte at @offset_B along with the
+    /// offset of that byte within the texel's element or None if the byte
+    /// is padding.
+    pub fn texel_at_offset_B(
+        &self,
+        offset_B: u64,
+    ) -> Option<(ImageTexel, u32)> {
+        if offset_B >= self.size_B {
+            return None;
+        }
+
+        if self.sample_layout != SampleLayout::_1x1 {
+            let (texel_sa, el_offset_B) =
+                self.msaa_as_samples().texel_at_offset_B(offset_B)?;
+            let px_ext_sa = self.sample_layout.px_extent_sa();
+            let sa_x = texel_sa.offset_px.x % px_ext_sa.width;
+            let sa_y = texel_sa.offset_px.y % px_ext_sa.height;
+            let sample = (0..self.sample_layout.samples())
+                .find(|&s| {
+                    let sa_offset = self.sample_layout.sa_offset(s as u8);
+                    u32::from(sa_offset.x) == sa_x
+                        && u32::from(sa_offset.y) == sa_y
+                })
+                .unwrap();
+            let offset_px = Offset4D::new(
+                texel_sa.offset_px.x / px_ext_sa.width,
+                texel_sa.offset_px.y / px_ext_sa.height,
+                texel_sa.offset_px.z,
+                texel_sa.offset_px.a,
+            );
+            let texel = ImageTexel {
+                level: texel_sa.level,
+                offset_px,
+                sample,
+            };
+            return Some((texel, el_offset_B));
+        }
+
+        let layer = offset_B.checked_div(self.array_stride_B).unwrap_or(0);
+        if layer >= u64::from(self.extent_px.array_len) {
+            return None;
+        }
+        let layer_offset_B = offset_B - layer * self.array_stride_B;
+
+        let level = (0..self.num_levels)
+            .rev()
+            .find(|&l| self.levels[l as usize].offset_B <= layer_offset_B)?;
+        let lvl = &self.levels[level as usize];
+        let lvl_offset_B = layer_offset_B - lvl.offset_B;
+        if lvl_offset_B >= self.level_layer_size_B(level) {
+            return None;
+        }
+
+        let lvl_ext_B = self.level_extent_B(level);
+        let offset_B = if lvl.tiling.is_tiled() {
+            lvl.tiling.offset_to_B(lvl_ext_B, lvl_offset_B)
+        } else {
+            let row_stride_B = u64::from(lvl.row_stride_B);
+            Offset4D::new(
+                (lvl_offset_B % row_stride_B) as u32,
+                (lvl_offset_B / row_stride_B) as u32,
+                0,
+                0,
+            )
+        };
+        if offset_B.x >= lvl_ext_B.width
+            || offset_B.y >= lvl_ext_B.height
+            || offset_B.z >= lvl_ext_B.depth
+        {
+            return None;
+        }
+
+        let el_size_B = self.format.el_size_B();
+        let el_ext_sa = self.format.el_extent_sa();
+        let offset_px = Offset4D::new(
+            (offset_B.x / el_size_B) * el_ext_sa.width,
+            offset_B.y * el_ext_sa.height,
+            offset_B.z,
+            layer as u32,
+        );
+        let texel = ImageTexel {
+            level,
+            offset_px,
+            sample: 0,
+        };
+        Some((texel, offset_B.x % el_size_B))
+    }
+}
+
 /// Copies a GOB
 ///
 /// This trait should be implemented twice for each GOB type, once for
@@ -152,6 +474,13 @@ trait Copy16B {
     unsafe fn copy_16b(tiled: *mut [u8; 16], linear: *mut [u8; 16]) {
         Self::copy(tiled as *mut _, linear as *mut _, 16);
     }
+
+    /// Copies 32B of contiguous linear data to/from two 16B tiled chunks
+    unsafe fn copy_2x16b(tiled: [*mut [u8; 16]; 2], linear: *mut [u8; 32]) {
+        let linear = linear as *mut [u8; 16];
+        Self::copy_16b(tiled[0], linear);
+        Self::copy_16b(tiled[1], linear.add(1));
+    }
 }
 
 struct CopyGOBTuring2D<C: Copy16B> {
@@ -214,11 +543,19 @@ impl<C: Copy16B> CopyGOB for CopyGOBTuring2D<C> {
     }
 
     unsafe fn copy_whole_gob(tiled: usize, linear: LinearPointer) {
-        Self::for_each_16b(|offset, x, y| {
-            let tiled = tiled + (offset as usize);
-            let linear = linear.at(Offset4D::new(x, y, 0, 0));
-            C::copy_16b(tiled as *mut _, linear as *mut _);
-        });
+        // Each 32B half of a row is split into two 16B chunks which are 0x40
+        // apart in the GOB.  See for_each_16b().
+        for i in 0..2 {
+            for y in 0..8 {
+                let offset = i * 0x100 + ((y & 0x3) << 4) + ((y & 0x4) << 5);
+                let tiled = tiled + (offset as usize);
+                let linear = linear.at(Offset4D::new(i * 32, y, 0, 0));
+                C::copy_2x16b(
+                    [tiled as *mut _, (tiled + 0x40) as *mut _],
+                    linear as *mut _,
+                );
+            }
+        }
     }
 }
 
@@ -507,26 +844,321 @@ unsafe fn copy_tiled<CG: CopyGOB>(
     });
 }
 
-struct RawCopyToTiled {}
+/// The direction of a copy between tiled and linear memory
+trait CopyDirection {
+    const TO_TILED: bool;
+}
+
+struct ToTiled {}
+
+impl CopyDirection for ToTiled {
+    const TO_TILED: bool = true;
+}
+
+struct ToLinear {}
 
-impl Copy16B for RawCopyToTiled {
+impl CopyDirection for ToLinear {
+    const TO_TILED: bool = false;
+}
+
+struct RawCopy<D: CopyDirection> {
+    phantom: PhantomData<D>,
+}
+
+impl<D: CopyDirection> Copy16B for RawCopy<D> {
     const X_DIVISOR: u32 = 1;
 
     unsafe fn copy(tiled: *mut u8, linear: *mut u8, bytes: usize) {
         // This is backwards from memcpy
-        std::ptr::copy_nonoverlapping(linear, tiled, bytes);
+        if D::TO_TILED {
+            std::ptr::copy_nonoverlapping(linear, tiled, bytes);
+        } else {
+            std::ptr::copy_nonoverlapping(tiled, linear, bytes);
+        }
     }
 }
 
-struct RawCopyToLinear {}
+#[cfg(target_arch = "x86_64")]
+struct AVX2Copy<D: CopyDirection> {
+    phantom: PhantomData<D>,
+}
 
-impl Copy16B for RawCopyToLinear {
+#[cfg(target_arch = "x86_64")]
+impl<D: CopyDirection> Copy16B for AVX2Copy<D> {
     const X_DIVISOR: u32 = 1;
 
+    #[inline(always)]
     unsafe fn copy(tiled: *mut u8, linear: *mut u8, bytes: usize) {
-        // This is backwards from memcpy
-        std::ptr::copy_nonoverlapping(tiled, linear, bytes);
+        RawCopy::<D>::copy(tiled, linear, bytes);
     }
+
+    #[inline(always)]
+    unsafe fn copy_2x16b(tiled: [*mut [u8; 16]; 2], linear: *mut [u8; 32]) {
+        avx2_copy_2x16b::<D>(tiled, linear);
+    }
+}
+
+/// Moves a 32B linear row to/from two 16B tiled chunks with one AVX2 access
+///
+/// This only gets inlined into callers which also enable AVX2, which is why
+/// copy_tiled_avx2() exists.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn avx2_copy_2x16b<D: CopyDirection>(
+    tiled: [*mut [u8; 16]; 2],
+    linear: *mut [u8; 32],
+) {
+    use std::arch::x86_64::*;
+    let tiled = tiled.map(|t| t as *mut __m128i);
+    let linear = linear as *mut __m256i;
+    if D::TO_TILED {
+        let v = _mm256_loadu_si256(linear);
+        _mm_storeu_si128(tiled[0], _mm256_castsi256_si128(v));
+        _mm_storeu_si128(tiled[1], _mm256_extracti128_si256::<1>(v));
+    } else {
+        let lo = _mm_loadu_si128(tiled[0]);
+        let hi = _mm_loadu_si128(tiled[1]);
+        _mm256_storeu_si256(linear, _mm256_set_m128i(hi, lo));
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+struct NEONCopy<D: CopyDirection> {
+    phantom: PhantomData<D>,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl<D: CopyDirection> Copy16B for NEONCopy<D> {
+    const X_DIVISOR: u32 = 1;
+
+    #[inline(always)]
+    unsafe fn copy(tiled: *mut u8, linear: *mut u8, bytes: usize) {
+        RawCopy::<D>::copy(tiled, linear, bytes);
+    }
+
+    #[inline(always)]
+    unsafe fn copy_2x16b(tiled: [*mut [u8; 16]; 2], linear: *mut [u8; 32]) {
+        use std::arch::aarch64::*;
+        let tiled = tiled.map(|t| t as *mut u8);
+        let linear = linear as *mut u8;
+        if D::TO_TILED {
+            let v = vld1q_u8_x2(linear);
+            vst1q_u8(tiled[0], v.0);
+            vst1q_u8(tiled[1], v.1);
+        } else {
+            let lo = vld1q_u8(tiled[0]);
+            let hi = vld1q_u8(tiled[1]);
+            vst1q_u8_x2(linear, uint8x16x2_t(lo, hi));
+        }
+    }
+}
+
+/// The instruction set used for the 16B copies of tiled memcpy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CopyISA {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    AVX2,
+    #[cfg(target_arch = "aarch64")]
+    NEON,
+}
+
+impl CopyISA {
+    /// The best instruction set every CPU of this architecture supports
+    #[cfg(target_arch = "aarch64")]
+    const BASELINE: CopyISA = CopyISA::NEON;
+    #[cfg(not(target_arch = "aarch64"))]
+    const BASELINE: CopyISA = CopyISA::Scalar;
+
+    /// Returns every instruction set supported by the CPU, best last
+    #[cfg(test)]
+    fn supported() -> Vec<CopyISA> {
+        let mut isas = vec![CopyISA::Scalar];
+        if Self::BASELINE != CopyISA::Scalar {
+            isas.push(Self::BASELINE);
+        }
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            isas.push(CopyISA::AVX2);
+        }
+        isas
+    }
+
+    fn best() -> CopyISA {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            return CopyISA::AVX2;
+        }
+        Self::BASELINE
+    }
+}
+
+/// Controls how tiled copies are executed
+#[derive(Clone, Copy, Debug)]
+struct CopyConfig {
+    isa: CopyISA,
+    max_threads: usize,
+    /// Each thread copies at least this many bytes
+    min_thread_size_B: u64,
+}
+
+impl CopyConfig {
+    /// Spawning a thread costs about as much as copying a few hundred KiB so
+    /// anything smaller than this is copied on a single thread.
+    const MIN_THREAD_SIZE_B: u64 = 2 << 20;
+
+    fn new(max_threads: u32) -> CopyConfig {
+        CopyConfig {
+            isa: CopyISA::best(),
+            max_threads: max_threads.max(1) as usize,
+            min_thread_size_B: Self::MIN_THREAD_SIZE_B,
+        }
+    }
+}
+
+/// Runs copy_tiled() with AVX2 enabled
+///
+/// Everything copy_tiled() calls down to avx2_copy_2x16b() is inlined so the
+/// whole copy is compiled for AVX2 and the feature is checked once per copy
+/// rather than once per GOB.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn copy_tiled_avx2<D: CopyDirection>(
+    tiling: Tiling,
+    level_extent_B: Extent4D<units::Bytes>,
+    level_tiled_ptr: usize,
+    linear: LinearPointer,
+    start: Offset4D<units::Bytes>,
+    end: Offset4D<units::Bytes>,
+) {
+    copy_tiled::<CopyGOBTuring2D<AVX2Copy<D>>>(
+        tiling,
+        level_extent_B,
+        level_tiled_ptr,
+        linear,
+        start,
+        end,
+    );
+}
+
+/// Runs copy_tiled() with the given instruction set
+unsafe fn copy_tiled_isa<D: CopyDirection>(
+    isa: CopyISA,
+    tiling: Tiling,
+    level_extent_B: Extent4D<units::Bytes>,
+    level_tiled_ptr: usize,
+    linear: LinearPointer,
+    start: Offset4D<units::Bytes>,
+    end: Offset4D<units::Bytes>,
+) {
+    match isa {
+        CopyISA::Scalar => copy_tiled::<CopyGOBTuring2D<RawCopy<D>>>(
+            tiling,
+            level_extent_B,
+            level_tiled_ptr,
+            linear,
+            start,
+            end,
+        ),
+        #[cfg(target_arch = "x86_64")]
+        CopyISA::AVX2 => copy_tiled_avx2::<D>(
+            tiling,
+            level_extent_B,
+            level_tiled_ptr,
+            linear,
+            start,
+            end,
+        ),
+        #[cfg(target_arch = "aarch64")]
+        CopyISA::NEON => copy_tiled::<CopyGOBTuring2D<NEONCopy<D>>>(
+            tiling,
+            level_extent_B,
+            level_tiled_ptr,
+            linear,
+            start,
+            end,
+        ),
+    }
+}
+
+/// Runs copy_tiled(), splitting large copies across threads
+///
+/// The copy is split into bands of whole rows of tiles so no two threads
+/// ever touch the same tile.  The first band is copied on the calling thread.
+unsafe fn copy_tiled_mt<D: CopyDirection>(
+    config: CopyConfig,
+    tiling: Tiling,
+    level_extent_B: Extent4D<units::Bytes>,
+    level_tiled_ptr: usize,
+    linear: LinearPointer,
+    start: Offset4D<units::Bytes>,
+    end: Offset4D<units::Bytes>,
+) {
+    let size_B = u64::from(end.x - start.x)
+        * u64::from(end.y - start.y)
+        * u64::from(end.z - start.z);
+    let tl_height_B = tiling.extent_B().height;
+    let first_row = start.y / tl_height_B;
+    let num_rows = end.y.div_ceil(tl_height_B) - first_row;
+
+    let num_threads = config
+        .max_threads
+        .min(num_rows as usize)
+        .min((size_B / config.min_thread_size_B.max(1)) as usize);
+
+    if num_threads <= 1 {
+        copy_tiled_isa::<D>(
+            config.isa,
+            tiling,
+            level_extent_B,
+            level_tiled_ptr,
+            linear,
+            start,
+            end,
+        );
+        return;
+    }
+
+    let band = |t: usize| {
+        let rows =
+            |t: usize| first_row + (num_rows as usize * t / num_threads) as u32;
+        let y_start = std::cmp::max(start.y, rows(t) * tl_height_B);
+        let y_end = std::cmp::min(end.y, rows(t + 1) * tl_height_B);
+        let band_start = Offset4D::new(start.x, y_start, start.z, start.a);
+        let band_end = Offset4D::new(end.x, y_end, end.z, end.a);
+        let band_linear =
+            linear.offset(Offset4D::new(0, y_start - start.y, 0, 0));
+        (band_linear, band_start, band_end)
+    };
+
+    std::thread::scope(|scope| {
+        for t in 1..num_threads {
+            let (linear, start, end) = band(t);
+            scope.spawn(move || unsafe {
+                copy_tiled_isa::<D>(
+                    config.isa,
+                    tiling,
+                    level_extent_B,
+                    level_tiled_ptr,
+                    linear,
+                    start,
+                    end,
+                );
+            });
+        }
+
+        let (linear, start, end) = band(0);
+        copy_tiled_isa::<D>(
+            config.isa,
+            tiling,
+            level_extent_B,
+            level_tiled_ptr,
+            linear,
+            start,
+            end,
+        );
+    });
 }
 
 #[no_mangle]
@@ -540,25 +1172,17 @@ pub unsafe extern "C" fn nil_copy_linear_to_tiled(
     extent_B: Extent4D<units::Bytes>,
     tiling: &Tiling,
 ) {
-    let end_B = offset_B + extent_B;
-
-    let linear_src = linear_src as usize;
-    let tiled_dst = tiled_dst as usize;
-    let linear_pointer = LinearPointer::new(
-        linear_src,
-        1,
+    let copy = TiledCopy {
+        tiled: tiled_dst,
+        level_extent_B,
+        linear: linear_src as *mut c_void,
         linear_row_stride_B,
         linear_plane_stride_B,
-    );
-
-    copy_tiled::<CopyGOBTuring2D<RawCopyToTiled>>(
-        *tiling,
-        level_extent_B,
-        tiled_dst,
-        linear_pointer,
         offset_B,
-        end_B,
-    );
+        extent_B,
+        tiling: *tiling,
+    };
+    copy.run::<ToTiled>(CopyConfig::new(1));
 }
 
 #[no_mangle]
@@ -572,23 +1196,1453 @@ pub unsafe extern "C" fn nil_copy_tiled_to_linear(
     extent_B: Extent4D<units::Bytes>,
     tiling: &Tiling,
 ) {
-    let mut end_B = offset_B + extent_B;
-    end_B.a = 1;
-    let linear_dst = linear_dst as usize;
-    let tiled_src = tiled_src as usize;
+    let copy = TiledCopy {
+        tiled: tiled_src as *mut c_void,
+        level_extent_B,
+        linear: linear_dst,
+        linear_row_stride_B,
+        linear_plane_stride_B,
+        offset_B,
+        extent_B,
+        tiling: *tiling,
+    };
+    copy.run::<ToLinear>(CopyConfig::new(1));
+}
+
+/// Describes a copy between linear memory and one level of a tiled image
+///
+/// This carries the same arguments as nil_copy_linear_to_tiled() and
+/// nil_copy_tiled_to_linear() for the multithreaded variants.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TiledCopy {
+    /// The start of the tiled level
+    pub tiled: *mut c_void,
+    pub level_extent_B: Extent4D<units::Bytes>,
+    /// The linear data for @offset_B
+    pub linear: *mut c_void,
+    pub linear_row_stride_B: usize,
+    pub linear_plane_stride_B: usize,
+    pub offset_B: Offset4D<units::Bytes>,
+    pub extent_B: Extent4D<units::Bytes>,
+    pub tiling: Tiling,
+}
+
+impl TiledCopy {
+    unsafe fn run<D: CopyDirection>(&self, config: CopyConfig) {
+        let mut end_B = self.offset_B + self.extent_B;
+        end_B.a = 1;
+        let linear = LinearPointer::new(
+            self.linear as usize,
+            1,
+            self.linear_row_stride_B,
+            self.linear_plane_stride_B,
+        );
+
+        copy_tiled_mt::<D>(
+            config,
+            self.tiling,
+            self.level_extent_B,
+            self.tiled as usize,
+            linear,
+            self.offset_B,
+            end_B,
+        );
+    }
+}
+
+/// Same as nil_copy_linear_to_tiled() but large copies are split across up to
+/// @max_threads threads, including the calling one.
+#[no_mangle]
+pub unsafe extern "C" fn nil_copy_linear_to_tiled_mt(
+    copy: &TiledCopy,
+    max_threads: u32,
+) {
+    copy.run::<ToTiled>(CopyConfig::new(max_threads));
+}
+
+/// Same as nil_copy_tiled_to_linear() but large copies are split across up to
+/// @max_threads threads, including the calling one.
+#[no_mangle]
+pub unsafe extern "C" fn nil_copy_tiled_to_linear_mt(
+    copy: &TiledCopy,
+    max_threads: u32,
+) {
+    copy.run::<ToLinear>(CopyConfig::new(max_threads));
+}
+
+/// Copies a region between linear memory and an image
+///
+/// Unlike copy_tiled(), this takes care of levels, array layers and sample
+/// layouts.  @image_ptr points to the start of the image.  In linear memory,
+/// the samples of each pixel are stored next to each other and each Z slice
+/// or array layer is one plane stride after the previous one.
+unsafe fn copy_linear_image<D: CopyDirection>(
+    image_ptr: usize,
+    image: &Image,
+    level: u32,
+    offset_px: Offset4D<units::Pixels>,
+    extent_px: Extent4D<units::Pixels>,
+    linear: LinearPointer,
+) {
+    let linear_row_stride_B = linear.row_stride_B;
+    let linear_plane_stride_B = linear.plane_stride_B;
+
+    assert!(level < image.num_levels);
+    let lvl = &image.levels[level as usize];
+    let lvl_ext_px = image.level_extent_px(level);
+    assert!(offset_px.x + extent_px.width <= lvl_ext_px.width);
+    assert!(offset_px.y + extent_px.height <= lvl_ext_px.height);
+    assert!(offset_px.z + extent_px.depth <= lvl_ext_px.depth);
+    assert!(offset_px.a + extent_px.array_len <= lvl_ext_px.array_len);
+
+    let mut lvl_ext_B = image.level_extent_B(level);
+    lvl_ext_B.array_len = 1;
+
+    if image.sample_layout == SampleLayout::_1x1 {
+        let mut start_B = offset_px.to_B(image.format, image.sample_layout);
+        start_B.a = 0;
+        let mut extent_B = extent_px.to_B(image.format, image.sample_layout);
+        extent_B.array_len = 1;
+        let end_B = start_B + extent_B;
+
+        for a in 0..extent_px.array_len {
+            let layer_ptr = image_ptr
+                + image.level_layer_offset_B(level, offset_px.a + a) as usize;
+            let linear_ptr = linear.pointer
+                + (a * extent_px.depth) as usize * linear_plane_stride_B;
+
+            if lvl.tiling.is_tiled() {
+                let linear = LinearPointer::new(
+                    linear_ptr,
+                    1,
+                    linear_row_stride_B,
+                    linear_plane_stride_B,
+                );
+                copy_tiled_isa::<D>(
+                    CopyISA::best(),
+                    lvl.tiling,
+                    lvl_ext_B,
+                    layer_ptr,
+                    linear,
+                    start_B,
+                    end_B,
+                );
+            } else {
+                assert!(extent_B.depth == 1);
+                for y in 0..extent_B.height {
+                    let tiled = layer_ptr
+                        + (start_B.y + y) as usize * lvl.row_stride_B as usize
+                        + start_B.x as usize;
+                    let linear = linear_ptr + y as usize * linear_row_stride_B;
+                    RawCopy::<D>::copy(
+                        tiled as *mut u8,
+                        linear as *mut u8,
+                        extent_B.width as usize,
+                    );
+                }
+            }
+        }
+    } else {
+        // Each sample lives in its own spot in the image so we have to copy
+        // one element at a time.  Multisampled formats are never compressed
+        // so an element is a single sample.
+        let samples = image.sample_layout.samples();
+        let px_ext_sa = image.sample_layout.px_extent_sa();
+        let el_size_B = image.format.el_size_B();
+        debug_assert!(image.format.el_extent_sa() == Extent4D::new(1, 1, 1, 1));
+
+        for a in 0..extent_px.array_len {
+            for z in 0..extent_px.depth {
+                let plane = (a * extent_px.depth + z) as usize;
+                for y in 0..extent_px.height {
+                    let linear_row = linear.pointer
+                        + plane * linear_plane_stride_B
+                        + y as usize * linear_row_stride_B;
+                    for x in 0..extent_px.width {
+                        for s in 0..samples {
+                            let sa = image.sample_layout.sa_offset(s as u8);
+                            let x_sa = (offset_px.x + x) * px_ext_sa.width
+                                + u32::from(sa.x);
+                            let y_sa = (offset_px.y + y) * px_ext_sa.height
+                                + u32::from(sa.y);
+                            let offset_B = Offset4D::new(
+                                x_sa * el_size_B,
+                                y_sa,
+                                offset_px.z + z,
+                                offset_px.a + a,
+                            );
+                            let tiled = image_ptr
+                                + image.level_byte_offset_B(level, offset_B)
+                                    as usize;
+                            let linear = linear_row
+                                + ((x * samples + s) * el_size_B) as usize;
+                            RawCopy::<D>::copy(
+                                tiled as *mut u8,
+                                linear as *mut u8,
+                                el_size_B as usize,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nil_copy_linear_to_image(
+    image_dst: *mut c_void,
+    image: &Image,
+    level: u32,
+    offset_px: Offset4D<units::Pixels>,
+    extent_px: Extent4D<units::Pixels>,
+    linear_src: *const c_void,
+    linear_row_stride_B: usize,
+    linear_plane_stride_B: usize,
+) {
     let linear_pointer = LinearPointer::new(
-        linear_dst,
+        linear_src as usize,
         1,
         linear_row_stride_B,
         linear_plane_stride_B,
     );
 
-    copy_tiled::<CopyGOBTuring2D<RawCopyToLinear>>(
-        *tiling,
-        level_extent_B,
-        tiled_src,
+    copy_linear_image::<ToTiled>(
+        image_dst as usize,
+        image,
+        level,
+        offset_px,
+        extent_px,
+        linear_pointer,
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nil_copy_image_to_linear(
+    linear_dst: *mut c_void,
+    linear_row_stride_B: usize,
+    linear_plane_stride_B: usize,
+    image_src: *const c_void,
+    image: &Image,
+    level: u32,
+    offset_px: Offset4D<units::Pixels>,
+    extent_px: Extent4D<units::Pixels>,
+) {
+    let linear_pointer = LinearPointer::new(
+        linear_dst as usize,
+        1,
+        linear_row_stride_B,
+        linear_plane_stride_B,
+    );
+
+    copy_linear_image::<ToLinear>(
+        image_src as usize,
+        image,
+        level,
+        offset_px,
+        extent_px,
         linear_pointer,
-        offset_B,
-        end_B,
     );
 }
+
+/// Byte offsets within one level of an image for copies which walk the
+/// level a row at a time
+///
+/// For both linear and tiled levels, the offset of the byte at (x, y, z) is
+/// the offset of (0, y, z) plus a part which only depends on x.  Rows of a
+/// tiled level are contiguous in 16B chunks so the x part only has to be
+/// computed once per chunk and is the same for every row.
+struct RowAddressing<'a> {
+    image: &'a Image,
+    level: u32,
+    tiled: bool,
+    /// The start of the first 16B chunk in @x_offsets_B
+    x_start_B: u32,
+    /// For tiled levels, the x part of the offset of each 16B chunk
+    x_offsets_B: Vec<u64>,
+}
+
+impl<'a> RowAddressing<'a> {
+    fn new(image: &'a Image, level: u32, x_B: Range<u32>) -> Self {
+        let tiling = &image.levels[level as usize].tiling;
+        if !tiling.is_tiled() {
+            return RowAddressing {
+                image,
+                level,
+                tiled: false,
+                x_start_B: 0,
+                x_offsets_B: Vec::new(),
+            };
+        }
+
+        let mut lvl_ext_B = image.level_extent_B(level);
+        lvl_ext_B.array_len = 1;
+        let x_start_B = x_B.start & !0xf;
+        let x_offsets_B = (x_start_B..x_B.end)
+            .step_by(16)
+            .map(|x| tiling.offset_B(lvl_ext_B, Offset4D::new(x, 0, 0, 0)))
+            .collect();
+        RowAddressing {
+            image,
+            level,
+            tiled: true,
+            x_start_B,
+            x_offsets_B,
+        }
+    }
+
+    /// Returns the offset from the start of the image of the start of the
+    /// given row
+    fn row_offset_B(&self, y: u32, z: u32, a: u32) -> usize {
+        let offset_B = Offset4D::new(0, y, z, a);
+        self.image.level_byte_offset_B(self.level, offset_B) as usize
+    }
+
+    /// Returns the offset of the byte at @x from the start of its row
+    fn x_offset_B(&self, x: u32) -> usize {
+        if self.tiled {
+            let chunk = ((x - self.x_start_B) / 16) as usize;
+            self.x_offsets_B[chunk] as usize + (x % 16) as usize
+        } else {
+            x as usize
+        }
+    }
+
+    /// Returns how many bytes starting at @x are contiguous in memory
+    fn contiguous_B(&self, x: u32) -> u32 {
+        if self.tiled {
+            16 - x % 16
+        } else {
+            u32::MAX
+        }
+    }
+}
+
+/// Copies a region directly between two images with possibly different
+/// tilings
+///
+/// Both images must have the same element size and sample layout and both
+/// pointers point to the start of their image.  As with Vulkan image copies,
+/// @extent_px is in pixels of the source image, which matters when copying
+/// between compressed and uncompressed formats.  Z slices of 3D images and
+/// array layers of other images are treated the same so 3D images can be
+/// copied to and from 2D arrays.
+///
+/// Within a row, tiled images are contiguous in 16B chunks so we copy at
+/// most 16B at a time.
+#[no_mangle]
+pub unsafe extern "C" fn nil_copy_image_to_image(
+    image_dst: *mut c_void,
+    dst: &Image,
+    dst_level: u32,
+    dst_offset_px: Offset4D<units::Pixels>,
+    image_src: *const c_void,
+    src: &Image,
+    src_level: u32,
+    src_offset_px: Offset4D<units::Pixels>,
+    extent_px: Extent4D<units::Pixels>,
+) {
+    assert!(dst.format.el_size_B() == src.format.el_size_B());
+    assert!(dst.sample_layout == src.sample_layout);
+
+    let dst_offset_B = dst_offset_px.to_B(dst.format, dst.sample_layout);
+    let src_offset_B = src_offset_px.to_B(src.format, src.sample_layout);
+    let extent_B = extent_px.to_B(src.format, src.sample_layout);
+    let num_slices = extent_px.depth * extent_px.array_len;
+
+    let slice_B = |image: &Image,
+                   offset_B: Offset4D<units::Bytes>,
+                   i: u32|
+     -> Offset4D<units::Bytes> {
+        if image.dim == ImageDim::_3D {
+            Offset4D::new(offset_B.x, offset_B.y, offset_B.z + i, offset_B.a)
+        } else {
+            Offset4D::new(offset_B.x, offset_B.y, offset_B.z, offset_B.a + i)
+        }
+    };
+
+    for (image, level, offset_B) in [
+        (dst, dst_level, dst_offset_B),
+        (src, src_level, src_offset_B),
+    ] {
+        assert!(level < image.num_levels);
+        let lvl_ext_B = image.level_extent_B(level);
+        assert!(offset_B.x + extent_B.width <= lvl_ext_B.width);
+        assert!(offset_B.y + extent_B.height <= lvl_ext_B.height);
+        let last_B = slice_B(image, offset_B, num_slices - 1);
+        assert!(last_B.z < lvl_ext_B.depth);
+        assert!(last_B.a < lvl_ext_B.array_len);
+    }
+
+    let dst_x_B = dst_offset_B.x..(dst_offset_B.x + extent_B.width);
+    let src_x_B = src_offset_B.x..(src_offset_B.x + extent_B.width);
+    let dst_rows = RowAddressing::new(dst, dst_level, dst_x_B);
+    let src_rows = RowAddressing::new(src, src_level, src_x_B);
+
+    let image_dst = image_dst as *mut u8;
+    let image_src = image_src as *const u8;
+
+    for i in 0..num_slices {
+        let dst_slice_B = slice_B(dst, dst_offset_B, i);
+        let src_slice_B = slice_B(src, src_offset_B, i);
+        for y in 0..extent_B.height {
+            let dst_row = image_dst.add(dst_rows.row_offset_B(
+                dst_slice_B.y + y,
+                dst_slice_B.z,
+                dst_slice_B.a,
+            ));
+            let src_row = image_src.add(src_rows.row_offset_B(
+                src_slice_B.y + y,
+                src_slice_B.z,
+                src_slice_B.a,
+            ));
+
+            let mut x = 0;
+            while x < extent_B.width {
+                let dst_x = dst_slice_B.x + x;
+                let src_x = src_slice_B.x + x;
+                let len = (extent_B.width - x)
+                    .min(dst_rows.contiguous_B(dst_x))
+                    .min(src_rows.contiguous_B(src_x));
+
+                std::ptr::copy_nonoverlapping(
+                    src_row.add(src_rows.x_offset_B(src_x)),
+                    dst_row.add(dst_rows.x_offset_B(dst_x)),
+                    len as usize,
+                );
+
+                x += len;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+    use crate::image::{
+        ImageDim, ImageInitInfo, ImageUsageFlags, IMAGE_USAGE_LINEAR_BIT,
+        IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+    };
+    use crate::modifiers::DRM_FORMAT_MOD_INVALID;
+    use crate::test_util::{dev, Rand};
+    use nil_rs_bindings::*;
+    use nvidia_headers::classes::clc597;
+
+    fn copy_linear_to_tiled(
+        tiled: &mut [u8],
+        tiling: &Tiling,
+        level_extent_B: Extent4D<units::Bytes>,
+        linear: &[u8],
+    ) {
+        let row_stride_B = level_extent_B.width as usize;
+        let plane_stride_B = row_stride_B * level_extent_B.height as usize;
+        assert!(linear.len() == plane_stride_B * level_extent_B.depth as usize);
+        assert!(
+            tiled.len() as u64
+                >= level_extent_B.align(&tiling.extent_B()).size_B()
+        );
+        unsafe {
+            nil_copy_linear_to_tiled(
+                tiled.as_mut_ptr() as *mut c_void,
+                level_extent_B,
+                linear.as_ptr() as *const c_void,
+                row_stride_B,
+                plane_stride_B,
+                Offset4D::new(0, 0, 0, 0),
+                level_extent_B,
+                tiling,
+            );
+        }
+    }
+
+    #[test]
+    fn test_gob_offsets() {
+        let gob_type = GOBType::Fermi8;
+        for o in 0..512 {
+            assert_eq!(gob_type.offset_B(gob_type.offset_to_B(o)), o);
+        }
+
+        CopyGOBTuring2D::<RawCopy<ToTiled>>::for_each_16b(|offset, x, y| {
+            for i in 0..16 {
+                let offset_B = Offset4D::new(x + i, y, 0, 0);
+                assert_eq!(gob_type.offset_B(offset_B), offset + i);
+            }
+        });
+    }
+
+    #[test]
+    fn test_tiling_offsets_match_copy() {
+        let mut rand = Rand::new(0x5eed_1234_abcd_0001);
+        for _ in 0..64 {
+            let tiling = Tiling {
+                gob_type: GOBType::Fermi8,
+                x_log2: rand.range(0..2) as u8,
+                y_log2: rand.range(0..5) as u8,
+                z_log2: rand.range(0..3) as u8,
+            };
+            let ext_B = Extent4D::new(
+                rand.range(1..200),
+                rand.range(1..100),
+                rand.range(1..7),
+                1,
+            );
+
+            let aligned_size_B =
+                ext_B.align(&tiling.extent_B()).size_B() as usize;
+            let linear = rand.bytes(ext_B.size_B() as usize);
+            let mut tiled = vec![0_u8; aligned_size_B];
+            copy_linear_to_tiled(&mut tiled, &tiling, ext_B, &linear);
+
+            let linear_at = |o: Offset4D<units::Bytes>| {
+                let row = ext_B.width as usize;
+                let plane = row * ext_B.height as usize;
+                linear[o.z as usize * plane + o.y as usize * row + o.x as usize]
+            };
+
+            for z in 0..ext_B.depth {
+                for y in 0..ext_B.height {
+                    for x in 0..ext_B.width {
+                        let o = Offset4D::new(x, y, z, 0);
+                        let tiled_B = tiling.offset_B(ext_B, o) as usize;
+                        assert_eq!(tiled[tiled_B], linear_at(o));
+                    }
+                }
+            }
+
+            for tiled_B in 0..aligned_size_B {
+                let o = tiling.offset_to_B(ext_B, tiled_B as u64);
+                assert_eq!(tiling.offset_B(ext_B, o), tiled_B as u64);
+                if o.x < ext_B.width && o.y < ext_B.height && o.z < ext_B.depth
+                {
+                    assert_eq!(tiled[tiled_B], linear_at(o));
+                }
+            }
+        }
+    }
+
+    fn image(
+        dim: ImageDim,
+        p_format: pipe_format,
+        extent_px: Extent4D<units::Pixels>,
+        levels: u32,
+        samples: u32,
+        usage: ImageUsageFlags,
+    ) -> Image {
+        image_max_align(dim, p_format, extent_px, levels, samples, usage, 0)
+    }
+
+    fn image_max_align(
+        dim: ImageDim,
+        p_format: pipe_format,
+        extent_px: Extent4D<units::Pixels>,
+        levels: u32,
+        samples: u32,
+        usage: ImageUsageFlags,
+        max_alignment_B: u32,
+    ) -> Image {
+        let info = ImageInitInfo {
+            dim,
+            format: Format::try_from(p_format).unwrap(),
+            extent_px,
+            levels,
+            samples,
+            usage,
+            modifier: DRM_FORMAT_MOD_INVALID,
+            explicit_row_stride_B: 0,
+            max_alignment_B,
+        };
+        Image::new(
+            &dev(NV_DEVICE_TYPE_IGP, clc597::TURING_A),
+            std::slice::from_ref(&info),
+            0,
+        )
+    }
+
+    fn test_images() -> Vec<Image> {
+        let ext = |w, h, d, a| Extent4D::new(w, h, d, a);
+        vec![
+            image(
+                ImageDim::_1D,
+                PIPE_FORMAT_R8_UNORM,
+                ext(300, 1, 1, 3),
+                4,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(67, 45, 1, 3),
+                5,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R32G32B32A32_FLOAT,
+                ext(17, 130, 1, 1),
+                3,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_DXT1_RGBA,
+                ext(70, 33, 1, 2),
+                4,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R16_UNORM,
+                ext(99, 21, 1, 1),
+                1,
+                1,
+                IMAGE_USAGE_LINEAR_BIT,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(33, 17, 1, 2),
+                1,
+                2,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R32G32_UINT,
+                ext(21, 9, 1, 1),
+                1,
+                4,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8_UNORM,
+                ext(40, 19, 1, 1),
+                1,
+                8,
+                0,
+            ),
+            image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(37, 21, 13, 1),
+                4,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R16_UNORM,
+                ext(70, 9, 40, 1),
+                2,
+                1,
+                0,
+            ),
+            image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(300, 200, 1, 2),
+                6,
+                1,
+                IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+            ),
+            image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R8_UNORM,
+                ext(70, 40, 50, 1),
+                3,
+                1,
+                IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+            ),
+        ]
+    }
+
+    fn for_each_texel(image: &Image, mut f: impl FnMut(ImageTexel)) {
+        for level in 0..image.num_levels {
+            let ext_px = image.level_extent_px(level);
+            for a in 0..ext_px.array_len {
+                for z in 0..ext_px.depth {
+                    for y in 0..ext_px.height {
+                        for x in 0..ext_px.width {
+                            for sample in 0..image.sample_layout.samples() {
+                                f(ImageTexel {
+                                    level,
+                                    offset_px: Offset4D::new(x, y, z, a),
+                                    sample,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the texel at the top-left corner of the element containing
+    /// the given texel
+    fn el_texel(image: &Image, texel: ImageTexel) -> ImageTexel {
+        let el_ext_sa = image.format.el_extent_sa();
+        let mut offset_px = texel.offset_px;
+        offset_px.x -= offset_px.x % el_ext_sa.width;
+        offset_px.y -= offset_px.y % el_ext_sa.height;
+        ImageTexel { offset_px, ..texel }
+    }
+
+    #[test]
+    fn test_image_texel_inverse() {
+        for image in test_images() {
+            let el_size_B = image.format.el_size_B();
+
+            let mut num_texel_B = 0_u64;
+            for_each_texel(&image, |texel| {
+                let offset_B = image.texel_offset_B(texel);
+                assert!(offset_B + u64::from(el_size_B) <= image.size_B);
+                for b in 0..el_size_B {
+                    let found =
+                        image.texel_at_offset_B(offset_B + u64::from(b));
+                    assert_eq!(found, Some((el_texel(&image, texel), b)));
+                }
+                if el_texel(&image, texel) == texel {
+                    num_texel_B += u64::from(el_size_B);
+                }
+            });
+
+            // Every byte which maps to a texel maps back to the same byte and
+            // there are exactly as many of them as there are texel bytes.
+            let mut num_found_B = 0_u64;
+            for offset_B in 0..image.size_B {
+                if let Some((texel, b)) = image.texel_at_offset_B(offset_B) {
+                    assert_eq!(
+                        image.texel_offset_B(texel) + u64::from(b),
+                        offset_B
+                    );
+                    num_found_B += 1;
+                }
+            }
+            assert_eq!(num_found_B, num_texel_B);
+        }
+    }
+
+    #[test]
+    fn test_image_texel_offsets_match_copy() {
+        let mut rand = Rand::new(0x5eed_1234_abcd_0002);
+        for image in test_images() {
+            let mut mem = vec![0_u8; image.size_B as usize];
+
+            // Fill every level and layer through the tiled copy code
+            let mut linear = Vec::new();
+            for level in 0..image.num_levels {
+                let lvl = &image.levels[level as usize];
+                let mut lvl_ext_B = image.level_extent_B(level);
+                lvl_ext_B.array_len = 1;
+
+                let mut lvl_linear = Vec::new();
+                for layer in 0..image.extent_px.array_len {
+                    let layer_linear = rand.bytes(lvl_ext_B.size_B() as usize);
+                    let layer_offset_B =
+                        image.level_layer_offset_B(level, layer) as usize;
+                    if lvl.tiling.is_tiled() {
+                        copy_linear_to_tiled(
+                            &mut mem[layer_offset_B..],
+                            &lvl.tiling,
+                            lvl_ext_B,
+                            &layer_linear,
+                        );
+                    } else {
+                        let row_B = lvl_ext_B.width as usize;
+                        for (y, row) in layer_linear.chunks(row_B).enumerate() {
+                            let dst =
+                                layer_offset_B + y * lvl.row_stride_B as usize;
+                            mem[dst..dst + row_B].copy_from_slice(row);
+                        }
+                    }
+                    lvl_linear.push(layer_linear);
+                }
+                linear.push((lvl_ext_B, lvl_linear));
+            }
+
+            let px_ext_sa = image.sample_layout.px_extent_sa();
+            let el_ext_sa = image.format.el_extent_sa();
+            let el_size_B = image.format.el_size_B() as usize;
+            for_each_texel(&image, |texel| {
+                let (lvl_ext_B, lvl_linear) = &linear[texel.level as usize];
+                let layer_linear = &lvl_linear[texel.offset_px.a as usize];
+
+                let sa_offset =
+                    image.sample_layout.sa_offset(texel.sample as u8);
+                let x_sa = texel.offset_px.x * px_ext_sa.width
+                    + u32::from(sa_offset.x);
+                let y_sa = texel.offset_px.y * px_ext_sa.height
+                    + u32::from(sa_offset.y);
+                let x_B = (x_sa / el_ext_sa.width) as usize * el_size_B;
+                let y_el = (y_sa / el_ext_sa.height) as usize;
+                let z = texel.offset_px.z as usize;
+
+                let row_B = lvl_ext_B.width as usize;
+                let plane_B = row_B * lvl_ext_B.height as usize;
+                let linear_B = z * plane_B + y_el * row_B + x_B;
+
+                let offset_B = image.texel_offset_B(texel) as usize;
+                assert_eq!(
+                    mem[offset_B..offset_B + el_size_B],
+                    layer_linear[linear_B..linear_B + el_size_B]
+                );
+            });
+        }
+    }
+
+    /// The size of a copy unit in pixels and bytes
+    ///
+    /// A unit is one element of single-sampled images and one pixel of
+    /// multisampled images.
+    fn unit_px_B(image: &Image) -> (Extent4D<units::Pixels>, (u32, u32)) {
+        let el_ext_sa = image.format.el_extent_sa();
+        let px_ext_sa = image.sample_layout.px_extent_sa();
+        let unit_px = Extent4D::new(
+            el_ext_sa.width / px_ext_sa.width.min(el_ext_sa.width),
+            el_ext_sa.height / px_ext_sa.height.min(el_ext_sa.height),
+            1,
+            1,
+        );
+        let unit_B =
+            (px_ext_sa.width * image.format.el_size_B(), px_ext_sa.height);
+        (unit_px, unit_B)
+    }
+
+    /// Returns the extent of a level in copy units with Z slices or array
+    /// layers in depth
+    fn level_extent_units(image: &Image, level: u32) -> (u32, u32, u32) {
+        let (_, unit_B) = unit_px_B(image);
+        let lvl_ext_B = image.level_extent_B(level);
+        let slices = if image.dim == ImageDim::_3D {
+            lvl_ext_B.depth
+        } else {
+            lvl_ext_B.array_len
+        };
+        (
+            lvl_ext_B.width / unit_B.0,
+            lvl_ext_B.height / unit_B.1,
+            slices,
+        )
+    }
+
+    fn slice_offset_B(
+        image: &Image,
+        x_B: u32,
+        y_B: u32,
+        slice: u32,
+    ) -> Offset4D<units::Bytes> {
+        if image.dim == ImageDim::_3D {
+            Offset4D::new(x_B, y_B, slice, 0)
+        } else {
+            Offset4D::new(x_B, y_B, 0, slice)
+        }
+    }
+
+    fn slice_offset_px(
+        image: &Image,
+        x_px: u32,
+        y_px: u32,
+        slice: u32,
+    ) -> Offset4D<units::Pixels> {
+        if image.dim == ImageDim::_3D {
+            Offset4D::new(x_px, y_px, slice, 0)
+        } else {
+            Offset4D::new(x_px, y_px, 0, slice)
+        }
+    }
+
+    /// Picks a random region of the given size in units within the limit
+    fn rand_region(rand: &mut Rand, limit: (u32, u32, u32)) -> [(u32, u32); 3] {
+        let mut pick = |limit: u32| {
+            let len = rand.range(1..limit + 1);
+            (rand.range(0..limit - len + 1), len)
+        };
+        [pick(limit.0), pick(limit.1), pick(limit.2)]
+    }
+
+    fn copy_test_images() -> Vec<Image> {
+        let ext = |w, h, d, a| Extent4D::new(w, h, d, a);
+        let mut images = test_images();
+        images.push(image(
+            ImageDim::_3D,
+            PIPE_FORMAT_R32G32_UINT,
+            ext(23, 45, 37, 1),
+            3,
+            1,
+            0,
+        ));
+        images.push(image(
+            ImageDim::_2D,
+            PIPE_FORMAT_R16_UNORM,
+            ext(50, 30, 1, 2),
+            1,
+            2,
+            0,
+        ));
+        images
+    }
+
+    #[test]
+    fn test_linear_image_round_trip() {
+        let mut rand = Rand::new(0x5eed_1234_abcd_0003);
+        for image in copy_test_images() {
+            for _ in 0..8 {
+                let level = rand.range(0..image.num_levels);
+                let (unit_px, _) = unit_px_B(&image);
+                let samples = image.sample_layout.samples();
+                let el_size_B = image.format.el_size_B();
+                let [x, y, slice] =
+                    rand_region(&mut rand, level_extent_units(&image, level));
+
+                let offset_px = slice_offset_px(
+                    &image,
+                    x.0 * unit_px.width,
+                    y.0 * unit_px.height,
+                    slice.0,
+                );
+                let mut extent_px = Extent4D::new(
+                    x.1 * unit_px.width,
+                    y.1 * unit_px.height,
+                    1,
+                    1,
+                );
+                if image.dim == ImageDim::_3D {
+                    extent_px.depth = slice.1;
+                } else {
+                    extent_px.array_len = slice.1;
+                }
+
+                // Clamp to the level for partial blocks at the edge
+                let lvl_ext_px = image.level_extent_px(level);
+                extent_px.width =
+                    extent_px.width.min(lvl_ext_px.width - offset_px.x);
+                extent_px.height =
+                    extent_px.height.min(lvl_ext_px.height - offset_px.y);
+
+                let row_B = (x.1 * samples * el_size_B) as usize
+                    + rand.range(0..3) as usize * 16;
+                let plane_B = row_B * y.1 as usize + rand.range(0..2) as usize;
+                let linear = rand.bytes(plane_B * slice.1 as usize);
+
+                let mut mem = rand.bytes(image.size_B as usize);
+                unsafe {
+                    nil_copy_linear_to_image(
+                        mem.as_mut_ptr() as *mut c_void,
+                        &image,
+                        level,
+                        offset_px,
+                        extent_px,
+                        linear.as_ptr() as *const c_void,
+                        row_B,
+                        plane_B,
+                    );
+                }
+
+                for s in 0..slice.1 {
+                    for v in 0..y.1 {
+                        for u in 0..x.1 {
+                            for sample in 0..samples {
+                                let texel = ImageTexel {
+                                    level,
+                                    offset_px: slice_offset_px(
+                                        &image,
+                                        (x.0 + u) * unit_px.width,
+                                        (y.0 + v) * unit_px.height,
+                                        slice.0 + s,
+                                    ),
+                                    sample,
+                                };
+                                let img_B =
+                                    image.texel_offset_B(texel) as usize;
+                                let lin_B = s as usize * plane_B
+                                    + v as usize * row_B
+                                    + ((u * samples + sample) * el_size_B)
+                                        as usize;
+                                let n = el_size_B as usize;
+                                assert_eq!(
+                                    mem[img_B..img_B + n],
+                                    linear[lin_B..lin_B + n]
+                                );
+                            }
+                        }
+                    }
+                }
+
+                let mut linear_out = vec![0_u8; linear.len()];
+                unsafe {
+                    nil_copy_image_to_linear(
+                        linear_out.as_mut_ptr() as *mut c_void,
+                        row_B,
+                        plane_B,
+                        mem.as_ptr() as *const c_void,
+                        &image,
+                        level,
+                        offset_px,
+                        extent_px,
+                    );
+                }
+
+                let region_B = (x.1 * samples * el_size_B) as usize;
+                for s in 0..slice.1 as usize {
+                    for v in 0..y.1 as usize {
+                        let start = s * plane_B + v * row_B;
+                        assert_eq!(
+                            linear_out[start..start + region_B],
+                            linear[start..start + region_B]
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_image_to_image(
+        rand: &mut Rand,
+        dst: &Image,
+        src: &Image,
+        dst_level: u32,
+        src_level: u32,
+    ) {
+        let (src_unit_px, src_unit_B) = unit_px_B(src);
+        let (dst_unit_px, dst_unit_B) = unit_px_B(dst);
+        assert!(src_unit_B == dst_unit_B);
+
+        let src_lim = level_extent_units(src, src_level);
+        let dst_lim = level_extent_units(dst, dst_level);
+        let lim = (
+            src_lim.0.min(dst_lim.0),
+            src_lim.1.min(dst_lim.1),
+            src_lim.2.min(dst_lim.2),
+        );
+        let [x, y, slice] = rand_region(rand, lim);
+        let src_start = (
+            rand.range(0..src_lim.0 - x.1 + 1),
+            rand.range(0..src_lim.1 - y.1 + 1),
+            rand.range(0..src_lim.2 - slice.1 + 1),
+        );
+        let dst_start = (x.0, y.0, slice.0);
+
+        let src_offset_px = slice_offset_px(
+            src,
+            src_start.0 * src_unit_px.width,
+            src_start.1 * src_unit_px.height,
+            src_start.2,
+        );
+        let dst_offset_px = slice_offset_px(
+            dst,
+            dst_start.0 * dst_unit_px.width,
+            dst_start.1 * dst_unit_px.height,
+            dst_start.2,
+        );
+        let mut extent_px = Extent4D::new(
+            x.1 * src_unit_px.width,
+            y.1 * src_unit_px.height,
+            1,
+            1,
+        );
+        if src.dim == ImageDim::_3D {
+            extent_px.depth = slice.1;
+        } else {
+            extent_px.array_len = slice.1;
+        }
+
+        let src_mem = rand.bytes(src.size_B as usize);
+        let mut dst_mem = rand.bytes(dst.size_B as usize);
+
+        let mut expected = dst_mem.clone();
+        for s in 0..slice.1 {
+            for v in 0..y.1 * src_unit_B.1 {
+                for u in 0..x.1 * src_unit_B.0 {
+                    let src_B = slice_offset_B(
+                        src,
+                        src_start.0 * src_unit_B.0 + u,
+                        src_start.1 * src_unit_B.1 + v,
+                        src_start.2 + s,
+                    );
+                    let dst_B = slice_offset_B(
+                        dst,
+                        dst_start.0 * dst_unit_B.0 + u,
+                        dst_start.1 * dst_unit_B.1 + v,
+                        dst_start.2 + s,
+                    );
+                    let src_B = src.level_byte_offset_B(src_level, src_B);
+                    let dst_B = dst.level_byte_offset_B(dst_level, dst_B);
+                    expected[dst_B as usize] = src_mem[src_B as usize];
+                }
+            }
+        }
+
+        unsafe {
+            nil_copy_image_to_image(
+                dst_mem.as_mut_ptr() as *mut c_void,
+                dst,
+                dst_level,
+                dst_offset_px,
+                src_mem.as_ptr() as *const c_void,
+                src,
+                src_level,
+                src_offset_px,
+                extent_px,
+            );
+        }
+
+        assert!(dst_mem == expected);
+    }
+
+    #[test]
+    fn test_image_to_image() {
+        let mut rand = Rand::new(0x5eed_1234_abcd_0004);
+        let ext = |w, h, d, a| Extent4D::new(w, h, d, a);
+
+        let pairs = [
+            // Same image with different tilings
+            (
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R8G8B8A8_UNORM,
+                    ext(97, 61, 1, 3),
+                    4,
+                    1,
+                    0,
+                ),
+                image_max_align(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R8G8B8A8_UNORM,
+                    ext(97, 61, 1, 3),
+                    4,
+                    1,
+                    0,
+                    512,
+                ),
+            ),
+            // Tiled to linear and back
+            (
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R16_UNORM,
+                    ext(130, 40, 1, 1),
+                    1,
+                    1,
+                    IMAGE_USAGE_LINEAR_BIT,
+                ),
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R16_UNORM,
+                    ext(130, 40, 1, 1),
+                    1,
+                    1,
+                    0,
+                ),
+            ),
+            // 3D with deep tiles to and from a 2D array
+            (
+                image(
+                    ImageDim::_3D,
+                    PIPE_FORMAT_R8_UNORM,
+                    ext(80, 33, 20, 1),
+                    2,
+                    1,
+                    0,
+                ),
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R8_UNORM,
+                    ext(80, 33, 1, 20),
+                    2,
+                    1,
+                    0,
+                ),
+            ),
+            // Sparse to regular
+            (
+                image(
+                    ImageDim::_3D,
+                    PIPE_FORMAT_R32G32_UINT,
+                    ext(40, 20, 12, 1),
+                    1,
+                    1,
+                    IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+                ),
+                image_max_align(
+                    ImageDim::_3D,
+                    PIPE_FORMAT_R32G32_UINT,
+                    ext(40, 20, 12, 1),
+                    1,
+                    1,
+                    0,
+                    2048,
+                ),
+            ),
+            // Multisampled with different tilings
+            (
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R32G32_UINT,
+                    ext(35, 19, 1, 2),
+                    1,
+                    4,
+                    0,
+                ),
+                image_max_align(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R32G32_UINT,
+                    ext(35, 19, 1, 2),
+                    1,
+                    4,
+                    0,
+                    512,
+                ),
+            ),
+            (
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R8_UNORM,
+                    ext(41, 23, 1, 1),
+                    1,
+                    8,
+                    0,
+                ),
+                image_max_align(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R8_UNORM,
+                    ext(41, 23, 1, 1),
+                    1,
+                    8,
+                    0,
+                    1024,
+                ),
+            ),
+            // Compressed to and from uncompressed with the same block size
+            (
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_DXT1_RGBA,
+                    ext(70, 36, 1, 2),
+                    3,
+                    1,
+                    0,
+                ),
+                image(
+                    ImageDim::_2D,
+                    PIPE_FORMAT_R32G32_UINT,
+                    ext(18, 9, 1, 2),
+                    3,
+                    1,
+                    0,
+                ),
+            ),
+        ];
+
+        for (a, b) in &pairs {
+            for _ in 0..8 {
+                let a_level = rand.range(0..a.num_levels);
+                let b_level = rand.range(0..b.num_levels);
+                check_image_to_image(&mut rand, a, b, a_level, b_level);
+                check_image_to_image(&mut rand, b, a, b_level, a_level);
+            }
+        }
+    }
+
+    /// Copies a random region of every tiled level of @image between tiled
+    /// and linear memory with the given config and returns both buffers
+    fn run_tiled_copies(
+        seed: u64,
+        image: &Image,
+        config: CopyConfig,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut rand = Rand::new(seed);
+        let mut results = Vec::new();
+        for level in 0..image.num_levels {
+            let tiling = image.levels[level as usize].tiling;
+            if !tiling.is_tiled() {
+                continue;
+            }
+
+            let mut lvl_ext_B = image.level_extent_B(level);
+            lvl_ext_B.array_len = 1;
+            let gob_ext_B = tiling.gob_type.extent_B();
+            let tiled_size_B =
+                lvl_ext_B.align(&tiling.extent_B()).size_B() as usize;
+
+            for i in 0..4 {
+                let mut pick = |len: u32, align: u32| {
+                    if i % 2 == 0 && len >= align {
+                        // Whole GOBs
+                        let start = rand.range(0..len / align);
+                        let end = rand.range(start + 1..len / align + 1);
+                        (start * align, end * align)
+                    } else {
+                        let start = rand.range(0..len);
+                        (start, rand.range(start + 1..len + 1))
+                    }
+                };
+                let x = pick(lvl_ext_B.width, gob_ext_B.width);
+                let y = pick(lvl_ext_B.height, gob_ext_B.height);
+                let z = pick(lvl_ext_B.depth, gob_ext_B.depth);
+                let start_B = Offset4D::new(x.0, y.0, z.0, 0);
+                let end_B = Offset4D::new(x.1, y.1, z.1, 1);
+
+                let row_B = (x.1 - x.0) as usize + rand.range(0..32) as usize;
+                let plane_B =
+                    row_B * (y.1 - y.0) as usize + rand.range(0..8) as usize;
+                let linear_size_B = plane_B * (z.1 - z.0) as usize;
+
+                let mut tiled = rand.bytes(tiled_size_B);
+                let linear = rand.bytes(linear_size_B);
+                unsafe {
+                    copy_tiled_mt::<ToTiled>(
+                        config,
+                        tiling,
+                        lvl_ext_B,
+                        tiled.as_mut_ptr() as usize,
+                        LinearPointer::new(
+                            linear.as_ptr() as usize,
+                            1,
+                            row_B,
+                            plane_B,
+                        ),
+                        start_B,
+                        end_B,
+                    );
+                }
+
+                let tiled_src = rand.bytes(tiled_size_B);
+                let mut linear = rand.bytes(linear_size_B);
+                unsafe {
+                    copy_tiled_mt::<ToLinear>(
+                        config,
+                        tiling,
+                        lvl_ext_B,
+                        tiled_src.as_ptr() as usize,
+                        LinearPointer::new(
+                            linear.as_mut_ptr() as usize,
+                            1,
+                            row_B,
+                            plane_B,
+                        ),
+                        start_B,
+                        end_B,
+                    );
+                }
+
+                results.push((tiled, linear));
+            }
+        }
+        results
+    }
+
+    fn copy_configs() -> Vec<CopyConfig> {
+        let mut configs = Vec::new();
+        for isa in CopyISA::supported() {
+            configs.push(CopyConfig {
+                isa,
+                max_threads: 1,
+                min_thread_size_B: u64::MAX,
+            });
+            configs.push(CopyConfig {
+                isa,
+                max_threads: 4,
+                min_thread_size_B: 1,
+            });
+        }
+        configs
+    }
+
+    #[test]
+    fn test_simd_mt_copies_match_scalar() {
+        let scalar = CopyConfig {
+            isa: CopyISA::Scalar,
+            max_threads: 1,
+            min_thread_size_B: u64::MAX,
+        };
+        for (i, image) in test_images().iter().enumerate() {
+            let seed = 0x5eed_1234_abcd_0100 + i as u64;
+            let expected = run_tiled_copies(seed, image, scalar);
+            for config in copy_configs() {
+                let results = run_tiled_copies(seed, image, config);
+                assert!(results == expected, "{config:?} differs from scalar");
+            }
+        }
+    }
+
+    /// Prints the throughput of a scalar copy, a single-threaded copy with the
+    /// best instruction set and a copy with one thread per CPU
+    ///
+    /// The 512x512 image fits in cache while the 4096x4096 one is mostly
+    /// limited by memory bandwidth.  Run in release mode with --ignored
+    /// --nocapture.
+    #[test]
+    #[ignore]
+    fn bench_tiled_copy() {
+        let num_cpus =
+            std::thread::available_parallelism().map_or(1, |n| n.get());
+        let scalar = CopyConfig {
+            isa: CopyISA::Scalar,
+            ..CopyConfig::new(1)
+        };
+        let configs =
+            [scalar, CopyConfig::new(1), CopyConfig::new(num_cpus as u32)];
+
+        for size in [512, 4096] {
+            let image = image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                Extent4D::new(size, size, 1, 1),
+                1,
+                1,
+                0,
+            );
+            let lvl_ext_B = image.level_extent_B(0);
+            let size_B = lvl_ext_B.size_B() as usize;
+
+            let mut tiled = vec![0_u8; image.size_B as usize];
+            let mut linear = Rand::new(1).bytes(size_B);
+            let copy = TiledCopy {
+                tiled: tiled.as_mut_ptr() as *mut c_void,
+                level_extent_B: lvl_ext_B,
+                linear: linear.as_mut_ptr() as *mut c_void,
+                linear_row_stride_B: lvl_ext_B.width as usize,
+                linear_plane_stride_B: size_B,
+                offset_B: Offset4D::new(0, 0, 0, 0),
+                extent_B: lvl_ext_B,
+                tiling: image.levels[0].tiling,
+            };
+
+            // Copy 1 GiB per config and direction
+            let iters = (1_u32 << 30) / size_B as u32;
+            for config in configs {
+                for to_tiled in [true, false] {
+                    let mut time = std::time::Instant::now();
+                    // The first iteration warms up the caches and page tables
+                    for i in 0..=iters {
+                        if i == 1 {
+                            time = std::time::Instant::now();
+                        }
+                        unsafe {
+                            if to_tiled {
+                                copy.run::<ToTiled>(config);
+                            } else {
+                                copy.run::<ToLinear>(config);
+                            }
+                        }
+                    }
+                    let secs = time.elapsed().as_secs_f64();
+                    let dir = if to_tiled { "to tiled" } else { "to linear" };
+                    println!(
+                        "{size}x{size} {:?} x{} {dir}: {:.2} GiB/s",
+                        config.isa,
+                        config.max_threads,
+                        1.0 / secs,
+                    );
+                }
+            }
+        }
+    }
+}
@@ -6,6 +6,7 @@ use crate::image::SampleLayout;
 use crate::tiling::{GOBType, Tiling};
 use crate::Minify;
 
+use std::fmt;
 use std::ops::Add;
 
 pub mod units {
@@ -103,6 +104,16 @@ impl<U> Extent4D<U> {
     }
 }
 
+impl<U> fmt::Display for Extent4D<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}x{}x{}",
+            self.width, self.height, self.depth, self.array_len
+        )
+    }
+}
+
 impl Extent4D<units::Pixels> {
     pub fn to_sa(
         self,
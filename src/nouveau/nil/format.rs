@@ -76,6 +76,11 @@ impl Format {
         self.description().colorspace == UTIL_FORMAT_COLORSPACE_SRGB
     }
 
+    pub(crate) fn name(&self) -> &'static str {
+        let name = unsafe { std::ffi::CStr::from_ptr(self.description().name) };
+        name.to_str().unwrap()
+    }
+
     pub fn supports_texturing(&self, dev: &nv_device_info) -> bool {
         if self.info().support() & NIL_FORMAT_SUPPORTS_TEXTURE_BIT == 0 {
             return false;
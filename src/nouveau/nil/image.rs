@@ -9,6 +9,7 @@ use crate::Minify;
 
 use nil_rs_bindings::*;
 use nvidia_headers::classes::{cl9097, clc597};
+use std::fmt;
 
 pub const MAX_LEVELS: usize = 16;
 
@@ -17,6 +18,7 @@ pub const IMAGE_USAGE_2D_VIEW_BIT: ImageUsageFlags = 1 << 0;
 pub const IMAGE_USAGE_LINEAR_BIT: ImageUsageFlags = 1 << 1;
 pub const IMAGE_USAGE_SPARSE_RESIDENCY_BIT: ImageUsageFlags = 1 << 2;
 pub const IMAGE_USAGE_VIDEO_BIT: ImageUsageFlags = 1 << 3;
+pub const IMAGE_USAGE_COMPRESSIBLE_BIT: ImageUsageFlags = 1 << 4;
 
 #[derive(Clone, Debug, Copy, PartialEq, Default)]
 #[repr(u8)]
@@ -41,6 +43,31 @@ pub enum SampleLayout {
     Invalid,
 }
 
+impl fmt::Display for ImageDim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDim::_1D => write!(f, "1D"),
+            ImageDim::_2D => write!(f, "2D"),
+            ImageDim::_3D => write!(f, "3D"),
+        }
+    }
+}
+
+impl fmt::Display for SampleLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleLayout::_1x1 => write!(f, "1x1"),
+            SampleLayout::_2x1 => write!(f, "2x1"),
+            SampleLayout::_2x1D3d => write!(f, "2x1_d3d"),
+            SampleLayout::_2x2 => write!(f, "2x2"),
+            SampleLayout::_4x2 => write!(f, "4x2"),
+            SampleLayout::_4x2D3d => write!(f, "4x2_d3d"),
+            SampleLayout::_4x4 => write!(f, "4x4"),
+            SampleLayout::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct SampleOffset {
     pub x: u8,
@@ -166,6 +193,16 @@ pub struct ImageLevel {
     pub row_stride_B: u32,
 }
 
+impl fmt::Display for ImageLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "offset_B={:#x} row_stride_B={:#x} tiling={}",
+            self.offset_B, self.row_stride_B, self.tiling
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Image {
@@ -184,6 +221,31 @@ pub struct Image {
     pub pte_kind: u8,
 }
 
+/// Writes the layout of the image with one field per line
+///
+/// The output is used for the golden layout tests so it needs to stay stable.
+/// Only the first num_levels levels are written.
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "dim: {}", self.dim)?;
+        writeln!(f, "format: {}", self.format.name())?;
+        writeln!(f, "extent_px: {}", self.extent_px)?;
+        writeln!(f, "sample_layout: {}", self.sample_layout)?;
+        writeln!(f, "num_levels: {}", self.num_levels)?;
+        writeln!(f, "mip_tail_first_lod: {}", self.mip_tail_first_lod)?;
+        writeln!(f, "array_stride_B: {:#x}", self.array_stride_B)?;
+        writeln!(f, "align_B: {:#x}", self.align_B)?;
+        writeln!(f, "size_B: {:#x}", self.size_B)?;
+        writeln!(f, "compressed: {}", self.compressed)?;
+        writeln!(f, "tile_mode: {:#x}", self.tile_mode)?;
+        writeln!(f, "pte_kind: {:#x}", self.pte_kind)?;
+        for l in 0..self.num_levels {
+            writeln!(f, "level {l}: {}", self.levels[l as usize])?;
+        }
+        Ok(())
+    }
+}
+
 impl Image {
     #[no_mangle]
     pub extern "C" fn nil_image_new(
@@ -236,13 +298,8 @@ impl Image {
             if info.modifier == DRM_FORMAT_MOD_LINEAR {
                 Tiling::default()
             } else {
-                let bl_mod =
-                    BlockLinearModifier::try_from(info.modifier).unwrap();
-
-                // We don't support compression yet
-                assert!(bl_mod.compression_type() == CompressionType::None);
-
-                bl_mod
+                BlockLinearModifier::try_from(info.modifier)
+                    .unwrap()
                     .tiling()
                     .clamp(info.extent_px.to_B(info.format, sample_layout))
             }
@@ -390,6 +447,25 @@ impl Image {
         }
 
         if image.levels[0].tiling.is_tiled() {
+            image.compressed = if info.modifier != DRM_FORMAT_MOD_INVALID {
+                let bl_mod =
+                    BlockLinearModifier::try_from(info.modifier).unwrap();
+                let compression_type = bl_mod.compression_type();
+                assert!(
+                    compression_type == CompressionType::None
+                        || compression_type == CompressionType::for_dev(dev)
+                );
+                compression_type != CompressionType::None
+            } else {
+                (info.usage & IMAGE_USAGE_COMPRESSIBLE_BIT) != 0
+                    && (info.usage & IMAGE_USAGE_SPARSE_RESIDENCY_BIT) == 0
+                    && Self::supports_compression(
+                        dev,
+                        info.format,
+                        info.samples,
+                    )
+            };
+
             image.pte_kind = Self::choose_pte_kind(
                 dev,
                 info.format,
@@ -412,6 +488,18 @@ impl Image {
             if image.pte_kind >= 0xb && image.pte_kind <= 0xe {
                 image.align_B = std::cmp::max(image.align_B, 1 << 16);
             }
+
+            // The kernel allocates compression tags for whole big pages so
+            // compressed images need to be aligned and sized to match.  Before
+            // Turing, a compression tag line covers 128 KiB.
+            if image.compressed {
+                let comp_align_B = if dev.cls_eng3d >= clc597::TURING_A {
+                    1 << 16
+                } else {
+                    1 << 17
+                };
+                image.align_B = std::cmp::max(image.align_B, comp_align_B);
+            }
         } else {
             // Linear images need to be aligned to 128B for render to work
             image.align_B = std::cmp::max(image.align_B, 128);
@@ -423,7 +511,7 @@ impl Image {
     }
 
     /// The size in bytes of an extent at a given level.
-    fn level_extent_B(&self, level: u32) -> Extent4D<units::Bytes> {
+    pub(crate) fn level_extent_B(&self, level: u32) -> Extent4D<units::Bytes> {
         self.level_extent_px(level)
             .to_B(self.format, self.sample_layout)
     }
@@ -671,6 +759,18 @@ impl Image {
         image_2d_out
     }
 
+    /// Returns true if images with the given format and sample count can use
+    /// a compressible PTE kind on the given device
+    pub fn supports_compression(
+        dev: &nil_rs_bindings::nv_device_info,
+        format: Format,
+        samples: u32,
+    ) -> bool {
+        CompressionType::for_dev(dev) != CompressionType::None
+            && Self::choose_pte_kind(dev, format, samples, true)
+                != Self::choose_pte_kind(dev, format, samples, false)
+    }
+
     pub fn choose_pte_kind(
         dev: &nil_rs_bindings::nv_device_info,
         format: Format,
@@ -729,7 +829,13 @@ impl Image {
                     NV_MMU_PTE_KIND_S8
                 }
             }
-            _ => NV_MMU_PTE_KIND_GENERIC_MEMORY,
+            _ => {
+                if compressed {
+                    NV_MMU_PTE_KIND_GENERIC_MEMORY_COMPRESSIBLE_DISABLE_PLC
+                } else {
+                    NV_MMU_PTE_KIND_GENERIC_MEMORY
+                }
+            }
         }
         .try_into()
         .unwrap()
@@ -933,3 +1039,323 @@ pub struct View {
     // VK_EXT_image_view_min_lod
     pub min_lod_clamp: f32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::dev;
+    use nvidia_headers::classes::{clb197, clc797};
+    use std::fmt::Write;
+
+    // Golden files are regenerated by running the tests with
+    // NIL_UPDATE_GOLDEN set to the golden/ directory in the source tree.
+    const DEVICES: [(&str, nv_device_type, u16, &str); 3] = [
+        (
+            "maxwell",
+            NV_DEVICE_TYPE_DIS,
+            clb197::MAXWELL_B,
+            include_str!("golden/image_layout_maxwell.txt"),
+        ),
+        (
+            "turing",
+            NV_DEVICE_TYPE_DIS,
+            clc597::TURING_A,
+            include_str!("golden/image_layout_turing.txt"),
+        ),
+        (
+            "ampere_soc",
+            NV_DEVICE_TYPE_SOC,
+            clc797::AMPERE_B,
+            include_str!("golden/image_layout_ampere_soc.txt"),
+        ),
+    ];
+
+    const FORMATS: [pipe_format; 10] = [
+        PIPE_FORMAT_R8_UNORM,
+        PIPE_FORMAT_R8G8B8A8_UNORM,
+        PIPE_FORMAT_R16G16B16A16_FLOAT,
+        PIPE_FORMAT_R32G32B32A32_FLOAT,
+        PIPE_FORMAT_Z16_UNORM,
+        PIPE_FORMAT_Z24_UNORM_S8_UINT,
+        PIPE_FORMAT_Z32_FLOAT_S8X24_UINT,
+        PIPE_FORMAT_S8_UINT,
+        PIPE_FORMAT_DXT1_RGBA,
+        PIPE_FORMAT_ASTC_12x10,
+    ];
+
+    struct Shape {
+        dim: ImageDim,
+        extent_px: Extent4D<units::Pixels>,
+        levels: u32,
+        samples: u32,
+        usage: ImageUsageFlags,
+        max_alignment_B: u32,
+    }
+
+    const fn shape(
+        dim: ImageDim,
+        extent_px: Extent4D<units::Pixels>,
+        levels: u32,
+        samples: u32,
+        usage: ImageUsageFlags,
+    ) -> Shape {
+        Shape {
+            dim,
+            extent_px,
+            levels,
+            samples,
+            usage,
+            max_alignment_B: 0,
+        }
+    }
+
+    const fn ext(w: u32, h: u32, d: u32, a: u32) -> Extent4D<units::Pixels> {
+        Extent4D::new(w, h, d, a)
+    }
+
+    const SHAPES: [Shape; 15] = [
+        shape(ImageDim::_1D, ext(1000, 1, 1, 1), 10, 1, 0),
+        shape(ImageDim::_1D, ext(64, 1, 1, 6), 7, 1, 0),
+        shape(ImageDim::_2D, ext(1, 1, 1, 1), 1, 1, 0),
+        shape(ImageDim::_2D, ext(1000, 600, 1, 1), 10, 1, 0),
+        shape(ImageDim::_2D, ext(256, 256, 1, 6), 9, 1, 0),
+        shape(ImageDim::_2D, ext(333, 77, 1, 3), 1, 2, 0),
+        shape(ImageDim::_2D, ext(333, 77, 1, 1), 1, 4, 0),
+        shape(ImageDim::_2D, ext(256, 128, 1, 1), 1, 8, 0),
+        shape(ImageDim::_3D, ext(64, 64, 64, 1), 7, 1, 0),
+        shape(
+            ImageDim::_3D,
+            ext(100, 50, 17, 1),
+            5,
+            1,
+            IMAGE_USAGE_2D_VIEW_BIT,
+        ),
+        shape(
+            ImageDim::_2D,
+            ext(1920, 1080, 1, 1),
+            1,
+            1,
+            IMAGE_USAGE_LINEAR_BIT,
+        ),
+        shape(
+            ImageDim::_2D,
+            ext(1024, 1024, 1, 2),
+            11,
+            1,
+            IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+        ),
+        shape(
+            ImageDim::_3D,
+            ext(128, 128, 128, 1),
+            8,
+            1,
+            IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+        ),
+        shape(
+            ImageDim::_2D,
+            ext(1920, 1080, 1, 1),
+            1,
+            4,
+            IMAGE_USAGE_COMPRESSIBLE_BIT,
+        ),
+        Shape {
+            max_alignment_B: 1 << 12,
+            ..shape(ImageDim::_2D, ext(4096, 4096, 1, 1), 4, 1, 0)
+        },
+    ];
+
+    /// Filters out combinations which Vulkan doesn't allow
+    fn is_valid(format: Format, shape: &Shape) -> bool {
+        let el_extent_sa = format.el_extent_sa();
+        let is_block_compressed =
+            el_extent_sa.width > 1 || el_extent_sa.height > 1;
+        if is_block_compressed
+            && (shape.dim != ImageDim::_2D || shape.samples > 1)
+        {
+            return false;
+        }
+
+        if format.is_depth_or_stencil()
+            && (shape.dim == ImageDim::_3D
+                || (shape.usage & IMAGE_USAGE_LINEAR_BIT) != 0)
+        {
+            return false;
+        }
+
+        true
+    }
+
+    fn write_image(
+        out: &mut String,
+        dev: &nv_device_info,
+        infos: &[ImageInitInfo],
+        plane: usize,
+    ) {
+        let info = &infos[plane];
+        write!(
+            out,
+            "## {} {} {} levels={} samples={} usage={:#x}",
+            info.dim,
+            info.format.name(),
+            info.extent_px,
+            info.levels,
+            info.samples,
+            info.usage,
+        )
+        .unwrap();
+        if info.modifier != DRM_FORMAT_MOD_INVALID {
+            write!(out, " modifier={:#x}", info.modifier).unwrap();
+        }
+        if info.max_alignment_B != 0 {
+            write!(out, " max_alignment_B={:#x}", info.max_alignment_B)
+                .unwrap();
+        }
+        if infos.len() > 1 {
+            write!(out, " plane={}/{}", plane, infos.len()).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        let image = Image::new(dev, infos, plane);
+        writeln!(out, "{image}").unwrap();
+    }
+
+    fn layouts_for_dev(dev: &nv_device_info) -> String {
+        let mut out = String::new();
+
+        for p_format in FORMATS {
+            let format = Format::try_from(p_format).unwrap();
+            for shape in &SHAPES {
+                if !is_valid(format, shape) {
+                    continue;
+                }
+
+                let info = ImageInitInfo {
+                    dim: shape.dim,
+                    format,
+                    extent_px: shape.extent_px,
+                    levels: shape.levels,
+                    samples: shape.samples,
+                    usage: shape.usage,
+                    modifier: DRM_FORMAT_MOD_INVALID,
+                    explicit_row_stride_B: 0,
+                    max_alignment_B: shape.max_alignment_B,
+                };
+                write_image(&mut out, dev, std::slice::from_ref(&info), 0);
+            }
+        }
+
+        for p_format in
+            [PIPE_FORMAT_R8G8B8A8_UNORM, PIPE_FORMAT_Z24_UNORM_S8_UINT]
+        {
+            let format = Format::try_from(p_format).unwrap();
+            let mut mods = [0; MAX_DRM_FORMAT_MODS];
+            let mut mod_count = MAX_DRM_FORMAT_MODS;
+            drm_format_mods_for_format(
+                dev,
+                format,
+                true,
+                &mut mod_count,
+                &mut mods,
+            );
+            for modifier in &mods[..mod_count] {
+                let info = ImageInitInfo {
+                    dim: ImageDim::_2D,
+                    format,
+                    extent_px: Extent4D::new(1920, 1080, 1, 1),
+                    levels: 1,
+                    samples: 1,
+                    usage: 0,
+                    modifier: *modifier,
+                    explicit_row_stride_B: 0,
+                    max_alignment_B: 0,
+                };
+                write_image(&mut out, dev, std::slice::from_ref(&info), 0);
+            }
+        }
+
+        // NV12 and 3-plane 4:2:0 video images
+        let plane = |p_format, w, h| ImageInitInfo {
+            dim: ImageDim::_2D,
+            format: Format::try_from(p_format).unwrap(),
+            extent_px: Extent4D::new(w, h, 1, 1),
+            levels: 1,
+            samples: 1,
+            usage: IMAGE_USAGE_VIDEO_BIT,
+            modifier: DRM_FORMAT_MOD_INVALID,
+            explicit_row_stride_B: 0,
+            max_alignment_B: 0,
+        };
+        let planar_images = [
+            vec![
+                plane(PIPE_FORMAT_R8_UNORM, 1920, 1080),
+                plane(PIPE_FORMAT_R8G8_UNORM, 960, 540),
+            ],
+            vec![
+                plane(PIPE_FORMAT_R8_UNORM, 1280, 720),
+                plane(PIPE_FORMAT_R8_UNORM, 640, 360),
+                plane(PIPE_FORMAT_R8_UNORM, 640, 360),
+            ],
+        ];
+        for infos in &planar_images {
+            for p in 0..infos.len() {
+                write_image(&mut out, dev, infos, p);
+            }
+        }
+
+        out
+    }
+
+    fn check_golden(name: &str, expected: &str, actual: &str) {
+        if expected == actual {
+            return;
+        }
+
+        if let Some(dir) = std::env::var_os("NIL_UPDATE_GOLDEN") {
+            let path = std::path::Path::new(&dir)
+                .join(format!("image_layout_{name}.txt"));
+            std::fs::write(path, actual).unwrap();
+            return;
+        }
+
+        // Report the first image which changed rather than the whole file
+        let mut expected_images = expected.split("\n\n");
+        let mut actual_images = actual.split("\n\n");
+        loop {
+            let (e, a) = match (expected_images.next(), actual_images.next()) {
+                (None, None) => break,
+                (Some(e), Some(a)) if e == a => continue,
+                (e, a) => (e.unwrap_or("<none>"), a.unwrap_or("<none>")),
+            };
+            panic!(
+                "Image layout changed on {name}\n\
+                 --- expected\n{e}\n+++ actual\n{a}\n\
+                 If this is intended, re-run with NIL_UPDATE_GOLDEN set \
+                 to the nil/golden directory and review the diff."
+            );
+        }
+
+        // Splitting is lossless so different strings always differ in at least
+        // one image.
+        unreachable!();
+    }
+
+    #[test]
+    #[should_panic(expected = "Image layout changed on extra")]
+    fn test_golden_extra_image() {
+        check_golden("extra", "image a\n\nimage b", "image a\n\nimage b\n\nc");
+    }
+
+    #[test]
+    #[should_panic(expected = "Image layout changed on missing")]
+    fn test_golden_missing_image() {
+        check_golden("missing", "image a\n\nimage b", "image a");
+    }
+
+    #[test]
+    fn test_image_layout_golden() {
+        for (name, type_, cls_eng3d, expected) in DEVICES {
+            let actual = layouts_for_dev(&dev(type_, cls_eng3d));
+            check_golden(name, expected, &actual);
+        }
+    }
+}
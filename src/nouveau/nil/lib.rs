@@ -1,14 +1,30 @@
 // Copyright © 2024 Collabora, Ltd.
 // SPDX-License-Identifier: MIT
 
+extern crate bitview;
 extern crate nil_rs_bindings;
 extern crate nvidia_headers;
 
+use bitview::SetFieldU64;
+use std::ops::Range;
+
+macro_rules! set_enum {
+    ($th:expr, $cls:ident, $field:ident, $enum:ident) => {
+        paste::paste! {
+            $th.set_field($cls::$field, $cls::[<$field _ $enum>])
+        }
+    };
+}
+
 mod copy;
 mod extent;
 mod format;
 mod image;
 mod modifiers;
+mod sampler;
+mod sparse;
+#[cfg(test)]
+mod test_util;
 mod tic;
 mod tiling;
 
@@ -36,3 +52,16 @@ impl Minify<u32> for u32 {
         std::cmp::max(1, self >> level)
     }
 }
+
+const FIXED_FRAC_BITS: usize = 8;
+
+pub(crate) trait SetUFixed {
+    fn set_ufixed(&mut self, range: Range<usize>, val: f32);
+}
+
+impl<T: SetFieldU64> SetUFixed for T {
+    fn set_ufixed(&mut self, range: Range<usize>, val: f32) {
+        let bits = bitview::ufixed_to_bits(val, range.len(), FIXED_FRAC_BITS);
+        self.set_field_u64(range, bits);
+    }
+}
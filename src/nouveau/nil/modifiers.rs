@@ -8,7 +8,7 @@ use crate::tiling::{GOBType, Tiling};
 use bitview::*;
 use nvidia_headers::classes::{cl9097, clc597};
 
-pub const MAX_DRM_FORMAT_MODS: usize = 7;
+pub const MAX_DRM_FORMAT_MODS: usize = 13;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -90,7 +90,26 @@ impl TryFrom<u64> for CompressionType {
             2 => Ok(CompressionType::ROP3DTwo),
             3 => Ok(CompressionType::CDEHorizontal),
             4 => Ok(CompressionType::CDEVertical),
-            _ => Err("Invalid gob/kind version"),
+            _ => Err("Invalid compression type"),
+        }
+    }
+}
+
+impl CompressionType {
+    /// Returns the compression type used for compressed block-linear images
+    /// on the given device or None if the device doesn't support compression.
+    ///
+    /// Only discrete GPUs have the compression tag memory required for ROP/3D
+    /// compression.  Tegra uses CDE instead, which we don't support.
+    pub fn for_dev(dev: &nil_rs_bindings::nv_device_info) -> CompressionType {
+        if dev.type_ != nil_rs_bindings::NV_DEVICE_TYPE_DIS {
+            CompressionType::None
+        } else if dev.cls_eng3d >= clc597::TURING_A {
+            CompressionType::ROP3DTwo
+        } else if dev.cls_eng3d >= cl9097::FERMI_A {
+            CompressionType::ROP3DOne
+        } else {
+            CompressionType::None
         }
     }
 }
@@ -118,6 +137,10 @@ impl TryFrom<u64> for BlockLinearModifier {
             || bv.get_bit_range_u64(26..56) != 0
         {
             Err("unknown reserved bits")
+        } else if CompressionType::try_from(bv.get_bit_range_u64(23..26))
+            .is_err()
+        {
+            Err("unknown compression type")
         } else {
             Ok(BlockLinearModifier { drm_modifier })
         }
@@ -184,15 +207,21 @@ impl BlockLinearModifier {
 pub extern "C" fn nil_drm_format_mods_for_format(
     dev: &nil_rs_bindings::nv_device_info,
     format: Format,
+    allow_compression: bool,
     mod_count: &mut usize,
     mods: &mut [u64; MAX_DRM_FORMAT_MODS],
 ) {
-    drm_format_mods_for_format(dev, format, mod_count, mods)
+    drm_format_mods_for_format(dev, format, allow_compression, mod_count, mods)
 }
 
+/// Returns the modifiers supported for the given format, best first
+///
+/// Compressed modifiers are only advertised if allow_compression is set.
+/// Compression is still experimental so drivers have to opt in.
 pub fn drm_format_mods_for_format(
     dev: &nil_rs_bindings::nv_device_info,
     format: Format,
+    allow_compression: bool,
     mod_count: &mut usize,
     mods: &mut [u64; MAX_DRM_FORMAT_MODS],
 ) {
@@ -200,46 +229,60 @@ pub fn drm_format_mods_for_format(
     *mod_count = 0;
 
     if format.is_depth_or_stencil() {
-        return;
-    }
-
-    if !format.supports_color_targets(dev) {
-        return;
-    }
+        if !format.supports_depth_stencil(dev) {
+            return;
+        }
+    } else {
+        if !format.supports_color_targets(dev) {
+            return;
+        }
 
-    // These formats don't have a corresponding fourcc format
-    let p_format: nil_rs_bindings::pipe_format = format.into();
-    if p_format == nil_rs_bindings::PIPE_FORMAT_R11G11B10_FLOAT
-        || p_format == nil_rs_bindings::PIPE_FORMAT_R9G9B9E5_FLOAT
-    {
-        return;
+        // These formats don't have a corresponding fourcc format
+        let p_format: nil_rs_bindings::pipe_format = format.into();
+        if p_format == nil_rs_bindings::PIPE_FORMAT_R11G11B10_FLOAT
+            || p_format == nil_rs_bindings::PIPE_FORMAT_R9G9B9E5_FLOAT
+        {
+            return;
+        }
     }
 
-    let compression_type = CompressionType::None;
     let sector_layout = SectorLayout::for_dev(dev);
     let gob_kind_version = GOBKindVersion::for_dev(dev);
-    let pte_kind = Image::choose_pte_kind(dev, format, 1, false);
-
-    // We assume bigger tiling is better
-    for i in 0..6 {
-        let height_log2 = 5 - i;
-
-        let bl_mod = BlockLinearModifier::block_linear_2d(
-            compression_type,
-            sector_layout,
-            gob_kind_version,
-            pte_kind,
-            height_log2,
-        );
 
+    let mut compression_types = vec![CompressionType::None];
+    if allow_compression && Image::supports_compression(dev, format, 1) {
+        // Compressed modifiers go first so they are preferred
+        compression_types.insert(0, CompressionType::for_dev(dev));
+    }
+
+    for compression_type in compression_types {
+        let compressed = compression_type != CompressionType::None;
+        let pte_kind = Image::choose_pte_kind(dev, format, 1, compressed);
+
+        // We assume bigger tiling is better
+        for i in 0..6 {
+            let height_log2 = 5 - i;
+
+            let bl_mod = BlockLinearModifier::block_linear_2d(
+                compression_type,
+                sector_layout,
+                gob_kind_version,
+                pte_kind,
+                height_log2,
+            );
+
+            assert!(*mod_count < max_mod_count);
+            mods[*mod_count] = bl_mod.drm_modifier;
+            *mod_count += 1;
+        }
+    }
+
+    // The hardware can't render depth or stencil to linear images
+    if !format.is_depth_or_stencil() {
         assert!(*mod_count < max_mod_count);
-        mods[*mod_count] = bl_mod.drm_modifier;
+        mods[*mod_count] = DRM_FORMAT_MOD_LINEAR;
         *mod_count += 1;
     }
-
-    assert!(*mod_count < max_mod_count);
-    mods[*mod_count] = DRM_FORMAT_MOD_LINEAR;
-    *mod_count += 1;
 }
 
 pub fn drm_format_mod_is_supported(
@@ -247,8 +290,12 @@ pub fn drm_format_mod_is_supported(
     format: Format,
     modifier: u64,
 ) -> bool {
+    if format.is_depth_or_stencil() && !format.supports_depth_stencil(dev) {
+        return false;
+    }
+
     if modifier == DRM_FORMAT_MOD_LINEAR {
-        return true;
+        return !format.is_depth_or_stencil();
     }
 
     let Ok(bl_mod) = BlockLinearModifier::try_from(modifier) else {
@@ -267,11 +314,18 @@ pub fn drm_format_mod_is_supported(
         return false;
     }
 
-    if bl_mod.compression_type() != CompressionType::None {
-        return false;
+    let compressed = bl_mod.compression_type() != CompressionType::None;
+    if compressed {
+        if bl_mod.compression_type() != CompressionType::for_dev(dev) {
+            return false;
+        }
+
+        if !Image::supports_compression(dev, format, 1) {
+            return false;
+        }
     }
 
-    let pte_kind = Image::choose_pte_kind(dev, format, 1, false);
+    let pte_kind = Image::choose_pte_kind(dev, format, 1, compressed);
     if bl_mod.pte_kind() != pte_kind {
         return false;
     }
@@ -279,6 +333,13 @@ pub fn drm_format_mod_is_supported(
     return true;
 }
 
+fn modifier_is_compressed(modifier: u64) -> bool {
+    match BlockLinearModifier::try_from(modifier) {
+        Ok(bl_mod) => bl_mod.compression_type() != CompressionType::None,
+        Err(_) => false,
+    }
+}
+
 fn score_drm_format_mod(modifier: u64) -> u32 {
     if modifier == DRM_FORMAT_MOD_LINEAR {
         return 1;
@@ -299,6 +360,7 @@ fn score_drm_format_mod(modifier: u64) -> u32 {
 pub fn select_best_drm_format_mod(
     dev: &nil_rs_bindings::nv_device_info,
     format: Format,
+    allow_compression: bool,
     modifiers: &[u64],
 ) -> u64 {
     let mut best = DRM_FORMAT_MOD_INVALID;
@@ -309,6 +371,10 @@ pub fn select_best_drm_format_mod(
             continue;
         }
 
+        if !allow_compression && modifier_is_compressed(modifier) {
+            continue;
+        }
+
         let score = score_drm_format_mod(modifier);
         if score > best_score {
             best = modifier;
@@ -323,10 +389,280 @@ pub fn select_best_drm_format_mod(
 pub extern "C" fn nil_select_best_drm_format_mod(
     dev: &nil_rs_bindings::nv_device_info,
     format: Format,
+    allow_compression: bool,
     modifier_count: usize,
     modifiers: *const u64,
 ) -> u64 {
     let modifiers =
         unsafe { std::slice::from_raw_parts(modifiers, modifier_count) };
-    select_best_drm_format_mod(dev, format, modifiers)
+    select_best_drm_format_mod(dev, format, allow_compression, modifiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extent::Extent4D;
+    use crate::image::{ImageDim, ImageInitInfo};
+    use crate::test_util::dev;
+    use nil_rs_bindings::*;
+    use nvidia_headers::classes::clb197;
+
+    fn mods_for_format_opt(
+        dev: &nv_device_info,
+        format: Format,
+        allow_compression: bool,
+    ) -> Vec<u64> {
+        let mut mods = [0; MAX_DRM_FORMAT_MODS];
+        let mut mod_count = MAX_DRM_FORMAT_MODS;
+        drm_format_mods_for_format(
+            dev,
+            format,
+            allow_compression,
+            &mut mod_count,
+            &mut mods,
+        );
+        mods[..mod_count].to_vec()
+    }
+
+    fn mods_for_format(dev: &nv_device_info, format: Format) -> Vec<u64> {
+        mods_for_format_opt(dev, format, true)
+    }
+
+    fn rgba8() -> Format {
+        PIPE_FORMAT_R8G8B8A8_UNORM.try_into().unwrap()
+    }
+
+    fn depth_stencil_formats() -> Vec<Format> {
+        [
+            PIPE_FORMAT_Z16_UNORM,
+            PIPE_FORMAT_Z24X8_UNORM,
+            PIPE_FORMAT_X8Z24_UNORM,
+            PIPE_FORMAT_Z24_UNORM_S8_UINT,
+            PIPE_FORMAT_S8_UINT_Z24_UNORM,
+            PIPE_FORMAT_Z32_FLOAT,
+            PIPE_FORMAT_Z32_FLOAT_S8X24_UINT,
+            PIPE_FORMAT_S8_UINT,
+        ]
+        .into_iter()
+        .map(|p_format| p_format.try_into().unwrap())
+        .collect()
+    }
+
+    fn image_2d(
+        dev: &nv_device_info,
+        format: Format,
+        width: u32,
+        height: u32,
+        modifier: u64,
+        explicit_row_stride_B: u32,
+    ) -> Image {
+        let info = ImageInitInfo {
+            dim: ImageDim::_2D,
+            format,
+            extent_px: Extent4D::new(width, height, 1, 1),
+            levels: 1,
+            samples: 1,
+            usage: 0,
+            modifier,
+            explicit_row_stride_B,
+            max_alignment_B: 0,
+        };
+        Image::new(dev, std::slice::from_ref(&info), 0)
+    }
+
+    #[test]
+    fn test_compressed_mods_advertised() {
+        for (cls_eng3d, compression_type) in [
+            (clb197::MAXWELL_B, CompressionType::ROP3DOne),
+            (clc597::TURING_A, CompressionType::ROP3DTwo),
+        ] {
+            let dev = dev(NV_DEVICE_TYPE_DIS, cls_eng3d);
+            let mods = mods_for_format(&dev, rgba8());
+            assert_eq!(mods.len(), MAX_DRM_FORMAT_MODS);
+
+            let bl_mod = BlockLinearModifier::try_from(mods[0]).unwrap();
+            assert!(bl_mod.compression_type() == compression_type);
+            assert_eq!(
+                bl_mod.pte_kind(),
+                Image::choose_pte_kind(&dev, rgba8(), 1, true)
+            );
+
+            for &modifier in &mods {
+                assert!(drm_format_mod_is_supported(&dev, rgba8(), modifier));
+            }
+            assert_eq!(
+                select_best_drm_format_mod(&dev, rgba8(), true, &mods),
+                mods[0]
+            );
+        }
+    }
+
+    #[test]
+    fn test_compression_is_opt_in() {
+        let dev = dev(NV_DEVICE_TYPE_DIS, clc597::TURING_A);
+        let mods = mods_for_format_opt(&dev, rgba8(), false);
+        assert_eq!(mods.len(), 7);
+        assert!(!mods.iter().any(|&m| modifier_is_compressed(m)));
+
+        // Compressed modifiers are still valid for import but we never pick
+        // them unless asked to.
+        let all_mods = mods_for_format(&dev, rgba8());
+        assert!(modifier_is_compressed(all_mods[0]));
+        assert!(drm_format_mod_is_supported(&dev, rgba8(), all_mods[0]));
+        assert_eq!(
+            select_best_drm_format_mod(&dev, rgba8(), false, &all_mods),
+            mods[0]
+        );
+    }
+
+    #[test]
+    fn test_no_compression_on_igp() {
+        let igp = dev(NV_DEVICE_TYPE_IGP, clc597::TURING_A);
+        let mods = mods_for_format(&igp, rgba8());
+        assert_eq!(mods.len(), 7);
+        for &modifier in &mods {
+            if let Ok(bl_mod) = BlockLinearModifier::try_from(modifier) {
+                assert!(bl_mod.compression_type() == CompressionType::None);
+            }
+        }
+
+        let dis = dev(NV_DEVICE_TYPE_DIS, clc597::TURING_A);
+        let compressed = mods_for_format(&dis, rgba8())[0];
+        assert!(!drm_format_mod_is_supported(&igp, rgba8(), compressed));
+    }
+
+    #[test]
+    fn test_reject_bad_compression() {
+        let turing = dev(NV_DEVICE_TYPE_DIS, clc597::TURING_A);
+        let maxwell = dev(NV_DEVICE_TYPE_DIS, clb197::MAXWELL_B);
+
+        // Compression types from another GPU generation
+        let compressed = mods_for_format(&maxwell, rgba8())[0];
+        assert!(!drm_format_mod_is_supported(&turing, rgba8(), compressed));
+
+        // Compressed modifiers must use a compressible PTE kind
+        let bad_kind = BlockLinearModifier::block_linear_2d(
+            CompressionType::ROP3DTwo,
+            SectorLayout::for_dev(&turing),
+            GOBKindVersion::for_dev(&turing),
+            Image::choose_pte_kind(&turing, rgba8(), 1, false),
+            4,
+        );
+        assert!(!drm_format_mod_is_supported(
+            &turing,
+            rgba8(),
+            bad_kind.drm_modifier
+        ));
+
+        // Reserved compression types
+        let reserved = mods_for_format(&turing, rgba8())[0] | (7 << 23);
+        assert!(BlockLinearModifier::try_from(reserved).is_err());
+        assert!(!drm_format_mod_is_supported(&turing, rgba8(), reserved));
+    }
+
+    #[test]
+    fn test_compressed_image() {
+        let dev = dev(NV_DEVICE_TYPE_DIS, clb197::MAXWELL_B);
+        let modifier = mods_for_format(&dev, rgba8())[0];
+        let image = image_2d(&dev, rgba8(), 100, 100, modifier, 0);
+        assert!(image.compressed);
+        assert_eq!(
+            image.pte_kind,
+            BlockLinearModifier::try_from(modifier).unwrap().pte_kind()
+        );
+        assert_eq!(image.align_B, 1 << 17);
+        assert_eq!(image.size_B % (1 << 17), 0);
+    }
+
+    #[test]
+    fn test_depth_stencil_mods() {
+        for (type_, cls_eng3d) in [
+            (NV_DEVICE_TYPE_IGP, clc597::TURING_A),
+            (NV_DEVICE_TYPE_DIS, clb197::MAXWELL_B),
+            (NV_DEVICE_TYPE_DIS, clc597::TURING_A),
+        ] {
+            let dev = dev(type_, cls_eng3d);
+            for format in depth_stencil_formats() {
+                let mods = mods_for_format(&dev, format);
+                assert!(!mods.is_empty());
+                assert!(!mods.contains(&DRM_FORMAT_MOD_LINEAR));
+                assert!(!drm_format_mod_is_supported(
+                    &dev,
+                    format,
+                    DRM_FORMAT_MOD_LINEAR
+                ));
+
+                for &modifier in &mods {
+                    assert!(drm_format_mod_is_supported(
+                        &dev, format, modifier
+                    ));
+
+                    let bl_mod =
+                        BlockLinearModifier::try_from(modifier).unwrap();
+                    let compressed =
+                        bl_mod.compression_type() != CompressionType::None;
+                    assert_eq!(
+                        bl_mod.pte_kind(),
+                        Image::choose_pte_kind(&dev, format, 1, compressed)
+                    );
+                }
+
+                // Color modifiers have the wrong PTE kind for depth/stencil
+                for modifier in mods_for_format(&dev, rgba8()) {
+                    if modifier != DRM_FORMAT_MOD_LINEAR
+                        && Image::choose_pte_kind(&dev, format, 1, false)
+                            != Image::choose_pte_kind(&dev, rgba8(), 1, false)
+                    {
+                        assert!(!mods.contains(&modifier));
+                        assert!(!drm_format_mod_is_supported(
+                            &dev, format, modifier
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_depth_stencil_export_import() {
+        for (type_, cls_eng3d) in [
+            (NV_DEVICE_TYPE_IGP, clc597::TURING_A),
+            (NV_DEVICE_TYPE_DIS, clb197::MAXWELL_B),
+            (NV_DEVICE_TYPE_DIS, clc597::TURING_A),
+        ] {
+            let dev = dev(type_, cls_eng3d);
+            for format in depth_stencil_formats() {
+                let mods = mods_for_format(&dev, format);
+                for (width, height) in [(1, 1), (37, 300), (1920, 1080)] {
+                    for &modifier in &mods {
+                        let exported =
+                            image_2d(&dev, format, width, height, modifier, 0);
+                        assert!(exported.levels[0].tiling.is_tiled());
+                        assert_eq!(
+                            exported.pte_kind,
+                            BlockLinearModifier::try_from(modifier)
+                                .unwrap()
+                                .pte_kind()
+                        );
+
+                        let imported = image_2d(
+                            &dev,
+                            format,
+                            width,
+                            height,
+                            modifier,
+                            exported.levels[0].row_stride_B,
+                        );
+                        assert_eq!(exported, imported);
+                    }
+
+                    // The modifier picked from the full list should be the
+                    // first one we advertise
+                    let best =
+                        select_best_drm_format_mod(&dev, format, true, &mods);
+                    assert_eq!(best, mods[0]);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,672 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use bitview::*;
+use nil_rs_bindings::*;
+use nvidia_headers::classes::cl9097::tex as cl9097;
+use nvidia_headers::classes::cl9097::FERMI_A;
+use nvidia_headers::classes::cla097::tex as cla097;
+use nvidia_headers::classes::cla097::KEPLER_A;
+use nvidia_headers::classes::clb197::tex as clb197;
+use nvidia_headers::classes::clb197::MAXWELL_B;
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplerFilter {
+    Nearest,
+    Linear,
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplerMipFilter {
+    /// Always sample the base level
+    None,
+    Nearest,
+    Linear,
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplerAddressMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+    ClampToBorder,
+    MirrorClampToEdge,
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplerCompareOp {
+    Never,
+    Less,
+    Equal,
+    LessOrEqual,
+    Greater,
+    NotEqual,
+    GreaterOrEqual,
+    Always,
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplerReductionMode {
+    WeightedAverage,
+    Min,
+    Max,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerBorderColor {
+    /// The border color as either 32-bit floats or 32-bit integers
+    pub color: [u32; 4],
+    pub is_int: bool,
+
+    /// If true, the border color is for a custom format and format_swizzle
+    /// and format_srgb describe the views the sampler will be used with.
+    /// Otherwise, the border color is assumed to either be one of the
+    /// built-in border colors or only be used with unswizzled views.
+    pub has_format: bool,
+    pub format_swizzle: [pipe_swizzle; 4],
+    pub format_srgb: bool,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sampler {
+    pub mag_filter: SamplerFilter,
+    pub min_filter: SamplerFilter,
+    pub mip_filter: SamplerMipFilter,
+    pub address_mode: [SamplerAddressMode; 3],
+    pub compare_enable: bool,
+    pub compare_op: SamplerCompareOp,
+
+    pub anisotropy_enable: bool,
+
+    /// Also picks the trilinear optimization level, even if anisotropic
+    /// filtering is disabled
+    pub max_anisotropy: f32,
+    pub lod_bias: f32,
+    pub min_lod: f32,
+    pub max_lod: f32,
+    pub reduction_mode: SamplerReductionMode,
+    pub seamless_cube_map: bool,
+    pub unnormalized_coords: bool,
+    pub border_color: SamplerBorderColor,
+}
+
+fn nv9097_address_mode(mode: SamplerAddressMode) -> u32 {
+    match mode {
+        SamplerAddressMode::Repeat => cl9097::TEXSAMP_ADDRESS_U_WRAP,
+        SamplerAddressMode::MirroredRepeat => cl9097::TEXSAMP_ADDRESS_U_MIRROR,
+        SamplerAddressMode::ClampToEdge => {
+            cl9097::TEXSAMP_ADDRESS_U_CLAMP_TO_EDGE
+        }
+        SamplerAddressMode::ClampToBorder => cl9097::TEXSAMP_ADDRESS_U_BORDER,
+        SamplerAddressMode::MirrorClampToEdge => {
+            cl9097::TEXSAMP_ADDRESS_U_MIRROR_ONCE_CLAMP_TO_EDGE
+        }
+    }
+}
+
+impl BitFieldEnum for SamplerAddressMode {
+    fn to_field_bits(self) -> u64 {
+        nv9097_address_mode(self).into()
+    }
+
+    fn from_field_bits(bits: u64) -> Option<Self> {
+        [
+            SamplerAddressMode::Repeat,
+            SamplerAddressMode::MirroredRepeat,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToBorder,
+            SamplerAddressMode::MirrorClampToEdge,
+        ]
+        .into_iter()
+        .find(|mode| mode.to_field_bits() == bits)
+    }
+}
+
+fn nv9097_compare_func(op: SamplerCompareOp) -> u32 {
+    match op {
+        SamplerCompareOp::Never => cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_NEVER,
+        SamplerCompareOp::Less => cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_LESS,
+        SamplerCompareOp::Equal => cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_EQUAL,
+        SamplerCompareOp::LessOrEqual => {
+            cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_LEQUAL
+        }
+        SamplerCompareOp::Greater => {
+            cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_GREATER
+        }
+        SamplerCompareOp::NotEqual => {
+            cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_NOTEQUAL
+        }
+        SamplerCompareOp::GreaterOrEqual => {
+            cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_GEQUAL
+        }
+        SamplerCompareOp::Always => {
+            cl9097::TEXSAMP_DEPTH_COMPARE_FUNC_ZC_ALWAYS
+        }
+    }
+}
+
+impl BitFieldEnum for SamplerCompareOp {
+    fn to_field_bits(self) -> u64 {
+        nv9097_compare_func(self).into()
+    }
+
+    fn from_field_bits(bits: u64) -> Option<Self> {
+        [
+            SamplerCompareOp::Never,
+            SamplerCompareOp::Less,
+            SamplerCompareOp::Equal,
+            SamplerCompareOp::LessOrEqual,
+            SamplerCompareOp::Greater,
+            SamplerCompareOp::NotEqual,
+            SamplerCompareOp::GreaterOrEqual,
+            SamplerCompareOp::Always,
+        ]
+        .into_iter()
+        .find(|op| op.to_field_bits() == bits)
+    }
+}
+
+impl BitFieldEnum for SamplerMipFilter {
+    fn to_field_bits(self) -> u64 {
+        match self {
+            SamplerMipFilter::None => cl9097::TEXSAMP_MIP_FILTER_MIP_NONE,
+            SamplerMipFilter::Nearest => cl9097::TEXSAMP_MIP_FILTER_MIP_POINT,
+            SamplerMipFilter::Linear => cl9097::TEXSAMP_MIP_FILTER_MIP_LINEAR,
+        }
+        .into()
+    }
+
+    fn from_field_bits(bits: u64) -> Option<Self> {
+        [
+            SamplerMipFilter::None,
+            SamplerMipFilter::Nearest,
+            SamplerMipFilter::Linear,
+        ]
+        .into_iter()
+        .find(|filter| filter.to_field_bits() == bits)
+    }
+}
+
+impl BitFieldEnum for SamplerReductionMode {
+    fn to_field_bits(self) -> u64 {
+        match self {
+            SamplerReductionMode::WeightedAverage => {
+                clb197::TEXSAMP_REDUCTION_FILTER_RED_NONE
+            }
+            SamplerReductionMode::Min => {
+                clb197::TEXSAMP_REDUCTION_FILTER_RED_MINIMUM
+            }
+            SamplerReductionMode::Max => {
+                clb197::TEXSAMP_REDUCTION_FILTER_RED_MAXIMUM
+            }
+        }
+        .into()
+    }
+
+    fn from_field_bits(bits: u64) -> Option<Self> {
+        [
+            SamplerReductionMode::WeightedAverage,
+            SamplerReductionMode::Min,
+            SamplerReductionMode::Max,
+        ]
+        .into_iter()
+        .find(|mode| mode.to_field_bits() == bits)
+    }
+}
+
+/// A texture sampler control block
+///
+/// Each field uses the range from the first class which has it.  Kepler added
+/// the cube map filtering and coordinate normalization fields and Maxwell
+/// added the reduction filter, all in bits which are reserved on the classes
+/// before them.
+#[derive(BitStruct)]
+#[bitfield(address_u: SamplerAddressMode = cl9097::TEXSAMP_ADDRESS_U)]
+#[bitfield(address_v: SamplerAddressMode = cl9097::TEXSAMP_ADDRESS_V)]
+#[bitfield(address_p: SamplerAddressMode = cl9097::TEXSAMP_ADDRESS_P)]
+#[bitfield(depth_compare: bool = cl9097::TEXSAMP_DEPTH_COMPARE)]
+#[bitfield(
+    depth_compare_func: SamplerCompareOp = cl9097::TEXSAMP_DEPTH_COMPARE_FUNC
+)]
+#[bitfield(srgb_conversion: bool = cl9097::TEXSAMP_S_R_G_B_CONVERSION)]
+#[bitfield(font_filter_width: u32 = cl9097::TEXSAMP_FONT_FILTER_WIDTH)]
+#[bitfield(font_filter_height: u32 = cl9097::TEXSAMP_FONT_FILTER_HEIGHT)]
+#[bitfield(max_anisotropy: u32 = cl9097::TEXSAMP_MAX_ANISOTROPY)]
+#[bitfield(mag_filter: u32 = cl9097::TEXSAMP_MAG_FILTER)]
+#[bitfield(min_filter: u32 = cl9097::TEXSAMP_MIN_FILTER)]
+#[bitfield(mip_filter: SamplerMipFilter = cl9097::TEXSAMP_MIP_FILTER)]
+#[bitfield(
+    cubemap_filtering: u32 = cla097::TEXSAMP_CUBEMAP_INTERFACE_FILTERING
+)]
+#[bitfield(
+    reduction_filter: SamplerReductionMode = clb197::TEXSAMP_REDUCTION_FILTER
+)]
+#[bitfield(mip_lod_bias: sfixed<8> = cl9097::TEXSAMP_MIP_LOD_BIAS)]
+#[bitfield(
+    force_unnormalized_coords: bool =
+        cla097::TEXSAMP_FLOAT_COORD_NORMALIZATION
+)]
+#[bitfield(trilin_opt: u32 = cl9097::TEXSAMP_TRILIN_OPT)]
+#[bitfield(min_lod_clamp: ufixed<8> = cl9097::TEXSAMP_MIN_LOD_CLAMP)]
+#[bitfield(max_lod_clamp: ufixed<8> = cl9097::TEXSAMP_MAX_LOD_CLAMP)]
+#[bitfield(srgb_border_color_r: u8 = cl9097::TEXSAMP_S_R_G_B_BORDER_COLOR_R)]
+#[bitfield(srgb_border_color_g: u8 = cl9097::TEXSAMP_S_R_G_B_BORDER_COLOR_G)]
+#[bitfield(srgb_border_color_b: u8 = cl9097::TEXSAMP_S_R_G_B_BORDER_COLOR_B)]
+#[bitfield(border_color_r: u32 = cl9097::TEXSAMP_BORDER_COLOR_R)]
+#[bitfield(border_color_g: u32 = cl9097::TEXSAMP_BORDER_COLOR_G)]
+#[bitfield(border_color_b: u32 = cl9097::TEXSAMP_BORDER_COLOR_B)]
+#[bitfield(border_color_a: u32 = cl9097::TEXSAMP_BORDER_COLOR_A)]
+struct TexSamp([u32; 8]);
+
+fn nv9097_max_anisotropy(max_anisotropy: f32) -> u32 {
+    if max_anisotropy >= 16.0 {
+        cl9097::TEXSAMP_MAX_ANISOTROPY_ANISO_16_TO_1
+    } else if max_anisotropy >= 12.0 {
+        cl9097::TEXSAMP_MAX_ANISOTROPY_ANISO_12_TO_1
+    } else {
+        // The rest of the enum goes up by 2 at a time
+        (max_anisotropy.max(0.0) as u32) >> 1
+    }
+}
+
+fn nv9097_trilin_opt(max_anisotropy: f32) -> u32 {
+    // No idea if we want this but matching nouveau
+    if max_anisotropy >= 12.0 {
+        0
+    } else if max_anisotropy >= 4.0 {
+        6
+    } else if max_anisotropy >= 2.0 {
+        4
+    } else {
+        0
+    }
+}
+
+/// Same as util_format_linear_to_srgb_float()
+fn linear_to_srgb(l: f32) -> f32 {
+    if l <= 0.0 {
+        0.0
+    } else if l < 0.0031308 {
+        12.92 * l
+    } else if l < 1.0 {
+        1.055 * l.powf(0.41666) - 0.055
+    } else {
+        1.0
+    }
+}
+
+fn float_to_unorm8(f: f32) -> u8 {
+    (f.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+impl SamplerBorderColor {
+    fn swizzle_component(
+        &self,
+        color: &[u32; 4],
+        swizzle: pipe_swizzle,
+    ) -> u32 {
+        match swizzle {
+            PIPE_SWIZZLE_X => color[0],
+            PIPE_SWIZZLE_Y => color[1],
+            PIPE_SWIZZLE_Z => color[2],
+            PIPE_SWIZZLE_W => color[3],
+            PIPE_SWIZZLE_0 => 0,
+            PIPE_SWIZZLE_1 => {
+                if self.is_int {
+                    1
+                } else {
+                    1.0_f32.to_bits()
+                }
+            }
+            other => panic!("Invalid component swizzle {}", other),
+        }
+    }
+
+    /// Returns the border color as the hardware sees it along with the
+    /// 8-bit sRGB-encoded RGB border color used for sRGB views.
+    fn hw_color(&self) -> ([u32; 4], [u8; 3]) {
+        if !self.has_format {
+            // Nothing to do but convert the border color to sRGB
+            let srgb = std::array::from_fn(|i| {
+                float_to_unorm8(linear_to_srgb(f32::from_bits(self.color[i])))
+            });
+            return (self.color, srgb);
+        }
+
+        let mut color = self.color;
+        if self.format_srgb {
+            for c in &mut color[0..3] {
+                *c = linear_to_srgb(f32::from_bits(*c)).to_bits();
+            }
+        }
+
+        let color = self
+            .format_swizzle
+            .map(|s| self.swizzle_component(&color, s));
+        let srgb =
+            std::array::from_fn(|i| float_to_unorm8(f32::from_bits(color[i])));
+
+        (color, srgb)
+    }
+}
+
+fn nv9097_set_tsc(tsc: &mut TexSamp, sampler: &Sampler) {
+    let [u, v, p] = sampler.address_mode;
+    tsc.set_address_u(u);
+    tsc.set_address_v(v);
+    tsc.set_address_p(p);
+
+    if sampler.compare_enable {
+        tsc.set_depth_compare(true);
+        tsc.set_depth_compare_func(sampler.compare_op);
+    }
+
+    tsc.set_srgb_conversion(true);
+    tsc.set_font_filter_width(cl9097::TEXSAMP_FONT_FILTER_WIDTH_SIZE_2);
+    tsc.set_font_filter_height(cl9097::TEXSAMP_FONT_FILTER_HEIGHT_SIZE_2);
+
+    let aniso = sampler.anisotropy_enable;
+    if aniso {
+        tsc.set_max_anisotropy(nv9097_max_anisotropy(sampler.max_anisotropy));
+    }
+
+    tsc.set_mag_filter(match sampler.mag_filter {
+        SamplerFilter::Nearest => cl9097::TEXSAMP_MAG_FILTER_MAG_POINT,
+        SamplerFilter::Linear => cl9097::TEXSAMP_MAG_FILTER_MAG_LINEAR,
+    });
+
+    tsc.set_min_filter(match sampler.min_filter {
+        SamplerFilter::Nearest => cl9097::TEXSAMP_MIN_FILTER_MIN_POINT,
+        SamplerFilter::Linear if aniso => cl9097::TEXSAMP_MIN_FILTER_MIN_ANISO,
+        SamplerFilter::Linear => cl9097::TEXSAMP_MIN_FILTER_MIN_LINEAR,
+    });
+
+    tsc.set_mip_filter(sampler.mip_filter);
+
+    tsc.set_mip_lod_bias(sampler.lod_bias);
+    tsc.set_trilin_opt(nv9097_trilin_opt(sampler.max_anisotropy));
+
+    tsc.set_min_lod_clamp(sampler.min_lod);
+    tsc.set_max_lod_clamp(sampler.max_lod);
+
+    let (color, srgb) = sampler.border_color.hw_color();
+    tsc.set_srgb_border_color_r(srgb[0]);
+    tsc.set_srgb_border_color_g(srgb[1]);
+    tsc.set_srgb_border_color_b(srgb[2]);
+    tsc.set_border_color_r(color[0]);
+    tsc.set_border_color_g(color[1]);
+    tsc.set_border_color_b(color[2]);
+    tsc.set_border_color_a(color[3]);
+}
+
+fn nv9097_fill_tsc(sampler: &Sampler, desc_out: &mut [u32; 8]) {
+    // Fermi takes the coordinate normalization from the texture header and
+    // has no control over filtering across cube faces.
+    assert!(!sampler.unnormalized_coords);
+    assert!(sampler.reduction_mode == SamplerReductionMode::WeightedAverage);
+
+    let mut tsc = TexSamp([0; 8]);
+    nv9097_set_tsc(&mut tsc, sampler);
+    *desc_out = tsc.0;
+}
+
+fn nva097_set_tsc(tsc: &mut TexSamp, sampler: &Sampler) {
+    nv9097_set_tsc(tsc, sampler);
+
+    tsc.set_cubemap_filtering(if sampler.seamless_cube_map {
+        cla097::TEXSAMP_CUBEMAP_INTERFACE_FILTERING_AUTO_SPAN_SEAM
+    } else {
+        cla097::TEXSAMP_CUBEMAP_INTERFACE_FILTERING_USE_WRAP
+    });
+
+    tsc.set_force_unnormalized_coords(sampler.unnormalized_coords);
+}
+
+fn nva097_fill_tsc(sampler: &Sampler, desc_out: &mut [u32; 8]) {
+    assert!(sampler.reduction_mode == SamplerReductionMode::WeightedAverage);
+
+    let mut tsc = TexSamp([0; 8]);
+    nva097_set_tsc(&mut tsc, sampler);
+    *desc_out = tsc.0;
+}
+
+fn nvb197_fill_tsc(sampler: &Sampler, desc_out: &mut [u32; 8]) {
+    let mut tsc = TexSamp([0; 8]);
+    nva097_set_tsc(&mut tsc, sampler);
+    tsc.set_reduction_filter(sampler.reduction_mode);
+    *desc_out = tsc.0;
+}
+
+impl Sampler {
+    #[no_mangle]
+    pub extern "C" fn nil_sampler_fill_tsc(
+        &self,
+        dev: &nil_rs_bindings::nv_device_info,
+        desc_out: &mut [u32; 8],
+    ) {
+        self.fill_tsc(dev, desc_out);
+    }
+
+    pub fn fill_tsc(
+        &self,
+        dev: &nil_rs_bindings::nv_device_info,
+        desc_out: &mut [u32; 8],
+    ) {
+        if dev.cls_eng3d >= MAXWELL_B {
+            nvb197_fill_tsc(self, desc_out);
+        } else if dev.cls_eng3d >= KEPLER_A {
+            nva097_fill_tsc(self, desc_out);
+        } else if dev.cls_eng3d >= FERMI_A {
+            nv9097_fill_tsc(self, desc_out);
+        } else {
+            panic!("Tesla and older not supported");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::dev;
+    use crate::tic::IDENTITY_SWIZZLE;
+
+    fn fill_tsc(cls_eng3d: u16, sampler: &Sampler) -> [u32; 8] {
+        let mut desc = [0xdeadbeef; 8];
+        sampler.fill_tsc(&dev(NV_DEVICE_TYPE_IGP, cls_eng3d), &mut desc);
+        desc
+    }
+
+    fn border_color(color: [u32; 4], is_int: bool) -> SamplerBorderColor {
+        SamplerBorderColor {
+            color: color,
+            is_int: is_int,
+            has_format: false,
+            format_swizzle: IDENTITY_SWIZZLE,
+            format_srgb: false,
+        }
+    }
+
+    fn linear_sampler() -> Sampler {
+        Sampler {
+            mag_filter: SamplerFilter::Linear,
+            min_filter: SamplerFilter::Linear,
+            mip_filter: SamplerMipFilter::Linear,
+            address_mode: [SamplerAddressMode::Repeat; 3],
+            compare_enable: false,
+            compare_op: SamplerCompareOp::Never,
+            anisotropy_enable: false,
+            max_anisotropy: 1.0,
+            lod_bias: 0.0,
+            min_lod: 0.0,
+            max_lod: 1000.0,
+            reduction_mode: SamplerReductionMode::WeightedAverage,
+            seamless_cube_map: true,
+            unnormalized_coords: false,
+            border_color: border_color([0; 4], false),
+        }
+    }
+
+    #[test]
+    fn test_linear() {
+        assert_eq!(
+            fill_tsc(MAXWELL_B, &linear_sampler()),
+            [0x00026000, 0x000002e2, 0x00fff000, 0, 0, 0, 0, 0],
+        );
+    }
+
+    #[test]
+    fn test_kepler_everything() {
+        let one = 1.0_f32.to_bits();
+        let sampler = Sampler {
+            mag_filter: SamplerFilter::Nearest,
+            min_filter: SamplerFilter::Linear,
+            mip_filter: SamplerMipFilter::Nearest,
+            address_mode: [
+                SamplerAddressMode::ClampToEdge,
+                SamplerAddressMode::ClampToBorder,
+                SamplerAddressMode::MirrorClampToEdge,
+            ],
+            compare_enable: true,
+            compare_op: SamplerCompareOp::LessOrEqual,
+            anisotropy_enable: true,
+            max_anisotropy: 16.0,
+            lod_bias: -1.5,
+            min_lod: 0.25,
+            max_lod: 4.5,
+            reduction_mode: SamplerReductionMode::WeightedAverage,
+            seamless_cube_map: false,
+            unnormalized_coords: true,
+            border_color: border_color([one; 4], false),
+        };
+        assert_eq!(
+            fill_tsc(KEPLER_A, &sampler),
+            [
+                0x00726f5a, 0x03e800b1, 0xff480040, 0x0ffff000, one, one, one,
+                one,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_typed_fields() {
+        let sampler = Sampler {
+            mip_filter: SamplerMipFilter::None,
+            address_mode: [
+                SamplerAddressMode::MirroredRepeat,
+                SamplerAddressMode::ClampToBorder,
+                SamplerAddressMode::MirrorClampToEdge,
+            ],
+            compare_enable: true,
+            compare_op: SamplerCompareOp::GreaterOrEqual,
+            lod_bias: -2.25,
+            min_lod: 1.5,
+            max_lod: 7.75,
+            reduction_mode: SamplerReductionMode::Max,
+            unnormalized_coords: true,
+            ..linear_sampler()
+        };
+        let tsc = TexSamp(fill_tsc(MAXWELL_B, &sampler));
+
+        assert_eq!(tsc.address_u(), SamplerAddressMode::MirroredRepeat);
+        assert_eq!(tsc.address_v(), SamplerAddressMode::ClampToBorder);
+        assert_eq!(tsc.address_p(), SamplerAddressMode::MirrorClampToEdge);
+        assert!(tsc.depth_compare());
+        assert_eq!(tsc.depth_compare_func(), SamplerCompareOp::GreaterOrEqual);
+        assert_eq!(tsc.mip_filter(), SamplerMipFilter::None);
+        assert_eq!(tsc.reduction_filter(), SamplerReductionMode::Max);
+        assert!(tsc.force_unnormalized_coords());
+        assert_eq!(tsc.mip_lod_bias(), -2.25);
+        assert_eq!(tsc.min_lod_clamp(), 1.5);
+        assert_eq!(tsc.max_lod_clamp(), 7.75);
+
+        // Values the hardware doesn't define are printed rather than decoded
+        let mut desc = tsc.0;
+        desc[0] |= 0x7;
+        let dbg = format!("{:?}", TexSamp(desc));
+        assert!(dbg.contains("address_u: <invalid 0x7>"));
+        assert!(dbg.contains("address_v: ClampToBorder"));
+    }
+
+    #[test]
+    fn test_fermi_aniso() {
+        let sampler = Sampler {
+            anisotropy_enable: true,
+            max_anisotropy: 4.0,
+            max_lod: 0.0,
+            ..linear_sampler()
+        };
+        assert_eq!(
+            fill_tsc(FERMI_A, &sampler),
+            [0x00226000, 0x180000f2, 0, 0, 0, 0, 0, 0],
+        );
+    }
+
+    #[test]
+    fn test_trilin_opt_without_aniso() {
+        let sampler = Sampler {
+            max_anisotropy: 4.0,
+            ..linear_sampler()
+        };
+        assert_eq!(
+            fill_tsc(MAXWELL_B, &sampler),
+            [0x00026000, 0x180002e2, 0x00fff000, 0, 0, 0, 0, 0],
+        );
+    }
+
+    #[test]
+    fn test_reduction_int_border() {
+        let sampler = Sampler {
+            reduction_mode: SamplerReductionMode::Min,
+            border_color: SamplerBorderColor {
+                has_format: true,
+                format_swizzle: [
+                    PIPE_SWIZZLE_W,
+                    PIPE_SWIZZLE_0,
+                    PIPE_SWIZZLE_X,
+                    PIPE_SWIZZLE_1,
+                ],
+                ..border_color([1, 2, 3, 4], true)
+            },
+            ..linear_sampler()
+        };
+        assert_eq!(
+            fill_tsc(MAXWELL_B, &sampler),
+            [0x00026000, 0x000006e2, 0x00fff000, 0, 4, 0, 1, 1],
+        );
+    }
+
+    #[test]
+    fn test_srgb_custom_border() {
+        let one = 1.0_f32.to_bits();
+        let sampler = Sampler {
+            border_color: SamplerBorderColor {
+                has_format: true,
+                format_swizzle: [
+                    PIPE_SWIZZLE_Y,
+                    PIPE_SWIZZLE_X,
+                    PIPE_SWIZZLE_0,
+                    PIPE_SWIZZLE_W,
+                ],
+                format_srgb: true,
+                ..border_color([one, 0, 0, one], false)
+            },
+            ..linear_sampler()
+        };
+        assert_eq!(
+            fill_tsc(MAXWELL_B, &sampler),
+            [0x00026000, 0x000002e2, 0x00fff000, 0x000ff000, 0, one, 0, one],
+        );
+    }
+}
@@ -0,0 +1,919 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+use crate::extent::{units, Extent4D, Offset4D};
+use crate::image::Image;
+use crate::tiling::{sparse_block_extent_px, Tiling};
+
+// Sparse images are tiled with 64K tiles that are exactly one sparse block.
+// Every level which is large enough to use the full sparse tiling can be
+// bound one block at a time.  The remaining levels have smaller tiles and
+// make up the mip tail which has to be bound as a whole.
+//
+// The mip tail of each array layer starts on a sparse block boundary as long
+// as level 0 uses the full sparse tiling.  If the whole image is in the mip
+// tail, the array layers are only aligned to the level 0 tiling so the mip
+// tails of the layers are not separately bindable and the whole image is one
+// single mip tail.
+
+/// A sparse block of an image
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SparseBlock {
+    pub level: u32,
+    /// The first pixel of the block with the array layer in @offset_px.a
+    pub offset_px: Offset4D<units::Pixels>,
+    /// The extent of the block, clamped to the level
+    pub extent_px: Extent4D<units::Pixels>,
+    /// The offset of the block from the start of the image
+    pub offset_B: u64,
+}
+
+/// The mip tail of a sparse image
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SparseMipTail {
+    /// The first level in the mip tail or num_levels if there is none
+    pub first_lod: u32,
+    /// If true, the whole image is a single mip tail and the array layers
+    /// cannot be bound separately
+    pub single: bool,
+    /// The offset of the mip tail of the first array layer
+    pub offset_B: u64,
+    /// The size of the mip tail of one array layer or of the whole image if
+    /// @single is set
+    pub size_B: u64,
+    /// The distance between the mip tails of two array layers
+    pub stride_B: u64,
+}
+
+/// A contiguous range of a sparse bind
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SparseBindRange {
+    /// The offset of the range from the start of the image
+    pub image_offset_B: u64,
+    /// The offset of the range from the start of the bound memory
+    pub mem_offset_B: u64,
+    pub size_B: u64,
+}
+
+impl Image {
+    fn sparse_tiling(&self) -> Tiling {
+        Tiling::sparse(self.format, self.dim)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_sparse_block_extent_px(
+        &self,
+    ) -> Extent4D<units::Pixels> {
+        self.sparse_block_extent_px()
+    }
+
+    pub fn sparse_block_extent_px(&self) -> Extent4D<units::Pixels> {
+        sparse_block_extent_px(self.format, self.dim, self.sample_layout)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_sparse_block_size_B(&self) -> u32 {
+        self.sparse_block_size_B()
+    }
+
+    pub fn sparse_block_size_B(&self) -> u32 {
+        self.sparse_tiling().size_B()
+    }
+
+    /// Returns the extent of a level in sparse blocks
+    fn level_extent_bl(&self, level: u32) -> Extent4D<units::Tiles> {
+        assert!(level < self.num_levels);
+        assert!(level < self.mip_tail_first_lod);
+
+        let tiling = self.levels[level as usize].tiling;
+        debug_assert!(tiling == self.sparse_tiling());
+
+        let mut lvl_ext_px = self.level_extent_px(level);
+        lvl_ext_px.array_len = 1;
+        lvl_ext_px.to_tl(&tiling, self.format, self.sample_layout)
+    }
+
+    /// Returns the offset of a sparse block from the start of the image
+    fn sparse_block_offset_B(
+        &self,
+        level: u32,
+        layer: u32,
+        lvl_ext_bl: Extent4D<units::Tiles>,
+        offset_bl: Offset4D<units::Tiles>,
+    ) -> u64 {
+        let bl_idx = (u64::from(offset_bl.z) * u64::from(lvl_ext_bl.height)
+            + u64::from(offset_bl.y))
+            * u64::from(lvl_ext_bl.width)
+            + u64::from(offset_bl.x);
+        self.level_layer_offset_B(level, layer)
+            + bl_idx * u64::from(self.sparse_block_size_B())
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn nil_image_sparse_blocks(
+        &self,
+        level: u32,
+        layer: u32,
+        block_count: &mut usize,
+        blocks: *mut SparseBlock,
+    ) {
+        write_c_array(self.sparse_blocks(level, layer), block_count, blocks);
+    }
+
+    /// Returns every sparse block of one array layer of a level
+    ///
+    /// The blocks are returned in X, then Y, then Z order.  @level must not
+    /// be in the mip tail.
+    pub fn sparse_blocks(&self, level: u32, layer: u32) -> Vec<SparseBlock> {
+        assert!(layer < self.extent_px.array_len);
+
+        let lvl_ext_px = self.level_extent_px(level);
+        let lvl_ext_bl = self.level_extent_bl(level);
+        let bl_ext_px = self.sparse_block_extent_px();
+
+        let mut blocks = Vec::new();
+        for z in 0..lvl_ext_bl.depth {
+            for y in 0..lvl_ext_bl.height {
+                for x in 0..lvl_ext_bl.width {
+                    let offset_px = Offset4D::new(
+                        x * bl_ext_px.width,
+                        y * bl_ext_px.height,
+                        z * bl_ext_px.depth,
+                        layer,
+                    );
+                    let extent_px = Extent4D::new(
+                        bl_ext_px.width.min(lvl_ext_px.width - offset_px.x),
+                        bl_ext_px.height.min(lvl_ext_px.height - offset_px.y),
+                        bl_ext_px.depth.min(lvl_ext_px.depth - offset_px.z),
+                        1,
+                    );
+                    blocks.push(SparseBlock {
+                        level,
+                        offset_px,
+                        extent_px,
+                        offset_B: self.sparse_block_offset_B(
+                            level,
+                            layer,
+                            lvl_ext_bl,
+                            Offset4D::new(x, y, z, 0),
+                        ),
+                    });
+                }
+            }
+        }
+        blocks
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_sparse_mip_tail(&self) -> SparseMipTail {
+        self.sparse_mip_tail()
+    }
+
+    pub fn sparse_mip_tail(&self) -> SparseMipTail {
+        let first_lod = self.mip_tail_first_lod;
+        if first_lod >= self.num_levels {
+            SparseMipTail {
+                first_lod: self.num_levels,
+                single: false,
+                offset_B: 0,
+                size_B: 0,
+                stride_B: 0,
+            }
+        } else if first_lod == 0 {
+            SparseMipTail {
+                first_lod,
+                single: true,
+                offset_B: 0,
+                size_B: self.size_B,
+                stride_B: 0,
+            }
+        } else {
+            SparseMipTail {
+                first_lod,
+                single: false,
+                offset_B: self.mip_tail_offset_B(),
+                size_B: self.mip_tail_size_B().into(),
+                stride_B: self.array_stride_B,
+            }
+        }
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_sparse_single_mip_tail_size_B(&self) -> u64 {
+        self.sparse_single_mip_tail_size_B()
+    }
+
+    /// Returns the size of the mip tails of all array layers when bound as a
+    /// single mip tail
+    pub fn sparse_single_mip_tail_size_B(&self) -> u64 {
+        let tail = self.sparse_mip_tail();
+        if tail.single {
+            tail.size_B
+        } else {
+            tail.size_B * u64::from(self.extent_px.array_len)
+        }
+    }
+
+    /// Returns the image ranges of a range of the single mip tail
+    ///
+    /// The single mip tail is the mip tails of all array layers one after
+    /// the other.  @tail_offset_B is relative to the start of the single mip
+    /// tail and the returned memory offsets are relative to @tail_offset_B.
+    pub fn sparse_mip_tail_bind_ranges(
+        &self,
+        tail_offset_B: u64,
+        size_B: u64,
+    ) -> Vec<SparseBindRange> {
+        let tail = self.sparse_mip_tail();
+        let end_B = tail_offset_B + size_B;
+        assert!(end_B <= self.sparse_single_mip_tail_size_B());
+
+        if tail.single {
+            return if size_B > 0 {
+                vec![SparseBindRange {
+                    image_offset_B: tail_offset_B,
+                    mem_offset_B: 0,
+                    size_B,
+                }]
+            } else {
+                Vec::new()
+            };
+        }
+
+        let mut ranges = Vec::new();
+        let mut offset_B = tail_offset_B;
+        while offset_B < end_B {
+            let layer = offset_B / tail.size_B;
+            let layer_offset_B = offset_B % tail.size_B;
+            let range_B =
+                std::cmp::min(tail.size_B - layer_offset_B, end_B - offset_B);
+            ranges.push(SparseBindRange {
+                image_offset_B: tail.offset_B
+                    + layer * tail.stride_B
+                    + layer_offset_B,
+                mem_offset_B: offset_B - tail_offset_B,
+                size_B: range_B,
+            });
+            offset_B += range_B;
+        }
+        ranges
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn nil_image_sparse_mip_tail_bind_ranges(
+        &self,
+        tail_offset_B: u64,
+        size_B: u64,
+        range_count: &mut usize,
+        ranges: *mut SparseBindRange,
+    ) {
+        write_c_array(
+            self.sparse_mip_tail_bind_ranges(tail_offset_B, size_B),
+            range_count,
+            ranges,
+        );
+    }
+
+    #[no_mangle]
+    pub extern "C" fn nil_image_sparse_bind_is_valid(
+        &self,
+        level: u32,
+        offset_px: Offset4D<units::Pixels>,
+        extent_px: Extent4D<units::Pixels>,
+    ) -> bool {
+        self.sparse_bind_is_valid(level, offset_px, extent_px)
+    }
+
+    /// Returns true if the given region of a level can be bound
+    ///
+    /// The array layer is in @offset_px.a and only one layer can be bound at
+    /// a time.  The region must not be empty, has to start on a sparse block
+    /// boundary, and has to be made of whole sparse blocks except where it
+    /// touches the edge of the level.  Levels in the mip tail can't be bound
+    /// this way.
+    pub fn sparse_bind_is_valid(
+        &self,
+        level: u32,
+        offset_px: Offset4D<units::Pixels>,
+        extent_px: Extent4D<units::Pixels>,
+    ) -> bool {
+        if level >= self.num_levels || level >= self.mip_tail_first_lod {
+            return false;
+        }
+
+        if offset_px.a >= self.extent_px.array_len || extent_px.array_len != 1 {
+            return false;
+        }
+
+        let lvl_ext_px = self.level_extent_px(level);
+        let bl_ext_px = self.sparse_block_extent_px();
+        let dim_is_valid = |offset: u32, extent: u32, lvl: u32, bl: u32| {
+            let Some(end) = offset.checked_add(extent) else {
+                return false;
+            };
+            extent > 0
+                && end <= lvl
+                && offset.is_multiple_of(bl)
+                && (extent.is_multiple_of(bl) || end == lvl)
+        };
+
+        dim_is_valid(
+            offset_px.x,
+            extent_px.width,
+            lvl_ext_px.width,
+            bl_ext_px.width,
+        ) && dim_is_valid(
+            offset_px.y,
+            extent_px.height,
+            lvl_ext_px.height,
+            bl_ext_px.height,
+        ) && dim_is_valid(
+            offset_px.z,
+            extent_px.depth,
+            lvl_ext_px.depth,
+            bl_ext_px.depth,
+        )
+    }
+
+    /// Returns the contiguous image ranges covered by a bind
+    ///
+    /// Each row of sparse blocks in the bind is contiguous in the image.  The
+    /// bound memory is laid out as the blocks of the bind in X, then Y, then
+    /// Z order and the memory offsets are relative to the start of the bind.
+    pub fn sparse_bind_ranges(
+        &self,
+        level: u32,
+        offset_px: Offset4D<units::Pixels>,
+        extent_px: Extent4D<units::Pixels>,
+    ) -> Vec<SparseBindRange> {
+        assert!(self.sparse_bind_is_valid(level, offset_px, extent_px));
+
+        let tiling = self.levels[level as usize].tiling;
+        let lvl_ext_bl = self.level_extent_bl(level);
+        let bind_ext_bl =
+            extent_px.to_tl(&tiling, self.format, self.sample_layout);
+        let bind_offset_bl =
+            offset_px.to_tl(&tiling, self.format, self.sample_layout);
+
+        let bl_size_B = u64::from(self.sparse_block_size_B());
+        let row_size_B = u64::from(bind_ext_bl.width) * bl_size_B;
+
+        let mut ranges = Vec::new();
+        for z in 0..bind_ext_bl.depth {
+            for y in 0..bind_ext_bl.height {
+                let image_offset_B = self.sparse_block_offset_B(
+                    level,
+                    offset_px.a,
+                    lvl_ext_bl,
+                    Offset4D::new(
+                        bind_offset_bl.x,
+                        bind_offset_bl.y + y,
+                        bind_offset_bl.z + z,
+                        0,
+                    ),
+                );
+                let mem_row =
+                    u64::from(z) * u64::from(bind_ext_bl.height) + u64::from(y);
+                ranges.push(SparseBindRange {
+                    image_offset_B,
+                    mem_offset_B: mem_row * row_size_B,
+                    size_B: row_size_B,
+                });
+            }
+        }
+        ranges
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn nil_image_sparse_bind_ranges(
+        &self,
+        level: u32,
+        offset_px: Offset4D<units::Pixels>,
+        extent_px: Extent4D<units::Pixels>,
+        range_count: &mut usize,
+        ranges: *mut SparseBindRange,
+    ) {
+        write_c_array(
+            self.sparse_bind_ranges(level, offset_px, extent_px),
+            range_count,
+            ranges,
+        );
+    }
+}
+
+/// Returns an array to C
+///
+/// If @out is NULL, only the number of elements is returned.  Otherwise, it
+/// must have room for at least that many elements.
+unsafe fn write_c_array<T: Copy>(vec: Vec<T>, count: &mut usize, out: *mut T) {
+    *count = vec.len();
+    if !out.is_null() {
+        let out = std::slice::from_raw_parts_mut(out, vec.len());
+        out.copy_from_slice(&vec);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+    use crate::image::{
+        ImageDim, ImageInitInfo, SampleLayout, IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+    };
+    use crate::modifiers::DRM_FORMAT_MOD_INVALID;
+    use crate::test_util::dev;
+    use nil_rs_bindings::*;
+    use nvidia_headers::classes::clc597;
+
+    fn sparse_image(
+        dim: ImageDim,
+        p_format: pipe_format,
+        extent_px: Extent4D<units::Pixels>,
+        levels: u32,
+        samples: u32,
+    ) -> Image {
+        let dev = dev(NV_DEVICE_TYPE_IGP, clc597::TURING_A);
+        let info = ImageInitInfo {
+            dim,
+            format: Format::try_from(p_format).unwrap(),
+            extent_px,
+            levels,
+            samples,
+            usage: IMAGE_USAGE_SPARSE_RESIDENCY_BIT,
+            modifier: DRM_FORMAT_MOD_INVALID,
+            explicit_row_stride_B: 0,
+            max_alignment_B: 0,
+        };
+        Image::new(&dev, std::slice::from_ref(&info), 0)
+    }
+
+    fn sparse_images() -> Vec<Image> {
+        let ext = |w, h, d, a| Extent4D::new(w, h, d, a);
+        let mut images = vec![
+            // Big enough to have bindable levels and a mip tail
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(1000, 600, 1, 3),
+                10,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8_UNORM,
+                ext(513, 256, 1, 1),
+                3,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R32G32B32A32_FLOAT,
+                ext(300, 129, 1, 2),
+                4,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_DXT1_RGBA,
+                ext(1024, 1024, 1, 1),
+                11,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R16_UNORM,
+                ext(100, 70, 90, 1),
+                7,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R32G32_UINT,
+                ext(64, 16, 40, 1),
+                2,
+                1,
+            ),
+            // Everything is in the mip tail
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(20, 20, 1, 5),
+                3,
+                1,
+            ),
+            sparse_image(
+                ImageDim::_3D,
+                PIPE_FORMAT_R8_UNORM,
+                ext(8, 8, 8, 1),
+                1,
+                1,
+            ),
+            // No mip tail at all
+            sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R8G8B8A8_UNORM,
+                ext(512, 256, 1, 2),
+                1,
+                1,
+            ),
+        ];
+        for samples in [2, 4, 8, 16] {
+            images.push(sparse_image(
+                ImageDim::_2D,
+                PIPE_FORMAT_R32_UINT,
+                ext(300, 200, 1, 2),
+                1,
+                samples,
+            ));
+        }
+        images
+    }
+
+    fn all_blocks(image: &Image) -> Vec<SparseBlock> {
+        let tail = image.sparse_mip_tail();
+        let mut blocks = Vec::new();
+        for level in 0..tail.first_lod {
+            for layer in 0..image.extent_px.array_len {
+                blocks.extend(image.sparse_blocks(level, layer));
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_sparse_blocks() {
+        for image in sparse_images() {
+            let bl_ext_px = image.sparse_block_extent_px();
+            let bl_size_B = u64::from(image.sparse_block_size_B());
+            assert!(bl_size_B == 1 << 16);
+
+            for block in all_blocks(&image) {
+                assert!(block.offset_B % bl_size_B == 0);
+                assert!(block.offset_B + bl_size_B <= image.size_B);
+                assert!(block.offset_px.x % bl_ext_px.width == 0);
+                assert!(block.offset_px.y % bl_ext_px.height == 0);
+                assert!(block.offset_px.z % bl_ext_px.depth == 0);
+
+                let lvl_ext_px = image.level_extent_px(block.level);
+                assert!(block.extent_px.width > 0);
+                assert!(
+                    block.offset_px.x + block.extent_px.width
+                        <= lvl_ext_px.width
+                );
+                assert!(
+                    block.offset_px.y + block.extent_px.height
+                        <= lvl_ext_px.height
+                );
+                assert!(
+                    block.offset_px.z + block.extent_px.depth
+                        <= lvl_ext_px.depth
+                );
+
+                // Every block is exactly one bind
+                assert!(image.sparse_bind_is_valid(
+                    block.level,
+                    block.offset_px,
+                    block.extent_px,
+                ));
+                let ranges = image.sparse_bind_ranges(
+                    block.level,
+                    block.offset_px,
+                    block.extent_px,
+                );
+                assert!(
+                    ranges
+                        == [SparseBindRange {
+                            image_offset_B: block.offset_B,
+                            mem_offset_B: 0,
+                            size_B: bl_size_B,
+                        }]
+                );
+            }
+
+            // The blocks of a level cover the whole level
+            for level in 0..image.sparse_mip_tail().first_lod {
+                let lvl_ext_px = image.level_extent_px(level);
+                let area: u64 = image
+                    .sparse_blocks(level, 0)
+                    .iter()
+                    .map(|b| {
+                        u64::from(b.extent_px.width)
+                            * u64::from(b.extent_px.height)
+                            * u64::from(b.extent_px.depth)
+                    })
+                    .sum();
+                assert!(
+                    area == u64::from(lvl_ext_px.width)
+                        * u64::from(lvl_ext_px.height)
+                        * u64::from(lvl_ext_px.depth)
+                );
+            }
+
+            // The first pixel of each block lives in that block
+            if image.sample_layout != SampleLayout::_4x4 {
+                for block in all_blocks(&image) {
+                    let texel_B =
+                        image.texel_offset_B(crate::copy::ImageTexel {
+                            level: block.level,
+                            offset_px: block.offset_px,
+                            sample: 0,
+                        });
+                    assert!(texel_B >= block.offset_B);
+                    assert!(texel_B < block.offset_B + bl_size_B);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_c_sparse_blocks() {
+        for image in sparse_images() {
+            let level = image.sparse_mip_tail().first_lod.saturating_sub(1);
+            if level >= image.mip_tail_first_lod {
+                continue;
+            }
+            let layer = image.extent_px.array_len - 1;
+
+            let mut count = 0;
+            unsafe {
+                image.nil_image_sparse_blocks(
+                    level,
+                    layer,
+                    &mut count,
+                    std::ptr::null_mut(),
+                );
+            }
+            let expected = image.sparse_blocks(level, layer);
+            assert!(count == expected.len());
+
+            let mut blocks = Vec::with_capacity(count);
+            unsafe {
+                image.nil_image_sparse_blocks(
+                    level,
+                    layer,
+                    &mut count,
+                    blocks.as_mut_ptr(),
+                );
+                blocks.set_len(count);
+            }
+            assert!(blocks == expected);
+        }
+    }
+
+    #[test]
+    fn test_sparse_blocks_and_mip_tails_cover_image() {
+        for image in sparse_images() {
+            let tail = image.sparse_mip_tail();
+            let mut ranges: Vec<(u64, u64)> = all_blocks(&image)
+                .iter()
+                .map(|b| (b.offset_B, u64::from(image.sparse_block_size_B())))
+                .collect();
+
+            if tail.single {
+                assert!(tail.first_lod == 0);
+                ranges.push((tail.offset_B, tail.size_B));
+            } else if tail.first_lod < image.num_levels {
+                assert!(tail.offset_B % (1 << 16) == 0);
+                assert!(tail.size_B % (1 << 16) == 0);
+                assert!(tail.stride_B % (1 << 16) == 0);
+                for a in 0..image.extent_px.array_len {
+                    ranges.push((
+                        tail.offset_B + u64::from(a) * tail.stride_B,
+                        tail.size_B,
+                    ));
+                }
+            } else {
+                assert!(tail.first_lod == image.num_levels);
+                assert!(tail.size_B == 0);
+            }
+
+            ranges.sort();
+            let mut end_B = 0;
+            for (offset_B, size_B) in ranges {
+                assert!(offset_B == end_B);
+                end_B = offset_B + size_B;
+            }
+            let layers_size_B =
+                image.array_stride_B * u64::from(image.extent_px.array_len);
+            if tail.single {
+                assert!(end_B == image.size_B);
+            } else {
+                assert!(end_B == layers_size_B);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_mip_tail_bind_ranges() {
+        for image in sparse_images() {
+            let tail = image.sparse_mip_tail();
+            let tail_size_B = image.sparse_single_mip_tail_size_B();
+            if tail.first_lod == image.num_levels {
+                assert!(tail_size_B == 0);
+                continue;
+            }
+
+            // Binding the whole thing binds every mip tail
+            let ranges = image.sparse_mip_tail_bind_ranges(0, tail_size_B);
+            if tail.single {
+                assert!(ranges.len() == 1);
+            } else {
+                assert!(ranges.len() == image.extent_px.array_len as usize);
+            }
+            let mut mem_offset_B = 0;
+            for (a, range) in ranges.iter().enumerate() {
+                assert!(range.mem_offset_B == mem_offset_B);
+                assert!(range.size_B == tail.size_B);
+                assert!(
+                    range.image_offset_B
+                        == tail.offset_B + a as u64 * tail.stride_B
+                );
+                mem_offset_B += range.size_B;
+            }
+
+            // A bind straddling two layers gets split
+            if !tail.single && image.extent_px.array_len > 1 {
+                let start_B = tail.size_B - (1 << 16);
+                let ranges =
+                    image.sparse_mip_tail_bind_ranges(start_B, 2 << 16);
+                assert!(
+                    ranges
+                        == [
+                            SparseBindRange {
+                                image_offset_B: tail.offset_B + start_B,
+                                mem_offset_B: 0,
+                                size_B: 1 << 16,
+                            },
+                            SparseBindRange {
+                                image_offset_B: tail.offset_B + tail.stride_B,
+                                mem_offset_B: 1 << 16,
+                                size_B: 1 << 16,
+                            },
+                        ]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_bind_validation() {
+        for image in sparse_images() {
+            let tail = image.sparse_mip_tail();
+            let bl_ext_px = image.sparse_block_extent_px();
+            let layers = image.extent_px.array_len;
+
+            // Mip tail levels can't be bound with image binds
+            for level in tail.first_lod..image.num_levels {
+                let lvl_ext_px = image.level_extent_px(level);
+                let mut extent_px = lvl_ext_px;
+                extent_px.array_len = 1;
+                assert!(!image.sparse_bind_is_valid(
+                    level,
+                    Offset4D::new(0, 0, 0, 0),
+                    extent_px,
+                ));
+            }
+
+            for level in 0..tail.first_lod {
+                let mut lvl_ext_px = image.level_extent_px(level);
+                lvl_ext_px.array_len = 1;
+                let origin = Offset4D::new(0, 0, 0, layers - 1);
+
+                // The whole level
+                assert!(image.sparse_bind_is_valid(level, origin, lvl_ext_px));
+
+                // Past the last layer or several layers at once
+                let past = Offset4D::new(0, 0, 0, layers);
+                assert!(!image.sparse_bind_is_valid(level, past, lvl_ext_px));
+                let mut two_layers = lvl_ext_px;
+                two_layers.array_len = 2;
+                assert!(!image.sparse_bind_is_valid(level, origin, two_layers));
+
+                // Empty
+                let mut empty = lvl_ext_px;
+                empty.width = 0;
+                assert!(!image.sparse_bind_is_valid(level, origin, empty));
+
+                // Past the edge of the level
+                let mut too_wide = lvl_ext_px;
+                too_wide.width += 1;
+                assert!(!image.sparse_bind_is_valid(level, origin, too_wide));
+
+                // Misaligned offsets
+                for offset in [
+                    Offset4D::new(1, 0, 0, 0),
+                    Offset4D::new(0, 1, 0, 0),
+                    Offset4D::new(0, 0, 1, 0),
+                ] {
+                    let mut extent_px = lvl_ext_px;
+                    extent_px.width -= offset.x;
+                    extent_px.height -= offset.y;
+                    if offset.z >= extent_px.depth {
+                        continue;
+                    }
+                    extent_px.depth -= offset.z;
+                    assert!(
+                        !image.sparse_bind_is_valid(level, offset, extent_px)
+                    );
+                }
+
+                // A partial block is only allowed at the edge
+                if lvl_ext_px.width > bl_ext_px.width {
+                    let mut extent_px = lvl_ext_px;
+                    extent_px.width = bl_ext_px.width - 1;
+                    assert!(
+                        !image.sparse_bind_is_valid(level, origin, extent_px)
+                    );
+                    extent_px.width = bl_ext_px.width;
+                    assert!(
+                        image.sparse_bind_is_valid(level, origin, extent_px)
+                    );
+
+                    let x = bl_ext_px.width;
+                    let offset = Offset4D::new(x, 0, 0, 0);
+                    extent_px.width = lvl_ext_px.width - x;
+                    assert!(
+                        image.sparse_bind_is_valid(level, offset, extent_px)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_bind_ranges_match_blocks() {
+        for image in sparse_images() {
+            let tail = image.sparse_mip_tail();
+            let bl_ext_px = image.sparse_block_extent_px();
+            let bl_size_B = u64::from(image.sparse_block_size_B());
+            let layer = image.extent_px.array_len - 1;
+
+            for level in 0..tail.first_lod {
+                let mut lvl_ext_px = image.level_extent_px(level);
+                lvl_ext_px.array_len = 1;
+
+                // Skip the first block in each dimension if there is more
+                // than one so the bind doesn't start at the origin.
+                let start = |lvl: u32, bl: u32| if lvl > bl { bl } else { 0 };
+                let offset_px = Offset4D::new(
+                    start(lvl_ext_px.width, bl_ext_px.width),
+                    start(lvl_ext_px.height, bl_ext_px.height),
+                    start(lvl_ext_px.depth, bl_ext_px.depth),
+                    layer,
+                );
+                let extent_px = Extent4D::new(
+                    lvl_ext_px.width - offset_px.x,
+                    lvl_ext_px.height - offset_px.y,
+                    lvl_ext_px.depth - offset_px.z,
+                    1,
+                );
+
+                let mut expected: Vec<u64> = image
+                    .sparse_blocks(level, layer)
+                    .iter()
+                    .filter(|b| {
+                        b.offset_px.x >= offset_px.x
+                            && b.offset_px.y >= offset_px.y
+                            && b.offset_px.z >= offset_px.z
+                    })
+                    .map(|b| b.offset_B)
+                    .collect();
+
+                // Memory is laid out in the same order as sparse_blocks()
+                let ranges =
+                    image.sparse_bind_ranges(level, offset_px, extent_px);
+                let mut bound = Vec::new();
+                let mut mem_offset_B = 0;
+                for range in ranges {
+                    assert!(range.mem_offset_B == mem_offset_B);
+                    assert!(range.size_B % bl_size_B == 0);
+                    for i in 0..range.size_B / bl_size_B {
+                        bound.push(range.image_offset_B + i * bl_size_B);
+                    }
+                    mem_offset_B += range.size_B;
+                }
+                assert!(bound == expected);
+
+                // Every block is bound exactly once
+                expected.sort();
+                expected.dedup();
+                assert!(expected.len() == bound.len());
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_sparse_1d_is_unsupported() {
+        sparse_image(
+            ImageDim::_1D,
+            PIPE_FORMAT_R8G8B8A8_UNORM,
+            Extent4D::new(4096, 1, 1, 1),
+            1,
+            1,
+        );
+    }
+}
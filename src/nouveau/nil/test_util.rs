@@ -0,0 +1,43 @@
+// Copyright © 2024 Collabora, Ltd.
+// SPDX-License-Identifier: MIT
+
+//! Helpers shared by the unit tests
+
+use nil_rs_bindings::*;
+
+use std::ops::Range;
+
+/// Returns a device with the given type and 3D class and everything else
+/// zeroed
+pub fn dev(type_: nv_device_type, cls_eng3d: u16) -> nv_device_info {
+    // SAFETY: nv_device_info is plain old data
+    let mut dev: nv_device_info = unsafe { std::mem::zeroed() };
+    dev.type_ = type_;
+    dev.cls_eng3d = cls_eng3d;
+    dev
+}
+
+/// A tiny xorshift PRNG so the tests are random but reproducible
+pub struct Rand(u64);
+
+impl Rand {
+    pub fn new(seed: u64) -> Rand {
+        assert!(seed != 0);
+        Rand(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn range(&mut self, range: Range<u32>) -> u32 {
+        range.start + (self.next() % u64::from(range.end - range.start)) as u32
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
@@ -1,8 +1,6 @@
 // Copyright © 2024 Collabora, Ltd.
 // SPDX-License-Identifier: MIT
 
-#![allow(unused_macros)]
-
 extern crate bitview;
 extern crate nvidia_headers;
 
@@ -15,8 +13,6 @@ use nvidia_headers::classes::clb097::MAXWELL_A;
 use nvidia_headers::classes::clc097::tex as clc097;
 use nvidia_headers::classes::clc097::PASCAL_A;
 use nvidia_headers::classes::clc397::VOLTA_A;
-use paste::paste;
-use std::ops::Range;
 
 use crate::extent::{units, Extent4D};
 use crate::format::Format;
@@ -26,30 +22,7 @@ use crate::image::SampleLayout;
 use crate::image::View;
 use crate::image::ViewType;
 use crate::tiling::GOBType;
-
-macro_rules! set_enum {
-    ($th:expr, $cls:ident, $field:ident, $enum:ident) => {
-        paste! {
-            $th.set_field($cls::$field, $cls::[<$field _ $enum>])
-        }
-    };
-}
-
-trait SetUFixed {
-    fn set_ufixed(&mut self, range: Range<usize>, val: f32);
-}
-
-const FIXED_FRAC_BITS: u32 = 8;
-
-impl<T: SetFieldU64> SetUFixed for T {
-    fn set_ufixed(&mut self, range: Range<usize>, val: f32) {
-        assert!(range.len() >= FIXED_FRAC_BITS as usize);
-        let scaled = val * ((1 << FIXED_FRAC_BITS) as f32);
-        let scaled_max = ((1 << range.len()) - 1) as f32;
-        let u_val = scaled.clamp(0.0, scaled_max).round() as u32;
-        self.set_field(range, u_val);
-    }
-}
+use crate::SetUFixed;
 
 fn nv9097_th_v2_source(
     fmt: &nil_tic_format,
@@ -553,36 +526,55 @@ fn nv9097_nil_fill_buffer_tic(
     set_enum!(th, cl9097, TEXHEADV2_TEXTURE_TYPE, ONE_D_BUFFER);
 }
 
+/// The Maxwell+ texture header for 1D buffers
+///
+/// The first word holds the format and swizzle, which we share with the
+/// other header layouts, so it's filled out by nvb097_set_th_bl_0().
+#[derive(BitStruct)]
+#[bitfield(address_lo: u32 = clb097::TEXHEAD_1D_ADDRESS_BITS31TO0)]
+#[bitfield(address_hi: u32 = clb097::TEXHEAD_1D_ADDRESS_BITS47TO32)]
+#[bitfield(header_version: u32 = clb097::TEXHEAD_1D_HEADER_VERSION)]
+#[bitfield(
+    width_minus_one_lo: u32 = clb097::TEXHEAD_1D_WIDTH_MINUS_ONE_BITS15TO0
+)]
+#[bitfield(
+    width_minus_one_hi: u32 = clb097::TEXHEAD_1D_WIDTH_MINUS_ONE_BITS31TO16
+)]
+#[bitfield(texture_type: u32 = clb097::TEXHEAD_1D_TEXTURE_TYPE)]
+#[bitfield(sector_promotion: u32 = clb097::TEXHEAD_1D_SECTOR_PROMOTION)]
+struct TexHead1D([u32; 8]);
+
 fn nvb097_nil_fill_buffer_tic(
     base_address: u64,
     format: Format,
     num_elements: u32,
     desc_out: &mut [u32; 8],
 ) {
-    *desc_out = [0u32; 8];
-    let mut th = BitMutView::new(desc_out);
+    let mut th = TexHead1D([0; 8]);
 
     assert!(format.supports_buffer());
-    nvb097_set_th_bl_0(&mut th, &format, IDENTITY_SWIZZLE);
-
-    th.set_field(clb097::TEXHEAD_1D_ADDRESS_BITS31TO0, base_address as u32);
-    th.set_field(clb097::TEXHEAD_1D_ADDRESS_BITS47TO32, base_address >> 32);
+    nvb097_set_th_bl_0(
+        &mut BitMutView::new(&mut th.0),
+        &format,
+        IDENTITY_SWIZZLE,
+    );
 
-    set_enum!(th, clb097, TEXHEAD_1D_HEADER_VERSION, SELECT_ONE_D_BUFFER);
+    th.set_address_lo(base_address as u32);
+    th.set_address_hi((base_address >> 32) as u32);
 
-    th.set_field(
-        clb097::TEXHEAD_1D_WIDTH_MINUS_ONE_BITS15TO0,
-        (num_elements - 1) & 0xffff,
-    );
-    th.set_field(
-        clb097::TEXHEAD_1D_WIDTH_MINUS_ONE_BITS31TO16,
-        (num_elements - 1) >> 16,
+    th.set_header_version(
+        clb097::TEXHEAD_1D_HEADER_VERSION_SELECT_ONE_D_BUFFER,
     );
 
-    set_enum!(th, clb097, TEXHEAD_1D_TEXTURE_TYPE, ONE_D_BUFFER);
+    th.set_width_minus_one_lo((num_elements - 1) & 0xffff);
+    th.set_width_minus_one_hi((num_elements - 1) >> 16);
+
+    th.set_texture_type(clb097::TEXHEAD_1D_TEXTURE_TYPE_ONE_D_BUFFER);
 
     // TODO: Do we need this?
-    set_enum!(th, clb097, TEXHEAD_1D_SECTOR_PROMOTION, PROMOTE_TO_2_V);
+    th.set_sector_promotion(clb097::TEXHEAD_1D_SECTOR_PROMOTION_PROMOTE_TO_2_V);
+
+    *desc_out = th.0;
 }
 
 impl Image {
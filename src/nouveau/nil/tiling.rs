@@ -9,6 +9,8 @@ use crate::image::{
 };
 use crate::ILog2Ceil;
 
+use std::fmt;
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum GOBType {
@@ -96,6 +98,19 @@ impl Tiling {
     }
 }
 
+impl fmt::Display for Tiling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gob_type {
+            GOBType::Linear => write!(f, "Linear"),
+            GOBType::Fermi8 => write!(
+                f,
+                "Fermi8(x_log2={}, y_log2={}, z_log2={})",
+                self.x_log2, self.y_log2, self.z_log2
+            ),
+        }
+    }
+}
+
 pub fn sparse_block_extent_el(
     format: Format,
     dim: ImageDim,
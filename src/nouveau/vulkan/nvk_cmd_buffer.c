@@ -639,6 +639,7 @@ nvk_cmd_dirty_cbufs_for_descriptors(struct nvk_cmd_buffer *cmd,
          case NVK_CBUF_TYPE_INVALID:
          case NVK_CBUF_TYPE_ROOT_DESC:
          case NVK_CBUF_TYPE_SHADER_DATA:
+         case NVK_CBUF_TYPE_NAK_DEBUG:
             break;
 
          case NVK_CBUF_TYPE_DESC_SET:
@@ -1030,6 +1031,17 @@ nvk_cmd_buffer_get_cbuf_addr(struct nvk_cmd_buffer *cmd,
       };
       return true;
 
+   case NVK_CBUF_TYPE_NAK_DEBUG:
+      if (shader->nak_debug_mem == NULL) {
+         *addr_out = (struct nvk_buffer_address) { .size = 0 };
+      } else {
+         *addr_out = (struct nvk_buffer_address) {
+            .base_addr = shader->nak_debug_mem->va->addr,
+            .size = NAK_DEBUG_CBUF_SIZE,
+         };
+      }
+      return true;
+
    case NVK_CBUF_TYPE_DESC_SET:
       nvk_descriptor_state_get_root(desc, sets[cbuf->desc_set], addr_out);
       return true;
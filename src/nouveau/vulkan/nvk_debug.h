@@ -35,6 +35,9 @@ enum nvk_debug {
 
    /* Force all memory allocations to go to GART */
    NVK_DEBUG_FORCE_GART = 1ull << 7,
+
+   /* Compress images and advertise compressed DRM format modifiers */
+   NVK_DEBUG_COMPRESS = 1ull << 8,
 };
 
 #endif /* NVK_DEBUG_H */
@@ -199,6 +199,7 @@ nvk_get_drm_format_modifier_properties_list(const struct nvk_physical_device *pd
    size_t mod_count = NIL_MAX_DRM_FORMAT_MODS;
    enum pipe_format p_format = nvk_format_to_pipe_format(vk_format);
    nil_drm_format_mods_for_format(&pdev->info, nil_format(p_format),
+                                  nvk_use_compression(pdev),
                                   &mod_count, &mods);
    if (mod_count == 0) {
       p->drmFormatModifierCount = 0;
@@ -790,6 +791,13 @@ nvk_image_init(struct nvk_device *dev,
                           VK_IMAGE_USAGE_VIDEO_ENCODE_DPB_BIT_KHR))
       usage |= NIL_IMAGE_USAGE_VIDEO_BIT;
 
+   /* Host image copies access the memory directly so those images have to
+    * stay uncompressed.
+    */
+   if (nvk_use_compression(pdev) &&
+       !(image->vk.usage & VK_IMAGE_USAGE_HOST_TRANSFER_BIT_EXT))
+      usage |= NIL_IMAGE_USAGE_COMPRESSIBLE_BIT;
+
    uint32_t explicit_row_stride_B = 0;
 
    /* This section is removed by the optimizer for non-ANDROID builds */
@@ -838,6 +846,7 @@ nvk_image_init(struct nvk_device *dev,
             nvk_format_to_pipe_format(pCreateInfo->format);
          image->vk.drm_format_mod =
             nil_select_best_drm_format_mod(&pdev->info, nil_format(p_format),
+                                           nvk_use_compression(pdev),
                                            mod_list_info->drmFormatModifierCount,
                                            mod_list_info->pDrmFormatModifiers);
          assert(image->vk.drm_format_mod != DRM_FORMAT_MOD_INVALID);
@@ -1249,19 +1258,22 @@ nvk_fill_sparse_image_memory_reqs(const struct nil_image *nil,
       nvk_fill_sparse_image_fmt_props(aspects, nil->format.p_format,
                                       nil->dim, nil->sample_layout);
 
-   assert(nil->mip_tail_first_lod <= nil->num_levels);
+   const struct nil_sparse_mip_tail mip_tail = nil_image_sparse_mip_tail(nil);
+   assert(mip_tail.first_lod <= nil->num_levels);
    VkSparseImageMemoryRequirements sparse_memory_reqs = {
       .formatProperties = sparse_format_props,
-      .imageMipTailFirstLod = nil->mip_tail_first_lod,
+      .imageMipTailFirstLod = mip_tail.first_lod,
       .imageMipTailStride = 0,
    };
 
-   if (nil->mip_tail_first_lod == 0) {
-      sparse_memory_reqs.imageMipTailSize = nil->size_B;
+   if (mip_tail.single) {
+      /* The whole image is the mip tail so it's bound with opaque binds */
+      sparse_memory_reqs.imageMipTailSize =
+         nil_image_sparse_single_mip_tail_size_B(nil);
       sparse_memory_reqs.imageMipTailOffset = 0;
-   } else if (nil->mip_tail_first_lod < nil->num_levels) {
+   } else if (mip_tail.first_lod < nil->num_levels) {
       sparse_memory_reqs.imageMipTailSize =
-         nil_image_mip_tail_size_B(nil) * nil->extent_px.array_len;
+         nil_image_sparse_single_mip_tail_size_B(nil);
       sparse_memory_reqs.imageMipTailOffset = NVK_MIP_TAIL_START_OFFSET;
    } else {
       sparse_memory_reqs.imageMipTailSize = 0;
@@ -1553,15 +1565,11 @@ queue_image_plane_bind(struct nvk_queue *queue,
                        const VkSparseImageMemoryBind *bind)
 {
    VK_FROM_HANDLE(nvk_device_memory, mem, bind->memory);
-   uint64_t image_bind_offset_B;
 
    const uint64_t mem_bind_offset_B = bind->memoryOffset;
    const uint32_t layer = bind->subresource.arrayLayer;
    const uint32_t level = bind->subresource.mipLevel;
 
-   const struct nil_tiling plane_tiling = plane->nil.levels[level].tiling;
-   const uint32_t tile_size_B = nil_tiling_size_B(&plane_tiling);
-
    const struct nil_Extent4D_Pixels bind_extent_px = {
       .width = bind->extent.width,
       .height = bind->extent.height,
@@ -1575,76 +1583,32 @@ queue_image_plane_bind(struct nvk_queue *queue,
       .a = layer,
    };
 
-   const struct nil_Extent4D_Pixels level_extent_px =
-      nil_image_level_extent_px(&plane->nil, level);
-   const struct nil_Extent4D_Tiles level_extent_tl =
-      nil_extent4d_px_to_tl(level_extent_px, &plane_tiling,
-                            plane->nil.format,
-                            plane->nil.sample_layout);
-
-   /* Convert the extent and offset to tiles */
-   const struct nil_Extent4D_Tiles bind_extent_tl =
-      nil_extent4d_px_to_tl(bind_extent_px, &plane_tiling,
-                            plane->nil.format,
-                            plane->nil.sample_layout);
-   const struct nil_Offset4D_Tiles bind_offset_tl =
-      nil_offset4d_px_to_tl(bind_offset_px, &plane_tiling,
-                            plane->nil.format,
-                            plane->nil.sample_layout);
-
-   image_bind_offset_B =
-      nil_image_level_layer_offset_B(&plane->nil, level, layer);
-
-   /* We can only bind contiguous ranges, so we'll split the image into rows
-    * of tiles that are guaranteed to be contiguous, and bind in terms of
-    * these rows
-    */
+   assert(nil_image_sparse_bind_is_valid(&plane->nil, level,
+                                         bind_offset_px, bind_extent_px));
 
-   /* First, get the size of the bind. Since we have the extent in terms of
-    * tiles already, we just need to multiply that by the tile size to get
-    * the size in bytes
+   /* We can only bind contiguous ranges so NIL splits the bind into rows of
+    * sparse blocks which are guaranteed to be contiguous.
     */
-   uint64_t row_bind_size_B = bind_extent_tl.width * tile_size_B;
-
-   const uint32_t nvkmd_bind_count = bind_extent_tl.depth *
-                                     bind_extent_tl.height;
+   size_t range_count;
+   nil_image_sparse_bind_ranges(&plane->nil, level, bind_offset_px,
+                                bind_extent_px, &range_count, NULL);
+   STACK_ARRAY(struct nil_sparse_bind_range, ranges, range_count);
+   nil_image_sparse_bind_ranges(&plane->nil, level, bind_offset_px,
+                                bind_extent_px, &range_count, ranges);
+
+   const uint32_t nvkmd_bind_count = range_count;
    STACK_ARRAY(struct nvkmd_ctx_bind, nvkmd_binds, nvkmd_bind_count);
    uint32_t nvkmd_bind_idx = 0;
 
-   /* Second, start walking the binding region in units of tiles, starting
-    * from the third dimension
-    */
-   for (uint32_t z_tl = 0; z_tl < bind_extent_tl.depth; z_tl++) {
-      /* Start walking the rows to be bound */
-      for (uint32_t y_tl = 0; y_tl < bind_extent_tl.height; y_tl++) {
-         /* For the bind offset, get a memory offset to the start of the row
-          * in terms of the bind extent
-          */
-         const uint64_t mem_row_start_tl =
-            y_tl * bind_extent_tl.width +
-            z_tl * bind_extent_tl.width * bind_extent_tl.height;
-
-         const uint32_t image_x_tl = bind_offset_tl.x;
-         const uint32_t image_y_tl = bind_offset_tl.y + y_tl;
-         const uint32_t image_z_tl = bind_offset_tl.z + z_tl;
-
-         /* The image offset is calculated in terms of the level extent */
-         const uint64_t image_row_start_tl =
-            image_x_tl +
-            image_y_tl * level_extent_tl.width +
-            image_z_tl * level_extent_tl.width * level_extent_tl.height;
-
-         nvkmd_binds[nvkmd_bind_idx++] = (struct nvkmd_ctx_bind) {
-            .op = mem ? NVKMD_BIND_OP_BIND : NVKMD_BIND_OP_UNBIND,
-            .va = plane->va,
-            .va_offset_B = image_bind_offset_B +
-                           image_row_start_tl * tile_size_B,
-            .mem = mem ? mem->mem : NULL,
-            .mem_offset_B = mem_bind_offset_B +
-                            mem_row_start_tl * tile_size_B,
-            .range_B = row_bind_size_B,
-         };
-      }
+   for (uint32_t i = 0; i < range_count; i++) {
+      nvkmd_binds[nvkmd_bind_idx++] = (struct nvkmd_ctx_bind) {
+         .op = mem ? NVKMD_BIND_OP_BIND : NVKMD_BIND_OP_UNBIND,
+         .va = plane->va,
+         .va_offset_B = ranges[i].image_offset_B,
+         .mem = mem ? mem->mem : NULL,
+         .mem_offset_B = mem_bind_offset_B + ranges[i].mem_offset_B,
+         .range_B = ranges[i].size_B,
+      };
    }
 
    assert(nvkmd_bind_idx == nvkmd_bind_count);
@@ -1652,6 +1616,7 @@ queue_image_plane_bind(struct nvk_queue *queue,
                                     nvkmd_bind_count, nvkmd_binds);
 
    STACK_ARRAY_FINISH(nvkmd_binds);
+   STACK_ARRAY_FINISH(ranges);
 
    return result;
 }
@@ -1769,14 +1734,8 @@ queue_image_plane_bind_mip_tail(struct nvk_queue *queue,
    nvk_image_plane_size_align_B(nvk_queue_device(queue), image, plane,
                                 &plane_size_B, &plane_align_B);
 
-   const uint64_t mip_tail_offset_B =
-      nil_image_mip_tail_offset_B(&plane->nil);
-   const uint64_t mip_tail_size_B =
-      nil_image_mip_tail_size_B(&plane->nil);
-   const uint64_t mip_tail_stride_B = plane->nil.array_stride_B;
-
    const uint64_t whole_mip_tail_size_B =
-      mip_tail_size_B * plane->nil.extent_px.array_len;
+      nil_image_sparse_single_mip_tail_size_B(&plane->nil);
 
    uint64_t plane_offset_B, mem_offset_B, bind_size_B;
    if (!next_opaque_bind_plane(bind, whole_mip_tail_size_B, plane_align_B,
@@ -1786,44 +1745,28 @@ queue_image_plane_bind_mip_tail(struct nvk_queue *queue,
 
    VK_FROM_HANDLE(nvk_device_memory, mem, bind->memory);
 
-   /* Range within the virtual mip_tail space */
-   const uint64_t mip_bind_start_B = plane_offset_B;
-   const uint64_t mip_bind_end_B = mip_bind_start_B + bind_size_B;
-
-   /* Range of array slices covered by this bind */
-   const uint32_t start_a = mip_bind_start_B / mip_tail_size_B;
-   const uint32_t end_a = DIV_ROUND_UP(mip_bind_end_B, mip_tail_size_B);
-
-   const uint32_t nvkmd_bind_count = end_a - start_a;
+   /* The bind is a range within the virtual single mip tail space which NIL
+    * splits into ranges within the mip tails of the array slices.
+    */
+   size_t range_count;
+   nil_image_sparse_mip_tail_bind_ranges(&plane->nil, plane_offset_B,
+                                         bind_size_B, &range_count, NULL);
+   STACK_ARRAY(struct nil_sparse_bind_range, ranges, range_count);
+   nil_image_sparse_mip_tail_bind_ranges(&plane->nil, plane_offset_B,
+                                         bind_size_B, &range_count, ranges);
+
+   const uint32_t nvkmd_bind_count = range_count;
    STACK_ARRAY(struct nvkmd_ctx_bind, nvkmd_binds, nvkmd_bind_count);
    uint32_t nvkmd_bind_idx = 0;
 
-   for (uint32_t a = start_a; a < end_a; a++) {
-      /* Range within the virtual mip_tail space of this array slice */
-      const uint64_t a_mip_bind_start_B =
-         MAX2(a * mip_tail_size_B, mip_bind_start_B);
-      const uint64_t a_mip_bind_end_B =
-         MIN2((a + 1) * mip_tail_size_B, mip_bind_end_B);
-
-      /* Offset and range within this mip_tail slice */
-      const uint64_t a_offset_B = a_mip_bind_start_B - a * mip_tail_size_B;
-      const uint64_t a_range_B = a_mip_bind_end_B - a_mip_bind_start_B;
-
-      /* Offset within the current bind operation */
-      const uint64_t a_bind_offset_B =
-         a_mip_bind_start_B - mip_bind_start_B;
-
-      /* Offset within the image */
-      const uint64_t a_image_offset_B =
-         mip_tail_offset_B + (a * mip_tail_stride_B) + a_offset_B;
-
+   for (uint32_t i = 0; i < range_count; i++) {
       nvkmd_binds[nvkmd_bind_idx++] = (struct nvkmd_ctx_bind) {
          .op = mem ? NVKMD_BIND_OP_BIND : NVKMD_BIND_OP_UNBIND,
          .va = plane->va,
-         .va_offset_B = a_image_offset_B,
+         .va_offset_B = ranges[i].image_offset_B,
          .mem = mem ? mem->mem : NULL,
-         .mem_offset_B = mem_offset_B + a_bind_offset_B,
-         .range_B = a_range_B,
+         .mem_offset_B = mem_offset_B + ranges[i].mem_offset_B,
+         .range_B = ranges[i].size_B,
       };
    }
 
@@ -1832,6 +1775,7 @@ queue_image_plane_bind_mip_tail(struct nvk_queue *queue,
                                     nvkmd_bind_count, nvkmd_binds);
 
    STACK_ARRAY_FINISH(nvkmd_binds);
+   STACK_ARRAY_FINISH(ranges);
 
    return result;
 }
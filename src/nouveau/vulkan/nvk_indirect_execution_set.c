@@ -8,6 +8,7 @@
 #include "nvk_entrypoints.h"
 #include "nvk_device.h"
 #include "nvk_shader.h"
+#include "nvkmd/nvkmd.h"
 #include "vk_pipeline.h"
 
 static void *
@@ -50,6 +51,16 @@ nvk_ies_cs_qmd_init(const struct nvk_physical_device *pdev,
          };
          break;
 
+      case NVK_CBUF_TYPE_NAK_DEBUG:
+         if (shader->nak_debug_mem != NULL) {
+            qmd_info.cbufs[qmd_info.num_cbufs++] = (struct nak_qmd_cbuf) {
+               .index = c,
+               .addr = shader->nak_debug_mem->va->addr,
+               .size = NAK_DEBUG_CBUF_SIZE,
+            };
+         }
+         break;
+
       default:
          unreachable("Unsupported cbuf type");
       }
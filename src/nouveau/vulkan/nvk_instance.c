@@ -93,6 +93,7 @@ nvk_init_debug_flags(struct nvk_instance *instance)
       { "no_cbuf", NVK_DEBUG_NO_CBUF },
       { "edb_bview", NVK_DEBUG_FORCE_EDB_BVIEW },
       { "gart", NVK_DEBUG_FORCE_GART },
+      { "compress", NVK_DEBUG_COMPRESS },
       { NULL, 0 },
    };
 
@@ -59,6 +59,7 @@ struct lower_descriptors_ctx {
    bool use_nak;
    bool clamp_desc_array_bounds;
    bool indirect_bind;
+   bool reserve_nak_debug_cbuf;
    nir_address_format ubo_addr_format;
    nir_address_format ssbo_addr_format;
 
@@ -423,6 +424,13 @@ build_cbuf_map(nir_shader *nir, struct lower_descriptors_ctx *ctx)
       };
    }
 
+   /* NAK can't know which slots are free so we pick one for it */
+   if (ctx->reserve_nak_debug_cbuf) {
+      ctx->cbuf_map->cbufs[ctx->cbuf_map->cbuf_count++] = (struct nvk_cbuf) {
+         .type = NVK_CBUF_TYPE_NAK_DEBUG,
+      };
+   }
+
    if (ctx->indirect_bind)
       return;
 
@@ -1599,6 +1607,9 @@ nvk_nir_lower_descriptors(nir_shader *nir,
       .ubo_addr_format = nvk_ubo_addr_format(pdev, rs),
    };
 
+   ctx.reserve_nak_debug_cbuf = ctx.use_nak &&
+                                nak_needs_debug_cbuf(pdev->nak);
+
    assert(set_layout_count <= NVK_MAX_SETS);
    for (uint32_t s = 0; s < set_layout_count; s++) {
       if (set_layouts[s] != NULL)
@@ -74,6 +74,12 @@ nvk_use_edb_buffer_views(const struct nvk_physical_device *pdev)
    return pdev->debug_flags & NVK_DEBUG_FORCE_EDB_BVIEW;
 }
 
+static inline bool
+nvk_use_compression(const struct nvk_physical_device *pdev)
+{
+   return pdev->debug_flags & NVK_DEBUG_COMPRESS;
+}
+
 static inline const struct nvk_instance *
 nvk_physical_device_instance(const struct nvk_physical_device *pdev)
 {
@@ -11,6 +11,7 @@
 #include "nvk_physical_device.h"
 #include "nvk_sampler.h"
 #include "nvk_shader.h"
+#include "nvkmd/nvkmd.h"
 
 #include "vk_nir_convert_ycbcr.h"
 #include "vk_pipeline.h"
@@ -86,6 +87,7 @@ nvk_physical_device_compiler_flags(const struct nvk_physical_device *pdev)
 {
    bool no_cbufs = pdev->debug_flags & NVK_DEBUG_NO_CBUF;
    bool use_edb_buffer_views = nvk_use_edb_buffer_views(pdev);
+   bool nak_debug_cbuf = nak_needs_debug_cbuf(pdev->nak);
    uint64_t prog_debug = nvk_cg_get_prog_debug();
    uint64_t prog_optimize = nvk_cg_get_prog_optimize();
    uint64_t nak_stages = nvk_nak_stages(&pdev->info);
@@ -100,6 +102,7 @@ nvk_physical_device_compiler_flags(const struct nvk_physical_device *pdev)
       | (prog_optimize << 8)
       | ((uint64_t)no_cbufs << 12)
       | ((uint64_t)use_edb_buffer_views << 13)
+      | ((uint64_t)nak_debug_cbuf << 14)
       | (nak_stages << 16)
       | (nak_flags << 48);
 }
@@ -560,7 +563,14 @@ nvk_compile_nir_with_nak(const struct nvk_physical_device *pdev,
    if (rs->storage_buffers == VK_PIPELINE_ROBUSTNESS_BUFFER_BEHAVIOR_ROBUST_BUFFER_ACCESS_2_EXT)
       robust2_modes |= nir_var_mem_ssbo;
 
-   shader->nak = nak_compile_shader(nir, dump_asm, pdev->nak, robust2_modes, fs_key);
+   int8_t debug_cbuf = NAK_NO_DEBUG_CBUF;
+   for (uint32_t c = 0; c < shader->cbuf_map.cbuf_count; c++) {
+      if (shader->cbuf_map.cbufs[c].type == NVK_CBUF_TYPE_NAK_DEBUG)
+         debug_cbuf = c;
+   }
+
+   shader->nak = nak_compile_shader(nir, dump_asm, pdev->nak, robust2_modes,
+                                    fs_key, debug_cbuf);
 
    if (!shader->nak)
       return vk_errorf(pdev, VK_ERROR_UNKNOWN, "Internal compiler error in NAK");
@@ -612,6 +622,80 @@ nvk_compile_nir(struct nvk_device *dev, nir_shader *nir,
    return VK_SUCCESS;
 }
 
+static VkResult
+nvk_shader_alloc_nak_debug(struct nvk_device *dev, struct nvk_shader *shader)
+{
+   if (shader->nak == NULL)
+      return VK_SUCCESS;
+
+   const uint32_t profile_size =
+      shader->nak->profile_block_count * sizeof(uint32_t);
+   const uint32_t sanitize_size =
+      shader->nak->sanitize_check_count * sizeof(struct nak_sanitize_error);
+   if (profile_size == 0 && sanitize_size == 0)
+      return VK_SUCCESS;
+
+   /* The cbuf comes first, followed by the buffers it points to */
+   const uint32_t profile_offset = NAK_DEBUG_CBUF_SIZE;
+   const uint32_t sanitize_offset = profile_offset + profile_size;
+   const uint32_t total_size = sanitize_offset + sanitize_size;
+
+   VkResult result = nvkmd_dev_alloc_mapped_mem(dev->nvkmd, &dev->vk.base,
+                                                total_size, 0 /* align_B */,
+                                                NVKMD_MEM_GART,
+                                                NVKMD_MEM_MAP_RDWR,
+                                                &shader->nak_debug_mem);
+   if (result != VK_SUCCESS)
+      return result;
+
+   void *map = shader->nak_debug_mem->map;
+   const uint64_t addr = shader->nak_debug_mem->va->addr;
+   memset(map, 0, total_size);
+   *(uint64_t *)(map + NAK_DEBUG_CBUF_PROFILE_ADDR) = addr + profile_offset;
+   *(uint64_t *)(map + NAK_DEBUG_CBUF_SANITIZE_ADDR) = addr + sanitize_offset;
+
+   return VK_SUCCESS;
+}
+
+static void
+nvk_shader_report_nak_debug(struct nvk_shader *shader)
+{
+   const void *map = shader->nak_debug_mem->map;
+
+   if (shader->nak->profile_block_count > 0) {
+      const uint32_t *counters = map + NAK_DEBUG_CBUF_SIZE;
+      fprintf(stderr, "NAK block profile for %s shader:\n",
+              _mesa_shader_stage_to_string(shader->info.stage));
+      for (uint32_t i = 0; i < shader->nak->profile_block_count; i++) {
+         fprintf(stderr, "    0x%05x: %u\n",
+                 shader->nak->profile_block_offsets[i], counters[i]);
+      }
+   }
+
+   const struct nak_sanitize_error *errors =
+      map + NAK_DEBUG_CBUF_SIZE +
+      shader->nak->profile_block_count * sizeof(uint32_t);
+   for (uint32_t i = 0; i < shader->nak->sanitize_check_count; i++) {
+      if (errors[i].count == 0)
+         continue;
+
+      const struct nak_sanitize_check *check = &shader->nak->sanitize_checks[i];
+      const char *kind;
+      switch (check->kind) {
+      case NAK_SANITIZE_SHARED_BOUNDS: kind = "shared memory"; break;
+      case NAK_SANITIZE_LOCAL_BOUNDS:  kind = "local memory";  break;
+      case NAK_SANITIZE_UNDEF_ADDR:    kind = "undefined address"; break;
+      default: unreachable("Invalid sanitize check kind");
+      }
+
+      fprintf(stderr, "NAK sanitize: %s shader access at 0x%05x failed "
+                      "%u times (%s, %u bytes at 0x%x, limit 0x%x)\n",
+              _mesa_shader_stage_to_string(shader->info.stage),
+              check->offset, errors[i].count, kind, check->size,
+              errors[i].addr, check->limit);
+   }
+}
+
 static VkResult
 nvk_shader_upload(struct nvk_device *dev, struct nvk_shader *shader)
 {
@@ -947,6 +1031,11 @@ nvk_shader_destroy(struct vk_device *vk_dev,
                     shader->upload_size);
    }
 
+   if (shader->nak_debug_mem != NULL) {
+      nvk_shader_report_nak_debug(shader);
+      nvkmd_mem_unref(shader->nak_debug_mem);
+   }
+
    if (shader->nak) {
       nak_shader_bin_destroy(shader->nak);
    } else {
@@ -1006,6 +1095,12 @@ nvk_compile_shader(struct nvk_device *dev,
       return result;
    }
 
+   result = nvk_shader_alloc_nak_debug(dev, shader);
+   if (result != VK_SUCCESS) {
+      nvk_shader_destroy(&dev->vk, &shader->vk, pAllocator);
+      return result;
+   }
+
    if (info->stage == MESA_SHADER_FRAGMENT) {
       if (state != NULL && state->ms != NULL) {
          shader->sample_shading_enable = state->ms->sample_shading_enable;
@@ -1182,6 +1277,14 @@ nvk_shader_serialize(struct vk_device *vk_dev,
    if (shader->nak != NULL && shader->nak->asm_str != NULL)
       return false;
 
+   /* Or instrumented shaders since we need the NAK debug info to make sense
+    * of what they write.
+    */
+   for (uint32_t c = 0; c < shader->cbuf_map.cbuf_count; c++) {
+      if (shader->cbuf_map.cbufs[c].type == NVK_CBUF_TYPE_NAK_DEBUG)
+         return false;
+   }
+
    blob_write_bytes(blob, &shader->info, sizeof(shader->info));
    blob_write_bytes(blob, &shader->cbuf_map, sizeof(shader->cbuf_map));
    blob_write_bytes(blob, &shader->sample_shading_enable,
@@ -60,6 +60,9 @@ enum ENUM_PACKED nvk_cbuf_type {
    NVK_CBUF_TYPE_DESC_SET,
    NVK_CBUF_TYPE_DYNAMIC_UBO,
    NVK_CBUF_TYPE_UBO_DESC,
+
+   /* Holds the addresses of the buffers written by NAK instrumentation */
+   NVK_CBUF_TYPE_NAK_DEBUG,
 };
 
 PRAGMA_DIAGNOSTIC_PUSH
@@ -114,6 +117,11 @@ struct nvk_shader {
    /* Address of the start of the shader data section */
    uint64_t data_addr;
 
+   /* Bound as NVK_CBUF_TYPE_NAK_DEBUG.  Only allocated for shaders NAK
+    * instrumented, see nak_needs_debug_cbuf().
+    */
+   struct nvkmd_mem *nak_debug_mem;
+
    uint16_t push_dw_count;
    uint16_t vtgm_push_dw_count;
    uint32_t *push_dw;